@@ -0,0 +1,388 @@
+//! `--broker`: everything a client of the listener sends is relayed to all
+//! the other clients, like `ncat --broker`.
+//!
+//! `--chat` relays whole lines instead, each labelled with its sender, and
+//! announces clients as they come and go.
+
+use crate::{
+    args::{Args, Protocol},
+    network::{report_transfer, stdin_unless_disabled},
+    stats::{ExitReason, Transfer},
+};
+use anyhow::Result;
+use log::{error, info};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Chunks waiting for a client before it counts as too slow and is dropped.
+const QUEUE_LENGTH: usize = 64;
+
+/// A connected client as the others see it.
+struct Subscriber {
+    peer: SocketAddr,
+    queue: SyncSender<Arc<[u8]>>,
+    stream: TcpStream,
+}
+
+struct Broker {
+    clients: Mutex<HashMap<u64, Subscriber>>,
+    stdout: bool,
+    chat: bool,
+}
+
+/// Serves clients of `listener` concurrently until the process is stopped.
+/// In a chat the server takes part too, typing on stdin and reading stdout.
+pub fn serve(listener: &TcpListener, args: &Args, timeout: Option<Duration>) -> Result<()> {
+    let broker = Broker::new(args.broker_stdout || args.chat, args.chat);
+    let input = if args.chat {
+        Some(stdin_unless_disabled(args)?)
+    } else {
+        None
+    };
+    broker.run(listener, input, args, timeout)
+}
+
+/// The label of a chat line, after ncat's.
+fn label(id: u64) -> String {
+    format!("<user{}>", id)
+}
+
+impl Broker {
+    fn new(stdout: bool, chat: bool) -> Self {
+        Broker {
+            clients: Mutex::new(HashMap::new()),
+            stdout,
+            chat,
+        }
+    }
+
+    /// Serves clients and, given `input`, broadcasts its lines as the server's.
+    fn run(
+        &self,
+        listener: &TcpListener,
+        input: Option<Box<dyn Read + Send>>,
+        args: &Args,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        thread::scope(|scope| {
+            if let Some(input) = input {
+                scope.spawn(move || {
+                    for line in BufReader::new(input).split(b'\n') {
+                        match line {
+                            Ok(line) => self.publish(None, &chat_line("<server>", &line)),
+                            Err(e) => {
+                                error!("Failed to read stdin: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+            for (id, stream) in (1..).zip(listener.incoming()) {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                scope.spawn(move || {
+                    if let Err(e) = self.serve_client(id, stream, args, timeout) {
+                        error!("Failed to handle connection: {:#}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    /// Publishes what the client sends and writes it what the others send,
+    /// until it disconnects or is dropped for falling behind.
+    fn serve_client(
+        &self,
+        id: u64,
+        stream: TcpStream,
+        args: &Args,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let peer = stream.peer_addr()?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        let (queue, chunks) = mpsc::sync_channel(QUEUE_LENGTH);
+        let subscriber = Subscriber {
+            peer,
+            queue,
+            stream: stream.try_clone()?,
+        };
+        let transfer = Transfer::new(peer, Protocol::Tcp);
+        let count = {
+            let mut clients = self.clients.lock().unwrap();
+            clients.insert(id, subscriber);
+            clients.len()
+        };
+        info!("{} joined the broker, {} clients connected", peer, count);
+        if self.chat {
+            let announcement = format!("{} is connected as {}.", peer, label(id));
+            self.publish(Some(id), &chat_line("<announce>", announcement.as_bytes()));
+        }
+
+        let result = thread::scope(|scope| {
+            let writer = scope.spawn(|| deliver(&stream, chunks, &transfer));
+            let mut buffer = vec![0u8; args.buffer_size];
+            let mut lines = Vec::new();
+            let received = loop {
+                match (&stream).read(&mut buffer) {
+                    Ok(0) => break Ok(()),
+                    Ok(n) if self.chat => {
+                        transfer.add_received(n as u64);
+                        lines.extend_from_slice(&buffer[..n]);
+                        self.publish_lines(id, &mut lines, args.buffer_size);
+                    }
+                    Ok(n) => {
+                        transfer.add_received(n as u64);
+                        self.publish(Some(id), &buffer[..n]);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => break Err(e),
+                }
+            };
+            // Without its queue the writer finishes what is left and stops.
+            self.clients.lock().unwrap().remove(&id);
+            if self.chat {
+                // A last line without a newline still gets through.
+                self.publish_lines(id, &mut lines, 0);
+                let announcement = format!("{} is disconnected.", label(id));
+                self.publish(Some(id), &chat_line("<announce>", announcement.as_bytes()));
+            }
+            let delivered = writer.join().expect("broker writer panicked");
+            received.and(delivered)
+        });
+        let _ = stream.shutdown(Shutdown::Both);
+        info!("{} left the broker", peer);
+
+        let result = result.map(|()| ExitReason::Eof).map_err(Into::into);
+        report_transfer(&transfer, &result, args);
+        result.map(|_| ())
+    }
+
+    /// Relays the complete lines in `pending` with the sender's label, leaving
+    /// a partial one for the next read unless it has reached `limit` bytes.
+    fn publish_lines(&self, from: u64, pending: &mut Vec<u8>, limit: usize) {
+        let end = match pending.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => newline + 1,
+            None if !pending.is_empty() && pending.len() >= limit => pending.len(),
+            None => return,
+        };
+        let label = label(from);
+        let message: Vec<u8> = pending[..end]
+            .split_inclusive(|&b| b == b'\n')
+            .flat_map(|line| chat_line(&label, line.strip_suffix(b"\n").unwrap_or(line)))
+            .collect();
+        pending.drain(..end);
+        self.publish(Some(from), &message);
+    }
+
+    /// Queues `data` for every client but the one it came from (the server
+    /// when None), dropping the clients whose queue is full rather than
+    /// waiting for them.
+    fn publish(&self, from: Option<u64>, data: &[u8]) {
+        if self.stdout && from.is_some() {
+            let mut stdout = io::stdout().lock();
+            if let Err(e) = stdout.write_all(data).and_then(|()| stdout.flush()) {
+                error!("Failed to write to stdout: {}", e);
+            }
+        }
+        let chunk: Arc<[u8]> = data.into();
+        self.clients.lock().unwrap().retain(|&id, client| {
+            if Some(id) == from {
+                return true;
+            }
+            match client.queue.try_send(chunk.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    let _ = writeln!(
+                        io::stderr(),
+                        "netpipe: disconnecting {}, which fell {} chunks behind",
+                        client.peer,
+                        QUEUE_LENGTH
+                    );
+                    let _ = client.stream.shutdown(Shutdown::Both);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// `line` with `label` in front and a newline at the end.
+fn chat_line(label: &str, line: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(label.len() + line.len() + 2);
+    message.extend_from_slice(label.as_bytes());
+    message.push(b' ');
+    message.extend_from_slice(line);
+    message.push(b'\n');
+    message
+}
+
+/// Writes the chunks queued for a client until the queue is dropped.
+fn deliver(
+    mut stream: &TcpStream,
+    chunks: Receiver<Arc<[u8]>>,
+    transfer: &Transfer,
+) -> io::Result<()> {
+    for chunk in chunks {
+        if let Err(e) = stream.write_all(&chunk) {
+            // Wake up the reader, the client is gone.
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(e);
+        }
+        transfer.add_sent(chunk.len() as u64);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn read_exactly(stream: &mut TcpStream, len: usize) -> String {
+        let mut data = vec![0u8; len];
+        stream.read_exact(&mut data).unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    fn connected(broker: &Broker) -> usize {
+        broker.clients.lock().unwrap().len()
+    }
+
+    fn wait_for(broker: &Broker, count: usize) {
+        while connected(broker) != count {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_clients_hear_each_other() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let broker = Arc::new(Broker::new(false, false));
+        let server = broker.clone();
+        thread::spawn(move || {
+            let args = Args::parse_from(["test", "--listen", "--broker"]);
+            server.run(&listener, None, &args, Some(Duration::from_secs(5)))
+        });
+
+        let mut clients: Vec<TcpStream> = (0..3)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        wait_for(&broker, 3);
+
+        clients[1].write_all(b"hello").unwrap();
+        assert_eq!(read_exactly(&mut clients[0], 5), "hello");
+        assert_eq!(read_exactly(&mut clients[2], 5), "hello");
+        clients[2].write_all(b"world").unwrap();
+        assert_eq!(read_exactly(&mut clients[0], 5), "world");
+        assert_eq!(read_exactly(&mut clients[1], 5), "world");
+
+        // A client that leaves is forgotten, and the others carry on.
+        clients.remove(0);
+        wait_for(&broker, 2);
+        clients[0].write_all(b"again").unwrap();
+        assert_eq!(read_exactly(&mut clients[1], 5), "again");
+    }
+
+    #[test]
+    fn test_slow_clients_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let broker = Arc::new(Broker::new(false, false));
+        let server = broker.clone();
+        thread::spawn(move || {
+            let args = Args::parse_from(["test", "--listen", "--broker"]);
+            server.run(&listener, None, &args, None)
+        });
+
+        let mut publisher = TcpStream::connect(address).unwrap();
+        // This client never reads, so its queue fills once the socket buffers do.
+        let _laggard = TcpStream::connect(address).unwrap();
+        wait_for(&broker, 2);
+        let chunk = vec![0u8; 64 * 1024];
+        while connected(&broker) == 2 {
+            publisher.write_all(&chunk).unwrap();
+        }
+        assert_eq!(connected(&broker), 1);
+    }
+
+    #[test]
+    fn test_chat_labels_lines_and_announces_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // The server's stdin, kept open until the clients are there.
+        let (mut stdin, server_input) = {
+            let pipe = TcpListener::bind("127.0.0.1:0").unwrap();
+            let writer = TcpStream::connect(pipe.local_addr().unwrap()).unwrap();
+            (writer, pipe.accept().unwrap().0)
+        };
+        let broker = Arc::new(Broker::new(false, true));
+        let server = broker.clone();
+        thread::spawn(move || {
+            let args = Args::parse_from(["test", "--listen", "--chat", "--buffer-size", "512"]);
+            let input: Box<dyn Read + Send> = Box::new(server_input);
+            server.run(&listener, Some(input), &args, Some(Duration::from_secs(5)))
+        });
+
+        let mut first = TcpStream::connect(address).unwrap();
+        wait_for(&broker, 1);
+        let mut second = TcpStream::connect(address).unwrap();
+        wait_for(&broker, 2);
+        let mut first_sees = BufReader::new(first.try_clone().unwrap()).lines();
+        let mut second_sees = BufReader::new(second.try_clone().unwrap()).lines();
+        let joined = first_sees.next().unwrap().unwrap();
+        assert!(joined.starts_with("<announce> 127.0.0.1:"), "{}", joined);
+        assert!(joined.ends_with(" is connected as <user2>."), "{}", joined);
+
+        // Lines split across reads arrive whole, and longer ones in pieces.
+        first.write_all(b"hel").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        first.write_all(b"lo\nwor").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        first.write_all(b"ld\n").unwrap();
+        assert_eq!(second_sees.next().unwrap().unwrap(), "<user1> hello");
+        assert_eq!(second_sees.next().unwrap().unwrap(), "<user1> world");
+        second.write_all(&[b'x'; 512]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        second.write_all(b"yz\n").unwrap();
+        let piece = first_sees.next().unwrap().unwrap();
+        assert_eq!(piece, format!("<user2> {}", "x".repeat(512)));
+        assert_eq!(first_sees.next().unwrap().unwrap(), "<user2> yz");
+
+        stdin.write_all(b"from the server\n").unwrap();
+        assert_eq!(
+            first_sees.next().unwrap().unwrap(),
+            "<server> from the server"
+        );
+        assert_eq!(
+            second_sees.next().unwrap().unwrap(),
+            "<server> from the server"
+        );
+
+        second.write_all(b"bye").unwrap();
+        second.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(first_sees.next().unwrap().unwrap(), "<user2> bye");
+        assert_eq!(
+            first_sees.next().unwrap().unwrap(),
+            "<announce> <user2> is disconnected."
+        );
+    }
+}
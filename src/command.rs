@@ -1,11 +1,30 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::info;
 use std::{
     io::{self, Read, Write},
     process::{Command, Stdio},
+    thread,
 };
 
-pub fn execute_command<R: Read>(mut input: R, command: &str) -> Result<()> {
+pub fn execute_command<R: Read + Send>(input: R, command: &str) -> Result<()> {
+    run_command(input, command, io::stdout())
+}
+
+/// Runs `command` with `input` as its stdin and returns what it wrote to stdout.
+pub fn capture_command<R: Read + Send>(input: R, command: &str) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    run_command(input, command, &mut output)?;
+    Ok(output)
+}
+
+/// Runs `command`, feeding `input` to its stdin while its stdout is copied to
+/// `output` as it is produced, so neither side is ever held in memory. The
+/// child's stderr goes straight to ours.
+pub fn run_command<R: Read + Send, W: Write>(
+    mut input: R,
+    command: &str,
+    mut output: W,
+) -> Result<()> {
     info!("Executing command: {}", command);
     let mut child = Command::new("sh")
         .arg("-c")
@@ -13,15 +32,52 @@ pub fn execute_command<R: Read>(mut input: R, command: &str) -> Result<()> {
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()?;
+    let mut stdin = child.stdin.take().expect("child stdin is piped");
+    let mut stdout = child.stdout.take().expect("child stdout is piped");
 
-    if let Some(ref mut stdin) = child.stdin {
-        let mut buffer = Vec::new();
-        input.read_to_end(&mut buffer)?;
-        stdin.write_all(&buffer)?;
-    }
+    thread::scope(|scope| -> Result<()> {
+        // Dropping `stdin` at the end of the copy is what signals EOF to the child.
+        let feeder = scope.spawn(move || match io::copy(&mut input, &mut stdin) {
+            // The command may exit without reading all of its input.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => result.map(|_| ()),
+        });
+        io::copy(&mut stdout, &mut output)?;
+        output.flush()?;
+        feeder
+            .join()
+            .map_err(|_| anyhow!("Command input thread panicked"))??;
+        Ok(())
+    })?;
 
-    let output = child.wait_with_output()?;
-    io::stdout().write_all(&output.stdout)?;
-    io::stderr().write_all(&output.stderr)?;
+    child.wait()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_command_output() {
+        let output = capture_command(&b"hello"[..], "tr a-z A-Z").unwrap();
+        assert_eq!(output, b"HELLO");
+    }
+
+    #[test]
+    fn test_run_command_streams_large_payloads() {
+        // Far more than a pipe buffer holds in either direction, which deadlocks
+        // unless input and output are copied at the same time.
+        let size = 8 * 1024 * 1024;
+        let input = io::repeat(b'x').take(size);
+        let output = capture_command(input, "cat").unwrap();
+        assert_eq!(output.len() as u64, size);
+    }
+
+    #[test]
+    fn test_run_command_tolerates_unread_input() {
+        let input = io::repeat(b'y').take(1024 * 1024);
+        let output = capture_command(input, "head -c 3").unwrap();
+        assert_eq!(output, b"yyy");
+    }
+}
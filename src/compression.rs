@@ -0,0 +1,214 @@
+//! Stream compression for `--compress`, `--decompress` and
+//! `--auto-decompress`.
+//!
+//! Both directions wrap the copy loops' reader or writer, so memory use stays
+//! bounded however long the stream is. Compressed output only leaves once the
+//! compressor has gathered enough input, which suits bulk transfers rather
+//! than interactive sessions.
+
+use crate::args::Compression;
+use flate2::{read::GzEncoder, write::GzDecoder};
+use log::info;
+use std::{
+    io::{self, Read, Write},
+    mem,
+};
+
+/// Compresses everything read from `input`.
+pub fn compressed(
+    input: Box<dyn Read + Send>,
+    algorithm: Compression,
+    level: Option<i32>,
+) -> io::Result<Box<dyn Read + Send>> {
+    let level = level.unwrap_or(algorithm.default_level());
+    Ok(match algorithm {
+        Compression::Gzip => Box::new(GzEncoder::new(
+            input,
+            flate2::Compression::new(level as u32),
+        )),
+        Compression::Zstd => Box::new(zstd::stream::read::Encoder::new(input, level)?),
+    })
+}
+
+/// Decompresses everything written through it into `output`.
+pub fn decompressed(output: Box<dyn Write>, algorithm: Compression) -> io::Result<Box<dyn Write>> {
+    Ok(match algorithm {
+        Compression::Gzip => Box::new(GzDecoder::new(output)),
+        Compression::Zstd => Box::new(zstd::stream::write::Decoder::new(output)?),
+    })
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// What the first bytes of a stream say about it.
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+    /// Too few bytes to tell yet.
+    Undecided,
+    Plain,
+    Compressed(Compression),
+}
+
+fn sniff(head: &[u8]) -> Sniffed {
+    let magics = [
+        (GZIP_MAGIC, Compression::Gzip),
+        (ZSTD_MAGIC, Compression::Zstd),
+    ];
+    for (magic, algorithm) in magics {
+        if head.starts_with(magic) {
+            return Sniffed::Compressed(algorithm);
+        }
+    }
+    if magics
+        .iter()
+        .any(|(magic, _)| head.len() < magic.len() && magic.starts_with(head))
+    {
+        return Sniffed::Undecided;
+    }
+    Sniffed::Plain
+}
+
+/// Decompresses the stream written through it if it starts with a gzip or
+/// zstd magic number, and passes it on untouched otherwise.
+///
+/// Bytes that might still turn out to be a magic number are held back until
+/// the next write settles it, or until the writer is dropped.
+pub struct AutoDecompress {
+    inner: Option<Box<dyn Write>>,
+    decided: bool,
+    head: Vec<u8>,
+}
+
+impl AutoDecompress {
+    pub fn new(inner: Box<dyn Write>) -> Self {
+        AutoDecompress {
+            inner: Some(inner),
+            decided: false,
+            head: Vec::new(),
+        }
+    }
+}
+
+impl Write for AutoDecompress {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.decided {
+            return self.inner.as_mut().unwrap().write(buf);
+        }
+        self.head.extend_from_slice(buf);
+        let inner = match sniff(&self.head) {
+            Sniffed::Undecided => return Ok(buf.len()),
+            Sniffed::Plain => self.inner.take().unwrap(),
+            Sniffed::Compressed(algorithm) => {
+                info!("Received data is {}-compressed, decompressing", algorithm);
+                decompressed(self.inner.take().unwrap(), algorithm)?
+            }
+        };
+        let inner = self.inner.insert(inner);
+        self.decided = true;
+        inner.write_all(&mem::take(&mut self.head))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Some(inner) if self.decided => inner.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for AutoDecompress {
+    fn drop(&mut self) {
+        // A stream shorter than a magic number is plain data.
+        if let Some(inner) = &mut self.inner {
+            if !self.decided {
+                let _ = inner.write_all(&self.head);
+            }
+            let _ = inner.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Writes `data` through [`AutoDecompress`] `chunk` bytes at a time.
+    fn receive(data: &[u8], chunk: usize) -> Vec<u8> {
+        let output = Shared::default();
+        {
+            let mut auto = AutoDecompress::new(Box::new(output.clone()));
+            for piece in data.chunks(chunk) {
+                auto.write_all(piece).unwrap();
+                auto.flush().unwrap();
+            }
+        }
+        output.0.take()
+    }
+
+    fn compress(data: &[u8], algorithm: Compression) -> Vec<u8> {
+        let mut output = Vec::new();
+        compressed(Box::new(io::Cursor::new(data.to_vec())), algorithm, None)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_sniff_magic_numbers() {
+        assert_eq!(sniff(&[]), Sniffed::Undecided);
+        assert_eq!(sniff(&[0x1f]), Sniffed::Undecided);
+        assert_eq!(sniff(&[0x1f, 0x8b]), Sniffed::Compressed(Compression::Gzip));
+        assert_eq!(sniff(&[0x28, 0xb5, 0x2f]), Sniffed::Undecided);
+        assert_eq!(
+            sniff(&[0x28, 0xb5, 0x2f, 0xfd, 0]),
+            Sniffed::Compressed(Compression::Zstd)
+        );
+        assert_eq!(sniff(&[0x1f, 0x8c]), Sniffed::Plain);
+        assert_eq!(sniff(b"hello"), Sniffed::Plain);
+    }
+
+    #[test]
+    fn test_auto_decompress_inflates_compressed_input() {
+        let data = b"archive contents\n".repeat(100);
+        for algorithm in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compress(&data, algorithm);
+            for chunk in [1, 3, 4096] {
+                assert_eq!(receive(&compressed, chunk), data, "{} {}", algorithm, chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_decompress_passes_plain_input_through() {
+        for chunk in [1, 2, 64] {
+            assert_eq!(receive(b"plain text", chunk), b"plain text");
+            assert_eq!(receive(b"\x1f\x8cnot gzip", chunk), b"\x1f\x8cnot gzip");
+            assert_eq!(receive(b"\x28\xb5\x2f!", chunk), b"\x28\xb5\x2f!");
+        }
+    }
+
+    #[test]
+    fn test_auto_decompress_keeps_input_shorter_than_the_magic() {
+        assert_eq!(receive(&[0x1f], 1), [0x1f]);
+        assert_eq!(receive(&[0x28, 0xb5, 0x2f], 1), [0x28, 0xb5, 0x2f]);
+        assert!(receive(&[], 1).is_empty());
+    }
+}
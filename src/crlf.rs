@@ -0,0 +1,161 @@
+//! Line-ending conversion for `-C/--crlf` and `--strip-cr`.
+
+use std::io::{self, Read, Write};
+
+/// Turns bare `\n` into `\r\n` while reading, leaving existing `\r\n` alone even
+/// when a read boundary falls between the two bytes.
+pub struct Crlf<R> {
+    inner: R,
+    last_was_cr: bool,
+    pending_lf: bool,
+    scratch: Vec<u8>,
+}
+
+impl<R> Crlf<R> {
+    pub fn new(inner: R) -> Self {
+        Crlf {
+            inner,
+            last_was_cr: false,
+            pending_lf: false,
+            scratch: Vec::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for Crlf<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pending_lf {
+            self.pending_lf = false;
+            buf[0] = b'\n';
+            return Ok(1);
+        }
+
+        // Every byte may grow into two, so read at most half a buffer.
+        let want = (buf.len() / 2).max(1);
+        self.scratch.resize(want, 0);
+        let n = self.inner.read(&mut self.scratch[..want])?;
+        let mut out = 0;
+        for &byte in &self.scratch[..n] {
+            if byte == b'\n' && !self.last_was_cr {
+                buf[out] = b'\r';
+                out += 1;
+                if out == buf.len() {
+                    self.pending_lf = true;
+                    self.last_was_cr = false;
+                    continue;
+                }
+            }
+            buf[out] = byte;
+            out += 1;
+            self.last_was_cr = byte == b'\r';
+        }
+        Ok(out)
+    }
+}
+
+/// Drops the `\r` of every `\r\n` while writing, holding back a trailing `\r`
+/// until the next write shows whether a `\n` follows it.
+pub struct StripCr<W: Write> {
+    inner: W,
+    pending_cr: bool,
+}
+
+impl<W: Write> StripCr<W> {
+    pub fn new(inner: W) -> Self {
+        StripCr {
+            inner,
+            pending_cr: false,
+        }
+    }
+}
+
+impl<W: Write> Write for StripCr<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut cleaned = Vec::with_capacity(buf.len() + 1);
+        for &byte in buf {
+            if self.pending_cr && byte != b'\n' {
+                cleaned.push(b'\r');
+            }
+            self.pending_cr = byte == b'\r';
+            if !self.pending_cr {
+                cleaned.push(byte);
+            }
+        }
+        self.inner.write_all(&cleaned)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for StripCr<W> {
+    fn drop(&mut self) {
+        // A lone `\r` at the very end was never part of a `\r\n`.
+        if self.pending_cr {
+            let _ = self.inner.write_all(b"\r");
+            let _ = self.inner.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads `input` through `Crlf`, `chunk` bytes of input at a time.
+    fn convert(input: &[u8], chunk: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut crlf = Crlf::new(input);
+        let mut buffer = vec![0u8; chunk * 2];
+        loop {
+            let n = crlf.read(&mut buffer).unwrap();
+            if n == 0 {
+                return output;
+            }
+            output.extend_from_slice(&buffer[..n]);
+        }
+    }
+
+    #[test]
+    fn test_crlf_converts_bare_lf_only() {
+        let input = b"HELO example.com\nMAIL FROM:<a@b>\r\nDATA\n";
+        let expected = b"HELO example.com\r\nMAIL FROM:<a@b>\r\nDATA\r\n";
+        for chunk in 1..=input.len() {
+            assert_eq!(convert(input, chunk), expected, "chunk {}", chunk);
+        }
+    }
+
+    #[test]
+    fn test_crlf_with_one_byte_buffer() {
+        let mut crlf = Crlf::new(&b"a\n"[..]);
+        let mut output = Vec::new();
+        crlf.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"a\r\n");
+
+        let mut crlf = Crlf::new(&b"\n\n"[..]);
+        let mut byte = [0u8; 1];
+        let mut output = Vec::new();
+        while crlf.read(&mut byte).unwrap() == 1 {
+            output.push(byte[0]);
+        }
+        assert_eq!(output, b"\r\n\r\n");
+    }
+
+    #[test]
+    fn test_strip_cr_across_writes() {
+        let mut output = Vec::new();
+        {
+            let mut strip = StripCr::new(&mut output);
+            for piece in [&b"one\r"[..], b"\ntwo\r", b"x\r\n", b"end\r"] {
+                strip.write_all(piece).unwrap();
+                strip.flush().unwrap();
+            }
+        }
+        assert_eq!(output, b"one\ntwo\rx\nend\r");
+    }
+}
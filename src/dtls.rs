@@ -0,0 +1,738 @@
+//! DTLS 1.2 for `--tls` over UDP, on OpenSSL. Built with the `dtls` feature.
+//!
+//! Server certificates go through the same verifiers as TCP TLS, so
+//! `--tls-ca`, `--tls-pin` and `--insecure` mean the same here, and
+//! `--tls-keylog` logs the session secrets. Client certificates and ALPN are
+//! not supported.
+//!
+//! [`Session`] does no I/O of its own: OpenSSL reads and writes a queue of
+//! datagrams in memory, so that one socket can serve many peers. Every
+//! application datagram travels in a record of its own, so datagram
+//! boundaries survive. OpenSSL resends lost handshake flights on its own
+//! timer, which the client looks at while it waits for answers. The server
+//! answers a ClientHello with a cookie (HelloVerifyRequest) and keeps nothing
+//! for the peer until the cookie comes back, and it gives up on handshakes
+//! that stall.
+
+use crate::args::Args;
+use crate::tls;
+use anyhow::{anyhow, bail, Context, Result};
+use foreign_types::ForeignTypeRef;
+use log::debug;
+use openssl::{
+    error::ErrorStack,
+    pkey::PKey,
+    ssl::{
+        ErrorCode, Ssl, SslContext, SslContextBuilder, SslMethod, SslOptions, SslStream,
+        SslVerifyMode, SslVersion,
+    },
+    x509::X509,
+};
+use ring::hmac;
+use rustls::{
+    client::danger::ServerCertVerifier,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    KeyLog,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    mem,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const HANDSHAKE: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+/// Content type, version, epoch, sequence number and length.
+const RECORD_HEADER: usize = 13;
+
+/// Handshake datagrams stay well below the IPv6 minimum MTU of 1280.
+const MTU: u32 = 1200;
+/// The most application data one record, and so one datagram, can carry.
+pub const MAX_DATA: usize = 16384;
+
+/// How often the client checks OpenSSL's retransmission timer while it
+/// waits for the server.
+const TIMER_CHECK: Duration = Duration::from_millis(100);
+/// Without `--timeout` the handshake gives up after this long.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The most handshakes a listener keeps going at once.
+const MAX_HANDSHAKES: usize = 64;
+/// A listener forgets a handshake that has not finished this long after the
+/// peer's cookie came back.
+const HANDSHAKE_EXPIRY: Duration = Duration::from_secs(10);
+
+extern "C" {
+    // Part of libssl, but not of openssl-sys. The `BIO_ADDR`s are opaque.
+    fn DTLSv1_listen(ssl: *mut openssl_sys::SSL, client: *mut libc::c_void) -> libc::c_int;
+    fn BIO_ADDR_new() -> *mut libc::c_void;
+    fn BIO_ADDR_free(address: *mut libc::c_void);
+}
+
+/// How the client's check of the server certificate went, once it ran.
+type Verdict = Arc<Mutex<Option<Result<(), rustls::Error>>>>;
+
+/// The datagrams between OpenSSL and the socket: each read takes one
+/// received datagram, and each write is a datagram to send.
+#[derive(Default)]
+struct Datagrams {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(datagram) = self.incoming.pop_front() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok(n)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a received datagram amounted to.
+#[derive(Default)]
+pub struct Received {
+    /// Application datagrams, in the order they arrived.
+    pub data: Vec<Vec<u8>>,
+    /// Datagrams to send back, such as the next handshake flight.
+    pub replies: Vec<Vec<u8>>,
+    /// The peer sent close_notify.
+    pub closed: bool,
+    /// The session failed; `replies` holds the alert that tells the peer.
+    pub error: Option<anyhow::Error>,
+}
+
+/// One end of a DTLS association.
+pub struct Session {
+    stream: SslStream<Datagrams>,
+    /// The client's check of the server certificate; `None` on the server.
+    verdict: Option<Verdict>,
+    /// When the session was set up, so that a listener can give up on a
+    /// handshake that stalls.
+    started: Instant,
+}
+
+impl Session {
+    /// Starts a client handshake, whose first flight comes out of [`Self::poll`].
+    fn client(args: &Args, server_name: &str) -> Result<Self> {
+        let name = ServerName::try_from(server_name.to_string())
+            .with_context(|| format!("{} is not a valid TLS server name", server_name))?;
+        let mut builder = SslContext::builder(SslMethod::dtls_client())?;
+        configure(&mut builder, args)?;
+        let mut ssl = Ssl::new(&builder.build())?;
+        ssl.set_connect_state();
+        ssl.set_mtu(MTU)?;
+        if server_name.parse::<IpAddr>().is_err() {
+            ssl.set_hostname(server_name)?;
+        }
+        let verdict = Verdict::default();
+        ssl.set_verify_callback(
+            SslVerifyMode::PEER,
+            check_server(tls::server_verifier(args)?, name, verdict.clone()),
+        );
+        Ok(Session {
+            stream: SslStream::new(ssl, Datagrams::default())?,
+            verdict: Some(verdict),
+            started: Instant::now(),
+        })
+    }
+
+    fn is_client(&self) -> bool {
+        self.verdict.is_some()
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        !self.stream.ssl().is_init_finished()
+    }
+
+    /// Seals one application datagram. OpenSSL writes no record for an
+    /// empty one, so that comes back empty and is not to be sent.
+    pub fn seal(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() > MAX_DATA {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("A DTLS record carries at most {} bytes", MAX_DATA),
+            ));
+        }
+        self.stream.ssl_write(data).map_err(|e| {
+            e.into_io_error()
+                .unwrap_or_else(|e| io::Error::other(e.to_string()))
+        })?;
+        Ok(self.take_outgoing())
+    }
+
+    /// The alert that tells the peer we are done.
+    pub fn close_notify(&mut self) -> Vec<u8> {
+        // Without an answer from the peer this only ever reports that the
+        // alert went out, which is all that is wanted.
+        let _ = self.stream.shutdown();
+        self.take_outgoing()
+    }
+
+    /// Everything OpenSSL wrote since the last call, in one datagram.
+    fn take_outgoing(&mut self) -> Vec<u8> {
+        mem::take(&mut self.stream.get_mut().outgoing).concat()
+    }
+
+    /// Processes a received datagram.
+    pub fn handle(&mut self, datagram: &[u8]) -> Received {
+        // OpenSSL would take an empty read for the end of the stream.
+        if !datagram.is_empty() {
+            self.stream.get_mut().incoming.push_back(datagram.to_vec());
+        }
+        self.poll()
+    }
+
+    /// Lets OpenSSL move on without a new datagram, which sends the first
+    /// flight of a client or, once its timer has run out, the last one again.
+    fn poll(&mut self) -> Received {
+        let mut received = Received::default();
+        if let Err(e) = self.advance(&mut received) {
+            received.error = Some(e);
+        }
+        received.replies = mem::take(&mut self.stream.get_mut().outgoing);
+        received
+    }
+
+    fn advance(&mut self, received: &mut Received) -> Result<()> {
+        if self.is_handshaking() {
+            match self.stream.do_handshake() {
+                Ok(()) => self.handshake_done()?,
+                Err(e) if e.code() == ErrorCode::WANT_READ => return Ok(()),
+                Err(e) => return Err(self.failure(e)),
+            }
+        }
+        let mut buffer = vec![0u8; MAX_DATA];
+        loop {
+            match self.stream.ssl_read(&mut buffer) {
+                Ok(n) => received.data.push(buffer[..n].to_vec()),
+                Err(e) if e.code() == ErrorCode::WANT_READ => return Ok(()),
+                Err(e) if e.code() == ErrorCode::ZERO_RETURN => {
+                    received.closed = true;
+                    return Ok(());
+                }
+                Err(e) => return Err(self.failure(e)),
+            }
+        }
+    }
+
+    fn handshake_done(&mut self) -> Result<()> {
+        if let Some(verdict) = &self.verdict {
+            // The callback runs for every certificate OpenSSL looks at, which
+            // always takes in the server's own.
+            if verdict.lock().unwrap().is_none() {
+                bail!("The server's certificate was never checked");
+            }
+        }
+        let ssl = self.stream.ssl();
+        debug!(
+            "DTLS handshake done: {} with {}",
+            ssl.version_str(),
+            ssl.current_cipher()
+                .map_or("no cipher suite", |cipher| cipher.name())
+        );
+        Ok(())
+    }
+
+    /// Turns an OpenSSL error into one that says which certificate check
+    /// failed, if that is what it was.
+    fn failure(&self, e: openssl::ssl::Error) -> anyhow::Error {
+        if let Some(verdict) = &self.verdict {
+            if let Some(Err(rejection)) = &*verdict.lock().unwrap() {
+                return anyhow!(tls::describe(rejection, "server"));
+            }
+        }
+        let peer = if self.is_client() { "server" } else { "client" };
+        anyhow!("The DTLS session with the {} failed: {}", peer, e)
+    }
+}
+
+/// The settings shared by both ends: DTLS 1.2 or newer, handshake
+/// datagrams below [`MTU`], and `--tls-keylog`.
+fn configure(builder: &mut SslContextBuilder, args: &Args) -> Result<()> {
+    builder.set_min_proto_version(Some(SslVersion::DTLS1_2))?;
+    builder.set_options(SslOptions::NO_QUERY_MTU);
+    if let Some(key_log) = tls::key_log(args)? {
+        builder.set_keylog_callback(move |_, line| log_line(&*key_log, line));
+    }
+    Ok(())
+}
+
+/// Hands a line of OpenSSL's NSS key log to `key_log`.
+fn log_line(key_log: &dyn KeyLog, line: &str) {
+    let unhex = |hex: &str| -> Option<Vec<u8>> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect()
+    };
+    let mut fields = line.split(' ');
+    if let (Some(label), Some(Some(client_random)), Some(Some(secret))) = (
+        fields.next(),
+        fields.next().map(unhex),
+        fields.next().map(unhex),
+    ) {
+        key_log.log(label, &client_random, &secret);
+    }
+}
+
+/// The verify callback of a client: OpenSSL's own chain checks are set
+/// aside, and the chain the server sent goes through `verifier` once OpenSSL
+/// gets to the server's certificate.
+fn check_server(
+    verifier: Arc<dyn ServerCertVerifier>,
+    name: ServerName<'static>,
+    verdict: Verdict,
+) -> impl Fn(bool, &mut openssl::x509::X509StoreContextRef) -> bool + Send + Sync + 'static {
+    move |_, context| {
+        if context.error_depth() != 0 {
+            return true;
+        }
+        let certificates: Vec<CertificateDer> = context
+            .chain()
+            .into_iter()
+            .flatten()
+            .filter_map(|certificate| certificate.to_der().ok())
+            .map(CertificateDer::from)
+            .collect();
+        let Some((end_entity, intermediates)) = certificates.split_first() else {
+            return false;
+        };
+        let result = verifier
+            .verify_server_cert(end_entity, intermediates, &name, &[], UnixTime::now())
+            .map(|_| ());
+        let accepted = result.is_ok();
+        *verdict.lock().unwrap() = Some(result);
+        accepted
+    }
+}
+
+/// Runs a client handshake with the peer `socket` is connected to, verifying
+/// that its certificate is valid for `--tls-sni` or else `server_name`.
+/// OpenSSL sends lost flights again after 1, 2, 4... seconds until `timeout`.
+pub fn connect(
+    socket: &UdpSocket,
+    server_name: &str,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<Session> {
+    let server_name = args.tls_sni.as_deref().unwrap_or(server_name);
+    let mut session = Session::client(args, server_name)?;
+    let read_timeout = socket.read_timeout()?;
+    let result = drive_handshake(socket, &mut session, timeout);
+    socket.set_read_timeout(read_timeout)?;
+    result.with_context(|| format!("DTLS handshake with {} failed", server_name))?;
+    Ok(session)
+}
+
+fn drive_handshake(
+    socket: &UdpSocket,
+    session: &mut Session,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout.unwrap_or(HANDSHAKE_TIMEOUT);
+    let mut buffer = vec![0u8; 65535];
+    let mut received = session.poll();
+    loop {
+        for reply in &received.replies {
+            socket.send(reply)?;
+        }
+        if let Some(e) = received.error {
+            return Err(e);
+        }
+        if received.closed {
+            bail!("The server closed the session during the handshake");
+        }
+        if !session.is_handshaking() {
+            return Ok(());
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            bail!("No answer within the timeout");
+        }
+        socket.set_read_timeout(Some(left.min(TIMER_CHECK)))?;
+        received = match socket.recv(&mut buffer) {
+            Ok(amt) => session.handle(&buffer[..amt]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                session.poll()
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                bail!(
+                    "{} is not accepting datagrams (port unreachable)",
+                    socket.peer_addr()?
+                )
+            }
+            Err(e) => return Err(e.into()),
+        };
+    }
+}
+
+/// Whether `datagram` starts with the record of a ClientHello, which begins
+/// a new handshake.
+fn is_client_hello(datagram: &[u8]) -> bool {
+    datagram.len() > RECORD_HEADER
+        && datagram[0] == HANDSHAKE
+        // Epoch 0, before any keys.
+        && datagram[3..5] == [0, 0]
+        && datagram[RECORD_HEADER] == CLIENT_HELLO
+}
+
+/// What a ClientHello from a peer without a session led to.
+enum Hello {
+    /// The cookie was missing or wrong; the HelloVerifyRequest to send.
+    Verify(Vec<Vec<u8>>),
+    /// A new session, ready to go on with the handshake.
+    Verified(Session),
+}
+
+/// The server side: keeps a session for each peer whose ClientHello came
+/// back with a valid cookie.
+pub struct Listener {
+    context: SslContext,
+    peer_index: openssl::ex_data::Index<Ssl, SocketAddr>,
+    sessions: HashMap<SocketAddr, Session>,
+}
+
+impl Listener {
+    pub fn new(args: &Args) -> Result<Self> {
+        let (certificates, key) = tls::server_identity(args)?;
+        let mut builder = SslContext::builder(SslMethod::dtls_server())?;
+        configure(&mut builder, args)?;
+        let (certificate, chain) = certificates
+            .split_first()
+            .context("The server has no certificate")?;
+        let certificate = X509::from_der(certificate)?;
+        builder.set_certificate(&certificate)?;
+        for certificate in chain {
+            builder.add_extra_chain_cert(X509::from_der(certificate)?)?;
+        }
+        let key = PKey::private_key_from_der(key.secret_der())?;
+        builder.set_private_key(&key)?;
+        builder.check_private_key()?;
+
+        // A cookie proves the peer receives at its address, so that spoofed
+        // ClientHellos only ever earn the small HelloVerifyRequest.
+        let mut secret = [0u8; 32];
+        getrandom::getrandom(&mut secret).map_err(|e| anyhow!("No randomness for DTLS: {}", e))?;
+        let cookie_key = hmac::Key::new(hmac::HMAC_SHA256, &secret);
+        let peer_index = Ssl::new_ex_index::<SocketAddr>()?;
+        builder.set_options(SslOptions::COOKIE_EXCHANGE);
+        let key = cookie_key.clone();
+        builder.set_cookie_generate_cb(move |ssl, buffer| {
+            let peer = ssl.ex_data(peer_index).map(ToString::to_string);
+            let tag = hmac::sign(&key, peer.unwrap_or_default().as_bytes());
+            buffer[..tag.as_ref().len()].copy_from_slice(tag.as_ref());
+            Ok(tag.as_ref().len())
+        });
+        builder.set_cookie_verify_cb(move |ssl, cookie| {
+            let peer = ssl.ex_data(peer_index).map(ToString::to_string);
+            hmac::verify(&cookie_key, peer.unwrap_or_default().as_bytes(), cookie).is_ok()
+        });
+        Ok(Listener {
+            context: builder.build(),
+            peer_index,
+            sessions: HashMap::new(),
+        })
+    }
+
+    /// Processes a datagram from `peer`. A session that fails or is closed
+    /// is forgotten.
+    pub fn handle(&mut self, peer: SocketAddr, datagram: &[u8]) -> Received {
+        let handshaking = match self.sessions.get(&peer) {
+            Some(session) => session.is_handshaking(),
+            None if !is_client_hello(datagram) => {
+                debug!("Dropping a datagram from {} without a DTLS session", peer);
+                return Received::default();
+            }
+            None => true,
+        };
+        // A ClientHello after a finished handshake starts a new one, but only
+        // replaces the session once its cookie is good.
+        let received =
+            if !self.sessions.contains_key(&peer) || (!handshaking && is_client_hello(datagram)) {
+                match self.listen(peer, datagram) {
+                    Ok(Hello::Verify(replies)) => {
+                        return Received {
+                            replies,
+                            ..Received::default()
+                        }
+                    }
+                    Ok(Hello::Verified(mut session)) => {
+                        let received = session.poll();
+                        self.sessions.insert(peer, session);
+                        received
+                    }
+                    Err(e) => {
+                        return Received {
+                            error: Some(e),
+                            ..Received::default()
+                        }
+                    }
+                }
+            } else {
+                self.sessions.get_mut(&peer).unwrap().handle(datagram)
+            };
+        if received.error.is_some() || received.closed {
+            self.sessions.remove(&peer);
+        }
+        received
+    }
+
+    /// Checks the cookie in a ClientHello the way `DTLSv1_listen` does,
+    /// without keeping anything: a ClientHello without a valid one only
+    /// earns a HelloVerifyRequest.
+    fn listen(&mut self, peer: SocketAddr, hello: &[u8]) -> Result<Hello> {
+        let mut session = self.accept(peer)?;
+        session.stream.get_mut().incoming.push_back(hello.to_vec());
+        // SAFETY: the SSL object is alive for the call, and the address is
+        // only written to before it is freed.
+        let result = unsafe {
+            let client = BIO_ADDR_new();
+            if client.is_null() {
+                return Err(ErrorStack::get().into());
+            }
+            let result = DTLSv1_listen(session.stream.ssl().as_ptr(), client);
+            BIO_ADDR_free(client);
+            result
+        };
+        match result {
+            1 => {}
+            0 => {
+                let replies = mem::take(&mut session.stream.get_mut().outgoing);
+                return Ok(Hello::Verify(replies));
+            }
+            _ => {
+                return Err(anyhow!(ErrorStack::get())
+                    .context(format!("Bad DTLS ClientHello from {}", peer)))
+            }
+        }
+        // The peer has shown it receives at its address; let it have a
+        // handshake if there is room.
+        self.sessions.retain(|_, session| {
+            !session.is_handshaking() || session.started.elapsed() < HANDSHAKE_EXPIRY
+        });
+        let handshakes = self
+            .sessions
+            .values()
+            .filter(|session| session.is_handshaking())
+            .count();
+        if handshakes >= MAX_HANDSHAKES {
+            bail!(
+                "Turning away {}: {} DTLS handshakes are already under way",
+                peer,
+                handshakes
+            );
+        }
+        Ok(Hello::Verified(session))
+    }
+
+    fn accept(&self, peer: SocketAddr) -> Result<Session> {
+        let mut ssl = Ssl::new(&self.context)?;
+        ssl.set_accept_state();
+        ssl.set_mtu(MTU)?;
+        ssl.set_ex_data(self.peer_index, peer);
+        Ok(Session {
+            stream: SslStream::new(ssl, Datagrams::default())?,
+            verdict: None,
+            started: Instant::now(),
+        })
+    }
+
+    /// Seals an application datagram for `peer`, if it has a session.
+    pub fn seal(&mut self, peer: SocketAddr, data: &[u8]) -> Option<io::Result<Vec<u8>>> {
+        let session = self.sessions.get_mut(&peer)?;
+        if session.is_handshaking() {
+            return None;
+        }
+        Some(session.seal(data))
+    }
+
+    pub fn forget(&mut self, peer: &SocketAddr) {
+        self.sessions.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::{fs, path::PathBuf, thread};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("netpipe-dtls-{}-{}", std::process::id(), name))
+    }
+
+    const PEER: &str = "127.0.0.1:4433";
+
+    /// Hands every datagram in `datagrams` to the listener as [`PEER`].
+    fn to_listener(listener: &mut Listener, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut replies = Vec::new();
+        for datagram in datagrams {
+            let received = listener.handle(PEER.parse().unwrap(), &datagram);
+            assert!(received.error.is_none(), "{:?}", received.error);
+            replies.extend(received.replies);
+        }
+        replies
+    }
+
+    fn to_client(session: &mut Session, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut replies = Vec::new();
+        for datagram in datagrams {
+            let received = session.handle(&datagram);
+            assert!(received.error.is_none(), "{:?}", received.error);
+            replies.extend(received.replies);
+        }
+        replies
+    }
+
+    /// A listener with a fresh certificate for localhost, and that certificate.
+    fn listener(name: &str) -> (Listener, CertificateDer<'static>) {
+        let cert = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let cert_path = temp_path(&format!("{}-cert.pem", name));
+        let key_path = temp_path(&format!("{}-key.pem", name));
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "--tls".as_ref(),
+            "--tls-cert".as_ref(),
+            cert_path.as_os_str(),
+            "--tls-key".as_ref(),
+            key_path.as_os_str(),
+        ]);
+        let listener = Listener::new(&args).unwrap();
+        fs::remove_file(&cert_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+        (listener, cert.cert.der().clone())
+    }
+
+    #[test]
+    fn test_handshake_recovers_from_a_lost_flight() {
+        let (mut listener, cert) = listener("lost-flight");
+        let pin = tls::fingerprint(&cert);
+        let client_args = Args::parse_from(["test", "--tls", "--tls-pin", &pin]);
+        let mut client = Session::client(&client_args, "localhost").unwrap();
+
+        // The first ClientHello only earns a cookie.
+        let verify = to_listener(&mut listener, client.poll().replies);
+        let hello = to_client(&mut client, verify);
+        let server_flight = to_listener(&mut listener, hello);
+        assert!(!server_flight.is_empty());
+
+        // The server's flight is lost: once the client's timer runs out it
+        // sends its hello again, and the server answers with its flight.
+        assert!(client.poll().replies.is_empty());
+        thread::sleep(Duration::from_millis(1100));
+        let resent = to_listener(&mut listener, client.poll().replies);
+        assert!(!resent.is_empty());
+        let finished = to_client(&mut client, resent);
+        let server_finished = to_listener(&mut listener, finished);
+        assert!(to_client(&mut client, server_finished).is_empty());
+        assert!(!client.is_handshaking());
+
+        let peer = PEER.parse().unwrap();
+        let record = client.seal(b"one").unwrap();
+        let received = listener.handle(peer, &record);
+        assert_eq!(received.data, [b"one".to_vec()]);
+        // A replayed record is dropped.
+        assert!(listener.handle(peer, &record).data.is_empty());
+
+        let reply = listener.seal(peer, b"two").unwrap().unwrap();
+        assert_eq!(client.handle(&reply).data, [b"two".to_vec()]);
+        assert!(listener.handle(peer, &client.close_notify()).closed);
+        assert!(listener.seal(peer, b"gone").is_none());
+    }
+
+    #[test]
+    fn test_listener_keeps_nothing_until_the_cookie_comes_back() {
+        let (mut listener, cert) = listener("cookies");
+        let pin = tls::fingerprint(&cert);
+        let client_args = Args::parse_from(["test", "--tls", "--tls-pin", &pin]);
+        let hello = Session::client(&client_args, "localhost")
+            .unwrap()
+            .poll()
+            .replies;
+
+        // Spoofed ClientHellos from many addresses only earn cookies.
+        for port in 1..=1000 {
+            let peer = SocketAddr::from(([192, 0, 2, 1], port));
+            let received = listener.handle(peer, &hello[0]);
+            assert!(received.error.is_none(), "{:?}", received.error);
+            assert_eq!(received.replies.len(), 1);
+        }
+        assert!(listener.sessions.is_empty());
+
+        // Peers that return their cookie get a handshake, up to the limit.
+        for port in 1..=MAX_HANDSHAKES as u16 + 1 {
+            let peer = SocketAddr::from(([192, 0, 2, 2], port));
+            let mut client = Session::client(&client_args, "localhost").unwrap();
+            let verify = listener.handle(peer, &client.poll().replies[0]).replies;
+            let hello = to_client(&mut client, verify);
+            let received = listener.handle(peer, &hello[0]);
+            if usize::from(port) <= MAX_HANDSHAKES {
+                assert!(received.error.is_none(), "{:?}", received.error);
+                assert!(!received.replies.is_empty());
+            } else {
+                assert!(received.error.is_some());
+                assert!(received.replies.is_empty());
+            }
+        }
+        assert_eq!(listener.sessions.len(), MAX_HANDSHAKES);
+
+        // Stalled handshakes make room once they expire.
+        for session in listener.sessions.values_mut() {
+            session.started -= HANDSHAKE_EXPIRY;
+        }
+        let mut client = Session::client(&client_args, "localhost").unwrap();
+        let verify = to_listener(&mut listener, client.poll().replies);
+        let hello = to_client(&mut client, verify);
+        assert!(!to_listener(&mut listener, hello).is_empty());
+        assert_eq!(listener.sessions.len(), 1);
+    }
+
+    #[test]
+    fn test_pin_mismatch_alerts_the_server() {
+        let (mut listener, _) = listener("mispinned");
+        let other_pin = format!("sha256:{}", "ab".repeat(32));
+        let client_args = Args::parse_from(["test", "--tls", "--tls-pin", &other_pin]);
+        let mut client = Session::client(&client_args, "localhost").unwrap();
+
+        let verify = to_listener(&mut listener, client.poll().replies);
+        let hello = to_client(&mut client, verify);
+        let mut received = Received::default();
+        for datagram in to_listener(&mut listener, hello) {
+            received = client.handle(&datagram);
+            if received.error.is_some() {
+                break;
+            }
+        }
+        let message = received.error.unwrap().to_string();
+        assert!(message.contains("matches no --tls-pin"), "{}", message);
+
+        let alert = listener.handle(PEER.parse().unwrap(), &received.replies[0]);
+        let message = alert.error.unwrap().to_string();
+        // OpenSSL picks the alert for a rejected certificate: unknown_ca.
+        assert!(message.contains("alert unknown ca"), "{}", message);
+    }
+}
@@ -0,0 +1,555 @@
+//! Text encodings for `--encode` and `--decode`.
+
+use crate::args::Encoding;
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+/// Encoded characters per output line, as `base64` writes them.
+const BASE64_LINE_WIDTH: usize = 76;
+
+fn base64_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Encodes a group of up to three bytes as four characters, padded with `=`.
+fn base64_group(out: &mut Vec<u8>, group: &[u8]) {
+    let bits = (u32::from(group[0]) << 16)
+        | (u32::from(*group.get(1).unwrap_or(&0)) << 8)
+        | u32::from(*group.get(2).unwrap_or(&0));
+    for i in 0..4 {
+        if i <= group.len() {
+            out.push(BASE64_ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3f]);
+        } else {
+            out.push(b'=');
+        }
+    }
+}
+
+/// Base64-encodes `data` on a single line, as protocol headers carry it.
+pub fn base64(data: &[u8]) -> String {
+    let mut out = Vec::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        base64_group(&mut out, group);
+    }
+    String::from_utf8(out).expect("the base64 alphabet is ASCII")
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Base64-encodes everything written through it in lines of 76 characters.
+///
+/// Up to two bytes are held back until the next write completes a group;
+/// the last group is padded and the last line ended when the encoder is
+/// dropped.
+pub struct Base64Encoder<W: Write> {
+    inner: W,
+    pending: [u8; 3],
+    filled: usize,
+    column: usize,
+}
+
+impl<W: Write> Base64Encoder<W> {
+    pub fn new(inner: W) -> Self {
+        Base64Encoder {
+            inner,
+            pending: [0; 3],
+            filled: 0,
+            column: 0,
+        }
+    }
+
+    fn push_group(&mut self, out: &mut Vec<u8>, group: &[u8]) {
+        base64_group(out, group);
+        self.column += 4;
+        if self.column == BASE64_LINE_WIDTH {
+            out.push(b'\n');
+            self.column = 0;
+        }
+    }
+}
+
+impl<W: Write> Write for Base64Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = Vec::with_capacity(buf.len() * 4 / 3 + 8);
+        let mut rest = buf;
+        if self.filled > 0 {
+            let take = (3 - self.filled).min(rest.len());
+            self.pending[self.filled..self.filled + take].copy_from_slice(&rest[..take]);
+            self.filled += take;
+            rest = &rest[take..];
+            if self.filled < 3 {
+                return Ok(buf.len());
+            }
+            let group = self.pending;
+            self.push_group(&mut out, &group);
+            self.filled = 0;
+        }
+        let mut groups = rest.chunks_exact(3);
+        for group in &mut groups {
+            self.push_group(&mut out, group);
+        }
+        let tail = groups.remainder();
+        self.pending[..tail.len()].copy_from_slice(tail);
+        self.filled = tail.len();
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Base64Encoder<W> {
+    fn drop(&mut self) {
+        let mut out = Vec::new();
+        if self.filled > 0 {
+            let group = self.pending;
+            self.push_group(&mut out, &group[..self.filled]);
+        }
+        if self.column > 0 {
+            out.push(b'\n');
+        }
+        let _ = self.inner.write_all(&out);
+        let _ = self.inner.flush();
+    }
+}
+
+/// Hex-encodes everything written through it as one continuous lowercase
+/// string, ended by a newline when the encoder is dropped.
+pub struct HexEncoder<W: Write> {
+    inner: W,
+    written: bool,
+}
+
+impl<W: Write> HexEncoder<W> {
+    pub fn new(inner: W) -> Self {
+        HexEncoder {
+            inner,
+            written: false,
+        }
+    }
+}
+
+impl<W: Write> Write for HexEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = String::with_capacity(buf.len() * 2);
+        for byte in buf {
+            let _ = write!(out, "{:02x}", byte);
+        }
+        self.inner.write_all(out.as_bytes())?;
+        self.written |= !buf.is_empty();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for HexEncoder<W> {
+    fn drop(&mut self) {
+        if self.written {
+            let _ = self.inner.write_all(b"\n");
+            let _ = self.inner.flush();
+        }
+    }
+}
+
+/// Turns text into bytes a piece at a time, for [`Decoded`].
+trait TextDecoder {
+    /// Decodes the next piece of input, appending whole bytes to `out`.
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Deals with anything left open at the end of the input.
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()>;
+}
+
+/// Decodes text while reading it from `inner`.
+pub struct Decoded<R> {
+    inner: R,
+    decoder: Box<dyn TextDecoder + Send>,
+    eof: bool,
+    input: Vec<u8>,
+    decoded: Vec<u8>,
+}
+
+impl<R> Decoded<R> {
+    pub fn new(inner: R, encoding: Encoding) -> Self {
+        let decoder: Box<dyn TextDecoder + Send> = match encoding {
+            Encoding::Base64 => Box::<Base64Decoder>::default(),
+            Encoding::Hex => Box::<HexDecoder>::default(),
+        };
+        Decoded {
+            inner,
+            decoder,
+            eof: false,
+            input: vec![0; 8192],
+            decoded: Vec::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for Decoded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.decoded.is_empty() && !self.eof {
+            let n = match self.inner.read(&mut self.input) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                self.eof = true;
+                self.decoder.finish(&mut self.decoded)?;
+            } else {
+                self.decoder.decode(&self.input[..n], &mut self.decoded)?;
+            }
+        }
+        let n = self.decoded.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.decoded[..n]);
+        self.decoded.drain(..n);
+        Ok(n)
+    }
+}
+
+/// Base64, ignoring whitespace such as line breaks.
+///
+/// Invalid input fails with the offset of the offending byte. A missing final
+/// padding is tolerated, but nothing but whitespace may follow padding.
+#[derive(Default)]
+struct Base64Decoder {
+    /// Input bytes consumed so far, for error messages.
+    offset: u64,
+    quantum: [u8; 4],
+    filled: usize,
+    padding: usize,
+    /// Set once padding has ended the data.
+    finished: bool,
+}
+
+impl Base64Decoder {
+    /// Appends the bytes of the first `sextets` sextets of the quantum.
+    fn emit(&mut self, sextets: usize, out: &mut Vec<u8>) {
+        let bits = self
+            .quantum
+            .iter()
+            .fold(0u32, |bits, &sextet| (bits << 6) | u32::from(sextet));
+        out.extend_from_slice(&bits.to_be_bytes()[1..1 + sextets * 6 / 8]);
+        self.quantum = [0; 4];
+        self.filled = 0;
+        self.padding = 0;
+    }
+}
+
+impl TextDecoder for Base64Decoder {
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        for &c in input {
+            let offset = self.offset;
+            self.offset += 1;
+            if c.is_ascii_whitespace() {
+                continue;
+            }
+            if self.finished {
+                return Err(invalid_data(format!(
+                    "base64 data continues after padding at byte {}",
+                    offset
+                )));
+            }
+            if c == b'=' {
+                if self.filled < 2 {
+                    return Err(invalid_data(format!(
+                        "unexpected base64 padding at byte {}",
+                        offset
+                    )));
+                }
+                self.padding += 1;
+                self.filled += 1;
+                if self.filled == 4 {
+                    let padding = self.padding;
+                    self.emit(4 - padding, out);
+                    self.finished = true;
+                }
+                continue;
+            }
+            let Some(value) = base64_value(c).filter(|_| self.padding == 0) else {
+                return Err(invalid_data(format!(
+                    "invalid base64 character {:?} at byte {}",
+                    char::from(c),
+                    offset
+                )));
+            };
+            self.quantum[self.filled] = value;
+            self.filled += 1;
+            if self.filled == 4 {
+                self.emit(4, out);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        match (self.filled, self.padding) {
+            (0, _) => Ok(()),
+            (1, _) => Err(invalid_data(format!(
+                "base64 input ends in the middle of a group at byte {}",
+                self.offset
+            ))),
+            (filled, 0) => {
+                self.emit(filled, out);
+                Ok(())
+            }
+            _ => Err(invalid_data(format!(
+                "base64 input ends in incomplete padding at byte {}",
+                self.offset
+            ))),
+        }
+    }
+}
+
+/// Whitespace-separated hex numbers of any even number of digits, each
+/// optionally prefixed with `0x`, with `#` starting a comment that runs to the
+/// end of the line.
+///
+/// Errors give the line and column of the offending character.
+struct HexDecoder {
+    line: u64,
+    column: u64,
+    in_comment: bool,
+    /// Where the current number started, while inside one.
+    token_start: Option<(u64, u64)>,
+    /// Hex digits of the current number so far, not counting a `0x` prefix.
+    digits: u64,
+    prefixed: bool,
+    high_nibble: u8,
+}
+
+impl Default for HexDecoder {
+    fn default() -> Self {
+        HexDecoder {
+            line: 1,
+            column: 0,
+            in_comment: false,
+            token_start: None,
+            digits: 0,
+            prefixed: false,
+            high_nibble: 0,
+        }
+    }
+}
+
+impl HexDecoder {
+    /// Checks the number that just ended.
+    fn end_token(&mut self) -> io::Result<()> {
+        if let Some((line, column)) = self.token_start.take() {
+            if self.digits == 0 {
+                return Err(invalid_data(format!(
+                    "0x without digits at line {}, column {}",
+                    line, column
+                )));
+            }
+            if self.digits % 2 == 1 {
+                return Err(invalid_data(format!(
+                    "odd number of hex digits at line {}, column {}",
+                    line, column
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TextDecoder for HexDecoder {
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        for &c in input {
+            if c == b'\n' {
+                self.end_token()?;
+                self.in_comment = false;
+                self.line += 1;
+                self.column = 0;
+                continue;
+            }
+            self.column += 1;
+            if self.in_comment {
+                continue;
+            }
+            if c.is_ascii_whitespace() || c == b'#' {
+                self.end_token()?;
+                self.in_comment = c == b'#';
+                continue;
+            }
+            if self.token_start.is_none() {
+                self.token_start = Some((self.line, self.column));
+                self.digits = 0;
+                self.prefixed = false;
+            }
+            // A leading `0x` is a prefix rather than part of the number.
+            if (c == b'x' || c == b'X')
+                && self.digits == 1
+                && self.high_nibble == 0
+                && !self.prefixed
+            {
+                self.digits = 0;
+                self.prefixed = true;
+                continue;
+            }
+            let Some(nibble) = char::from(c).to_digit(16).map(|d| d as u8) else {
+                return Err(invalid_data(format!(
+                    "invalid hex digit {:?} at line {}, column {}",
+                    char::from(c),
+                    self.line,
+                    self.column
+                )));
+            };
+            if self.digits.is_multiple_of(2) {
+                self.high_nibble = nibble;
+            } else {
+                out.push(self.high_nibble << 4 | nibble);
+            }
+            self.digits += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut Vec<u8>) -> io::Result<()> {
+        self.end_token()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads from a slice `chunk` bytes at a time.
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(self.1).min(buf.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn encode(data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        {
+            let mut encoder = Base64Encoder::new(&mut output);
+            for piece in data.chunks(chunk) {
+                encoder.write_all(piece).unwrap();
+            }
+        }
+        output
+    }
+
+    fn decode(text: &[u8], chunk: usize) -> io::Result<Vec<u8>> {
+        decode_as(Encoding::Base64, text, chunk)
+    }
+
+    fn decode_as(encoding: Encoding, text: &[u8], chunk: usize) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        Decoded::new(Trickle(text, chunk), encoding).read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_base64_padding() {
+        assert_eq!(encode(b"", 1), b"");
+        assert_eq!(encode(b"f", 1), b"Zg==\n");
+        assert_eq!(encode(b"fo", 1), b"Zm8=\n");
+        assert_eq!(encode(b"foo", 1), b"Zm9v\n");
+        assert_eq!(encode(b"foobar", 4), b"Zm9vYmFy\n");
+        assert_eq!(decode(b"Zg==", 4).unwrap(), b"f");
+        assert_eq!(decode(b"Zm8=\n", 4).unwrap(), b"fo");
+        assert_eq!(decode(b"Zm8", 4).unwrap(), b"fo");
+    }
+
+    #[test]
+    fn test_base64_round_trip_at_every_boundary() {
+        let data: Vec<u8> = (0..=255).cycle().take(200).collect();
+        let encoded = encode(&data, 200);
+        let lines: Vec<&[u8]> = encoded.split(|&b| b == b'\n').collect();
+        assert_eq!(lines[0].len(), 76);
+        for chunk in 1..=10 {
+            assert_eq!(encode(&data, chunk), encoded, "chunk {}", chunk);
+            assert_eq!(decode(&encoded, chunk).unwrap(), data, "chunk {}", chunk);
+        }
+    }
+
+    #[test]
+    fn test_base64_errors_name_the_offset() {
+        let err = |text: &[u8]| decode(text, 3).unwrap_err().to_string();
+        assert_eq!(err(b"Zm9v\nYm*y"), "invalid base64 character '*' at byte 7");
+        assert_eq!(
+            err(b"Zg==Zg=="),
+            "base64 data continues after padding at byte 4"
+        );
+        assert_eq!(err(b"Z==="), "unexpected base64 padding at byte 1");
+        assert_eq!(err(b"Zm=v"), "invalid base64 character 'v' at byte 3");
+        assert_eq!(
+            err(b"Zm9vY"),
+            "base64 input ends in the middle of a group at byte 5"
+        );
+        assert_eq!(
+            err(b"Zm="),
+            "base64 input ends in incomplete padding at byte 3"
+        );
+    }
+
+    #[test]
+    fn test_hex_input_with_prefixes_and_comments() {
+        let text = b"# SYN-ish test packet\n0x45 00 0X003c\t DEADbeef # flags\n\n  ff";
+        let expected = [0x45, 0x00, 0x00, 0x3c, 0xde, 0xad, 0xbe, 0xef, 0xff];
+        for chunk in 1..=text.len() {
+            assert_eq!(
+                decode_as(Encoding::Hex, text, chunk).unwrap(),
+                expected,
+                "chunk {}",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn test_hex_errors_name_line_and_column() {
+        let err = |text: &[u8]| decode_as(Encoding::Hex, text, 2).unwrap_err().to_string();
+        assert_eq!(
+            err(b"00 11\n22 3g"),
+            "invalid hex digit 'g' at line 2, column 5"
+        );
+        assert_eq!(
+            err(b"abc def"),
+            "odd number of hex digits at line 1, column 1"
+        );
+        assert_eq!(err(b"00\n  0x"), "0x without digits at line 2, column 3");
+        assert_eq!(err(b"0x0x00"), "invalid hex digit 'x' at line 1, column 4");
+        assert_eq!(
+            err(b"12 # ok\n1"),
+            "odd number of hex digits at line 2, column 1"
+        );
+    }
+
+    #[test]
+    fn test_hex_output_is_one_continuous_string() {
+        let mut output = Vec::new();
+        {
+            let mut encoder = HexEncoder::new(&mut output);
+            encoder.write_all(&[0xde, 0xad]).unwrap();
+            encoder.write_all(&[0x00, 0x0f]).unwrap();
+        }
+        assert_eq!(output, b"dead000f\n");
+    }
+}
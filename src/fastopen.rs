@@ -0,0 +1,73 @@
+//! TCP Fast Open on Linux, which lets the first data ride in the SYN to a
+//! server that has handed out a cookie before.
+//!
+//! Both ends fall back to a normal handshake on their own when the kernel,
+//! the server or a middlebox does not cooperate.
+
+use socket2::Socket;
+use std::{fs, io, mem, net::TcpStream, os::fd::AsRawFd};
+
+/// `tcpi_options` bit for data in the SYN having been acknowledged, which
+/// `libc` does not define.
+const TCPI_OPT_SYN_DATA: u8 = 32;
+
+/// `net.ipv4.tcp_fastopen` bit that lets servers accept data in the SYN.
+const SERVER_ENABLED: u32 = 2;
+
+fn set_option(socket: &impl AsRawFd, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    // SAFETY: the descriptor is open and `value` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes `connect` on `socket` wait for the first write, so that the write
+/// can go in the SYN.
+pub fn enable_connect(socket: &Socket) -> io::Result<()> {
+    set_option(socket, libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+/// Lets the listener `socket` accept data in the SYN from up to `queue`
+/// clients whose handshakes have not completed.
+pub fn enable_listen(socket: &Socket, queue: i32) -> io::Result<()> {
+    set_option(socket, libc::TCP_FASTOPEN, queue)
+}
+
+/// Whether the system lets servers take data in the SYN at all.
+pub fn server_enabled() -> bool {
+    fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen")
+        .ok()
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .is_some_and(|value| value & SERVER_ENABLED != 0)
+}
+
+/// Whether data in the SYN of `stream` was acknowledged, on either end.
+pub fn used(stream: &TcpStream) -> io::Result<bool> {
+    // SAFETY: tcp_info is plain data, and the kernel fills in no more than
+    // `len` bytes of it.
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info.tcpi_options & TCPI_OPT_SYN_DATA != 0)
+}
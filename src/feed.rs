@@ -0,0 +1,130 @@
+//! Standard input for a `--keep-open` listener, which serves one connection
+//! after another from the same stdin.
+
+use log::error;
+use std::{
+    io::{self, Read},
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+/// Reads stdin on one thread for the whole run and hands each chunk to the
+/// connection being served. A chunk read while no connection is attached
+/// waits for the next one, rather than going to a socket that is gone.
+pub struct StdinFeed {
+    shared: Arc<(Mutex<Attached>, Condvar)>,
+}
+
+#[derive(Default)]
+struct Attached {
+    /// Where chunks go while a connection is attached. The channel has no
+    /// buffer, so a chunk only leaves once the connection asks for it.
+    chunks: Option<SyncSender<Vec<u8>>>,
+    /// Counts attachments, so that a connection that went away is only
+    /// detached if no other has taken its place.
+    generation: u64,
+    ended: bool,
+}
+
+impl StdinFeed {
+    /// Starts reading `input` in chunks of up to `buffer_size` bytes.
+    pub fn spawn(mut input: impl Read + Send + 'static, buffer_size: usize) -> Self {
+        let shared = Arc::new((Mutex::new(Attached::default()), Condvar::new()));
+        let reader = shared.clone();
+        thread::spawn(move || {
+            let (attached, attaching) = &*reader;
+            let mut buffer = vec![0u8; buffer_size];
+            loop {
+                let n = match input.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("Failed to read stdin: {}", e);
+                        break;
+                    }
+                };
+                let mut chunk = buffer[..n].to_vec();
+                loop {
+                    let (generation, chunks) = {
+                        let current = attaching
+                            .wait_while(attached.lock().unwrap(), |a| a.chunks.is_none())
+                            .unwrap();
+                        (current.generation, current.chunks.clone().unwrap())
+                    };
+                    match chunks.send(chunk) {
+                        Ok(()) => break,
+                        // The connection ended without taking it.
+                        Err(mpsc::SendError(unsent)) => {
+                            chunk = unsent;
+                            let mut current = attached.lock().unwrap();
+                            if current.generation == generation {
+                                current.chunks = None;
+                            }
+                        }
+                    }
+                }
+            }
+            let mut current = attached.lock().unwrap();
+            current.ended = true;
+            current.chunks = None;
+        });
+        StdinFeed { shared }
+    }
+
+    /// Makes stdin go to the connection about to be served, until
+    /// [`Self::detach`].
+    pub fn attach(&self) -> Chunks {
+        let (chunks, receiver) = mpsc::sync_channel(0);
+        let (attached, attaching) = &*self.shared;
+        let mut current = attached.lock().unwrap();
+        // Once stdin has ended, the channel is closed from the start.
+        if !current.ended {
+            current.chunks = Some(chunks);
+            current.generation += 1;
+            attaching.notify_all();
+        }
+        Chunks {
+            receiver,
+            chunk: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Stops handing stdin to the connection that was attached, whose
+    /// [`Chunks`] then end.
+    pub fn detach(&self) {
+        self.shared.0.lock().unwrap().chunks = None;
+    }
+}
+
+/// One connection's share of stdin.
+pub struct Chunks {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.offset == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.offset);
+        buf[..n].copy_from_slice(&self.chunk[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,56 @@
+//! The line formats of `--stats-format` and `--scan-output`.
+//!
+//! Every reported item is a single line, so output streams as it is produced
+//! and the JSON format is newline-delimited.
+
+use crate::args::{ScanOutput, StatsFormat};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Grepable,
+}
+
+impl From<StatsFormat> for Format {
+    fn from(format: StatsFormat) -> Self {
+        match format {
+            StatsFormat::Text => Format::Text,
+            StatsFormat::Json => Format::Json,
+        }
+    }
+}
+
+impl From<ScanOutput> for Format {
+    fn from(format: ScanOutput) -> Self {
+        match format {
+            ScanOutput::Normal => Format::Text,
+            ScanOutput::Json => Format::Json,
+            ScanOutput::Grepable => Format::Grepable,
+        }
+    }
+}
+
+/// Something netpipe reports as a line of output.
+pub trait Line {
+    /// The line for people to read.
+    fn text(&self) -> String;
+
+    /// The JSON object, with the same fields whatever the item's state.
+    fn json(&self) -> Value;
+
+    /// A compact line of `key=value` fields for grep and cut.
+    fn grepable(&self) -> String {
+        self.text()
+    }
+}
+
+/// `item` in `format`, without the newline.
+pub fn line(item: &impl Line, format: Format) -> String {
+    match format {
+        Format::Text => item.text(),
+        Format::Json => item.json().to_string(),
+        Format::Grepable => item.grepable(),
+    }
+}
@@ -0,0 +1,168 @@
+//! Canonical hex dumps of the traffic for `--hex-dump`.
+
+use log::error;
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Where blocks are written once `--hex-dump` is given.
+static SINK: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/// Bytes shown on each line of a block.
+const LINE_WIDTH: usize = 16;
+
+/// Which way a block of traffic went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn marker(self) -> char {
+        match self {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        }
+    }
+}
+
+/// Starts dumping traffic to `path`, or to stderr without one.
+pub fn enable(path: Option<&Path>) -> io::Result<()> {
+    let sink: Box<dyn Write + Send> = match path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stderr()),
+    };
+    let _ = SINK.set(Mutex::new(sink));
+    Ok(())
+}
+
+/// Dumps `data`, found `offset` bytes into the session's traffic in
+/// `direction`. Does nothing unless [`enable`] was called.
+pub fn record(direction: Direction, offset: u64, data: &[u8]) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    if data.is_empty() {
+        return;
+    }
+    let block = format_block(direction, SystemTime::now(), offset, data);
+    // One write per block keeps the two directions from interleaving mid-block.
+    let mut sink = sink.lock().unwrap();
+    if let Err(e) = sink.write_all(block.as_bytes()).and_then(|_| sink.flush()) {
+        error!("Failed to write hex dump: {}", e);
+    }
+}
+
+/// A header with the direction, UTC time of day and length, followed by the
+/// data in `hexdump -C` layout.
+fn format_block(direction: Direction, time: SystemTime, offset: u64, data: &[u8]) -> String {
+    let mut block = format!(
+        "{} {} length={}\n",
+        direction.marker(),
+        time_of_day(time),
+        data.len()
+    );
+    for (i, line) in data.chunks(LINE_WIDTH).enumerate() {
+        let mut hex = String::with_capacity(3 * LINE_WIDTH + 1);
+        for (j, byte) in line.iter().enumerate() {
+            if j == LINE_WIDTH / 2 {
+                hex.push(' ');
+            }
+            let _ = write!(hex, "{:02x} ", byte);
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(
+            block,
+            "{:08x}  {:<49} |{}|",
+            offset + (i * LINE_WIDTH) as u64,
+            hex,
+            ascii
+        );
+    }
+    block
+}
+
+fn time_of_day(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() % 86400;
+    format!(
+        "{:02}:{:02}:{:02}.{:06}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64, micros: u32) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(micros.into())
+    }
+
+    #[test]
+    fn test_full_lines_are_aligned() {
+        let data: Vec<u8> = (0x41..0x61).collect();
+        assert_eq!(
+            format_block(Direction::Sent, at(0, 0), 0, &data),
+            "> 00:00:00.000000 length=32\n\
+             00000000  41 42 43 44 45 46 47 48  49 4a 4b 4c 4d 4e 4f 50  |ABCDEFGHIJKLMNOP|\n\
+             00000010  51 52 53 54 55 56 57 58  59 5a 5b 5c 5d 5e 5f 60  |QRSTUVWXYZ[\\]^_`|\n"
+        );
+    }
+
+    #[test]
+    fn test_partial_final_line_is_padded() {
+        let block = format_block(
+            Direction::Received,
+            at(86400 + 13 * 3600 + 4 * 60 + 5, 42),
+            0x20,
+            b"GET / HTTP/1.1\r\n\x00\xffok",
+        );
+        assert_eq!(
+            block,
+            "< 13:04:05.000042 length=20\n\
+             00000020  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|\n\
+             00000030  00 ff 6f 6b                                       |..ok|\n"
+        );
+        // The ASCII gutter starts in the same column on every line.
+        let columns: Vec<usize> = block
+            .lines()
+            .skip(1)
+            .map(|line| line.find('|').unwrap())
+            .collect();
+        assert_eq!(columns, [60, 60]);
+    }
+
+    #[test]
+    fn test_short_lines_before_and_after_the_gap() {
+        let block = format_block(Direction::Sent, at(0, 0), 0, b"abcdefghi");
+        assert_eq!(
+            block.lines().nth(1).unwrap(),
+            "00000000  61 62 63 64 65 66 67 68  69                       |abcdefghi|"
+        );
+        let block = format_block(Direction::Sent, at(0, 0), 0, b"hello\n");
+        assert_eq!(
+            block.lines().nth(1).unwrap(),
+            "00000000  68 65 6c 6c 6f 0a                                 |hello.|"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Best-effort copies of received data for `--mirror`.
+
+use crate::{
+    args::{Args, Endpoint, IpVersion, Protocol},
+    network::{resolve, unspecified_address, MAX_DATAGRAM_PAYLOAD},
+    stats::Transfer,
+};
+use anyhow::{bail, Result};
+use log::{error, info};
+use std::{
+    io::Write,
+    net::{TcpStream, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Chunks waiting for the mirror before new ones are dropped.
+const QUEUE_LENGTH: usize = 64;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hands received chunks to a background thread that writes them to the
+/// mirror. The session never waits for it: chunks that do not fit in the
+/// queue, or arrive after the mirror failed, are counted as dropped.
+pub struct Mirror {
+    queue: SyncSender<Vec<u8>>,
+    transfer: Arc<Transfer>,
+}
+
+impl Mirror {
+    /// Connects to `target` in the background and starts forwarding, counting
+    /// drops in `transfer`.
+    pub fn start(target: &Endpoint, args: &Args, transfer: Arc<Transfer>) -> Self {
+        let (queue, chunks) = mpsc::sync_channel(QUEUE_LENGTH);
+        transfer.attach_mirror();
+        let target = target.clone();
+        let protocol = args.mirror_protocol;
+        let ip_version = args.ip_version;
+        let worker_transfer = transfer.clone();
+        thread::spawn(move || {
+            if let Err(e) = forward(&target, protocol, ip_version, &chunks, &worker_transfer) {
+                error!("Mirroring to {} stopped: {:#}", target, e);
+            }
+            // Whatever is still queued can no longer be delivered.
+            for chunk in chunks.try_iter() {
+                worker_transfer.add_mirror_dropped(chunk.len() as u64);
+            }
+        });
+        Mirror { queue, transfer }
+    }
+
+    pub fn send(&self, data: &[u8]) {
+        if self.queue.try_send(data.to_vec()).is_err() {
+            self.transfer.add_mirror_dropped(data.len() as u64);
+        }
+    }
+}
+
+/// Writes queued chunks to the mirror until the session ends or the mirror
+/// fails.
+fn forward(
+    target: &Endpoint,
+    protocol: Protocol,
+    ip_version: Option<IpVersion>,
+    chunks: &Receiver<Vec<u8>>,
+    transfer: &Transfer,
+) -> Result<()> {
+    let address = resolve(&target.host, target.port, ip_version)?;
+    match protocol {
+        Protocol::Tcp => {
+            let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+            info!("Mirroring to {}", address);
+            for chunk in chunks {
+                if let Err(e) = stream.write_all(&chunk) {
+                    transfer.add_mirror_dropped(chunk.len() as u64);
+                    return Err(e.into());
+                }
+            }
+        }
+        Protocol::Udp => {
+            let socket = UdpSocket::bind(unspecified_address(address))?;
+            socket.connect(address)?;
+            info!("Mirroring to {} over UDP", address);
+            for chunk in chunks {
+                for datagram in chunk.chunks(MAX_DATAGRAM_PAYLOAD) {
+                    // Refusals come back asynchronously; the next datagram may
+                    // well get through, so keep going.
+                    if socket.send(datagram).is_err() {
+                        transfer.add_mirror_dropped(datagram.len() as u64);
+                    }
+                }
+            }
+        }
+        Protocol::Quic => bail!("Mirroring over QUIC is not supported"),
+    }
+    Ok(())
+}
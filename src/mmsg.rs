@@ -0,0 +1,240 @@
+//! Batched UDP receive on Linux: `recvmmsg` takes up to `--recv-batch`
+//! datagrams from the kernel per system call, which keeps a listener up with
+//! feeds of hundreds of thousands of datagrams a second.
+//!
+//! The socket also asks for `SO_RXQ_OVFL`, so that every datagram carries how
+//! many the kernel has dropped on it so far for want of buffer space, and
+//! `MSG_TRUNC` gives the full length of datagrams too large for a buffer.
+
+use socket2::SockAddr;
+use std::{
+    io, mem,
+    net::{SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    ptr,
+};
+
+/// Room for the `SO_RXQ_OVFL` control message, kept aligned for `cmsghdr`.
+type Control = [u64; 8];
+
+/// The buffers of one batch, and the datagrams the last `recvmmsg` left in
+/// them that have not been handed out yet.
+pub struct Batch {
+    buffers: Vec<Vec<u8>>,
+    addresses: Vec<libc::sockaddr_storage>,
+    controls: Vec<Control>,
+    /// The full length and sender of each datagram in the last batch.
+    received: Vec<(usize, SocketAddr)>,
+    next: usize,
+    drops: u64,
+}
+
+impl Batch {
+    /// Allocates room for `size` datagrams of up to `buffer_size` bytes and
+    /// turns on drop counting on `socket`.
+    pub fn new(socket: &UdpSocket, size: usize, buffer_size: usize) -> io::Result<Self> {
+        let enable: libc::c_int = 1;
+        // SAFETY: the descriptor is open and `enable` outlives the call.
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RXQ_OVFL,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Batch {
+            buffers: vec![vec![0; buffer_size]; size],
+            // SAFETY: sockaddr_storage is plain data that all-zero bytes make valid.
+            addresses: vec![unsafe { mem::zeroed() }; size],
+            controls: vec![[0; 8]; size],
+            received: Vec::with_capacity(size),
+            next: 0,
+            drops: 0,
+        })
+    }
+
+    /// Whether datagrams of the last batch are still waiting.
+    pub fn pending(&self) -> bool {
+        self.next < self.received.len()
+    }
+
+    /// Moves on to the next datagram and returns its sender, receiving a new
+    /// batch once the last one is used up. That waits for the first datagram
+    /// as long as the socket's read timeout, and takes whatever else is
+    /// already queued without waiting.
+    pub fn next(&mut self, socket: &UdpSocket) -> io::Result<SocketAddr> {
+        if !self.pending() {
+            self.receive(socket)?;
+        }
+        self.next += 1;
+        Ok(self.received[self.next - 1].1)
+    }
+
+    /// The datagram `next` moved on to, as much of it as the buffer held.
+    pub fn datagram(&self) -> &[u8] {
+        let (len, _) = self.received[self.next - 1];
+        let buffer = &self.buffers[self.next - 1];
+        &buffer[..len.min(buffer.len())]
+    }
+
+    /// The full length of the datagram `next` moved on to, if it was too
+    /// large for the buffer and cut short.
+    pub fn truncated(&self) -> Option<usize> {
+        let (len, _) = self.received[self.next - 1];
+        (len > self.buffers[self.next - 1].len()).then_some(len)
+    }
+
+    /// How many datagrams the kernel has dropped on the socket, as of the
+    /// latest one received.
+    pub fn drops(&self) -> u64 {
+        self.drops
+    }
+
+    fn receive(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        let mut iovecs: Vec<libc::iovec> = self
+            .buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(&mut self.addresses)
+            .zip(&mut self.controls)
+            .map(|((iovec, address), control)| {
+                // SAFETY: mmsghdr is plain data that all-zero bytes make valid.
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = address as *mut _ as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = mem::size_of_val(address) as libc::socklen_t;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                header.msg_hdr.msg_controllen = mem::size_of_val(control) as _;
+                header
+            })
+            .collect();
+        // SAFETY: every header points at buffers that outlive the call.
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as libc::c_uint,
+                libc::MSG_WAITFORONE | libc::MSG_TRUNC,
+                ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.received.clear();
+        self.next = 0;
+        for (header, address) in headers.iter().zip(&self.addresses).take(count as usize) {
+            // SAFETY: the kernel filled in `msg_namelen` bytes of the address.
+            let src = unsafe { SockAddr::new(*address, header.msg_hdr.msg_namelen) };
+            let src = src.as_socket().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "A datagram came from a non-IP address",
+                )
+            })?;
+            if let Some(drops) = dropped(&header.msg_hdr) {
+                self.drops = u64::from(drops);
+            }
+            self.received.push((header.msg_len as usize, src));
+        }
+        Ok(())
+    }
+}
+
+/// The drop counter in a received message's control data. The kernel only
+/// adds it once there have been drops.
+fn dropped(message: &libc::msghdr) -> Option<u32> {
+    // SAFETY: the control buffer holds `msg_controllen` bytes the kernel wrote.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(message);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SO_RXQ_OVFL {
+                return Some(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const u32));
+            }
+            cmsg = libc::CMSG_NXTHDR(message, cmsg);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::SockRef;
+    use std::time::Duration;
+
+    #[test]
+    fn test_batch_keeps_datagram_boundaries_and_senders() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        first.send_to(b"one", destination).unwrap();
+        second.send_to(b"", destination).unwrap();
+        first.send_to(b"four", destination).unwrap();
+
+        let mut batch = Batch::new(&socket, 2, 4).unwrap();
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let src = batch.next(&socket).unwrap();
+            assert_eq!(batch.truncated(), None);
+            received.push((batch.datagram().to_vec(), src));
+        }
+        assert!(!batch.pending());
+        assert_eq!(
+            received,
+            [
+                (b"one".to_vec(), first.local_addr().unwrap()),
+                (Vec::new(), second.local_addr().unwrap()),
+                (b"four".to_vec(), first.local_addr().unwrap()),
+            ]
+        );
+        assert_eq!(batch.drops(), 0);
+
+        first.send_to(b"fives", destination).unwrap();
+        batch.next(&socket).unwrap();
+        assert_eq!(batch.datagram(), b"five");
+        assert_eq!(batch.truncated(), Some(5));
+    }
+
+    #[test]
+    fn test_batch_counts_kernel_drops() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        SockRef::from(&socket).set_recv_buffer_size(4096).unwrap();
+        let mut batch = Batch::new(&socket, 8, 65535).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // Far more than the receive buffer holds, so the kernel drops some.
+        for _ in 0..200 {
+            sender.send_to(&[0; 1000], destination).unwrap();
+        }
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut received = 0;
+        while batch.next(&socket).is_ok() {
+            received += 1;
+        }
+        assert!(received < 200);
+        // The count comes with the next datagram to arrive.
+        sender.send_to(b"next", destination).unwrap();
+        batch.next(&socket).unwrap();
+        assert_eq!(batch.datagram(), b"next");
+        assert_eq!(batch.drops(), 200 - received);
+    }
+}
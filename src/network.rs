@@ -1,172 +1,6425 @@
-use crate::args::Args;
-use crate::command::execute_command;
-use anyhow::{bail, Result};
+use crate::args::{
+    Args, DatagramFraming, Encoding, Endpoint, ForwardTarget, IpVersion, Protocol, ProxyUrl,
+    Retries,
+};
+use crate::broker;
+use crate::command::{capture_command, execute_command, run_command};
+use crate::compression::{compressed, decompressed, AutoDecompress};
+use crate::crlf::{Crlf, StripCr};
+#[cfg(feature = "dtls")]
+use crate::dtls::{self, Listener, Session};
+use crate::encoding::{Base64Encoder, Decoded, HexEncoder};
+#[cfg(target_os = "linux")]
+use crate::fastopen;
+use crate::feed::StdinFeed;
+use crate::hexdump::{self, Direction};
+use crate::mirror::Mirror;
+#[cfg(target_os = "linux")]
+use crate::mmsg::Batch as RecvBatch;
+use crate::pcap::{self, Flow};
+use crate::progress::Progress;
+use crate::proxy;
+use crate::psk::{self, Opener, Sealer};
+use crate::quic::{self, QuicHandle, QuicReader, QuicStream, QuicWriter};
+use crate::recording::{self, received_data, Replay, Verified};
+use crate::records::RecordReader;
+use crate::reliable;
+use crate::scan;
+#[cfg(target_os = "linux")]
+use crate::sendfile::sendfile_all;
+use crate::sequence::{self, Reassembly};
+#[cfg(target_os = "linux")]
+use crate::ssm;
+use crate::starttls;
+use crate::stats::{Counted, ExitReason, Transfer};
+use crate::throttle::{chunk_size, Paced, Throttled, TokenBucket};
+use crate::tls::{self, TlsReader, TlsWriter};
+use crate::websocket::{self, WsReader, WsWriter};
+use anyhow::{anyhow, bail, Context, Result};
+#[cfg(not(feature = "dtls"))]
+use dtls::{Listener, Session};
 use log::{error, info};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
 use std::{
-    fs::File,
-    io::{self, BufReader, Read, Write},
-    net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream, UdpSocket},
-    time::Duration,
+    borrow::Cow,
+    collections::HashMap,
+    ffi::CString,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, IsTerminal, Read, Write},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV6, TcpListener, TcpStream,
+        ToSocketAddrs, UdpSocket,
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-pub fn is_valid_address(address: &str, version: &u8) -> bool {
+/// Checks that `address` is an IP literal of the requested version that can be
+/// used as a unicast endpoint, or the broadcast address with `broadcast`.
+/// `allow_any` skips everything but the parse.
+pub fn is_valid_address(address: &str, version: &u8, allow_any: bool, broadcast: bool) -> bool {
     match version {
-        4 => address.parse::<Ipv4Addr>().map_or(false, |ip| {
-            ip.is_global() || ip.is_shared() || ip.is_private() || ip.is_loopback()
-        }),
+        4 => address
+            .parse::<Ipv4Addr>()
+            .is_ok_and(|ip| allow_any || !(ip.is_multicast() || (ip.is_broadcast() && !broadcast))),
         6 => address
             .parse::<Ipv6Addr>()
-            .map_or(false, |ip| ip.is_global() || ip.is_loopback()),
+            .is_ok_and(|ip| allow_any || !ip.is_multicast()),
         _ => false,
     }
 }
 
-fn handle_tcp_connection(mut stream: TcpStream, args: &Args, timeout: Duration) -> Result<()> {
-    stream.set_read_timeout(Some(timeout))?;
-    if let Some(command) = &args.exec {
-        execute_command(stream, command)?;
-    } else {
-        let mut buffer = Vec::new();
-        stream.read_to_end(&mut buffer)?;
-        if let Some(file_path) = &args.file {
-            let mut file = File::create(file_path)?;
-            file.write_all(&buffer)?;
+/// Works out the IP version of a literal address, rejecting an explicit
+/// `--ip-version` that contradicts it.
+pub fn literal_ip_version(ip: &IpAddr, requested: Option<IpVersion>) -> Result<IpVersion> {
+    let actual = IpVersion::of(ip);
+    match requested {
+        Some(requested) if requested != actual => bail!(
+            "address {} is {} but --ip-version {} was requested",
+            ip,
+            actual,
+            requested.number()
+        ),
+        _ => Ok(actual),
+    }
+}
+
+/// The index of the network interface `name`.
+fn interface_index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `name` is a valid NUL-terminated string that outlives the call.
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "there is no interface by that name",
+        )),
+        index => Ok(index),
+    }
+}
+
+/// Returns `address` without the `%zone` suffix of a scoped IPv6 literal.
+pub fn strip_zone(address: &str) -> &str {
+    address.split_once('%').map_or(address, |(ip, _)| ip)
+}
+
+/// Turns the zone of a scoped IPv6 literal into a scope ID. Zones are either
+/// numeric (`fe80::1%3`) or interface names (`fe80::1%eth0`).
+fn parse_scope_id(zone: &str) -> Result<u32> {
+    if let Ok(index) = zone.parse::<u32>() {
+        return Ok(index);
+    }
+    interface_index(zone).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidInput => anyhow!("Invalid zone ID '{}'", zone),
+        _ => anyhow!("Network interface '{}' in zone ID does not exist", zone),
+    })
+}
+
+/// Resolves `host` (a name or an IP literal) to every address it has, restricted
+/// to the family selected by `--ip-version` when one was given.
+pub fn resolve_all(host: &str, port: u16, version: Option<IpVersion>) -> Result<Vec<SocketAddr>> {
+    if let Some((ip, zone)) = host.split_once('%') {
+        let ip: Ipv6Addr = ip
+            .parse()
+            .with_context(|| format!("Zone IDs are only valid on IPv6 literals, got '{}'", host))?;
+        return Ok(vec![
+            SocketAddrV6::new(ip, port, 0, parse_scope_id(zone)?).into()
+        ]);
+    }
+
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .with_context(|| match version {
+            Some(version) => format!("Failed to resolve '{}' to an {} address", host, version),
+            None => format!("Failed to resolve '{}'", host),
+        })?
+        .filter(|addr| version.is_none_or(|version| IpVersion::of(&addr.ip()) == version))
+        .collect();
+    if addrs.is_empty() {
+        match version {
+            Some(version) => bail!("'{}' has no {} address", host, version),
+            None => bail!("'{}' did not resolve to any address", host),
+        }
+    }
+    Ok(addrs)
+}
+
+/// Resolves `host` to its first address; see [`resolve_all`].
+pub fn resolve(host: &str, port: u16, version: Option<IpVersion>) -> Result<SocketAddr> {
+    Ok(resolve_all(host, port, version)?[0])
+}
+
+/// Orders the addresses of `host` for connection attempts.
+///
+/// Families alternate, starting with `--ip-version` or, failing that, whichever
+/// family the resolver listed first. `--no-fallback` drops the other family.
+pub fn connection_candidates(host: &str, port: u16, args: &Args) -> Result<Vec<SocketAddr>> {
+    let mut addrs = resolve_all(host, port, None)?;
+    // A socket bound to --source only reaches addresses of its own family.
+    if let Some(source) = args.source {
+        let version = IpVersion::of(&source);
+        addrs.retain(|addr| IpVersion::of(&addr.ip()) == version);
+        if addrs.is_empty() {
+            bail!(
+                "--source {} is an {} address, but '{}' has none",
+                source,
+                version,
+                host
+            );
+        }
+    }
+    let preferred = args
+        .ip_version
+        .unwrap_or_else(|| IpVersion::of(&addrs[0].ip()));
+    let (preferred_addrs, other_addrs): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| IpVersion::of(&addr.ip()) == preferred);
+
+    if args.no_fallback {
+        if preferred_addrs.is_empty() {
+            bail!("'{}' has no {} address", host, preferred);
+        }
+        return Ok(preferred_addrs);
+    }
+
+    Ok(interleave(preferred_addrs, other_addrs))
+}
+
+fn interleave(first: Vec<SocketAddr>, second: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let mut merged = Vec::with_capacity(first.len() + second.len());
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return merged,
+            (a, b) => merged.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// How long a connection attempt gets before the next candidate is tried in
+/// parallel, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to whichever candidate answers first, Happy Eyeballs style.
+///
+/// A new attempt starts every [`CONNECTION_ATTEMPT_DELAY`], or as soon as the
+/// previous one fails. Attempts still running when one succeeds are abandoned;
+/// their sockets are closed as soon as they finish. Each attempt gives up after
+/// `connect_timeout`, if one is set.
+pub fn connect_any(
+    candidates: &[SocketAddr],
+    source: &Source,
+    connect_timeout: Option<Duration>,
+) -> Result<TcpStream> {
+    let (sender, receiver) = mpsc::channel();
+    let mut next = 0;
+    let mut pending = 0;
+    let mut last_error = None;
+
+    loop {
+        if let Some(&addr) = candidates.get(next) {
+            let (sender, source) = (sender.clone(), source.clone());
+            thread::spawn(move || {
+                let _ = sender.send((addr, connect_from(addr, &source, connect_timeout)));
+            });
+            info!("Trying {}...", addr);
+            next += 1;
+            pending += 1;
+        }
+        if pending == 0 {
+            break;
+        }
+
+        let event = if next < candidates.len() {
+            receiver.recv_timeout(CONNECTION_ATTEMPT_DELAY)
         } else {
-            io::stdout().write_all(&buffer)?;
+            receiver
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+        };
+        match event {
+            Ok((addr, Ok(stream))) => {
+                info!("Connected to {} from {}", addr, stream.local_addr()?);
+                return Ok(stream);
+            }
+            Ok((addr, Err(e))) => {
+                info!("Connection to {} failed: {}", addr, e);
+                pending -= 1;
+                last_error = Some(match connect_timeout {
+                    Some(connect_timeout) if e.kind() == io::ErrorKind::TimedOut => anyhow!(
+                        "connection to {} timed out after {:?}",
+                        addr,
+                        connect_timeout
+                    ),
+                    _ => anyhow!(e).context(format!("Failed to connect to {}", addr)),
+                });
+            }
+            Err(_) => {}
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("No addresses to connect to")))
+}
+
+/// The local end a client connects from, as `--source` and `--source-port`
+/// pin it, and how its socket is set up.
+#[derive(Clone, Debug, Default)]
+pub struct Source {
+    pub ip: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub interface: Option<String>,
+    pub sndbuf: Option<usize>,
+    pub rcvbuf: Option<usize>,
+    pub ttl: Option<u32>,
+    pub tos: Option<u32>,
+    pub fast_open: bool,
+    pub fwmark: Option<u32>,
+    pub multicast_if: Option<Ipv4Addr>,
+    pub multicast_loop: bool,
+}
+
+impl Source {
+    pub fn of(args: &Args) -> Self {
+        Source {
+            ip: args.source,
+            port: args.source_port,
+            interface: args.interface.clone(),
+            sndbuf: args.sndbuf,
+            rcvbuf: args.rcvbuf,
+            ttl: args.ttl,
+            tos: args.tos_byte(),
+            fast_open: args.fastopen,
+            fwmark: args.fwmark,
+            multicast_if: args.multicast_if,
+            multicast_loop: args.multicast_loop,
         }
     }
+
+    /// The address to bind to before reaching `destination`, if any is pinned.
+    fn address_for(&self, destination: SocketAddr) -> Option<SocketAddr> {
+        if self.ip.is_none() && self.port.is_none() {
+            return None;
+        }
+        let ip = self
+            .ip
+            .unwrap_or_else(|| unspecified_address(destination).ip());
+        Some(SocketAddr::new(ip, self.port.unwrap_or(0)))
+    }
+}
+
+/// Explains a failure to bind to the `local` end a client asked for.
+fn bind_error(local: SocketAddr, e: io::Error) -> io::Error {
+    let hint = match e.kind() {
+        io::ErrorKind::AddrInUse => "; another socket holds the port, so pick another --source-port or wait for it to be released",
+        io::ErrorKind::PermissionDenied if local.port() < 1024 => "; ports below 1024 need root or CAP_NET_BIND_SERVICE",
+        io::ErrorKind::AddrNotAvailable => "; --source must be an address of this host",
+        _ => "",
+    };
+    io::Error::new(e.kind(), format!("cannot bind to {}: {}{}", local, e, hint))
+}
+
+/// Whether `--interface` can be honoured on this platform.
+pub const INTERFACE_SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+));
+
+/// Keeps `socket` to the network interface `name`: `SO_BINDTODEVICE` on
+/// Linux, `IP_BOUND_IF` and `IPV6_BOUND_IF` on Apple systems.
+#[allow(unused_variables)]
+fn bind_to_interface(socket: &Socket, domain: Domain, name: &str) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let result = socket.bind_device(Some(name.as_bytes()));
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let result = interface_index(name)
+        // interface_index fails rather than return 0.
+        .map(|index| std::num::NonZeroU32::new(index).unwrap())
+        .and_then(|index| {
+            if domain == Domain::IPV6 {
+                socket.bind_device_by_index_v6(Some(index))
+            } else {
+                socket.bind_device_by_index_v4(Some(index))
+            }
+        });
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    )))]
+    let result = Err(io::Error::from(io::ErrorKind::Unsupported));
+    result.map_err(|e| {
+        let hint = match e.raw_os_error() {
+            Some(libc::EPERM) | Some(libc::EACCES) => {
+                "; binding to an interface needs root or CAP_NET_RAW"
+            }
+            Some(libc::ENODEV) => "; there is no interface by that name",
+            _ => "",
+        };
+        io::Error::new(
+            e.kind(),
+            format!("cannot use interface {}: {}{}", name, e, hint),
+        )
+    })
+}
+
+/// Sets `--sndbuf` and `--rcvbuf` on `socket` and logs what the kernel made
+/// of them; Linux doubles the value for its bookkeeping and clamps it to
+/// `net.core.wmem_max` and `rmem_max`.
+fn set_buffer_sizes(
+    socket: &Socket,
+    sndbuf: Option<usize>,
+    rcvbuf: Option<usize>,
+) -> io::Result<()> {
+    if let Some(size) = sndbuf {
+        socket.set_send_buffer_size(size)?;
+        info!(
+            "Asked for a {} byte send buffer, got {}",
+            size,
+            socket.send_buffer_size()?
+        );
+    }
+    if let Some(size) = rcvbuf {
+        socket.set_recv_buffer_size(size)?;
+        info!(
+            "Asked for a {} byte receive buffer, got {}",
+            size,
+            socket.recv_buffer_size()?
+        );
+    }
     Ok(())
 }
 
-fn run_tcp_server(args: &Args, destination: String, timeout: Duration) -> Result<()> {
-    let listener = TcpListener::bind(destination.clone())?;
-    info!("Listening on {}...", destination);
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                if let Err(e) = handle_tcp_connection(stream, args, timeout) {
-                    error!("Failed to handle connection: {}", e);
-                }
+/// Sets the `--ttl` of packets to `destination`, as the multicast TTL when it
+/// is a group.
+fn set_ttl(socket: &Socket, destination: IpAddr, ttl: u32) -> io::Result<()> {
+    match destination {
+        IpAddr::V4(ip) if ip.is_multicast() => socket.set_multicast_ttl_v4(ttl)?,
+        IpAddr::V4(_) => socket.set_ttl(ttl)?,
+        IpAddr::V6(ip) if ip.is_multicast() => socket.set_multicast_hops_v6(ttl)?,
+        IpAddr::V6(_) => socket.set_unicast_hops_v6(ttl)?,
+    }
+    info!("Sending to {} with a TTL of {}", destination, ttl);
+    Ok(())
+}
+
+/// Sets how datagrams to the multicast `group` leave: through
+/// `--multicast-if`, and looped back to this host only with
+/// `--multicast-loop`.
+fn set_multicast_options(socket: &Socket, group: IpAddr, source: &Source) -> io::Result<()> {
+    match group {
+        IpAddr::V4(_) => {
+            if let Some(interface) = source.multicast_if {
+                socket.set_multicast_if_v4(&interface)?;
             }
-            Err(e) => error!("Failed to accept connection: {}", e),
+            socket.set_multicast_loop_v4(source.multicast_loop)?;
         }
+        IpAddr::V6(_) if source.multicast_if.is_some() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--multicast-if only picks the interface for IPv4 groups; use --interface for IPv6",
+            ))
+        }
+        IpAddr::V6(_) => socket.set_multicast_loop_v6(source.multicast_loop)?,
     }
     Ok(())
 }
 
-fn handle_udp_connection(socket: UdpSocket, args: &Args, timeout: Duration) -> Result<()> {
-    let mut buffer = vec![0u8; 65535];
-    let (amt, _src) = socket.recv_from(&mut buffer)?;
-    socket.set_read_timeout(Some(timeout))?;
-    buffer.truncate(amt);
+/// Whether `--multicast-source` can be honoured on this platform.
+pub const SSM_SUPPORTED: bool = cfg!(target_os = "linux");
 
-    if let Some(command) = &args.exec {
-        execute_command(io::Cursor::new(buffer), command)?;
+/// Whether `--fwmark` can be honoured on this platform.
+pub const FWMARK_SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+/// Sets the `--fwmark` routing policy can select on.
+#[allow(unused_variables)]
+fn set_fwmark(socket: &Socket, mark: u32) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let result = socket.set_mark(mark);
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let result = Err(io::Error::from(io::ErrorKind::Unsupported));
+    result.map_err(|e| {
+        let hint = match e.raw_os_error() {
+            Some(libc::EPERM) => "; marking needs root or CAP_NET_ADMIN",
+            _ => "",
+        };
+        io::Error::new(
+            e.kind(),
+            format!("cannot set --fwmark {:#x}: {}{}", mark, e, hint),
+        )
+    })?;
+    info!("Marking the socket with {:#x}", mark);
+    Ok(())
+}
+
+/// Whether `--freebind` can be honoured on this platform.
+pub const FREEBIND_SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd"
+));
+
+/// Lets `socket` bind to an address the host does not have, for `--freebind`.
+#[allow(unused_variables)]
+fn set_freebind(socket: &Socket, domain: Domain) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return if domain == Domain::IPV6 {
+        socket.set_freebind_ipv6(true)
     } else {
-        if let Some(file_path) = &args.file {
-            let mut file = File::create(file_path)?;
-            file.write_all(&buffer)?;
+        socket.set_freebind(true)
+    };
+    #[cfg(target_os = "freebsd")]
+    {
+        use std::os::fd::AsRawFd;
+        let (level, name) = if domain == Domain::IPV6 {
+            (libc::IPPROTO_IPV6, libc::IPV6_BINDANY)
+        } else {
+            (libc::IPPROTO_IP, libc::IP_BINDANY)
+        };
+        let on: libc::c_int = 1;
+        // SAFETY: the descriptor is open and `on` outlives the call.
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &on as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        return if result < 0 {
+            Err(io::Error::last_os_error())
         } else {
-            io::stdout().write_all(&buffer)?;
+            Ok(())
+        };
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Whether `--tcp-user-timeout` can be honoured on this platform.
+pub const TCP_USER_TIMEOUT_SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+/// Whether `--tos` and `--dscp` can be honoured on this platform.
+pub const TOS_SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "macos"
+));
+
+/// Marks the packets of `socket` with the TOS byte, or the traffic class for
+/// IPv6.
+#[allow(unused_variables)]
+fn set_tos(socket: &Socket, domain: Domain, tos: u32) -> io::Result<()> {
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "dragonfly",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "macos"
+    ))]
+    let result = if domain == Domain::IPV6 {
+        socket.set_tclass_v6(tos)
+    } else {
+        socket.set_tos(tos)
+    };
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "dragonfly",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "macos"
+    )))]
+    let result = Err(io::Error::from(io::ErrorKind::Unsupported));
+    result.map_err(|e| {
+        let hint = match e.raw_os_error() {
+            Some(libc::EPERM) | Some(libc::EACCES) => {
+                "; this system only lets privileged users set it, so run as root or with CAP_NET_ADMIN"
+            }
+            _ => "",
+        };
+        io::Error::new(
+            e.kind(),
+            format!("cannot mark packets with TOS {:#04x}: {}{}", tos, e, hint),
+        )
+    })?;
+    info!("Marking packets with TOS {:#04x} (DSCP {})", tos, tos >> 2);
+    Ok(())
+}
+
+/// A socket of type `ty` for reaching `destination`, set up and bound as
+/// `source` says.
+pub fn socket_from(source: &Source, destination: SocketAddr, ty: Type) -> io::Result<Socket> {
+    let domain = Domain::for_address(destination);
+    let socket = Socket::new(domain, ty, None)?;
+    if let Some(name) = &source.interface {
+        bind_to_interface(&socket, domain, name)?;
+    }
+    set_buffer_sizes(&socket, source.sndbuf, source.rcvbuf)?;
+    if let Some(ttl) = source.ttl {
+        set_ttl(&socket, destination.ip(), ttl)?;
+    }
+    if let Some(tos) = source.tos {
+        set_tos(&socket, domain, tos)?;
+    }
+    if let Some(mark) = source.fwmark {
+        set_fwmark(&socket, mark)?;
+    }
+    if destination.ip().is_multicast() {
+        set_multicast_options(&socket, destination.ip(), source)?;
+    }
+    #[cfg(target_os = "linux")]
+    if source.fast_open && ty == Type::STREAM {
+        fastopen::enable_connect(&socket)?;
+    }
+    let local = match source.address_for(destination) {
+        Some(local) => local,
+        // A UDP client is bound up front, so that its local end can be logged.
+        None if ty == Type::DGRAM => unspecified_address(destination),
+        None => return Ok(socket),
+    };
+    if source.port.is_some() && ty == Type::STREAM {
+        // The port is still in TIME_WAIT right after a --retry or a rerun.
+        socket.set_reuse_address(true)?;
+    }
+    socket
+        .bind(&local.into())
+        .map_err(|e| bind_error(local, e))?;
+    Ok(socket)
+}
+
+/// Connects to `addr` with a socket set up as `source` says.
+fn connect_from(
+    addr: SocketAddr,
+    source: &Source,
+    connect_timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let socket = socket_from(source, addr, Type::STREAM)?;
+    match connect_timeout {
+        Some(connect_timeout) => socket.connect_timeout(&addr.into(), connect_timeout)?,
+        None => socket.connect(&addr.into())?,
+    }
+    Ok(socket.into())
+}
+
+/// Whether to turn Nagle's algorithm off: with `--nodelay`, or when someone is
+/// typing into the connection, where each line should go out at once.
+fn wants_nodelay(args: &Args) -> bool {
+    args.nodelay
+        || (args.file.is_none()
+            && args.exec.is_none()
+            && !args.no_stdin
+            && io::stdin().is_terminal())
+}
+
+/// Applies the socket options asked for to a connected TCP stream, whether
+/// netpipe connected it or accepted it. A fast open client may not have sent
+/// its SYN yet, so the peer is not known here.
+fn set_stream_options(stream: &TcpStream, args: &Args) -> io::Result<()> {
+    if wants_nodelay(args) {
+        stream.set_nodelay(true)?;
+        info!("TCP_NODELAY is on");
+    }
+    if let Some(idle) = args.keepalive {
+        set_keepalive(stream, idle, args)?;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(ms) = args.tcp_user_timeout {
+        let timeout = Duration::from_millis(ms.into());
+        SockRef::from(stream).set_tcp_user_timeout(Some(timeout))?;
+        info!("TCP_USER_TIMEOUT is {:?}", timeout);
+    }
+    if let Some(linger) = args.linger {
+        SockRef::from(stream).set_linger(Some(Duration::from_secs(linger)))?;
+        info!("SO_LINGER is {}s", linger);
+    }
+    Ok(())
+}
+
+/// Turns on `--keepalive`, with the probe timing where the platform lets it be
+/// set and the system's defaults elsewhere.
+fn set_keepalive(stream: &TcpStream, idle: Option<Duration>, args: &Args) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "macos",
+        target_os = "ios"
+    ))]
+    {
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(idle) = idle {
+            keepalive = keepalive.with_time(idle);
+        }
+        if let Some(interval) = args.keepalive_interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        if let Some(count) = args.keepalive_count {
+            keepalive = keepalive.with_retries(count);
+        }
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "macos",
+        target_os = "ios"
+    )))]
+    {
+        socket.set_keepalive(true)?;
+        if idle.is_some() || args.keepalive_interval.is_some() || args.keepalive_count.is_some() {
+            info!("Keepalive timing cannot be set on this platform; using the system's");
         }
     }
+    info!(
+        "Keepalive is on (idle {:?}, interval {:?}, count {:?})",
+        idle, args.keepalive_interval, args.keepalive_count
+    );
     Ok(())
 }
 
-fn run_udp_server(args: &Args, destination: String, timeout: Duration) -> Result<()> {
-    let socket = UdpSocket::bind(destination.clone())?;
-    info!("Listening on {}...", destination);
-    handle_udp_connection(socket, args, timeout)
+/// Where a client's outgoing data comes from: `--file`, stdin, or nothing at
+/// all with `--no-stdin`.
+pub fn open_input(args: &Args) -> Result<Box<dyn Read + Send>> {
+    match &args.file {
+        Some(file_path) => Ok(with_send_filters(
+            Box::new(BufReader::new(File::open(file_path)?)),
+            args,
+        )?),
+        None => stdin_unless_disabled(args),
+    }
 }
 
-pub fn run_server(args: &Args, protocol: &str, timeout: Duration) -> Result<()> {
-    let address = args.address.as_ref().unwrap();
-    let port = args.port.unwrap();
-    let destination = format!("{}:{}", address, port);
+pub fn stdin_unless_disabled(args: &Args) -> Result<Box<dyn Read + Send>> {
+    if args.no_stdin {
+        Ok(Box::new(io::empty()))
+    } else {
+        Ok(with_send_filters(Box::new(io::stdin()), args)?)
+    }
+}
 
-    match protocol {
-        "tcp" => run_tcp_server(args, destination, timeout),
-        "udp" => run_udp_server(args, destination, timeout),
-        _ => bail!("Invalid protocol '{}'.", protocol.to_uppercase()),
+/// Applies the conversions asked for on data we send, such as `--decode`,
+/// `--crlf` and `--compress`, in that order.
+fn with_send_filters(input: Box<dyn Read + Send>, args: &Args) -> io::Result<Box<dyn Read + Send>> {
+    let input: Box<dyn Read + Send> = match args.decode {
+        Some(encoding) => Box::new(Decoded::new(input, encoding)),
+        None => input,
+    };
+    let input: Box<dyn Read + Send> = if args.crlf {
+        Box::new(Crlf::new(input))
+    } else {
+        input
+    };
+    match args.compress {
+        Some(algorithm) => compressed(input, algorithm, args.compress_level),
+        None => Ok(input),
     }
 }
 
-fn prepare_buffer_from_file_or_stdin(args: &Args) -> Result<Vec<u8>> {
-    if let Some(file_path) = &args.file {
-        let file = File::open(file_path)?;
-        let mut buf_reader = BufReader::new(file);
-        let mut buffer = Vec::new();
-        buf_reader.read_to_end(&mut buffer)?;
-        Ok(buffer)
+/// Applies the conversions asked for on data we receive, such as
+/// `--decompress` or `--auto-decompress`, `--strip-cr` and `--encode`, in that
+/// order.
+pub fn with_receive_filters(output: Box<dyn Write>, args: &Args) -> io::Result<Box<dyn Write>> {
+    let output: Box<dyn Write> = match args.encode {
+        Some(Encoding::Base64) => Box::new(Base64Encoder::new(output)),
+        Some(Encoding::Hex) => Box::new(HexEncoder::new(output)),
+        None => output,
+    };
+    let output: Box<dyn Write> = if args.strip_cr {
+        Box::new(StripCr::new(output))
     } else {
-        let mut buffer = Vec::new();
-        io::stdin().read_to_end(&mut buffer)?;
-        Ok(buffer)
+        output
+    };
+    match args.decompress {
+        Some(algorithm) => decompressed(output, algorithm),
+        None if args.auto_decompress => Ok(Box::new(AutoDecompress::new(output))),
+        None => Ok(output),
     }
 }
 
-fn run_tcp_client(destination: String, buffer: Vec<u8>, timeout: Duration) -> Result<()> {
-    let mut stream = TcpStream::connect(destination)?;
-    stream.set_write_timeout(Some(timeout))?;
-    stream.write_all(&buffer)?;
-    Ok(())
+/// Standard output, where clients write what they receive.
+pub fn client_output(args: &Args) -> io::Result<Box<dyn Write>> {
+    with_receive_filters(Box::new(io::stdout()), args)
 }
 
-fn run_udp_client(destination: String, buffer: Vec<u8>, timeout: Duration) -> Result<()> {
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.set_write_timeout(Some(timeout))?;
-    socket.send_to(&buffer, destination)?;
-    Ok(())
+pub fn open_output(args: &Args) -> Result<Box<dyn Write>> {
+    let output: Box<dyn Write> = match &args.file {
+        // Every connection of a keep-open server shares the file, so never truncate it.
+        Some(file_path) if args.keep_open => Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file_path)?,
+        ),
+        Some(file_path) => Box::new(File::create(file_path)?),
+        None => Box::new(io::stdout()),
+    };
+    Ok(with_receive_filters(output, args)?)
 }
 
-pub fn run_client(args: &Args, protocol: &str, timeout: Duration) -> Result<()> {
-    let address = args.address.as_ref().unwrap();
-    let port = args.port.unwrap();
-    let destination = format!("{}:{}", address, port);
+/// Tracks when a connection last carried data in either direction, and when
+/// its input ran out.
+#[derive(Clone)]
+struct Activity(Arc<Mutex<ActivityState>>);
 
-    let buffer = prepare_buffer_from_file_or_stdin(args)?;
+struct ActivityState {
+    last_traffic: Instant,
+    input_eof: Option<Instant>,
+}
 
-    match protocol {
-        "tcp" => run_tcp_client(destination, buffer, timeout),
-        "udp" => run_udp_client(destination, buffer, timeout),
-        _ => bail!("Invalid protocol '{}'.", protocol.to_uppercase()),
+impl Activity {
+    fn new() -> Self {
+        Activity(Arc::new(Mutex::new(ActivityState {
+            last_traffic: Instant::now(),
+            input_eof: None,
+        })))
+    }
+
+    fn touch(&self) {
+        self.0.lock().unwrap().last_traffic = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.0.lock().unwrap().last_traffic.elapsed()
+    }
+
+    fn mark_input_eof(&self) {
+        self.0.lock().unwrap().input_eof = Some(Instant::now());
+    }
+
+    fn since_input_eof(&self) -> Option<Duration> {
+        self.0.lock().unwrap().input_eof.map(|eof| eof.elapsed())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::{
-        net::{IpAddr, Ipv4Addr, SocketAddr},
-        sync::atomic::{AtomicBool, Ordering},
-        thread,
-        time::Duration,
-    };
+/// Passes on at most `remaining` bytes and quietly drops the rest, so a command
+/// whose output runs past `--max-bytes` can still finish.
+struct Capped<W> {
+    inner: W,
+    remaining: u64,
+}
 
-    // Test TCP communication with server handling a single connection
-    #[test]
-    fn test_tcp_communication() {
-        let server_handle = thread::spawn(|| {
-            let args = Args::parse_from(&[
-                "test",
-                "--listen",
-                "--address",
-                "127.0.0.1",
-                "--port",
-                "8080",
-            ]);
-            run_server(&args, &Protocol::Tcp, Duration::from_secs(1)).unwrap();
-        });
+impl<W> Capped<W> {
+    fn new(inner: W, limit: u64) -> Self {
+        Capped {
+            inner,
+            remaining: limit,
+        }
+    }
+}
 
-        thread::sleep(Duration::from_millis(100)); // Allow server to start
+impl<W: Write> Write for Capped<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.remaining.min(buf.len() as u64) as usize;
+        if len == 0 {
+            return Ok(buf.len());
+        }
+        let n = self.inner.write(&buf[..len])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
 
-        let client_handle = thread::spawn(|| {
-            let args = Args::parse_from(&["test", "--address", "127.0.0.1", "--port", "8080"]);
-            run_client(&args, &Protocol::Tcp, Duration::from_secs(1)).unwrap();
-        });
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-        client_handle.join().unwrap();
-        // Server will exit after handling one connection due to timeout in test
-        server_handle.join().unwrap();
+/// Hands traffic to `--hex-dump`, `--pcap` and `--record`, whichever are
+/// enabled.
+fn capture(flow: &Flow, direction: Direction, offset: u64, data: &[u8]) {
+    hexdump::record(direction, offset, data);
+    pcap::record(flow, direction, offset, data);
+    recording::record(direction, data);
+}
+
+/// Captures everything read or written through it as traffic in one direction.
+struct Captured<T> {
+    inner: T,
+    flow: Flow,
+    direction: Direction,
+    offset: u64,
+}
+
+impl<T> Captured<T> {
+    fn new(inner: T, flow: Flow, direction: Direction) -> Self {
+        Captured {
+            inner,
+            flow,
+            direction,
+            offset: 0,
+        }
+    }
+}
+
+impl<T: Read> Read for Captured<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        capture(&self.flow, self.direction, self.offset, &buf[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Captured<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        capture(&self.flow, self.direction, self.offset, &buf[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A connection to pipe data over: TCP, or the stream of a QUIC connection.
+pub enum Connection {
+    Tcp(TcpStream),
+    Quic(QuicStream),
+}
+
+impl From<TcpStream> for Connection {
+    fn from(stream: TcpStream) -> Self {
+        Connection::Tcp(stream)
+    }
+}
+
+impl From<QuicStream> for Connection {
+    fn from(stream: QuicStream) -> Self {
+        Connection::Quic(stream)
+    }
+}
+
+impl Connection {
+    fn protocol(&self) -> Protocol {
+        match self {
+            Connection::Tcp(_) => Protocol::Tcp,
+            Connection::Quic(_) => Protocol::Quic,
+        }
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr(),
+            Connection::Quic(stream) => Ok(stream.peer_addr()),
+        }
+    }
+
+    fn flow(&self) -> io::Result<Flow> {
+        let local = match self {
+            Connection::Tcp(stream) => stream.local_addr()?,
+            Connection::Quic(stream) => stream.local_addr()?,
+        };
+        Ok(Flow::new(self.protocol(), local, self.peer_addr()?))
+    }
+
+    fn set_timeouts(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            Connection::Quic(stream) => {
+                stream.set_read_timeout(timeout);
+                stream.set_write_timeout(timeout);
+                Ok(())
+            }
+        }
+    }
+
+    fn handle(&self) -> io::Result<Handle> {
+        Ok(match self {
+            Connection::Tcp(stream) => Handle::Tcp(stream.try_clone()?),
+            Connection::Quic(stream) => Handle::Quic(stream.handle()),
+        })
+    }
+}
+
+/// A second handle on a connection, for adjusting its read timeout and shutting
+/// it down from another thread.
+enum Handle {
+    Tcp(TcpStream),
+    Quic(QuicHandle),
+}
+
+impl Handle {
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        match self {
+            Handle::Tcp(stream) => stream.read_timeout(),
+            Handle::Quic(handle) => Ok(handle.read_timeout()),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Handle::Tcp(stream) => stream.set_read_timeout(timeout),
+            Handle::Quic(handle) => {
+                handle.set_read_timeout(timeout);
+                Ok(())
+            }
+        }
+    }
+
+    /// Closes the connection in both directions, waking up whatever is blocked
+    /// on it.
+    fn shutdown(&self) {
+        match self {
+            Handle::Tcp(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            Handle::Quic(handle) => handle.shutdown(),
+        }
+    }
+}
+
+/// The sending half of a connection, sealed with `--psk` or encrypted with
+/// `--tls` when given, and framed as WebSocket messages with `--ws`.
+enum Outgoing {
+    Plain(TcpStream),
+    Sealed(Sealer<TcpStream>),
+    Tls(TlsWriter),
+    Quic(QuicWriter),
+    Ws(WsWriter<Outgoing>),
+}
+
+impl Outgoing {
+    /// The TCP socket underneath, which QUIC does not have and WebSocket
+    /// keeps to itself.
+    fn socket(&self) -> Option<&TcpStream> {
+        match self {
+            Outgoing::Plain(stream) => Some(stream),
+            Outgoing::Sealed(sealer) => Some(sealer.get_ref()),
+            Outgoing::Tls(writer) => Some(writer.socket()),
+            Outgoing::Quic(_) | Outgoing::Ws(_) => None,
+        }
+    }
+
+    /// Ends the stream once the input is done. A `--psk` peer is always told,
+    /// since it cannot otherwise tell the end from a cut connection; a TLS peer
+    /// gets close_notify and the socket is shut down only with `half_close`,
+    /// which is also when a QUIC stream is finished and a WebSocket closed.
+    fn close(&mut self, half_close: bool) -> io::Result<()> {
+        match self {
+            Outgoing::Plain(_) => {}
+            Outgoing::Sealed(sealer) => sealer.finish()?,
+            Outgoing::Tls(writer) if half_close => writer.close_notify()?,
+            Outgoing::Tls(_) => {}
+            Outgoing::Quic(writer) if half_close => writer.finish()?,
+            Outgoing::Quic(_) => {}
+            Outgoing::Ws(writer) if half_close => writer.close()?,
+            Outgoing::Ws(_) => {}
+        }
+        if let Some(socket) = self.socket().filter(|_| half_close) {
+            socket.shutdown(Shutdown::Write)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Outgoing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Outgoing::Plain(stream) => stream.write(buf),
+            Outgoing::Sealed(sealer) => sealer.write(buf),
+            Outgoing::Tls(writer) => writer.write(buf),
+            Outgoing::Quic(writer) => writer.write(buf),
+            Outgoing::Ws(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Outgoing::Plain(stream) => stream.flush(),
+            Outgoing::Sealed(sealer) => sealer.flush(),
+            Outgoing::Tls(writer) => writer.flush(),
+            Outgoing::Quic(writer) => writer.flush(),
+            Outgoing::Ws(writer) => writer.flush(),
+        }
+    }
+}
+
+/// The receiving half of a connection, opened with `--psk` or decrypted with
+/// `--tls` when given, and unframed from WebSocket messages with `--ws`.
+enum Incoming {
+    Plain(TcpStream),
+    Opened(Opener<TcpStream>),
+    Tls(TlsReader),
+    Quic(QuicReader),
+    Ws(Box<WsReader<Incoming, Outgoing>>),
+}
+
+impl Incoming {
+    fn handle(&self) -> io::Result<Handle> {
+        Ok(match self {
+            Incoming::Plain(stream) => Handle::Tcp(stream.try_clone()?),
+            Incoming::Opened(opener) => Handle::Tcp(opener.get_ref().try_clone()?),
+            Incoming::Tls(reader) => Handle::Tcp(reader.socket().try_clone()?),
+            Incoming::Quic(reader) => Handle::Quic(reader.handle()),
+            Incoming::Ws(reader) => reader.get_ref().handle()?,
+        })
+    }
+}
+
+impl Read for Incoming {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Incoming::Plain(stream) => stream.read(buf),
+            Incoming::Opened(opener) => opener.read(buf),
+            Incoming::Tls(reader) => reader.read(buf),
+            Incoming::Quic(reader) => reader.read(buf),
+            Incoming::Ws(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Splits a connection into its receiving and sending halves, running the
+/// `--psk` or `--tls` handshake first when one is given, after any
+/// `--starttls` preamble, and then any `--ws` upgrade. QUIC connections are
+/// encrypted already.
+fn split(connection: Connection, args: &Args) -> Result<(Incoming, Outgoing)> {
+    let stream = match connection {
+        Connection::Tcp(stream) => stream,
+        Connection::Quic(stream) => {
+            let (reader, writer) = stream.split();
+            return Ok((Incoming::Quic(reader), Outgoing::Quic(writer)));
+        }
+    };
+    let (mut reader, mut writer) = secure(stream, args)?;
+    if args.ws.is_none() {
+        return Ok((reader, writer));
+    }
+    let role = if args.listen {
+        websocket::accept(&mut reader, &mut writer, args.ws_url())?;
+        websocket::Role::Server
+    } else {
+        let url = args.ws_url().context("--ws needs a URL to connect to")?;
+        websocket::connect(&mut reader, &mut writer, url, &args.headers)?;
+        websocket::Role::Client
+    };
+    let (reader, writer) = websocket::split(reader, writer, role, args.ws_binary);
+    Ok((Incoming::Ws(Box::new(reader)), Outgoing::Ws(writer)))
+}
+
+/// The TLS or `--psk` layer of [`split`] for a TCP connection.
+fn secure(mut stream: TcpStream, args: &Args) -> Result<(Incoming, Outgoing)> {
+    let ws_url = args.ws_url();
+    if args.tls || ws_url.is_some_and(|url| url.secure) {
+        let (reader, writer) = if args.listen {
+            tls::accept(stream, args)?
+        } else {
+            starttls::negotiate(&mut stream, args)?;
+            // The server's certificate must match the name we were asked to reach.
+            let host = match ws_url {
+                Some(url) => &url.host,
+                None => args.address.as_deref().unwrap_or_default(),
+            };
+            tls::connect(stream, strip_zone(host), args)?
+        };
+        return Ok((Incoming::Tls(reader), Outgoing::Tls(writer)));
+    }
+    let writer = stream.try_clone()?;
+    let Some(psk) = &args.psk else {
+        return Ok((Incoming::Plain(stream), Outgoing::Plain(writer)));
+    };
+    let (opener, sealer) = psk::handshake(stream, writer, &psk::load_passphrase(psk)?)?;
+    info!("Agreed on --psk keys, the connection is encrypted");
+    Ok((Incoming::Opened(opener), Outgoing::Sealed(sealer)))
+}
+
+/// Shuts a connection down once `--max-time` has passed, waking up whatever
+/// is blocked on it. Dropping the watchdog disarms it.
+struct Watchdog {
+    _disarm: Option<mpsc::Sender<()>>,
+    fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    fn arm(handle: Handle, max_time: Option<Duration>) -> Result<Self> {
+        let fired = Arc::new(AtomicBool::new(false));
+        let Some(max_time) = max_time else {
+            return Ok(Watchdog {
+                _disarm: None,
+                fired,
+            });
+        };
+        let (disarm, disarmed) = mpsc::channel::<()>();
+        let watchdog_fired = fired.clone();
+        thread::spawn(move || {
+            if disarmed.recv_timeout(max_time) == Err(mpsc::RecvTimeoutError::Timeout) {
+                watchdog_fired.store(true, Ordering::SeqCst);
+                handle.shutdown();
+            }
+        });
+        Ok(Watchdog {
+            _disarm: Some(disarm),
+            fired,
+        })
+    }
+
+    /// Whether the connection was shut down because time ran out.
+    fn fired(&self) -> bool {
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// Where a [`TelnetFilter`] is in the byte stream.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum TelnetState {
+    #[default]
+    Data,
+    /// After an IAC.
+    Command,
+    /// After IAC WILL, WONT, DO or DONT, waiting for the option byte.
+    Option(u8),
+    /// Inside IAC SB ... IAC SE.
+    Subnegotiation,
+    /// After an IAC inside a subnegotiation.
+    SubnegotiationCommand,
+}
+
+/// The `--telnet` layer between the socket and the output: strips negotiation
+/// from the data and refuses every option the peer offers or asks for.
+///
+/// The state carries over between calls, so sequences split across reads are
+/// handled.
+#[derive(Default)]
+struct TelnetFilter {
+    state: TelnetState,
+}
+
+impl TelnetFilter {
+    /// Splits `input` into the data to pass on and the replies to send back.
+    fn filter(&mut self, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut data = Vec::with_capacity(input.len());
+        let mut replies = Vec::new();
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (TelnetState::Data, IAC) => TelnetState::Command,
+                (TelnetState::Data, _) => {
+                    data.push(byte);
+                    TelnetState::Data
+                }
+                // IAC IAC is an escaped 255 data byte.
+                (TelnetState::Command, IAC) => {
+                    data.push(IAC);
+                    TelnetState::Data
+                }
+                (TelnetState::Command, WILL | WONT | DO | DONT) => TelnetState::Option(byte),
+                (TelnetState::Command, SB) => TelnetState::Subnegotiation,
+                // NOP, GA and the other two-byte commands carry nothing for us.
+                (TelnetState::Command, _) => TelnetState::Data,
+                (TelnetState::Option(verb), option) => {
+                    // Refusals are never answered, so negotiation cannot loop.
+                    match verb {
+                        DO => replies.extend([IAC, WONT, option]),
+                        WILL => replies.extend([IAC, DONT, option]),
+                        _ => {}
+                    }
+                    TelnetState::Data
+                }
+                (TelnetState::Subnegotiation, IAC) => TelnetState::SubnegotiationCommand,
+                (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+                (TelnetState::SubnegotiationCommand, SE) => TelnetState::Data,
+                (TelnetState::SubnegotiationCommand, _) => TelnetState::Subnegotiation,
+            };
+        }
+        (data, replies)
+    }
+}
+
+/// Why a connection stopped receiving.
+#[derive(Debug, PartialEq, Eq)]
+enum EndReason {
+    /// The peer closed its side of the connection.
+    Eof,
+    /// Nothing was sent or received for `--idle-timeout`.
+    Idle,
+    /// `--quit-after` elapsed since the input reached EOF.
+    Quit,
+    /// `--max-bytes` have been received.
+    Limit,
+}
+
+/// How often a receive loop waiting on `--quit-after` checks whether the
+/// input has reached EOF yet.
+const QUIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The shorter of two optional durations, where `None` means unbounded.
+fn shortest(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Copies the stream to `output` until EOF, until `activity` has been quiet for
+/// `--idle-timeout`, until `--quit-after` has passed since the input ran out, or
+/// until `--max-bytes` have been received.
+///
+/// The stream's own read timeout keeps its meaning: going that long without
+/// receiving anything is an error.
+fn receive_stream<W: Write>(
+    reader: &mut Incoming,
+    output: &mut W,
+    activity: &Activity,
+    transfer: &Transfer,
+    flow: &Flow,
+    mirror: Option<&Mirror>,
+    args: &Args,
+) -> Result<(u64, EndReason)> {
+    let idle_timeout = args.idle_timeout();
+    let quit_after = args.quit_after.map(Duration::from_secs);
+    let polling = idle_timeout.is_some() || quit_after.is_some();
+    let handle = reader.handle()?;
+    let read_timeout = handle.read_timeout()?;
+    let mut buffer = vec![0u8; chunk_size(args.buffer_size, args.rx_rate())];
+    let mut bucket = args.rx_rate().map(TokenBucket::new);
+    let mut received = 0;
+    let mut remaining = args.max_bytes;
+    let mut telnet = args.telnet.then(TelnetFilter::default);
+    let mut last_read = Instant::now();
+
+    loop {
+        let idle_left = idle_timeout.map(|idle| idle.saturating_sub(activity.idle_for()));
+        if idle_left == Some(Duration::ZERO) {
+            return Ok((received, EndReason::Idle));
+        }
+        let quit_left = quit_after.and_then(|quit| {
+            activity
+                .since_input_eof()
+                .map(|since| quit.saturating_sub(since))
+        });
+        if quit_left == Some(Duration::ZERO) {
+            return Ok((received, EndReason::Quit));
+        }
+
+        if polling {
+            let read_left = read_timeout.map(|t| t.saturating_sub(last_read.elapsed()));
+            let quit_wait = quit_after.map(|_| quit_left.unwrap_or(QUIT_POLL_INTERVAL));
+            let wait = shortest(shortest(read_left, idle_left), quit_wait);
+            // A zero read timeout is rejected by std, so wait at least a moment.
+            handle.set_read_timeout(wait.map(|w| w.max(Duration::from_millis(1))))?;
+        }
+
+        let len = remaining.map_or(buffer.len(), |r| r.min(buffer.len() as u64) as usize);
+        match reader.read(&mut buffer[..len]) {
+            Ok(0) => return Ok((received, EndReason::Eof)),
+            Ok(n) => {
+                activity.touch();
+                last_read = Instant::now();
+                capture(flow, Direction::Received, received, &buffer[..n]);
+                if let Some(mirror) = mirror {
+                    mirror.send(&buffer[..n]);
+                }
+                if let Some(telnet) = &mut telnet {
+                    let (data, replies) = telnet.filter(&buffer[..n]);
+                    // Once our input has ended the write half may be closed, and
+                    // a refusal the peer cannot receive is no reason to stop.
+                    if let Handle::Tcp(socket) = &handle {
+                        let _ = (&*socket).write_all(&replies);
+                    }
+                    output.write_all(&data)?;
+                } else {
+                    output.write_all(&buffer[..n])?;
+                }
+                output.flush()?;
+                transfer.add_received(n as u64);
+                received += n as u64;
+                if let Some(bucket) = &mut bucket {
+                    bucket.take(n);
+                }
+                if let Some(remaining) = &mut remaining {
+                    *remaining -= n as u64;
+                    if *remaining == 0 {
+                        return Ok((received, EndReason::Limit));
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e)
+                if is_timeout(&e)
+                    && polling
+                    && read_timeout.is_none_or(|t| last_read.elapsed() < t) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Copies `input` to `writer` in `--buffer-size` chunks, calling `on_chunk`
+/// with the size of every write.
+fn send_stream<R: Read, W: Write>(
+    mut input: R,
+    writer: &mut W,
+    buffer_size: usize,
+    on_chunk: &mut dyn FnMut(usize),
+) -> io::Result<u64> {
+    let mut buffer = vec![0u8; buffer_size];
+    let mut sent = 0;
+    loop {
+        let n = match input.read(&mut buffer) {
+            Ok(0) => return Ok(sent),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..n])?;
+        on_chunk(n);
+        sent += n as u64;
+    }
+}
+
+/// Copies `input` to the stream and the stream to `output` at the same time.
+///
+/// See [`pipe_duplex_with`] for when it returns.
+pub fn pipe_duplex<R, W>(
+    stream: impl Into<Connection>,
+    input: R,
+    output: W,
+    args: &Args,
+) -> Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let stream = stream.into();
+    let buffer_size = chunk_size(args.buffer_size, args.tx_rate());
+    let input = Captured::new(
+        Paced::new(input, args.interval).take(args.max_bytes.unwrap_or(u64::MAX)),
+        stream.flow()?,
+        Direction::Sent,
+    );
+    let send = move |writer: &mut Outgoing, on_chunk: &mut dyn FnMut(usize)| {
+        send_stream(input, writer, buffer_size, on_chunk)
+    };
+    pipe_duplex_with(stream, send, output, args)
+}
+
+/// Runs `send` against the stream while copying the stream to `output`.
+///
+/// `send` must call its callback with the size of every chunk it sends. The
+/// sending half runs on its own thread and, with `-N/--shutdown-after-eof`,
+/// shuts down the write side of the socket once `send` is done, so the peer
+/// sees EOF while we keep reading its reply. Returns once both directions are
+/// done, as soon as the connection has been idle for `--idle-timeout`, or
+/// `--quit-after` seconds after the sending side finished.
+fn pipe_duplex_with<S, W>(
+    stream: impl Into<Connection>,
+    send: S,
+    output: W,
+    args: &Args,
+) -> Result<()>
+where
+    S: FnOnce(&mut Outgoing, &mut dyn FnMut(usize)) -> io::Result<u64> + Send + 'static,
+    W: Write,
+{
+    let stream = stream.into();
+    let transfer = Transfer::new(stream.peer_addr()?, stream.protocol());
+    let result = exchange(stream, send, output, args, &transfer);
+    report_transfer(&transfer, &result, args);
+    result.map(|_| ())
+}
+
+/// The body of [`pipe_duplex_with`], counting both directions in `transfer`.
+fn exchange<S, W>(
+    stream: impl Into<Connection>,
+    send: S,
+    mut output: W,
+    args: &Args,
+    transfer: &Arc<Transfer>,
+) -> Result<ExitReason>
+where
+    S: FnOnce(&mut Outgoing, &mut dyn FnMut(usize)) -> io::Result<u64> + Send + 'static,
+    W: Write,
+{
+    let stream = stream.into();
+    let activity = Activity::new();
+    let flow = stream.flow()?;
+    let mirror = args
+        .mirror
+        .as_ref()
+        .map(|target| Mirror::start(target, args, transfer.clone()));
+    let (mut reader, mut writer) = split(stream, args)?;
+    let sender_activity = activity.clone();
+    let half_close = args.shutdown_after_eof;
+    let mut progress = args.progress.then(|| Progress::new(input_size(args)));
+    let sender_transfer = transfer.clone();
+    let mut bucket = args.tx_rate().map(TokenBucket::new);
+    let max_bytes = args.max_bytes;
+    let sender = thread::spawn(move || -> io::Result<u64> {
+        let mut on_chunk = |n: usize| {
+            sender_activity.touch();
+            sender_transfer.add_sent(n as u64);
+            if let Some(bucket) = &mut bucket {
+                bucket.take(n);
+            }
+            if let Some(progress) = &mut progress {
+                progress.advance(n as u64);
+            }
+        };
+        let sent = send(&mut writer, &mut on_chunk);
+        if let Some(progress) = &mut progress {
+            progress.finish();
+        }
+        let sent = sent?;
+        if Some(sent) == max_bytes {
+            info!("Sent --max-bytes ({} bytes), stopping", sent);
+        }
+        writer.close(half_close)?;
+        sender_activity.mark_input_eof();
+        Ok(sent)
+    });
+
+    let watchdog = Watchdog::arm(reader.handle()?, args.max_time())?;
+    let result = receive_stream(
+        &mut reader,
+        &mut output,
+        &activity,
+        transfer,
+        &flow,
+        mirror.as_ref(),
+        args,
+    );
+    output.flush()?;
+    if watchdog.fired() {
+        info!(
+            "Reached --max-time ({}s), closing connection",
+            args.max_time.unwrap_or(0)
+        );
+        // Like --max-bytes, whatever the sender was doing is cut short on purpose.
+        return Ok(ExitReason::TimeLimit);
+    }
+    let (received, reason) = result?;
+
+    if reason == EndReason::Quit {
+        info!(
+            "Input ended {}s ago, closing connection",
+            args.quit_after.unwrap_or(0)
+        );
+        reader.handle()?.shutdown();
+    }
+    if reason == EndReason::Limit {
+        info!(
+            "Received --max-bytes ({} bytes), closing connection",
+            received
+        );
+        // The sending side is cut short on purpose, so its outcome does not matter.
+        reader.handle()?.shutdown();
+        return Ok(ExitReason::Eof);
+    }
+    if reason == EndReason::Idle {
+        info!(
+            "Closing connection after {:?} without traffic",
+            args.idle_timeout().unwrap_or_default()
+        );
+        // Closing both halves also unblocks a sender stuck writing to the peer.
+        reader.handle()?.shutdown();
+        if !sender.is_finished() {
+            // The sender is waiting on its input; leave it behind.
+            info!("Received {} bytes", received);
+            return Ok(ExitReason::Timeout);
+        }
+    }
+
+    if let Incoming::Ws(ws) = &reader {
+        if reason == EndReason::Eof && !sender.is_finished() {
+            // A WebSocket cannot stay half-closed while the sender waits on its
+            // input, so answer the peer's close frame and leave the sender behind.
+            let _ = ws.close();
+            reader.handle()?.shutdown();
+            info!("Received {} bytes", received);
+            return Ok(ExitReason::Eof);
+        }
+    }
+
+    if args.listen && args.keep_open && reason == EndReason::Eof && !sender.is_finished() {
+        // The peer is gone and the next one is waiting to be accepted, so do
+        // not wait for the sender's input to end; leave the sender behind.
+        // Its stdin is detached once this returns.
+        reader.handle()?.shutdown();
+        info!("Received {} bytes", received);
+        return Ok(ExitReason::Eof);
+    }
+
+    let sent = sender
+        .join()
+        .map_err(|_| anyhow!("Sending thread panicked"))??;
+    info!("Sent {} bytes, received {} bytes", sent, received);
+    Ok(match reason {
+        EndReason::Idle => ExitReason::Timeout,
+        EndReason::Eof | EndReason::Quit | EndReason::Limit => ExitReason::Eof,
+    })
+}
+
+/// Serves one connection: runs `--exec` against it, or pipes it to stdout
+/// and from stdin. A `--keep-open` listener passes the `stdin` it shares
+/// between its connections.
+fn handle_connection(
+    stream: impl Into<Connection>,
+    args: &Args,
+    timeout: Option<Duration>,
+    stdin: Option<&StdinFeed>,
+) -> Result<()> {
+    let stream = stream.into();
+    stream.set_timeouts(timeout)?;
+    if let Some(command) = &args.exec {
+        // The command reads the peer's data until EOF (or a half-close), and its
+        // output streams back over the same connection.
+        let transfer = Transfer::new(stream.peer_addr()?, stream.protocol());
+        let limit = args.max_bytes.unwrap_or(u64::MAX);
+        let watchdog = Watchdog::arm(stream.handle()?, args.max_time())?;
+        let flow = stream.flow()?;
+        let (reader, mut writer) = split(stream, args)?;
+        let result = run_command(
+            Captured::new(
+                Throttled::new(Counted::new(reader, transfer.clone()), args.rx_rate()),
+                flow,
+                Direction::Received,
+            )
+            .take(limit),
+            command,
+            Capped::new(
+                Captured::new(
+                    Throttled::new(Counted::new(&mut writer, transfer.clone()), args.tx_rate()),
+                    flow,
+                    Direction::Sent,
+                ),
+                limit,
+            ),
+        )
+        .and_then(|()| Ok(writer.close(true)?));
+        let result = if watchdog.fired() {
+            info!("Reached --max-time, the command's connection was closed");
+            Ok(ExitReason::TimeLimit)
+        } else {
+            result.map(|_| ExitReason::Eof)
+        };
+        report_transfer(&transfer, &result, args);
+        result?;
+    } else {
+        // In listen mode `--file` receives the peer's data, so replies always come from stdin.
+        let Some(stdin) = stdin else {
+            return pipe_duplex(
+                stream,
+                stdin_unless_disabled(args)?,
+                open_output(args)?,
+                args,
+            );
+        };
+        let input = with_send_filters(Box::new(stdin.attach()), args)?;
+        let result = pipe_duplex(stream, input, open_output(args)?, args);
+        // Whatever stdin has next is for the next connection.
+        stdin.detach();
+        result?;
+    }
+    Ok(())
+}
+
+/// Reports how a session went when `--stats` or a related option is given.
+pub fn report_transfer(transfer: &Transfer, result: &Result<ExitReason>, args: &Args) {
+    if args.stats_enabled() {
+        let reason = match result {
+            Ok(reason) => *reason,
+            Err(e) => ExitReason::of(e),
+        };
+        transfer.report(reason, args);
+    }
+}
+
+/// Whether `--reuseport` can be honoured on this platform.
+pub const REUSEPORT_SUPPORTED: bool = cfg!(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos"))
+));
+
+/// Picks the address a server binds and whether its IPv6 socket should also
+/// accept IPv4 clients. Listening on `::` is dual-stack by default, and
+/// `--dual-stack` extends that to `0.0.0.0`.
+fn listen_address(destination: SocketAddr, args: &Args) -> Result<(SocketAddr, bool)> {
+    let wildcard = destination.ip().is_unspecified();
+    if args.dual_stack && !wildcard {
+        bail!("--dual-stack needs a wildcard listen address such as :: or 0.0.0.0");
+    }
+    if wildcard && (args.dual_stack || destination.is_ipv6()) {
+        let any = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), destination.port());
+        Ok((any, true))
+    } else {
+        Ok((destination, false))
+    }
+}
+
+fn bind_server_socket(destination: SocketAddr, ty: Type, args: &Args) -> Result<Socket> {
+    let (address, dual_stack) = listen_address(destination, args)?;
+    let domain = Domain::for_address(address);
+    let socket = Socket::new(domain, ty, None)?;
+    if dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    if let Some(name) = &args.interface {
+        bind_to_interface(&socket, domain, name)?;
+    }
+    if args.freebind {
+        set_freebind(&socket, domain)?;
+    }
+    // Accepted connections inherit the listener's buffers and marks.
+    set_buffer_sizes(&socket, args.sndbuf, args.rcvbuf)?;
+    if let Some(tos) = args.tos_byte() {
+        set_tos(&socket, domain, tos)?;
+    }
+    if let Some(mark) = args.fwmark {
+        set_fwmark(&socket, mark)?;
+    }
+    // Match what std's TcpListener::bind does on Unix.
+    if args.reuseaddr || (cfg!(unix) && ty == Type::STREAM) {
+        socket.set_reuse_address(true)?;
+    }
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    if args.reuseport {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&address.into()).map_err(|e| {
+        let hint = if e.kind() == io::ErrorKind::AddrNotAvailable && !args.freebind {
+            "; --freebind listens before the address is assigned to this host"
+        } else {
+            ""
+        };
+        anyhow!(e).context(format!("Failed to bind {}{}", address, hint))
+    })?;
+
+    let bound = socket
+        .local_addr()?
+        .as_socket()
+        .ok_or_else(|| anyhow!("Bound socket has no IP address"))?;
+    if dual_stack {
+        let v4 = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), bound.port());
+        info!("Listening on {} and {}...", bound, v4);
+    } else {
+        info!("Listening on {}...", bound);
+    }
+    Ok(socket)
+}
+
+fn bind_tcp_listener(destination: SocketAddr, args: &Args) -> Result<TcpListener> {
+    let socket = bind_server_socket(destination, Type::STREAM, args)?;
+    #[cfg(target_os = "linux")]
+    if args.fastopen {
+        fastopen::enable_listen(&socket, 128)?;
+        if !fastopen::server_enabled() {
+            info!("net.ipv4.tcp_fastopen does not enable servers, so clients will use normal handshakes");
+        }
+    }
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+fn bind_udp_server(destination: SocketAddr, args: &Args) -> Result<UdpSocket> {
+    Ok(bind_server_socket(destination, Type::DGRAM, args)?.into())
+}
+
+fn run_tcp_server(args: &Args, destination: SocketAddr, timeout: Option<Duration>) -> Result<()> {
+    let listener = bind_tcp_listener(destination, args)?;
+    serve_tcp(&listener, args, timeout)
+}
+
+/// An accepted connection, with the socket options asked for applied.
+fn accepted(stream: io::Result<TcpStream>, args: &Args) -> io::Result<TcpStream> {
+    let stream = stream?;
+    set_stream_options(&stream, args)?;
+    #[cfg(target_os = "linux")]
+    if args.fastopen {
+        info!(
+            "{} {} fast open",
+            stream.peer_addr()?,
+            if fastopen::used(&stream)? {
+                "used"
+            } else {
+                "did not use"
+            }
+        );
+    }
+    Ok(stream)
+}
+
+/// Accepts connections on `listener`, handling them one after another.
+///
+/// Without `--keep-open` the first accepted connection is the only one, and its
+/// outcome becomes the outcome of the server.
+fn serve_tcp(listener: &TcpListener, args: &Args, timeout: Option<Duration>) -> Result<()> {
+    if args.max_workers > 1 {
+        return serve_tcp_concurrently(listener, args, timeout);
+    }
+    let stdin = keep_open_stdin(args);
+
+    for stream in listener.incoming() {
+        let stream = match accepted(stream, args) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        if !args.keep_open {
+            return handle_connection(stream, args, timeout, None);
+        }
+        if let Err(e) = handle_connection(stream, args, timeout, stdin.as_ref()) {
+            error!("Failed to handle connection: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// The stdin that the connections of a `--keep-open` listener take turns
+/// with, when they read it at all.
+fn keep_open_stdin(args: &Args) -> Option<StdinFeed> {
+    (args.keep_open && args.exec.is_none() && !args.no_stdin)
+        .then(|| StdinFeed::spawn(io::stdin(), args.buffer_size))
+}
+
+/// Accepts connections and hands each one to a pool of `--max-workers` threads.
+///
+/// The hand-off channel has no buffer, so once every worker is busy new clients
+/// wait in the listen backlog instead of piling up in memory.
+fn serve_tcp_concurrently(
+    listener: &TcpListener,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(0);
+    let receiver = Mutex::new(receiver);
+    let active = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for _ in 0..args.max_workers {
+            scope.spawn(|| loop {
+                let stream = receiver.lock().unwrap().recv();
+                let Ok(stream) = stream else {
+                    break;
+                };
+
+                let count = active.fetch_add(1, Ordering::SeqCst) + 1;
+                info!("Active connections: {}", count);
+                if let Err(e) = handle_connection(stream, args, timeout, None) {
+                    error!("Failed to handle connection: {}", e);
+                }
+                let count = active.fetch_sub(1, Ordering::SeqCst) - 1;
+                info!("Active connections: {}", count);
+            });
+        }
+
+        for stream in listener.incoming() {
+            match accepted(stream, args) {
+                Ok(stream) => {
+                    if sender.send(stream).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => error!("Failed to accept connection: {}", e),
+            }
+        }
+        drop(sender);
+    });
+    Ok(())
+}
+
+/// Relays every accepted connection to `--forward`, each on its own thread,
+/// until the process is stopped.
+fn run_forward_server(
+    args: &Args,
+    destination: SocketAddr,
+    target: &ForwardTarget,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let listener = bind_tcp_listener(destination, args)?;
+    serve_forward(&listener, target, args, timeout)
+}
+
+fn serve_forward(
+    listener: &TcpListener,
+    target: &ForwardTarget,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let active = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match accepted(stream, args) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let active = &active;
+            scope.spawn(move || {
+                let count = active.fetch_add(1, Ordering::SeqCst) + 1;
+                info!("Active connections: {}", count);
+                let peer = stream.peer_addr();
+                let result = match target.protocol {
+                    Some(Protocol::Udp) => bridge_to_udp(stream, &target.endpoint, args, timeout),
+                    _ => forward_connection(stream, &target.endpoint, args, timeout),
+                };
+                if let Err(e) = result {
+                    let peer =
+                        peer.map_or_else(|_| "a client".to_string(), |peer| peer.to_string());
+                    report_forward_failure(&peer, &e);
+                }
+                let count = active.fetch_sub(1, Ordering::SeqCst) - 1;
+                info!("Active connections: {}", count);
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Tells about a client that could not be forwarded. Without --verbose
+/// nothing is logged, and a relay that silently drops clients is hard to debug.
+fn report_forward_failure(peer: &str, e: &anyhow::Error) {
+    let _ = writeln!(io::stderr(), "netpipe: failed to forward {}: {:#}", peer, e);
+}
+
+/// Connects to `target` on behalf of `client` and relays both directions
+/// until each has ended, counting what the client sent as received.
+fn forward_connection(
+    client: TcpStream,
+    target: &Endpoint,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let candidates = connection_candidates(&target.host, target.port, args)?;
+    let upstream = connect_any(
+        &candidates,
+        &Source::default(),
+        connect_timeout(args, timeout),
+    )?;
+    set_stream_options(&upstream, args)?;
+    for stream in [&client, &upstream] {
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+    }
+    info!(
+        "Forwarding {} to {}",
+        client.peer_addr()?,
+        upstream.peer_addr()?
+    );
+    let transfer = Transfer::new(client.peer_addr()?, Protocol::Tcp);
+    let buffer_size = args.buffer_size;
+    let result = thread::scope(|scope| {
+        let to_client =
+            scope.spawn(|| relay_half(&upstream, &client, buffer_size, |n| transfer.add_sent(n)));
+        let from_client = relay_half(&client, &upstream, buffer_size, |n| {
+            transfer.add_received(n)
+        });
+        let to_client = to_client
+            .join()
+            .map_err(|_| anyhow!("Forwarding thread panicked"))?;
+        Ok(from_client.and(to_client)?)
+    });
+    let result = result.map(|()| ExitReason::Eof);
+    report_transfer(&transfer, &result, args);
+    result.map(|_| ())
+}
+
+/// Relays a TCP client's records to `target` as datagrams and the replies
+/// back as records, until the client's input ends and, with
+/// `--idle-timeout`, no reply has come for that long.
+fn bridge_to_udp(
+    client: TcpStream,
+    target: &Endpoint,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let destination = resolve(&target.host, target.port, args.ip_version)?;
+    let socket = UdpSocket::bind(unspecified_address(destination))?;
+    socket.connect(destination)?;
+    // Replies are polled so that the end of the client's input is noticed.
+    socket.set_read_timeout(Some(BRIDGE_POLL))?;
+    client.set_read_timeout(timeout)?;
+    client.set_write_timeout(timeout)?;
+    info!("Bridging {} to udp://{}", client.peer_addr()?, destination);
+    let transfer = Transfer::new(client.peer_addr()?, Protocol::Tcp);
+    let input_done = AtomicBool::new(false);
+    let result = thread::scope(|scope| {
+        let replies = scope.spawn(|| -> io::Result<()> {
+            let mut buffer = vec![0u8; MAX_DATAGRAM_PAYLOAD];
+            let mut quiet_since = Instant::now();
+            let mut waiting = false;
+            loop {
+                match socket.recv(&mut buffer) {
+                    Ok(n) => {
+                        write_datagram(&mut &client, &buffer[..n], &args.datagram_framing)?;
+                        transfer.add_sent(n as u64);
+                        quiet_since = Instant::now();
+                    }
+                    Err(e) if is_timeout(&e) => {}
+                    // An ICMP error for an earlier datagram; later ones may get through.
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                        info!("udp://{} refused a datagram", destination)
+                    }
+                    Err(e) => return Err(e),
+                }
+                if input_done.load(Ordering::SeqCst) {
+                    if !waiting {
+                        waiting = true;
+                        quiet_since = Instant::now();
+                    }
+                    let linger = args.idle_timeout().unwrap_or_default();
+                    if quiet_since.elapsed() >= linger {
+                        break;
+                    }
+                }
+            }
+            match client.shutdown(Shutdown::Write) {
+                Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e),
+                _ => Ok(()),
+            }
+        });
+        let mut records = RecordReader::new(
+            &client,
+            args.datagram_framing,
+            args.max_record as usize,
+            args.oversize,
+        );
+        let sent = (|| -> io::Result<()> {
+            while let Some(record) = records.next_record()? {
+                socket.send(&record)?;
+                transfer.add_received(record.len() as u64);
+            }
+            Ok(())
+        })();
+        input_done.store(true, Ordering::SeqCst);
+        if sent.is_err() {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+        let replies = replies
+            .join()
+            .map_err(|_| anyhow!("Bridging thread panicked"))?;
+        Ok(sent.and(replies)?)
+    });
+    let result = result.map(|()| ExitReason::Eof);
+    report_transfer(&transfer, &result, args);
+    result.map(|_| ())
+}
+
+/// How often bridges check for idle peers and the end of their input.
+const BRIDGE_POLL: Duration = Duration::from_millis(100);
+
+/// A UDP client of [`run_udp_bridge`] and its connection to the target.
+struct BridgeSession {
+    id: u64,
+    upstream: TcpStream,
+    transfer: Arc<Transfer>,
+    last_datagram: Instant,
+}
+
+/// Relays the datagrams of every UDP client to `target` as records on a TCP
+/// connection of its own, and the records coming back as datagrams.
+/// `--idle-timeout` closes the connections of clients that went quiet.
+fn run_udp_bridge(
+    args: &Args,
+    destination: SocketAddr,
+    target: &Endpoint,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let socket = bind_udp_server(destination, args)?;
+    let _memberships = Memberships::join(&socket, args)?;
+    serve_udp_bridge(&socket, target, args, timeout)
+}
+
+fn serve_udp_bridge(
+    socket: &UdpSocket,
+    target: &Endpoint,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    socket.set_read_timeout(Some(BRIDGE_POLL))?;
+    let sessions = Mutex::new(HashMap::<SocketAddr, BridgeSession>::new());
+    let mut next_id = 0;
+    let mut buffer = vec![0u8; MAX_DATAGRAM_PAYLOAD];
+    thread::scope(|scope| loop {
+        if let Some(idle_timeout) = args.idle_timeout() {
+            for (peer, session) in sessions.lock().unwrap().iter() {
+                if session.last_datagram.elapsed() >= idle_timeout {
+                    info!("Closing the connection of idle UDP client {}", peer);
+                    // The session's thread sees the connection end and removes it.
+                    let _ = session.upstream.shutdown(Shutdown::Both);
+                }
+            }
+        }
+        let (n, peer) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => {
+                error!("Failed to receive a datagram: {}", e);
+                continue;
+            }
+        };
+        // Connecting to the target happens without holding the lock, as the
+        // other sessions' threads need it to finish.
+        let known = sessions.lock().unwrap().contains_key(&peer);
+        if !known {
+            let upstream = match connect_bridge_upstream(target, args, timeout) {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    report_forward_failure(&peer.to_string(), &e);
+                    continue;
+                }
+            };
+            let reader = match upstream.try_clone() {
+                Ok(reader) => reader,
+                Err(e) => {
+                    report_forward_failure(&peer.to_string(), &e.into());
+                    continue;
+                }
+            };
+            next_id += 1;
+            let session = BridgeSession {
+                id: next_id,
+                upstream,
+                transfer: Transfer::new(peer, Protocol::Udp),
+                last_datagram: Instant::now(),
+            };
+            let (id, transfer) = (session.id, session.transfer.clone());
+            sessions.lock().unwrap().insert(peer, session);
+            let sessions = &sessions;
+            scope.spawn(move || {
+                let result = bridge_replies(reader, socket, peer, &transfer, args);
+                let mut sessions = sessions.lock().unwrap();
+                if sessions.get(&peer).is_some_and(|session| session.id == id) {
+                    sessions.remove(&peer);
+                }
+                drop(sessions);
+                let result = result.map(|()| ExitReason::Eof);
+                report_transfer(&transfer, &result, args);
+                if let Err(e) = result {
+                    report_forward_failure(&peer.to_string(), &e);
+                }
+            });
+        }
+        let mut sessions = sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(&peer) else {
+            continue;
+        };
+        session.last_datagram = Instant::now();
+        match write_datagram(&mut &session.upstream, &buffer[..n], &args.datagram_framing) {
+            Ok(()) => session.transfer.add_received(n as u64),
+            Err(e) => {
+                info!("Failed to pass a datagram from {} on: {}", peer, e);
+                let _ = session.upstream.shutdown(Shutdown::Both);
+            }
+        }
+    })
+}
+
+/// Opens the TCP connection a UDP client's datagrams go over.
+fn connect_bridge_upstream(
+    target: &Endpoint,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<TcpStream> {
+    let candidates = connection_candidates(&target.host, target.port, args)?;
+    let upstream = connect_any(
+        &candidates,
+        &Source::default(),
+        connect_timeout(args, timeout),
+    )?;
+    upstream.set_write_timeout(timeout)?;
+    Ok(upstream)
+}
+
+/// Sends the records arriving from `upstream` to `peer` as datagrams until
+/// the connection ends.
+fn bridge_replies(
+    upstream: TcpStream,
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    transfer: &Transfer,
+    args: &Args,
+) -> Result<()> {
+    let mut records = RecordReader::new(
+        upstream,
+        args.datagram_framing,
+        args.max_record as usize,
+        args.oversize,
+    );
+    let result = (|| -> io::Result<()> {
+        while let Some(record) = records.next_record()? {
+            socket.send_to(&record, peer)?;
+            transfer.add_sent(record.len() as u64);
+        }
+        Ok(())
+    })();
+    // The records may have stopped in the middle; the other half goes too.
+    let _ = records.get_ref().shutdown(Shutdown::Both);
+    Ok(result?)
+}
+
+/// Copies `from` to `to` until EOF and then half-closes `to`. On an error
+/// both connections are shut down, so the opposite direction ends too.
+fn relay_half(
+    mut from: &TcpStream,
+    mut to: &TcpStream,
+    buffer_size: usize,
+    mut on_chunk: impl FnMut(u64),
+) -> io::Result<()> {
+    let mut buffer = vec![0u8; buffer_size];
+    let result = loop {
+        let n = match from.read(&mut buffer) {
+            Ok(0) => match to.shutdown(Shutdown::Write) {
+                // The other side may have closed the connection already.
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => break Ok(()),
+                result => break result,
+            },
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+        if let Err(e) = to.write_all(&buffer[..n]) {
+            break Err(e);
+        }
+        on_chunk(n as u64);
+    };
+    if result.is_err() {
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    }
+    result
+}
+
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn write_datagram<W: Write>(
+    output: &mut W,
+    datagram: &[u8],
+    framing: &DatagramFraming,
+) -> io::Result<()> {
+    match framing {
+        DatagramFraming::None => output.write_all(datagram)?,
+        DatagramFraming::Newline => {
+            output.write_all(datagram)?;
+            output.write_all(b"\n")?;
+        }
+        DatagramFraming::Length => {
+            output.write_all(&(datagram.len() as u32).to_be_bytes())?;
+            output.write_all(datagram)?;
+        }
+    }
+    output.flush()
+}
+
+/// Receives the UDP server's datagrams one `recv_from` at a time, where there
+/// is no `recvmmsg` to take them in batches.
+#[cfg(not(target_os = "linux"))]
+struct RecvBatch {
+    buffer: Vec<u8>,
+    len: usize,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl RecvBatch {
+    fn new(_socket: &UdpSocket, _size: usize, buffer_size: usize) -> io::Result<Self> {
+        Ok(RecvBatch {
+            buffer: vec![0u8; buffer_size],
+            len: 0,
+        })
+    }
+
+    fn pending(&self) -> bool {
+        false
+    }
+
+    fn next(&mut self, socket: &UdpSocket) -> io::Result<SocketAddr> {
+        let (len, src) = socket.recv_from(&mut self.buffer)?;
+        self.len = len;
+        Ok(src)
+    }
+
+    fn datagram(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// Always `None`: `recv_from` does not tell whether it cut the datagram.
+    fn truncated(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Includes the kernel's drops on the listening socket and the datagrams it
+/// truncated in `transfer`'s summary, where they are known.
+#[allow(unused_variables)]
+fn note_kernel_counts(incoming: &RecvBatch, transfer: &Transfer) {
+    #[cfg(target_os = "linux")]
+    {
+        transfer.set_kernel_drops(incoming.drops());
+        transfer.count_truncations();
+    }
+}
+
+/// Forgets UDP peers that have been silent for `idle_timeout`, returning them.
+fn expire_sessions(
+    sessions: &mut HashMap<SocketAddr, Instant>,
+    idle_timeout: Duration,
+) -> Vec<SocketAddr> {
+    let expired: Vec<SocketAddr> = sessions
+        .iter()
+        .filter(|(_, last_seen)| last_seen.elapsed() >= idle_timeout)
+        .map(|(peer, _)| *peer)
+        .collect();
+    for peer in &expired {
+        sessions.remove(peer);
+    }
+    expired
+}
+
+/// Receives datagrams until the read timeout expires or the `--once`/`--count`
+/// limit is reached, passing each one to `--exec` or the output sink.
+///
+/// Peers are tracked as sessions; with `--idle-timeout` a peer that stays
+/// quiet for that long has its session closed.
+///
+/// With `--reply` the sender gets the command's output back, or the contents of
+/// stdin when no command is given.
+///
+/// With `--tls` every peer goes through a DTLS handshake first, and only the
+/// application data of its session counts as datagrams.
+fn serve_udp(socket: &UdpSocket, args: &Args, timeout: Option<Duration>) -> Result<()> {
+    let mut transfers = HashMap::new();
+    let mut output = match &args.exec {
+        Some(_) => None,
+        None => Some(open_output(args)?),
+    };
+    let mut reassemblies = HashMap::new();
+    // No UDP datagram is larger than 65535 bytes, headers included.
+    let buffer_size = args.buffer_size.min(65535);
+    let mut incoming = RecvBatch::new(socket, args.recv_batch.into(), buffer_size)?;
+    let result = serve_udp_sessions(
+        socket,
+        args,
+        timeout,
+        &mut transfers,
+        &mut output,
+        &mut reassemblies,
+        &mut incoming,
+    );
+    // What --sequenced peers had held back can only be written once no more is coming.
+    let finished = match &mut output {
+        Some(output) => reassemblies.drain().try_for_each(|(peer, reassembly)| {
+            finish_sequence(peer, reassembly, output, transfers.get(&peer), args)
+        }),
+        None => Ok(()),
+    };
+    let result = result.and_then(|reason| Ok(finished.map(|()| reason)?));
+    for transfer in transfers.values() {
+        note_kernel_counts(&incoming, transfer);
+        report_transfer(transfer, &result, args);
+    }
+    result.map(|_| ())
+}
+
+/// Writes out the datagrams a `--sequenced` peer still had held back for
+/// missing ones, as its session ends.
+fn finish_sequence(
+    peer: SocketAddr,
+    mut reassembly: Reassembly,
+    output: &mut Box<dyn Write>,
+    transfer: Option<&Arc<Transfer>>,
+    args: &Args,
+) -> io::Result<()> {
+    for payload in reassembly.finish() {
+        write_datagram(output, &payload, &args.datagram_framing)?;
+    }
+    if let Some(transfer) = transfer {
+        transfer.set_sequence_gaps(reassembly.lost(), reassembly.reordered());
+    }
+    if reassembly.late() > 0 {
+        info!(
+            "Dropped {} datagrams from {} that came after their turn or twice",
+            reassembly.late(),
+            peer
+        );
+    }
+    Ok(())
+}
+
+/// The body of [`serve_udp`], counting each peer's traffic in `transfers` until
+/// its session closes and, with `--sequenced`, putting its datagrams back in
+/// order in `reassemblies`.
+fn serve_udp_sessions(
+    socket: &UdpSocket,
+    args: &Args,
+    timeout: Option<Duration>,
+    transfers: &mut HashMap<SocketAddr, Arc<Transfer>>,
+    output: &mut Option<Box<dyn Write>>,
+    reassemblies: &mut HashMap<SocketAddr, Reassembly>,
+    incoming: &mut RecvBatch,
+) -> Result<ExitReason> {
+    let limit = if args.once { Some(1) } else { args.count };
+    let idle_timeout = args.idle_timeout();
+
+    let reply = if args.reply && args.exec.is_none() {
+        let mut reply = Vec::new();
+        io::stdin().read_to_end(&mut reply)?;
+        Some(reply)
+    } else {
+        None
+    };
+    let mut listener = if args.tls {
+        Some(Listener::new(args)?)
+    } else {
+        None
+    };
+    let local = socket.local_addr()?;
+    let mut received = 0;
+    let (mut bytes_received, mut bytes_sent) = (0u64, 0u64);
+    // Whether a datagram was cut short yet, which is only told once.
+    let mut truncated = false;
+    let mut sessions = HashMap::new();
+    let mut last_datagram = Instant::now();
+    let deadline = args.max_time().map(|max_time| last_datagram + max_time);
+
+    while limit.is_none_or(|limit| received < limit) {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            info!("Reached --max-time, stopping");
+            return Ok(ExitReason::TimeLimit);
+        }
+        if let Some(idle_timeout) = idle_timeout {
+            let expired = expire_sessions(&mut sessions, idle_timeout);
+            for peer in &expired {
+                info!(
+                    "Closing session with {} after {:?} without traffic",
+                    peer, idle_timeout
+                );
+                if let (Some(reassembly), Some(output)) = (reassemblies.remove(peer), &mut *output)
+                {
+                    finish_sequence(*peer, reassembly, output, transfers.get(peer), args)?;
+                }
+                if let Some(transfer) = transfers.remove(peer) {
+                    note_kernel_counts(incoming, &transfer);
+                    report_transfer(&transfer, &Ok(ExitReason::Timeout), args);
+                }
+                if let Some(listener) = &mut listener {
+                    listener.forget(peer);
+                }
+            }
+            if !expired.is_empty() {
+                if let Some(output) = output {
+                    output.flush()?;
+                }
+            }
+        }
+
+        let timeout_left = timeout.map(|t| t.saturating_sub(last_datagram.elapsed()));
+        let idle_left = idle_timeout.and_then(|idle| {
+            sessions
+                .values()
+                .map(|last_seen| idle.saturating_sub(last_seen.elapsed()))
+                .min()
+        });
+        let deadline_left =
+            deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        // The rest of a batch is already here, so there is nothing to wait for.
+        if !incoming.pending() {
+            let wait = shortest(shortest(timeout_left, idle_left), deadline_left)
+                .map(|w| w.max(Duration::from_millis(1)));
+            socket.set_read_timeout(wait)?;
+        }
+
+        let src = match incoming.next(socket) {
+            Ok(result) => result,
+            Err(e) if is_timeout(&e) => {
+                if timeout.is_some_and(|t| last_datagram.elapsed() >= t) {
+                    info!("No datagram received within the timeout, stopping");
+                    return Ok(ExitReason::Timeout);
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let datagram = incoming.datagram();
+        if let Some(len) = incoming.truncated() {
+            if !truncated {
+                writeln!(
+                    io::stderr(),
+                    "netpipe: a {} byte datagram from {} was cut to {} bytes; raise --buffer-size to receive it whole",
+                    len,
+                    src,
+                    datagram.len()
+                )?;
+                truncated = true;
+            }
+            let transfer = transfers
+                .entry(src)
+                .or_insert_with(|| Transfer::new(src, Protocol::Udp));
+            transfer.add_truncated();
+        }
+        let datagrams = match &mut listener {
+            Some(listener) => {
+                let outcome = listener.handle(src, datagram);
+                for reply in &outcome.replies {
+                    socket.send_to(reply, src)?;
+                }
+                if let Some(e) = &outcome.error {
+                    error!("DTLS session with {} failed: {:#}", src, e);
+                } else if outcome.closed {
+                    info!("{} closed its DTLS session", src);
+                }
+                if outcome.data.is_empty() {
+                    // Handshake traffic keeps the server waiting like data does.
+                    last_datagram = Instant::now();
+                    continue;
+                }
+                outcome.data.into_iter().map(Cow::Owned).collect()
+            }
+            None => vec![Cow::Borrowed(datagram)],
+        };
+        for datagram in &datagrams {
+            if limit.is_some_and(|limit| received >= limit) {
+                break;
+            }
+            let datagram: &[u8] = datagram;
+            let amt = datagram.len();
+            if args.discard_keepalives && datagram == args.nat_keepalive_payload.as_bytes() {
+                last_datagram = Instant::now();
+                sessions.insert(src, last_datagram);
+                let transfer = transfers
+                    .entry(src)
+                    .or_insert_with(|| Transfer::new(src, Protocol::Udp));
+                transfer.count_keepalives();
+                transfer.add_keepalive(amt as u64);
+                continue;
+            }
+            if args
+                .max_bytes
+                .is_some_and(|max| bytes_received + amt as u64 > max)
+            {
+                info!(
+                    "A {} byte datagram from {} would exceed --max-bytes, stopping",
+                    amt, src
+                );
+                return Ok(ExitReason::Eof);
+            }
+            bytes_received += amt as u64;
+            received += 1;
+            last_datagram = Instant::now();
+            if sessions.insert(src, last_datagram).is_none() {
+                info!("New session from {}", src);
+            }
+            info!("Received {} bytes from {}", amt, src);
+            let transfer = transfers
+                .entry(src)
+                .or_insert_with(|| Transfer::new(src, Protocol::Udp));
+            if args.discard_keepalives {
+                transfer.count_keepalives();
+            }
+            note_kernel_counts(incoming, transfer);
+            let flow = Flow::new(Protocol::Udp, local, src);
+            capture(&flow, Direction::Received, transfer.received(), datagram);
+            transfer.add_received(amt as u64);
+
+            let response = if let Some(output) = output {
+                if args.sequenced {
+                    let reassembly = reassemblies.entry(src).or_default();
+                    match reassembly.push(datagram) {
+                        Some(payloads) => {
+                            for payload in &payloads {
+                                write_datagram(output, payload, &args.datagram_framing)?;
+                            }
+                        }
+                        None => info!(
+                            "A {} byte datagram from {} has no sequence number, dropping it",
+                            amt, src
+                        ),
+                    }
+                    transfer.set_sequence_gaps(reassembly.lost(), reassembly.reordered());
+                } else {
+                    write_datagram(output, datagram, &args.datagram_framing)?;
+                }
+                reply.clone()
+            } else if let Some(command) = &args.exec {
+                if args.reply {
+                    Some(capture_command(datagram, command)?)
+                } else {
+                    execute_command(datagram, command)?;
+                    None
+                }
+            } else {
+                None
+            };
+            if let Some(response) = response {
+                if args
+                    .max_bytes
+                    .is_some_and(|max| bytes_sent + response.len() as u64 > max)
+                {
+                    info!("Not replying to {}: it would exceed --max-bytes", src);
+                } else {
+                    let sent = match &mut listener {
+                        Some(listener) => match listener.seal(src, &response) {
+                            Some(record) => {
+                                let record = record?;
+                                if !record.is_empty() {
+                                    socket.send_to(&record, src)?;
+                                }
+                                response.len()
+                            }
+                            None => 0,
+                        },
+                        None => socket.send_to(&response, src)?,
+                    };
+                    capture(&flow, Direction::Sent, transfer.sent(), &response[..sent]);
+                    let sent = sent as u64;
+                    transfer.add_sent(sent);
+                    bytes_sent += sent;
+                }
+            }
+
+            if args.max_bytes == Some(bytes_received) {
+                info!("Received --max-bytes ({} bytes), stopping", bytes_received);
+                return Ok(ExitReason::Eof);
+            }
+        }
+    }
+    Ok(ExitReason::Eof)
+}
+
+fn run_udp_server(args: &Args, destination: SocketAddr, timeout: Option<Duration>) -> Result<()> {
+    let socket = bind_udp_server(destination, args)?;
+    let _memberships = Memberships::join(&socket, args)?;
+    if args.reliable {
+        return reliable::serve(&socket, args, timeout);
+    }
+    serve_udp(&socket, args, timeout)
+}
+
+/// The `--multicast-group` memberships of a UDP listener, left again when
+/// dropped.
+struct Memberships {
+    socket: UdpSocket,
+    joined: Vec<Membership>,
+}
+
+/// A group and the interface it is joined on.
+#[derive(Clone, Copy, Debug)]
+enum Membership {
+    /// An IPv4 group on the interface with this address, or on the one the
+    /// routing table picks for the unspecified address.
+    V4(Ipv4Addr, Ipv4Addr),
+    /// An IPv4 group on the interface with this index, for `--interface`.
+    V4Index(Ipv4Addr, u32),
+    /// An IPv6 group on the interface with this index, 0 letting the kernel
+    /// pick.
+    V6(Ipv6Addr, u32),
+    /// An IPv4 group's traffic from one source, on the interface with the
+    /// `--multicast-if` address.
+    V4Source(Ipv4Addr, Ipv4Addr, Ipv4Addr),
+    /// A group's traffic from one source, on the interface with this index.
+    Source(IpAddr, IpAddr, u32),
+}
+
+impl fmt::Display for Membership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Membership::V4(group, _) | Membership::V4Index(group, _) => write!(f, "{}", group),
+            Membership::V6(group, _) => write!(f, "{}", group),
+            Membership::V4Source(group, source, _) => write!(f, "{} from {}", group, source),
+            Membership::Source(group, source, _) => write!(f, "{} from {}", group, source),
+        }
+    }
+}
+
+impl Membership {
+    fn update(&self, socket: &UdpSocket, join: bool) -> io::Result<()> {
+        match (*self, join) {
+            (Membership::V4(group, interface), true) => {
+                socket.join_multicast_v4(&group, &interface)
+            }
+            (Membership::V4(group, interface), false) => {
+                socket.leave_multicast_v4(&group, &interface)
+            }
+            (Membership::V4Index(group, index), join) => {
+                update_v4_by_index(socket, group, index, join)
+            }
+            (Membership::V6(group, index), true) => socket.join_multicast_v6(&group, index),
+            (Membership::V6(group, index), false) => socket.leave_multicast_v6(&group, index),
+            #[cfg(target_os = "linux")]
+            (Membership::V4Source(group, source, interface), join) => {
+                ssm::update_v4(socket, group, source, interface, join)
+            }
+            #[cfg(target_os = "linux")]
+            (Membership::Source(group, source, index), join) => {
+                ssm::update(socket, group, source, index, join)
+            }
+            #[cfg(not(target_os = "linux"))]
+            (Membership::V4Source(..) | Membership::Source(..), _) => {
+                Err(io::Error::from(io::ErrorKind::Unsupported))
+            }
+        }
+    }
+}
+
+impl Memberships {
+    /// Joins every `--multicast-group` on `socket`, on `--multicast-if` or
+    /// `--interface` when given, for the traffic of the `--multicast-source`
+    /// in the same position only.
+    fn join(socket: &UdpSocket, args: &Args) -> Result<Self> {
+        let mut memberships = Memberships {
+            socket: socket.try_clone()?,
+            joined: Vec::new(),
+        };
+        if args.multicast_group.is_empty() {
+            return Ok(memberships);
+        }
+        let local = socket.local_addr()?;
+        let index = match &args.interface {
+            Some(name) => interface_index(name)
+                .with_context(|| format!("Cannot join multicast groups on {}", name))?,
+            None => 0,
+        };
+        for (position, &group) in args.multicast_group.iter().enumerate() {
+            let source = args.multicast_source.get(position).copied();
+            let membership = match group {
+                IpAddr::V4(_) if !local.is_ipv4() => {
+                    bail!(
+                        "Cannot join {} listening on {}; listen on 0.0.0.0",
+                        group,
+                        local
+                    )
+                }
+                IpAddr::V6(_) if !local.is_ipv6() => {
+                    bail!("Cannot join {} listening on {}; listen on ::", group, local)
+                }
+                IpAddr::V4(group) => match (source, args.multicast_if) {
+                    (Some(IpAddr::V4(source)), Some(interface)) => {
+                        Membership::V4Source(group, source, interface)
+                    }
+                    (Some(source), _) => Membership::Source(group.into(), source, index),
+                    (None, Some(interface)) => Membership::V4(group, interface),
+                    (None, None) if index != 0 => Membership::V4Index(group, index),
+                    (None, None) => Membership::V4(group, Ipv4Addr::UNSPECIFIED),
+                },
+                IpAddr::V6(group) => match source {
+                    Some(source) => Membership::Source(group.into(), source, index),
+                    None => Membership::V6(group, index),
+                },
+            };
+            membership
+                .update(socket, true)
+                .with_context(|| format!("Failed to join multicast group {}", membership))?;
+            info!("Joined multicast group {}", membership);
+            memberships.joined.push(membership);
+        }
+        Ok(memberships)
+    }
+}
+
+impl Drop for Memberships {
+    fn drop(&mut self) {
+        for membership in &self.joined {
+            match membership.update(&self.socket, false) {
+                Ok(()) => info!("Left multicast group {}", membership),
+                Err(e) => error!("Failed to leave multicast group {}: {}", membership, e),
+            }
+        }
+    }
+}
+
+/// Joins or leaves an IPv4 group on the interface with this index, which
+/// only `--interface` asks for.
+#[allow(unused_variables)]
+fn update_v4_by_index(
+    socket: &UdpSocket,
+    group: Ipv4Addr,
+    index: u32,
+    join: bool,
+) -> io::Result<()> {
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    ))]
+    let result = {
+        let socket = SockRef::from(socket);
+        let interface = socket2::InterfaceIndexOrAddress::Index(index);
+        if join {
+            socket.join_multicast_v4_n(&group, &interface)
+        } else {
+            socket.leave_multicast_v4_n(&group, &interface)
+        }
+    };
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    )))]
+    let result = Err(io::Error::from(io::ErrorKind::Unsupported));
+    result
+}
+
+/// Accepts QUIC connections on a UDP socket, handling them one after another
+/// like [`serve_tcp`] does.
+fn run_quic_server(args: &Args, destination: SocketAddr, timeout: Option<Duration>) -> Result<()> {
+    let socket = bind_udp_server(destination, args)?;
+    let stdin = keep_open_stdin(args);
+    loop {
+        let result = quic::accept(&socket, args, timeout)
+            .and_then(|stream| handle_connection(stream, args, timeout, stdin.as_ref()));
+        if !args.keep_open {
+            return result;
+        }
+        if let Err(e) = result {
+            error!("Failed to handle connection: {:#}", e);
+        }
+    }
+}
+
+pub fn run_server(args: &Args, protocol: &Protocol, timeout: Option<Duration>) -> Result<()> {
+    let destination = match args.ws_url() {
+        Some(url) => resolve(&url.host, url.port, args.ip_version)?,
+        None => resolve(
+            args.address.as_ref().unwrap(),
+            args.port().unwrap(),
+            args.ip_version,
+        )?,
+    };
+
+    match protocol {
+        Protocol::Tcp => match &args.forward {
+            Some(target) => run_forward_server(args, destination, target, timeout),
+            None if args.broker => {
+                broker::serve(&bind_tcp_listener(destination, args)?, args, timeout)
+            }
+            None => run_tcp_server(args, destination, timeout),
+        },
+        Protocol::Udp => match &args.forward {
+            Some(target) => run_udp_bridge(args, destination, &target.endpoint, timeout),
+            None => run_udp_server(args, destination, timeout),
+        },
+        Protocol::Quic => run_quic_server(args, destination, timeout),
+    }
+}
+
+/// The largest payload a single UDP datagram can carry over IPv4.
+pub const MAX_DATAGRAM_PAYLOAD: usize = 65507;
+
+/// The largest payload the UDP client can send in one datagram, which a DTLS
+/// record limits further.
+fn max_payload(args: &Args) -> usize {
+    if args.tls {
+        dtls::MAX_DATA
+    } else {
+        MAX_DATAGRAM_PAYLOAD
+    }
+}
+
+/// Reads the UDP client's payload, refusing input that would not fit in one
+/// datagram instead of buffering it all.
+pub fn prepare_datagram(args: &Args) -> Result<Vec<u8>> {
+    let max = max_payload(args);
+    let mut buffer = Vec::new();
+    open_input(args)?
+        .take(max as u64 + 1)
+        .read_to_end(&mut buffer)?;
+    if buffer.len() > max {
+        bail!(
+            "Input does not fit in a single UDP datagram ({} bytes at most)",
+            max
+        );
+    }
+    Ok(buffer)
+}
+
+/// How long a connection attempt may take: `--connect-timeout`, or `--timeout`
+/// without it.
+pub fn connect_timeout(args: &Args, timeout: Option<Duration>) -> Option<Duration> {
+    match args.connect_timeout {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => timeout,
+    }
+}
+
+/// Connects to the first of the comma-separated `hosts` that accepts,
+/// resolving them again, directly or through `--proxy`.
+pub fn connect_client(
+    args: &Args,
+    hosts: &str,
+    port: u16,
+    timeout: Option<Duration>,
+) -> Result<TcpStream> {
+    let connect_timeout = connect_timeout(args, timeout);
+    let mut last_error = None;
+    for host in hosts.split(',').map(str::trim) {
+        let connected = if args.proxy.is_empty() {
+            connection_candidates(host, port, args)
+                .and_then(|candidates| connect_any(&candidates, &Source::of(args), connect_timeout))
+        } else {
+            connect_through_proxies(&args.proxy, host, port, args, connect_timeout)
+        };
+        match connected {
+            Ok(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)?;
+                set_stream_options(&stream, args)?;
+                return Ok(stream);
+            }
+            Err(e) => {
+                info!("Could not reach {}: {:#}", host, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("the address has at least one host"))
+}
+
+fn run_tcp_client(args: &Args, host: &str, port: u16, timeout: Option<Duration>) -> Result<()> {
+    if let Some(retries) = args.retry {
+        return run_retrying_client(args, host, port, timeout, retries);
+    }
+    #[cfg(target_os = "linux")]
+    if args.fastopen {
+        return run_fast_open_client(args, host, port, timeout);
+    }
+    let stream = connect_client(args, host, port, timeout)?;
+
+    if let Some(limit) = args.banner {
+        let wait = timeout.unwrap_or(scan::BANNER_WAIT);
+        let banner = scan::grab_banner(&stream, limit, wait)?;
+        writeln!(io::stdout(), "{}", scan::escape(&banner))?;
+        return Ok(());
+    }
+    if let Some(path) = &args.replay {
+        return replay(stream, path, args);
+    }
+    if args.reverse {
+        info!("Serving the connection to {}", stream.peer_addr()?);
+        return handle_connection(stream, args, timeout, None);
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(file) = zero_copy_source(args)? {
+        let buffer_size = chunk_size(args.buffer_size, args.tx_rate());
+        let limit = args.max_bytes.unwrap_or(u64::MAX);
+        let send = move |writer: &mut Outgoing, on_chunk: &mut dyn FnMut(usize)| match sendfile_all(
+            &file,
+            writer.socket().expect("zero-copy sends go to a TCP socket"),
+            buffer_size,
+            limit,
+            &mut *on_chunk,
+        )? {
+            Some(sent) => Ok(sent),
+            None => {
+                info!("sendfile is not supported for this file, copying normally");
+                send_stream((&file).take(limit), writer, buffer_size, on_chunk)
+            }
+        };
+        return pipe_duplex_with(stream, send, client_output(args)?, args);
+    }
+
+    let input = open_input(args)?;
+    pipe_duplex(stream, input, client_output(args)?, args)
+}
+
+/// Pipes the input like [`pipe_duplex`], but reads its first chunk before
+/// connecting so that `--fastopen` can send it in the SYN.
+#[cfg(target_os = "linux")]
+fn run_fast_open_client(
+    args: &Args,
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let buffer_size = chunk_size(args.buffer_size, args.tx_rate());
+    let mut input = open_input(args)?.take(args.max_bytes.unwrap_or(u64::MAX));
+    let mut first = vec![0u8; buffer_size];
+    let n = loop {
+        match input.read(&mut first) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => break result?,
+        }
+    };
+    first.truncate(n);
+    // A deferred connect only happens on the first write, so with nothing to
+    // write the handshake is made the usual way.
+    let plain;
+    let connect_args = if first.is_empty() {
+        plain = Args {
+            fastopen: false,
+            ..args.clone()
+        };
+        &plain
+    } else {
+        args
+    };
+    let stream = connect_client(connect_args, host, port, timeout)?;
+    (&stream).write_all(&first)?;
+    if fastopen::used(&stream)? {
+        info!("Fast open sent {} bytes in the SYN", first.len());
+    } else {
+        info!("Fast open was not used; the server has not given out a cookie yet or does not support it");
+    }
+
+    let send = move |writer: &mut Outgoing, on_chunk: &mut dyn FnMut(usize)| {
+        on_chunk(first.len());
+        let rest = send_stream(input, writer, buffer_size, on_chunk)?;
+        Ok(first.len() as u64 + rest)
+    };
+    pipe_duplex_with(stream, send, client_output(args)?, args)
+}
+
+/// The input of a `--retry` client, shared by its connections, with the chunk
+/// a failed write left behind.
+struct Resumable {
+    input: Box<dyn Read + Send>,
+    pending: Option<Vec<u8>>,
+}
+
+/// Pipes the input over as many connections as `--retry` allows, until one
+/// ends without an error.
+fn run_retrying_client(
+    args: &Args,
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+    retries: Retries,
+) -> Result<()> {
+    let input =
+        Paced::new(open_input(args)?, args.interval).take(args.max_bytes.unwrap_or(u64::MAX));
+    let input = Arc::new(Mutex::new(Resumable {
+        input: Box::new(input),
+        pending: None,
+    }));
+    let buffer_size = chunk_size(args.buffer_size, args.tx_rate());
+    let mut failures = 0;
+    loop {
+        let error = match connect_client(args, host, port, timeout) {
+            Ok(stream) => {
+                failures = 0;
+                let stream = Connection::from(stream);
+                let flow = stream.flow()?;
+                let input = input.clone();
+                let send = move |writer: &mut Outgoing, on_chunk: &mut dyn FnMut(usize)| {
+                    let mut writer = Captured::new(writer, flow, Direction::Sent);
+                    send_resumable(&input, &mut writer, buffer_size, on_chunk)
+                };
+                match pipe_duplex_with(stream, send, client_output(args)?, args) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        failures += 1;
+        if !retries.allows(failures) {
+            return Err(error);
+        }
+        let delay = backoff(args.retry_interval, args.retry_max_interval, failures);
+        writeln!(
+            io::stderr(),
+            "netpipe: {:#}; reconnecting in {:?} (attempt {})",
+            error,
+            delay,
+            failures
+        )?;
+        thread::sleep(delay);
+    }
+}
+
+/// The wait before the attempt after `failures` failures in a row, doubling
+/// from `first` up to `cap`.
+fn backoff(first: Duration, cap: Duration, failures: u32) -> Duration {
+    first.saturating_mul(1 << (failures - 1).min(16)).min(cap)
+}
+
+/// Like [`send_stream`], but keeps a chunk that failed to go out in `input`
+/// so the next connection sends it first.
+fn send_resumable(
+    input: &Mutex<Resumable>,
+    writer: &mut impl Write,
+    buffer_size: usize,
+    on_chunk: &mut dyn FnMut(usize),
+) -> io::Result<u64> {
+    // Held for the whole connection, so a sender left behind by a dropped
+    // connection hands its chunk over before the next one starts.
+    let mut input = input.lock().unwrap();
+    let mut buffer = vec![0u8; buffer_size];
+    let mut sent = 0;
+    loop {
+        let chunk = match input.pending.take() {
+            Some(chunk) => chunk,
+            None => match input.input.read(&mut buffer) {
+                Ok(0) => return Ok(sent),
+                Ok(n) => buffer[..n].to_vec(),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            },
+        };
+        if let Err(e) = writer.write_all(&chunk) {
+            input.pending = Some(chunk);
+            return Err(e);
+        }
+        on_chunk(chunk.len());
+        sent += chunk.len() as u64;
+    }
+}
+
+/// Connects to the first `--proxy` and has each hop open a tunnel to the
+/// next, the last one to `host:port`. Only a plain socks5:// hop needs the
+/// name of what follows it resolved here; each step may take `timeout`.
+fn connect_through_proxies(
+    proxies: &[ProxyUrl],
+    host: &str,
+    port: u16,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<TcpStream> {
+    let first = &proxies[0];
+    let candidates = connection_candidates(&first.host, first.port, args)?;
+    let mut stream = connect_any(&candidates, &Source::of(args), timeout)
+        .with_context(|| format!("Failed to connect to proxy {}", first))?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    info!("Connected to proxy {}", first);
+    let targets = proxies[1..]
+        .iter()
+        .map(|next| (next.host.as_str(), next.port))
+        .chain([(host, port)]);
+    for (i, (proxy, (host, port))) in proxies.iter().zip(targets).enumerate() {
+        let host = if proxy.kind.resolves_names() {
+            strip_zone(host).to_string()
+        } else {
+            resolve(host, port, args.ip_version)?.ip().to_string()
+        };
+        let target = Endpoint { host, port };
+        let auth = proxy.credentials.as_deref().or(args.proxy_auth.as_deref());
+        proxy::tunnel(&mut stream, proxy, &target, auth)
+            .with_context(|| format!("Proxy hop {} of {} failed", i + 1, proxies.len()))?;
+    }
+    Ok(stream)
+}
+
+/// Sends what the client sent in a `--replay` recording, and with
+/// `--replay-verify` fails unless the response matches the recorded one.
+fn replay(stream: TcpStream, path: &Path, args: &Args) -> Result<()> {
+    let chunks = recording::load(path)
+        .with_context(|| format!("Failed to read recording {}", path.display()))?;
+    let expected = received_data(&chunks);
+    let input = Replay::new(chunks, args.replay_speed);
+    if !args.replay_verify {
+        return pipe_duplex(stream, input, client_output(args)?, args);
+    }
+    let mut output = Verified::new(client_output(args)?, expected);
+    pipe_duplex(stream, input, &mut output, args)?;
+    output.finish()
+}
+
+/// The size of the data the client sends, when it comes from a regular `--file`
+/// and is not compressed on the way.
+fn input_size(args: &Args) -> Option<u64> {
+    let file_path = args
+        .file
+        .as_ref()
+        .filter(|_| !args.listen && args.compress.is_none())?;
+    let metadata = fs::metadata(file_path).ok()?;
+    metadata.is_file().then_some(metadata.len())
+}
+
+/// The `--file` to hand to `sendfile`, when it is a regular file and nothing
+/// asks for the data to pass through userspace.
+#[cfg(target_os = "linux")]
+fn zero_copy_source(args: &Args) -> Result<Option<File>> {
+    // Pacing with --interval, rewriting with --crlf, --decode or --compress,
+    // capturing with --hex-dump, --pcap or --record, encrypting with --psk or
+    // --tls and framing with --ws all need the data in userspace.
+    let zero_copy = !(args.no_zero_copy
+        || args.psk.is_some()
+        || args.tls
+        || args.ws.is_some()
+        || args.interval.is_some()
+        || args.crlf
+        || args.decode.is_some()
+        || args.compress.is_some()
+        || args.hex_dump.is_some()
+        || args.pcap.is_some()
+        || args.record.is_some());
+    let Some(file_path) = args.file.as_ref().filter(|_| zero_copy) else {
+        return Ok(None);
+    };
+    let file = File::open(file_path)?;
+    if !file.metadata()?.is_file() {
+        return Ok(None);
+    }
+    info!("Sending {} with sendfile", file_path.display());
+    Ok(Some(file))
+}
+
+/// The wildcard address of `destination`'s family, for binding client sockets.
+pub fn unspecified_address(destination: SocketAddr) -> SocketAddr {
+    match destination {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Writes every datagram arriving on a connected socket to `output` until the
+/// read timeout expires, until `deadline`, or until the next one would take the
+/// total past `limit`. With a DTLS `session` the datagrams are its records,
+/// and the server closing the session ends the exchange.
+fn receive_udp_responses<W: Write>(
+    socket: &UdpSocket,
+    mut output: W,
+    transfer: &Transfer,
+    limit: Option<u64>,
+    deadline: Option<Instant>,
+    mut session: Option<&mut Session>,
+) -> Result<ExitReason> {
+    let read_timeout = socket.read_timeout()?;
+    let flow = udp_flow(socket)?;
+    let mut buffer = vec![0u8; 65535];
+    loop {
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                info!("Reached --max-time, stopping");
+                return Ok(ExitReason::TimeLimit);
+            }
+            socket.set_read_timeout(shortest(read_timeout, Some(left)))?;
+        }
+        match socket.recv(&mut buffer) {
+            Ok(amt) => {
+                let (datagrams, closed) = match session.as_deref_mut() {
+                    Some(session) => {
+                        let received = session.handle(&buffer[..amt]);
+                        for reply in &received.replies {
+                            socket.send(reply)?;
+                        }
+                        if let Some(e) = received.error {
+                            return Err(e);
+                        }
+                        let data = received.data.into_iter().map(Cow::Owned).collect();
+                        (data, received.closed)
+                    }
+                    None => (vec![Cow::Borrowed(&buffer[..amt])], false),
+                };
+                for datagram in &datagrams {
+                    let amt = datagram.len();
+                    if limit.is_some_and(|max| transfer.received() + amt as u64 > max) {
+                        info!("A {} byte response would exceed --max-bytes, stopping", amt);
+                        return Ok(ExitReason::Eof);
+                    }
+                    capture(&flow, Direction::Received, transfer.received(), datagram);
+                    transfer.add_received(amt as u64);
+                    output.write_all(datagram)?;
+                    output.flush()?;
+                }
+                if closed {
+                    info!("The server closed the DTLS session");
+                    return Ok(ExitReason::Eof);
+                }
+            }
+            Err(e) if is_timeout(&e) && deadline.is_some_and(|d| Instant::now() >= d) => {}
+            Err(e) if is_timeout(&e) => return Ok(ExitReason::Timeout),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                bail!(
+                    "{} is not accepting datagrams (port unreachable)",
+                    socket.peer_addr()?
+                )
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// The flow of a connected UDP socket.
+fn udp_flow(socket: &UdpSocket) -> io::Result<Flow> {
+    Ok(Flow::new(
+        Protocol::Udp,
+        socket.local_addr()?,
+        socket.peer_addr()?,
+    ))
+}
+
+/// What the UDP client sends: the whole input as one datagram, with
+/// `--interval` one datagram per line, or with `--datagram-size` one per
+/// chunk of that size.
+pub enum Payload {
+    Datagram(Vec<u8>),
+    Lines(Paced<Box<dyn Read + Send>>),
+    Chunks(Box<dyn Read + Send>),
+}
+
+impl Payload {
+    /// Passes each datagram to `send` until the input ends or `send` returns
+    /// false. Chunks carry their sequence number in front with `--sequenced`.
+    fn send_each(self, args: &Args, mut send: impl FnMut(&[u8]) -> Result<bool>) -> Result<()> {
+        let mut send = |datagram: &[u8]| match args.max_datagram {
+            Some(max) if datagram.len() > max => bail!(
+                "The {} byte datagram is larger than the --max-datagram limit of {} bytes",
+                datagram.len(),
+                max
+            ),
+            _ => send(datagram),
+        };
+        match self {
+            Payload::Datagram(datagram) => {
+                send(&datagram)?;
+            }
+            Payload::Lines(mut lines) => {
+                let mut buffer = vec![0u8; max_payload(args)];
+                loop {
+                    let n = lines.read(&mut buffer)?;
+                    if n == 0 || !send(&buffer[..n])? {
+                        break;
+                    }
+                }
+            }
+            Payload::Chunks(mut input) => {
+                let header = if args.sequenced { sequence::HEADER } else { 0 };
+                let size = args.datagram_size.unwrap_or(MAX_DATAGRAM_PAYLOAD) - header;
+                let mut chunk = Vec::with_capacity(size);
+                for number in 0u32.. {
+                    chunk.clear();
+                    input.by_ref().take(size as u64).read_to_end(&mut chunk)?;
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    let sent = if args.sequenced {
+                        send(&[&sequence::header(number)[..], &chunk].concat())?
+                    } else {
+                        send(&chunk)?
+                    };
+                    if !sent {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn run_udp_client(
+    args: &Args,
+    destination: SocketAddr,
+    payload: Payload,
+    output: Box<dyn Write>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let source = match args.source {
+        Some(source) if IpVersion::of(&source) != IpVersion::of(&destination.ip()) => bail!(
+            "--source {} is an {} address, but {} is not",
+            source,
+            IpVersion::of(&source),
+            destination
+        ),
+        _ => Source::of(args),
+    };
+    if (args.tls || args.reliable || args.nat_keepalive.is_some())
+        && destination.ip().is_multicast()
+    {
+        bail!(
+            "--tls, --reliable and --nat-keepalive need a single peer, so they cannot send to multicast group {}",
+            destination.ip()
+        );
+    }
+    let socket: UdpSocket = socket_from(&source, destination, Type::DGRAM)?.into();
+    if args.broadcast || destination.ip().is_multicast() {
+        return run_broadcast_client(socket, destination, args, payload, output, timeout);
+    }
+    let local = socket.local_addr()?;
+    socket.connect(destination).map_err(|e| {
+        let hint = if e.kind() == io::ErrorKind::PermissionDenied {
+            "; --broadcast is needed to send to a broadcast address"
+        } else {
+            ""
+        };
+        anyhow!(e).context(format!(
+            "Cannot reach {} address {} from {} socket {}{}",
+            IpVersion::of(&destination.ip()),
+            destination,
+            IpVersion::of(&local.ip()),
+            local,
+            hint
+        ))
+    })?;
+    info!("Sending to {} from {}", destination, socket.local_addr()?);
+    socket.set_write_timeout(timeout)?;
+    socket.set_read_timeout(timeout)?;
+    let mut session = match (args.tls, &args.address) {
+        (true, Some(address)) => Some(dtls::connect(&socket, address, args, timeout)?),
+        _ => None,
+    };
+
+    let transfer = Transfer::new(destination, Protocol::Udp);
+    let keepalive = match args.nat_keepalive {
+        Some(interval) => Some(NatKeepalive::start(&socket, interval, args, &transfer)?),
+        None => None,
+    };
+    let result = match payload {
+        Payload::Chunks(input) if args.reliable => reliable::send(&socket, input, args, &transfer),
+        payload => exchange_datagrams(&socket, args, payload, output, &transfer, session.as_mut()),
+    };
+    if let Some(session) = &mut session {
+        // A lost close_notify only leaves the server to time the peer out.
+        let _ = socket.send(&session.close_notify());
+    }
+    drop(keepalive);
+    report_transfer(&transfer, &result, args);
+    result.map(|_| ())
+}
+
+/// How long `--broadcast` and multicast clients wait for more replies without
+/// `--timeout`.
+const BROADCAST_WAIT: Duration = Duration::from_secs(2);
+
+/// Sends the payload to the broadcast or multicast `destination` and prints
+/// the replies.
+fn run_broadcast_client(
+    socket: UdpSocket,
+    destination: SocketAddr,
+    args: &Args,
+    payload: Payload,
+    output: Box<dyn Write>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    if destination.ip().is_multicast() {
+        info!(
+            "Sending to multicast group {} from {}",
+            destination,
+            socket.local_addr()?
+        );
+    } else {
+        if destination.is_ipv6() {
+            bail!("IPv6 has no broadcast, so --broadcast needs an IPv4 address");
+        }
+        socket.set_broadcast(true)?;
+        info!(
+            "Broadcasting to {} from {}",
+            destination,
+            socket.local_addr()?
+        );
+    }
+    socket.set_read_timeout(Some(timeout.unwrap_or(BROADCAST_WAIT)))?;
+    let transfer = Transfer::new(destination, Protocol::Udp);
+    let result = exchange_broadcast(&socket, destination, args, payload, output, &transfer);
+    report_transfer(&transfer, &result, args);
+    result.map(|_| ())
+}
+
+/// Sends the datagrams and, unless `--send-only`, prints the replies
+/// of every host that answers, naming each sender on stderr, until none has
+/// come for the timeout.
+fn exchange_broadcast(
+    socket: &UdpSocket,
+    destination: SocketAddr,
+    args: &Args,
+    payload: Payload,
+    mut output: Box<dyn Write>,
+    transfer: &Transfer,
+) -> Result<ExitReason> {
+    let local = socket.local_addr()?;
+    let flow = Flow::new(Protocol::Udp, local, destination);
+    payload.send_each(args, |datagram| {
+        let sent = socket.send_to(datagram, destination)?;
+        capture(&flow, Direction::Sent, transfer.sent(), &datagram[..sent]);
+        transfer.add_sent(sent as u64);
+        Ok(true)
+    })?;
+    if args.send_only {
+        return Ok(ExitReason::Eof);
+    }
+
+    let mut buffer = vec![0u8; 65535];
+    loop {
+        match socket.recv_from(&mut buffer) {
+            Ok((amt, peer)) => {
+                writeln!(io::stderr(), "netpipe: {} bytes from {}", amt, peer)?;
+                let flow = Flow::new(Protocol::Udp, local, peer);
+                capture(
+                    &flow,
+                    Direction::Received,
+                    transfer.received(),
+                    &buffer[..amt],
+                );
+                transfer.add_received(amt as u64);
+                output.write_all(&buffer[..amt])?;
+                output.flush()?;
+            }
+            // The hosts that were going to answer have had their chance.
+            Err(e) if is_timeout(&e) => return Ok(ExitReason::Eof),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Sends `--nat-keepalive` datagrams on a connected socket whenever it has
+/// sent nothing else for the interval, until dropped.
+struct NatKeepalive {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl NatKeepalive {
+    fn start(
+        socket: &UdpSocket,
+        interval: Duration,
+        args: &Args,
+        transfer: &Arc<Transfer>,
+    ) -> io::Result<Self> {
+        let socket = socket.try_clone()?;
+        let payload = args.nat_keepalive_payload.clone().into_bytes();
+        let transfer = Arc::clone(transfer);
+        transfer.count_keepalives();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            // Real traffic shows up as the sent counter moving between checks.
+            let check = (interval / 4).max(Duration::from_millis(10));
+            let mut sent = transfer.sent();
+            let mut last_traffic = Instant::now();
+            while stopped.recv_timeout(check) == Err(mpsc::RecvTimeoutError::Timeout) {
+                if transfer.sent() != sent {
+                    sent = transfer.sent();
+                    last_traffic = Instant::now();
+                } else if last_traffic.elapsed() >= interval {
+                    match socket.send(&payload) {
+                        Ok(n) => transfer.add_keepalive(n as u64),
+                        Err(e) => info!("Failed to send a NAT keepalive: {}", e),
+                    }
+                    last_traffic = Instant::now();
+                }
+            }
+        });
+        Ok(NatKeepalive {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for NatKeepalive {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Sends the client's datagrams and, unless `--send-only`, prints the responses.
+fn exchange_datagrams(
+    socket: &UdpSocket,
+    args: &Args,
+    payload: Payload,
+    output: Box<dyn Write>,
+    transfer: &Transfer,
+    mut session: Option<&mut Session>,
+) -> Result<ExitReason> {
+    let deadline = args.max_time().map(|max_time| Instant::now() + max_time);
+    let flow = udp_flow(socket)?;
+    // With --no-stdin and no file there is no payload, only responses to wait for.
+    let has_payload = !(args.no_stdin && args.file.is_none());
+    if has_payload {
+        payload.send_each(args, |datagram| {
+            send_datagram(
+                socket,
+                &flow,
+                datagram,
+                args,
+                transfer,
+                session.as_deref_mut(),
+            )
+        })?;
+    } else {
+        info!("Nothing to send, waiting for responses");
+    }
+
+    if args.send_only {
+        return Ok(ExitReason::Eof);
+    }
+    // Datagrams have no end of stream; the exchange ends when responses stop.
+    receive_udp_responses(socket, output, transfer, args.max_bytes, deadline, session)
+}
+
+/// Sends one datagram unless it would take the total past `--max-bytes`,
+/// returning whether it was sent. With a DTLS `session` it goes out as one
+/// record.
+fn send_datagram(
+    socket: &UdpSocket,
+    flow: &Flow,
+    datagram: &[u8],
+    args: &Args,
+    transfer: &Transfer,
+    session: Option<&mut Session>,
+) -> Result<bool> {
+    if args
+        .max_bytes
+        .is_some_and(|max| transfer.sent() + datagram.len() as u64 > max)
+    {
+        info!(
+            "The {} byte datagram would exceed --max-bytes, not sending it",
+            datagram.len()
+        );
+        return Ok(false);
+    }
+    let sent = match session {
+        Some(session) => {
+            // DTLS has no record for an empty datagram, so nothing goes out.
+            let record = session.seal(datagram)?;
+            if !record.is_empty() {
+                socket.send(&record)?;
+            }
+            datagram.len()
+        }
+        None => socket.send(datagram)?,
+    };
+    capture(flow, Direction::Sent, transfer.sent(), &datagram[..sent]);
+    transfer.add_sent(sent as u64);
+    Ok(true)
+}
+
+fn run_quic_client(args: &Args, host: &str, port: u16, timeout: Option<Duration>) -> Result<()> {
+    let connect_timeout = connect_timeout(args, timeout);
+    let destination = resolve(host, port, args.ip_version)?;
+    // The server's certificate must match the name we were asked to reach.
+    let stream = quic::connect(destination, strip_zone(host), args, connect_timeout)?;
+    stream.set_read_timeout(timeout);
+    stream.set_write_timeout(timeout);
+    pipe_duplex(stream, open_input(args)?, client_output(args)?, args)
+}
+
+pub fn run_client(args: &Args, protocol: &Protocol, timeout: Option<Duration>) -> Result<()> {
+    if let Some(url) = args.ws_url() {
+        return run_tcp_client(args, &url.host, url.port, timeout);
+    }
+    let address = args.address.as_ref().unwrap();
+    let port = args.port().unwrap();
+
+    match protocol {
+        Protocol::Tcp => run_tcp_client(args, address, port, timeout),
+        Protocol::Udp => {
+            let version = args.ip_version.or(args.source.as_ref().map(IpVersion::of));
+            let destination = resolve(address, port, version)?;
+            let payload = match (args.interval, args.datagram_size) {
+                (Some(interval), _) => {
+                    Payload::Lines(Paced::new(open_input(args)?, Some(interval)))
+                }
+                (None, Some(size)) if size > max_payload(args) => bail!(
+                    "--datagram-size {} is more than a DTLS record carries ({} bytes)",
+                    size,
+                    max_payload(args)
+                ),
+                (None, Some(_)) => Payload::Chunks(open_input(args)?),
+                (None, None) if args.reliable => Payload::Chunks(open_input(args)?),
+                (None, None) => Payload::Datagram(prepare_datagram(args)?),
+            };
+            run_udp_client(args, destination, payload, client_output(args)?, timeout)
+        }
+        Protocol::Quic => run_quic_client(args, address, port, timeout),
+    }
+}
+
+/// Stands in for the `dtls` module in builds without the `dtls` feature, where
+/// `main` turns `--tls` over UDP away before any of it is reached.
+#[cfg(not(feature = "dtls"))]
+mod dtls {
+    use crate::args::Args;
+    use anyhow::{bail, Result};
+    use std::{
+        io,
+        net::{SocketAddr, UdpSocket},
+        time::Duration,
+    };
+
+    pub const MAX_DATA: usize = 16384;
+
+    pub struct Received {
+        pub data: Vec<Vec<u8>>,
+        pub replies: Vec<Vec<u8>>,
+        pub closed: bool,
+        pub error: Option<anyhow::Error>,
+    }
+
+    pub enum Session {}
+
+    impl Session {
+        pub fn handle(&mut self, _: &[u8]) -> Received {
+            match *self {}
+        }
+
+        pub fn seal(&mut self, _: &[u8]) -> io::Result<Vec<u8>> {
+            match *self {}
+        }
+
+        pub fn close_notify(&mut self) -> Vec<u8> {
+            match *self {}
+        }
+    }
+
+    pub enum Listener {}
+
+    impl Listener {
+        pub fn new(_: &Args) -> Result<Self> {
+            bail!("netpipe was built without DTLS")
+        }
+
+        pub fn handle(&mut self, _: SocketAddr, _: &[u8]) -> Received {
+            match *self {}
+        }
+
+        pub fn seal(&mut self, _: SocketAddr, _: &[u8]) -> Option<io::Result<Vec<u8>>> {
+            match *self {}
+        }
+
+        pub fn forget(&mut self, _: &SocketAddr) {
+            match *self {}
+        }
+    }
+
+    pub fn connect(_: &UdpSocket, _: &str, _: &Args, _: Option<Duration>) -> Result<Session> {
+        bail!("netpipe was built without DTLS")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::{
+        ffi::OsStr,
+        fs,
+        io::{BufRead, Cursor},
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    fn no_args() -> Args {
+        Args::parse_from(["test"])
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("netpipe-{}-{}", std::process::id(), name))
+    }
+
+    fn send_and_close(destination: std::net::SocketAddr, payload: &[u8]) {
+        let mut stream = TcpStream::connect(destination).unwrap();
+        stream.write_all(payload).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        stream.read_to_end(&mut Vec::new()).unwrap();
+    }
+
+    /// A `Write` sink that only counts what passes through it.
+    #[derive(Clone, Default)]
+    struct CountingSink(Arc<AtomicUsize>);
+
+    impl Write for CountingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.fetch_add(buf.len(), Ordering::SeqCst);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A `Write` sink that can be inspected after being moved into a thread.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Test TCP communication with server handling a single connection
+    #[test]
+    fn test_is_valid_address_accepts_unicast() {
+        for address in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.1.100",
+            "100.64.0.1",
+            "8.8.8.8",
+        ] {
+            assert!(is_valid_address(address, &4, false, false), "{}", address);
+        }
+        for address in ["::1", "2001:4860:4860::8888", "fd00::1"] {
+            assert!(is_valid_address(address, &6, false, false), "{}", address);
+        }
+    }
+
+    #[test]
+    fn test_is_valid_address_rejects_wrong_family_and_non_unicast() {
+        assert!(!is_valid_address("::1", &4, false, false));
+        assert!(!is_valid_address("127.0.0.1", &6, false, false));
+        assert!(!is_valid_address("224.0.0.1", &4, false, false));
+        assert!(!is_valid_address("255.255.255.255", &4, false, false));
+        assert!(!is_valid_address("ff02::1", &6, false, false));
+
+        assert!(is_valid_address("224.0.0.1", &4, true, false));
+        assert!(!is_valid_address("::1", &4, true, false));
+        assert!(is_valid_address("255.255.255.255", &4, false, true));
+        assert!(!is_valid_address("224.0.0.1", &4, false, true));
+    }
+
+    #[test]
+    fn test_broadcast_prints_replies_from_any_host() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            let (n, client) = listener.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..n], b"who is there?");
+            // Devices answer from their own address, not the one probed.
+            for name in [&b"printer"[..], b"camera"] {
+                let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+                device.send_to(name, client).unwrap();
+            }
+        });
+        let args = Args::parse_from(["test", "-p", "udp", "--broadcast"]);
+        let output = SharedBuf::default();
+        let payload = Payload::Datagram(b"who is there?".to_vec());
+        let timeout = Some(Duration::from_millis(500));
+        run_udp_client(
+            &args,
+            destination,
+            payload,
+            Box::new(output.clone()),
+            timeout,
+        )
+        .unwrap();
+        server.join().unwrap();
+        assert_eq!(&*output.0.lock().unwrap(), b"printercamera");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_multicast_client_prints_replies_from_members() {
+        let group = Ipv4Addr::new(239, 255, 78, 81);
+        let member = UdpSocket::bind("0.0.0.0:0").unwrap();
+        member
+            .join_multicast_v4(&group, &Ipv4Addr::LOCALHOST)
+            .unwrap();
+        let destination = SocketAddr::from((group, member.local_addr().unwrap().port()));
+        let server = thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            let (n, client) = member.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..n], b"who is there?");
+            UdpSocket::bind("127.0.0.1:0")
+                .unwrap()
+                .send_to(b"sensor", client)
+                .unwrap();
+        });
+        let args = Args::parse_from(["test", "-p", "udp", "--multicast-if", "127.0.0.1"]);
+        let output = SharedBuf::default();
+        let payload = Payload::Datagram(b"who is there?".to_vec());
+        let timeout = Some(Duration::from_millis(500));
+        run_udp_client(
+            &args,
+            destination,
+            payload,
+            Box::new(output.clone()),
+            timeout,
+        )
+        .unwrap();
+        server.join().unwrap();
+        assert_eq!(&*output.0.lock().unwrap(), b"sensor");
+
+        let socket = socket_from(&Source::of(&args), destination, Type::DGRAM).unwrap();
+        assert_eq!(socket.multicast_if_v4().unwrap(), Ipv4Addr::LOCALHOST);
+        assert!(!socket.multicast_loop_v4().unwrap());
+        let args = Args::parse_from(["test", "-p", "udp", "--multicast-loop"]);
+        let socket = socket_from(&Source::of(&args), destination, Type::DGRAM).unwrap();
+        assert!(socket.multicast_loop_v4().unwrap());
+    }
+
+    #[test]
+    fn test_literal_ip_version_inference() {
+        let v4: IpAddr = "192.168.1.100".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(literal_ip_version(&v4, None).unwrap(), IpVersion::V4);
+        assert_eq!(literal_ip_version(&v6, None).unwrap(), IpVersion::V6);
+        assert_eq!(
+            literal_ip_version(&v4, Some(IpVersion::V4)).unwrap(),
+            IpVersion::V4
+        );
+        assert_eq!(
+            literal_ip_version(&v6, Some(IpVersion::V6)).unwrap(),
+            IpVersion::V6
+        );
+    }
+
+    #[test]
+    fn test_literal_ip_version_mismatch() {
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        let err = literal_ip_version(&v6, Some(IpVersion::V4)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "address 2001:db8::1 is IPv6 but --ip-version 4 was requested"
+        );
+
+        let v4: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(literal_ip_version(&v4, Some(IpVersion::V6)).is_err());
+    }
+
+    #[test]
+    fn test_resolve_literal_and_hostname() {
+        let literal = resolve("127.0.0.1", 80, Some(IpVersion::V4)).unwrap();
+        assert_eq!(literal, "127.0.0.1:80".parse().unwrap());
+
+        let v6_literal = resolve("::1", 80, None).unwrap();
+        assert_eq!(v6_literal, "[::1]:80".parse().unwrap());
+
+        let hostname = resolve("localhost", 8080, Some(IpVersion::V4)).unwrap();
+        assert!(hostname.ip().is_loopback());
+        assert_eq!(hostname.port(), 8080);
+    }
+
+    #[test]
+    fn test_resolve_errors_name_host_and_family() {
+        let err = resolve("::1", 80, Some(IpVersion::V4)).unwrap_err();
+        assert_eq!(err.to_string(), "'::1' has no IPv4 address");
+
+        let err = resolve("does-not-exist.invalid", 80, Some(IpVersion::V6)).unwrap_err();
+        assert!(err.to_string().contains("'does-not-exist.invalid'"));
+        assert!(err.to_string().contains("IPv6"));
+    }
+
+    #[test]
+    fn test_resolve_scoped_ipv6_literals() {
+        let numeric = resolve("fe80::1%3", 80, None).unwrap();
+        assert_eq!(
+            numeric,
+            SocketAddrV6::new("fe80::1".parse().unwrap(), 80, 0, 3).into()
+        );
+
+        let SocketAddr::V6(named) = resolve("fe80::1%lo", 80, Some(IpVersion::V6)).unwrap() else {
+            panic!("expected an IPv6 address");
+        };
+        assert_ne!(named.scope_id(), 0);
+        assert_eq!(strip_zone("fe80::1%lo"), "fe80::1");
+
+        let err = resolve("fe80::1%nosuchif0", 80, None).unwrap_err();
+        assert!(err.to_string().contains("'nosuchif0'"));
+        assert!(resolve("127.0.0.1%lo", 80, None).is_err());
+    }
+
+    #[test]
+    fn test_interleave_alternates_until_both_run_out() {
+        let v6: Vec<SocketAddr> = vec!["[::1]:1".parse().unwrap(), "[::2]:1".parse().unwrap()];
+        let v4: Vec<SocketAddr> = vec![
+            "10.0.0.1:1".parse().unwrap(),
+            "10.0.0.2:1".parse().unwrap(),
+            "10.0.0.3:1".parse().unwrap(),
+        ];
+        assert_eq!(
+            interleave(v6.clone(), v4.clone()),
+            vec![v6[0], v4[0], v6[1], v4[1], v4[2]]
+        );
+        assert_eq!(interleave(v4.clone(), Vec::new()), v4);
+    }
+
+    #[test]
+    fn test_connection_candidates_respect_no_fallback() {
+        let args = Args::parse_from(["test"]);
+        let candidates = connection_candidates("::1", 80, &args).unwrap();
+        assert_eq!(candidates, vec!["[::1]:80".parse().unwrap()]);
+
+        let args = Args::parse_from(["test", "--ip-version", "6", "--no-fallback"]);
+        assert!(connection_candidates("127.0.0.1", 80, &args).is_err());
+    }
+
+    #[test]
+    fn test_socks5h_leaves_host_names_to_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("socks5h://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            let mut request = [0u8; 4 + 1 + 15 + 2];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            request
+        });
+
+        let args = Args::parse_from(["test", "--proxy", &proxy]);
+        // The name does not resolve, so only the proxy can make sense of it.
+        connect_through_proxies(&args.proxy, "netpipe.invalid", 80, &args, None).unwrap();
+        let request = server.join().unwrap();
+        assert_eq!(request[..5], [5, 1, 0, 3, 15]);
+        assert_eq!(&request[5..20], b"netpipe.invalid");
+        assert_eq!(request[20..], [0, 80]);
+    }
+
+    #[test]
+    fn test_proxy_chain_tunnels_hop_by_hop() {
+        // One server plays both hops, since each tunnel just passes the next
+        // hop's requests through.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let first = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connect = [0u8; 64];
+            let n = stream.read(&mut connect).unwrap();
+            let connect = String::from_utf8_lossy(&connect[..n]).into_owned();
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, 2]).unwrap();
+            let mut auth = [0u8; 5];
+            stream.read_exact(&mut auth).unwrap();
+            stream.write_all(&[1, 0]).unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            // Host unreachable.
+            stream.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            (connect, auth, request)
+        });
+
+        let args = Args::parse_from([
+            "test",
+            "--proxy",
+            &first,
+            "--proxy",
+            "socks5://a:b@hop2.invalid:1080",
+        ]);
+        let err = connect_through_proxies(&args.proxy, "127.0.0.1", 80, &args, None).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            message.starts_with("Proxy hop 2 of 2 failed"),
+            "{}",
+            message
+        );
+        assert!(message.contains("host unreachable"), "{}", message);
+
+        let (connect, auth, request) = server.join().unwrap();
+        assert!(connect.starts_with("CONNECT hop2.invalid:1080 HTTP/1.1\r\n"));
+        assert_eq!(&auth, b"\x01\x01a\x01b");
+        assert_eq!(request, [5, 1, 0, 1, 127, 0, 0, 1, 0, 80]);
+    }
+
+    #[test]
+    fn test_forward_relays_concurrent_clients() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = ForwardTarget {
+            protocol: None,
+            endpoint: Endpoint {
+                host: "127.0.0.1".to_string(),
+                port: upstream.local_addr().unwrap().port(),
+            },
+        };
+        thread::spawn(move || {
+            for stream in upstream.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut request = String::new();
+                    stream.read_to_string(&mut request).unwrap();
+                    stream.write_all(request.to_uppercase().as_bytes()).unwrap();
+                });
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let args = Args::parse_from(["test", "--listen"]);
+            serve_forward(&listener, &target, &args, None)
+        });
+
+        // The first client waits on its half-close while the second is served.
+        let mut first = TcpStream::connect(address).unwrap();
+        first.write_all(b"first").unwrap();
+        let mut second = TcpStream::connect(address).unwrap();
+        second.write_all(b"second").unwrap();
+        for (mut client, expected) in [(second, "SECOND"), (first, "FIRST")] {
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert_eq!(response, expected);
+        }
+    }
+
+    #[test]
+    fn test_forward_closes_clients_when_the_target_is_down() {
+        let target = {
+            let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+            ForwardTarget {
+                protocol: None,
+                endpoint: Endpoint {
+                    host: "127.0.0.1".to_string(),
+                    port: closed.local_addr().unwrap().port(),
+                },
+            }
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let args = Args::parse_from(["test", "--listen"]);
+            serve_forward(&listener, &target, &args, None)
+        });
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(address).unwrap();
+            assert!(matches!(client.read(&mut [0u8; 1]), Ok(0) | Err(_)));
+        }
+    }
+
+    #[test]
+    fn test_forward_bridges_tcp_records_to_udp_datagrams() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = format!("udp://{}", upstream.local_addr().unwrap());
+        thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            loop {
+                let (n, peer) = upstream.recv_from(&mut buffer).unwrap();
+                let reply = buffer[..n].to_ascii_uppercase();
+                upstream.send_to(&reply, peer).unwrap();
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let args = Args::parse_from([
+                "test",
+                "--listen",
+                "--forward",
+                &target,
+                "--datagram-framing",
+                "newline",
+                "--max-record",
+                "5",
+                "--idle-timeout",
+                "1",
+            ]);
+            serve_forward(&listener, args.forward.as_ref().unwrap(), &args, None)
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"one\ntoo long\ntwo\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "ONE\nTWO\n");
+    }
+
+    #[test]
+    fn test_forward_bridges_udp_datagrams_to_tcp_records() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = format!("tcp://{}", upstream.local_addr().unwrap());
+        let received = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut request = [0u8; 13];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(b"\x00\x00\x00\x02ok").unwrap();
+            request
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let args = Args::parse_from([
+                "test",
+                "--listen",
+                "--protocol",
+                "udp",
+                "--forward",
+                &target,
+                "--datagram-framing",
+                "length",
+            ]);
+            serve_udp_bridge(
+                &socket,
+                &args.forward.as_ref().unwrap().endpoint,
+                &args,
+                None,
+            )
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.send_to(b"hello", address).unwrap();
+        client.send_to(b"", address).unwrap();
+        let mut reply = [0u8; 16];
+        let (n, _) = client.recv_from(&mut reply).unwrap();
+        assert_eq!(&reply[..n], b"ok");
+        assert_eq!(
+            &received.join().unwrap(),
+            b"\x00\x00\x00\x05hello\x00\x00\x00\x00"
+        );
+    }
+
+    #[test]
+    fn test_connect_any_falls_back_to_next_candidate() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let working = listener.local_addr().unwrap();
+        let refused = {
+            let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+            closed.local_addr().unwrap()
+        };
+        // TEST-NET-1 never answers, so this attempt either hangs or fails fast.
+        let blackhole: SocketAddr = "192.0.2.1:9".parse().unwrap();
+
+        let started = std::time::Instant::now();
+        let stream = connect_any(&[blackhole, refused, working], &Source::default(), None).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), working);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let err = connect_any(&[refused], &Source::default(), None).unwrap_err();
+        assert!(err.to_string().contains(&refused.to_string()));
+    }
+
+    #[test]
+    fn test_connect_any_reports_connect_timeout() {
+        // With a zero backlog and nobody accepting, the kernel soon starts
+        // dropping SYNs, which leaves further connection attempts hanging.
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener
+            .bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let destination = listener.local_addr().unwrap().as_socket().unwrap();
+
+        let mut held = Vec::new();
+        let err = loop {
+            match connect_any(
+                &[destination],
+                &Source::default(),
+                Some(Duration::from_millis(200)),
+            ) {
+                Ok(stream) => held.push(stream),
+                Err(e) => break e,
+            }
+            assert!(held.len() < 16, "backlog never filled up");
+        };
+        assert_eq!(
+            err.to_string(),
+            format!("connection to {} timed out after 200ms", destination)
+        );
+    }
+
+    #[test]
+    fn test_tcp_communication() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = Vec::new();
+            stream.read_to_end(&mut buffer).unwrap();
+            buffer
+        });
+
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(
+            stream,
+            Cursor::new(b"hello".to_vec()),
+            io::sink(),
+            &Args::parse_from(["test", "-N"]),
+        )
+        .unwrap();
+
+        assert_eq!(server_handle.join().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_tcp_client_duplex() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+
+        // Echo server: reply with whatever arrives, then close.
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            io::copy(&mut stream, &mut writer).unwrap();
+        });
+
+        let output = SharedBuf::default();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(
+            stream,
+            Cursor::new(b"ping\n".to_vec()),
+            output.clone(),
+            &Args::parse_from(["test", "-N"]),
+        )
+        .unwrap();
+        server_handle.join().unwrap();
+
+        assert_eq!(*output.0.lock().unwrap(), b"ping\n");
+    }
+
+    #[test]
+    fn test_tcp_server_and_client_exchange_both_ways() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+
+        let server_output = SharedBuf::default();
+        let server_sink = server_output.clone();
+        let server_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            pipe_duplex(
+                stream,
+                Cursor::new(b"from server".to_vec()),
+                server_sink,
+                &Args::parse_from(["test", "-N"]),
+            )
+            .unwrap();
+        });
+
+        let client_output = SharedBuf::default();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(
+            stream,
+            Cursor::new(b"from client".to_vec()),
+            client_output.clone(),
+            &Args::parse_from(["test", "-N"]),
+        )
+        .unwrap();
+        server_handle.join().unwrap();
+
+        assert_eq!(*server_output.0.lock().unwrap(), b"from client");
+        assert_eq!(*client_output.0.lock().unwrap(), b"from server");
+    }
+
+    #[test]
+    fn test_tcp_server_exits_after_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let args = Args::parse_from(["test", "--listen", "--exec", "cat > /dev/null"]);
+
+        let server_handle =
+            thread::spawn(move || serve_tcp(&listener, &args, Some(Duration::from_secs(1))));
+        send_and_close(destination, b"only one");
+
+        server_handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_server_without_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let args = Args::parse_from(["test", "--listen", "--exec", "cat > /dev/null"]);
+
+        let server_handle = thread::spawn(move || serve_tcp(&listener, &args, None));
+        send_and_close(destination, b"no deadline");
+
+        server_handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_server_read_timeout_expires() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let args = Args::parse_from(["test", "--listen", "--exec", "cat > /dev/null"]);
+
+        let server_handle =
+            thread::spawn(move || serve_tcp(&listener, &args, Some(Duration::from_millis(100))));
+        let _silent = TcpStream::connect(destination).unwrap();
+
+        assert!(server_handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_tcp_server_keep_open_handles_each_connection() {
+        let output = temp_path("keep-open");
+        let command = format!("cat >> {}", output.display());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let args = Args::parse_from(["test", "--listen", "--keep-open", "--exec", &command]);
+
+        // The server never returns in keep-open mode, so the thread is left running.
+        thread::spawn(move || serve_tcp(&listener, &args, Some(Duration::from_secs(1))));
+        send_and_close(destination, b"first\n");
+        send_and_close(destination, b"second\n");
+
+        let contents = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert_eq!(contents, "first\nsecond\n");
+    }
+
+    #[test]
+    fn test_tcp_server_workers_do_not_block_each_other() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--keep-open",
+            "--max-workers",
+            "2",
+            "--exec",
+            "cat > /dev/null",
+        ]);
+
+        thread::spawn(move || serve_tcp(&listener, &args, Some(Duration::from_secs(5))));
+
+        // Hold the first connection open without sending EOF, occupying one worker.
+        let _stalled = TcpStream::connect(destination).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = std::time::Instant::now();
+        send_and_close(destination, b"not blocked");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_tcp_server_workers_answer_concurrent_clients_separately() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--keep-open",
+            "--max-workers",
+            "2",
+            "--exec",
+            "tr a-z A-Z",
+        ]);
+
+        thread::spawn(move || serve_tcp(&listener, &args, Some(Duration::from_secs(5))));
+
+        // The first client stays connected while the second is served.
+        let mut first = TcpStream::connect(destination).unwrap();
+        first.write_all(b"first").unwrap();
+        let mut second = TcpStream::connect(destination).unwrap();
+        second.write_all(b"second").unwrap();
+        second.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        second.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "SECOND");
+
+        first.shutdown(Shutdown::Write).unwrap();
+        reply.clear();
+        first.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "FIRST");
+    }
+
+    #[test]
+    fn test_udp_server_receives_until_count() {
+        let output = temp_path("udp-count");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--count",
+            "2",
+            "--datagram-framing",
+            "newline",
+            "--file",
+            output.to_str().unwrap(),
+        ]);
+
+        let server_handle =
+            thread::spawn(move || serve_udp(&socket, &args, Some(Duration::from_secs(5))));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"one", destination).unwrap();
+        client.send_to(b"two", destination).unwrap();
+        server_handle.join().unwrap().unwrap();
+
+        let contents = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert_eq!(contents, "one\ntwo\n");
+    }
+
+    #[test]
+    fn test_udp_server_once_and_length_framing() {
+        let output = temp_path("udp-once");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--once",
+            "--datagram-framing",
+            "length",
+            "--file",
+            output.to_str().unwrap(),
+        ]);
+
+        let server_handle =
+            thread::spawn(move || serve_udp(&socket, &args, Some(Duration::from_secs(5))));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"abc", destination).unwrap();
+        server_handle.join().unwrap().unwrap();
+
+        let contents = fs::read(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert_eq!(contents, b"\0\0\0\x03abc");
+    }
+
+    #[test]
+    fn test_datagram_size_splits_and_sequences_the_input() {
+        let input = temp_path("datagram-size");
+        fs::write(&input, "abcdefgh").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = receiver.local_addr().unwrap();
+        let args = |extra: &[&str]| {
+            let mut argv = vec!["test", "-p", "udp", "--send-only", "--file"];
+            argv.push(input.to_str().unwrap());
+            argv.extend_from_slice(extra);
+            Args::parse_from(argv)
+        };
+        let mut buffer = [0u8; 64];
+        for (extra, expected) in [
+            (
+                &["--datagram-size", "3"][..],
+                &[&b"abc"[..], b"def", b"gh"][..],
+            ),
+            (
+                &["--datagram-size", "7", "--sequenced"],
+                &[b"\0\0\0\0abc", b"\0\0\0\x01def", b"\0\0\0\x02gh"],
+            ),
+        ] {
+            let args = args(extra);
+            let payload = Payload::Chunks(open_input(&args).unwrap());
+            run_udp_client(&args, destination, payload, Box::new(io::sink()), None).unwrap();
+            for datagram in expected {
+                let n = receiver.recv(&mut buffer).unwrap();
+                assert_eq!(&buffer[..n], *datagram);
+            }
+        }
+        fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn test_max_datagram_refuses_one_byte_over() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = receiver.local_addr().unwrap();
+        let args = Args::parse_from(["test", "-p", "udp", "--send-only", "--max-datagram", "8"]);
+        let send = |datagram: &[u8]| {
+            let payload = Payload::Datagram(datagram.to_vec());
+            run_udp_client(&args, destination, payload, Box::new(io::sink()), None)
+        };
+        send(b"12345678").unwrap();
+        let mut buffer = [0u8; 16];
+        let n = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"12345678");
+
+        let err = send(b"123456789").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The 9 byte datagram is larger than the --max-datagram limit of 8 bytes"
+        );
+        receiver.set_nonblocking(true).unwrap();
+        assert!(receiver.recv(&mut buffer).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_udp_server_counts_truncated_datagrams() {
+        let output = temp_path("truncated");
+        let stats = temp_path("truncated-stats");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--buffer-size",
+            "512",
+            "--count",
+            "2",
+            "--file",
+            output.to_str().unwrap(),
+            "--stats-format",
+            "json",
+            "--stats-file",
+            stats.to_str().unwrap(),
+        ]);
+        let server = thread::spawn(move || serve_udp(&socket, &args, Some(Duration::from_secs(5))));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&[b'a'; 512], destination).unwrap();
+        client.send_to(&[b'b'; 513], destination).unwrap();
+        server.join().unwrap().unwrap();
+
+        let written = fs::read(&output).unwrap();
+        assert_eq!(written.len(), 1024);
+        let summary: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&stats).unwrap()).unwrap();
+        assert_eq!(summary["datagrams_truncated"], 1);
+        assert_eq!(summary["kernel_drops"], 0);
+        fs::remove_file(&output).unwrap();
+        fs::remove_file(&stats).unwrap();
+    }
+
+    #[test]
+    fn test_sequenced_udp_server_restores_order() {
+        let output = temp_path("sequenced");
+        let stats = temp_path("sequenced-stats");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--sequenced",
+            "--count",
+            "5",
+            "--file",
+            output.to_str().unwrap(),
+            "--stats-format",
+            "json",
+            "--stats-file",
+            stats.to_str().unwrap(),
+        ]);
+        let server = thread::spawn(move || serve_udp(&socket, &args, Some(Duration::from_secs(5))));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // 2 is lost on the way and 1 arrives after 3 and 4.
+        for number in [0u32, 3, 4, 1, 5] {
+            let mut datagram = sequence::header(number).to_vec();
+            datagram.push(b'a' + number as u8);
+            client.send_to(&datagram, destination).unwrap();
+        }
+        server.join().unwrap().unwrap();
+
+        assert_eq!(fs::read_to_string(&output).unwrap(), "abdef");
+        let summary: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&stats).unwrap()).unwrap();
+        assert_eq!(summary["datagrams_lost"], 1);
+        assert_eq!(summary["datagrams_reordered"], 3);
+        fs::remove_file(&output).unwrap();
+        fs::remove_file(&stats).unwrap();
+    }
+
+    #[test]
+    fn test_nat_keepalive_fills_silences_apart_from_the_payload() {
+        let stats = temp_path("nat-keepalive-stats");
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = receiver.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "-p",
+            "udp",
+            "--nat-keepalive",
+            "100ms",
+            "--nat-keepalive-payload",
+            "k",
+            "--stats-format",
+            "json",
+            "--stats-file",
+            stats.to_str().unwrap(),
+        ]);
+        let payload = Payload::Datagram(b"data".to_vec());
+        run_udp_client(
+            &args,
+            destination,
+            payload,
+            Box::new(io::sink()),
+            Some(Duration::from_millis(600)),
+        )
+        .unwrap();
+
+        receiver.set_nonblocking(true).unwrap();
+        let mut buffer = [0u8; 16];
+        let n = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"data");
+        let mut keepalives = 0;
+        while let Ok(n) = receiver.recv(&mut buffer) {
+            assert_eq!(&buffer[..n], b"k");
+            keepalives += 1;
+        }
+        assert!(keepalives >= 2, "{} keepalives", keepalives);
+        let summary: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&stats).unwrap()).unwrap();
+        assert_eq!(summary["bytes_tx"], 4);
+        assert_eq!(summary["keepalives"], keepalives);
+        assert_eq!(summary["keepalive_bytes"], keepalives);
+        fs::remove_file(&stats).unwrap();
+    }
+
+    #[test]
+    fn test_udp_server_discards_keepalives() {
+        let output = temp_path("discard-keepalives");
+        let stats = temp_path("discard-keepalives-stats");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--discard-keepalives",
+            "--nat-keepalive-payload",
+            "ping",
+            "--count",
+            "2",
+            "--file",
+            output.to_str().unwrap(),
+            "--stats-format",
+            "json",
+            "--stats-file",
+            stats.to_str().unwrap(),
+        ]);
+        let server = thread::spawn(move || serve_udp(&socket, &args, Some(Duration::from_secs(5))));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for datagram in ["ping", "one ", "ping", "two"] {
+            client.send_to(datagram.as_bytes(), destination).unwrap();
+        }
+        server.join().unwrap().unwrap();
+
+        assert_eq!(fs::read_to_string(&output).unwrap(), "one two");
+        let summary: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&stats).unwrap()).unwrap();
+        assert_eq!(summary["bytes_rx"], 7);
+        assert_eq!(summary["keepalives"], 2);
+        assert_eq!(summary["keepalive_bytes"], 8);
+        fs::remove_file(&output).unwrap();
+        fs::remove_file(&stats).unwrap();
+    }
+
+    #[test]
+    fn test_udp_server_stops_on_timeout() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let args = Args::parse_from(["test", "--listen", "--exec", "cat > /dev/null"]);
+
+        serve_udp(&socket, &args, Some(Duration::from_millis(100))).unwrap();
+    }
+
+    #[test]
+    fn test_udp_server_replies_with_exec_output() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--once",
+            "--reply",
+            "--exec",
+            "tr a-z A-Z",
+        ]);
+
+        let server_handle =
+            thread::spawn(move || serve_udp(&socket, &args, Some(Duration::from_secs(5))));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.send_to(b"ping", destination).unwrap();
+
+        let mut buffer = [0u8; 16];
+        let (amt, src) = client.recv_from(&mut buffer).unwrap();
+        server_handle.join().unwrap().unwrap();
+        assert_eq!(&buffer[..amt], b"PING");
+        assert_eq!(src, destination);
+    }
+
+    #[test]
+    fn test_udp_client_prints_responses() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        client.send(b"query").unwrap();
+
+        let mut buffer = [0u8; 16];
+        let (amt, src) = server.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..amt], b"query");
+        server.send_to(b"answer 1;", src).unwrap();
+        server.send_to(b"answer 2", src).unwrap();
+
+        let output = SharedBuf::default();
+        let transfer = Transfer::new(server.local_addr().unwrap(), Protocol::Udp);
+        receive_udp_responses(&client, output.clone(), &transfer, None, None, None).unwrap();
+        assert_eq!(transfer.received(), 17);
+        assert_eq!(*output.0.lock().unwrap(), b"answer 1;answer 2");
+    }
+
+    #[test]
+    fn test_udp_client_reports_port_unreachable() {
+        let closed = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = closed.local_addr().unwrap();
+        drop(closed);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(destination).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        client.send(b"anyone?").unwrap();
+
+        let transfer = Transfer::new(client.peer_addr().unwrap(), Protocol::Udp);
+        let err =
+            receive_udp_responses(&client, io::sink(), &transfer, None, None, None).unwrap_err();
+        assert!(err.to_string().contains("port unreachable"));
+    }
+
+    #[test]
+    fn test_listen_address_dual_stack_rules() {
+        let args = Args::parse_from(["test", "--listen"]);
+        let v6_any: SocketAddr = "[::]:80".parse().unwrap();
+        let v4_any: SocketAddr = "0.0.0.0:80".parse().unwrap();
+        let v4_loopback: SocketAddr = "127.0.0.1:80".parse().unwrap();
+
+        assert_eq!(listen_address(v6_any, &args).unwrap(), (v6_any, true));
+        assert_eq!(listen_address(v4_any, &args).unwrap(), (v4_any, false));
+        assert_eq!(
+            listen_address(v4_loopback, &args).unwrap(),
+            (v4_loopback, false)
+        );
+
+        let args = Args::parse_from(["test", "--listen", "--dual-stack"]);
+        assert_eq!(listen_address(v4_any, &args).unwrap(), (v6_any, true));
+        assert!(listen_address(v4_loopback, &args).is_err());
+    }
+
+    #[test]
+    fn test_dual_stack_tcp_listener_accepts_both_families() {
+        let args = Args::parse_from(["test", "--listen"]);
+        let listener = bind_tcp_listener("[::]:0".parse().unwrap(), &args).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for client in ["127.0.0.1", "::1"] {
+            let destination = SocketAddr::new(client.parse().unwrap(), port);
+            let _stream = TcpStream::connect(destination).unwrap();
+            let (_, peer) = listener.accept().unwrap();
+            assert_eq!(peer.ip().to_canonical(), destination.ip());
+        }
+    }
+
+    #[test]
+    fn test_dual_stack_udp_socket_receives_both_families() {
+        let args = Args::parse_from(["test", "--listen", "--dual-stack"]);
+        let socket = bind_udp_server("0.0.0.0:0".parse().unwrap(), &args).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        for (client, bind) in [("127.0.0.1", "127.0.0.1:0"), ("::1", "[::1]:0")] {
+            let sender = UdpSocket::bind(bind).unwrap();
+            sender
+                .send_to(b"hi", SocketAddr::new(client.parse().unwrap(), port))
+                .unwrap();
+            let mut buffer = [0u8; 4];
+            let (amt, _) = socket.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..amt], b"hi");
+        }
+    }
+
+    #[test]
+    fn test_udp_client_sends_to_ipv6_destination() {
+        let server = UdpSocket::bind("[::1]:0").unwrap();
+        let args = Args::parse_from(["test", "--protocol", "udp", "--send-only"]);
+
+        run_udp_client(
+            &args,
+            server.local_addr().unwrap(),
+            Payload::Datagram(b"over v6".to_vec()),
+            Box::new(io::sink()),
+            Some(Duration::from_secs(1)),
+        )
+        .unwrap();
+
+        let mut buffer = [0u8; 16];
+        let (amt, src) = server.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..amt], b"over v6");
+        assert!(src.is_ipv6());
+    }
+
+    #[test]
+    fn test_tcp_idle_timeout_closes_quiet_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let args = Args::parse_from(["test", "--listen", "--idle-timeout", "1"]);
+
+        let server_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let started = Instant::now();
+            pipe_duplex(stream, io::empty(), io::sink(), &args).unwrap();
+            started.elapsed()
+        });
+
+        let mut client = TcpStream::connect(destination).unwrap();
+        client.write_all(b"one message, then silence").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // The server closes both halves once the connection has gone quiet.
+        client.read_to_end(&mut Vec::new()).unwrap();
+
+        let elapsed = server_handle.join().unwrap();
+        assert!(elapsed >= Duration::from_secs(1));
+        assert!(elapsed < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_expire_sessions_only_drops_quiet_peers() {
+        let quiet: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let chatty: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let mut sessions = HashMap::new();
+        sessions.insert(quiet, Instant::now() - Duration::from_secs(10));
+        sessions.insert(chatty, Instant::now());
+
+        let expired = expire_sessions(&mut sessions, Duration::from_secs(5));
+        assert_eq!(expired, vec![quiet]);
+        assert_eq!(sessions.keys().collect::<Vec<_>>(), vec![&chatty]);
+    }
+
+    #[test]
+    fn test_quit_after_zero_closes_right_after_eof() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        // The peer accepts but never replies or closes.
+        let _server_handle = thread::spawn(move || listener.accept().unwrap());
+
+        let args = Args::parse_from(["test", "--quit-after", "0"]);
+        let started = Instant::now();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(stream, Cursor::new(b"request".to_vec()), io::sink(), &args).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_quit_after_keeps_reading_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let _server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_to_end(&mut Vec::new()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            stream.write_all(b"late reply").unwrap();
+            // Hold the connection open; the client has to give up on its own.
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        let args = Args::parse_from(["test", "-N", "--quit-after", "1"]);
+        let output = SharedBuf::default();
+        let started = Instant::now();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(
+            stream,
+            Cursor::new(b"request".to_vec()),
+            output.clone(),
+            &args,
+        )
+        .unwrap();
+
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3));
+        assert_eq!(*output.0.lock().unwrap(), b"late reply");
+    }
+
+    #[test]
+    fn test_no_shutdown_keeps_write_half_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 7];
+            stream.read_exact(&mut request).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(300)))
+                .unwrap();
+            // No EOF arrives, so the read times out instead of returning 0.
+            let err = stream.read(&mut [0u8; 1]).unwrap_err();
+            stream.write_all(b"done").unwrap();
+            is_timeout(&err)
+        });
+
+        let args = Args::parse_from(["test", "--no-shutdown"]);
+        let output = SharedBuf::default();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(
+            stream,
+            Cursor::new(b"request".to_vec()),
+            output.clone(),
+            &args,
+        )
+        .unwrap();
+
+        assert!(server_handle.join().unwrap());
+        assert_eq!(*output.0.lock().unwrap(), b"done");
+        assert!(Args::try_parse_from(["test", "--quit-after", "1", "--no-shutdown"]).is_err());
+    }
+
+    #[test]
+    fn test_keep_open_server_does_not_wait_for_stdin_after_peer_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(destination).unwrap();
+            stream.write_all(b"hello").unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        // Input that never ends, like an interactive stdin.
+        let (input, _writer) = io::pipe().unwrap();
+        let args = Args::parse_from(["test", "--listen", "--keep-open"]);
+        let output = SharedBuf::default();
+        let started = Instant::now();
+        pipe_duplex(stream, input, output.clone(), &args).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(*output.0.lock().unwrap(), b"hello");
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_keep_open_server_hands_later_stdin_to_the_next_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let args = Args::parse_from(["test", "--listen", "--keep-open"]);
+        let (input, mut stdin) = io::pipe().unwrap();
+        let feed = StdinFeed::spawn(input, 1024);
+        let (served, finished) = mpsc::channel();
+        thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                handle_connection(stream, &args, Some(Duration::from_secs(5)), Some(&feed))
+                    .unwrap();
+                served.send(()).unwrap();
+            }
+        });
+        let receive = |stream: &mut TcpStream| {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut buffer = [0u8; 64];
+            let n = stream.read(&mut buffer).unwrap();
+            buffer[..n].to_vec()
+        };
+
+        let mut first = TcpStream::connect(destination).unwrap();
+        stdin.write_all(b"one\n").unwrap();
+        assert_eq!(receive(&mut first), b"one\n");
+        drop(first);
+        finished.recv().unwrap();
+
+        // Typed while no client is connected, so it waits for the next one.
+        stdin.write_all(b"two\n").unwrap();
+        let mut second = TcpStream::connect(destination).unwrap();
+        assert_eq!(receive(&mut second), b"two\n");
+    }
+
+    #[test]
+    fn test_half_closed_request_gets_exec_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let server_args = Args::parse_from(["test", "--listen", "--exec", "tr a-z A-Z"]);
+        let server_handle =
+            thread::spawn(move || serve_tcp(&listener, &server_args, Some(Duration::from_secs(5))));
+
+        let args = Args::parse_from(["test", "-N"]);
+        let output = SharedBuf::default();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(
+            stream,
+            Cursor::new(b"request".to_vec()),
+            output.clone(),
+            &args,
+        )
+        .unwrap();
+
+        server_handle.join().unwrap().unwrap();
+        assert_eq!(*output.0.lock().unwrap(), b"REQUEST");
+        assert!(Args::try_parse_from(["test", "-N", "--no-shutdown"]).is_err());
+    }
+
+    #[test]
+    fn test_reverse_client_serves_exec_like_a_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let client = thread::spawn(move || {
+            let args = Args::parse_from([
+                "test",
+                "--reverse",
+                "--exec",
+                "tr a-z A-Z",
+                "127.0.0.1",
+                &port,
+            ]);
+            run_tcp_client(
+                &args,
+                "127.0.0.1",
+                args.port().unwrap(),
+                Some(Duration::from_secs(5)),
+            )
+        });
+
+        // The listening side sends the request, as a client of `--exec` would.
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"request").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "REQUEST");
+        client.join().unwrap().unwrap();
+        assert!(Args::try_parse_from(["test", "--reverse", "--listen"]).is_err());
+    }
+
+    #[test]
+    fn test_client_tries_destinations_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let args = no_args();
+        let timeout = Some(Duration::from_secs(5));
+        let stream = connect_client(&args, "host.invalid, 127.0.0.1", port, timeout).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+        let err = connect_client(&args, "127.0.0.1,host.invalid", port + 1, timeout);
+        assert!(format!("{:#}", err.unwrap_err()).contains("host.invalid"));
+    }
+
+    #[test]
+    fn test_client_connects_from_source() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let args = Args::parse_from(["test", "-s", "127.0.0.2"]);
+        let timeout = Some(Duration::from_secs(5));
+        connect_client(&args, "127.0.0.1", port, timeout).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+        let args = Args::parse_from(["test", "--source", "::1"]);
+        let err = connect_client(&args, "127.0.0.1", port, timeout).unwrap_err();
+        assert!(
+            err.to_string().contains("--source ::1 is an IPv6 address"),
+            "{}",
+            err
+        );
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let args = Args::parse_from(["test", "-p", "udp", "--send-only", "-s", "127.0.0.3"]);
+        let payload = Payload::Datagram(b"from .3".to_vec());
+        let output = Box::new(io::sink());
+        run_udp_client(
+            &args,
+            server.local_addr().unwrap(),
+            payload,
+            output,
+            timeout,
+        )
+        .unwrap();
+        let (_, from) = server.recv_from(&mut [0u8; 16]).unwrap();
+        assert_eq!(from.ip(), "127.0.0.3".parse::<IpAddr>().unwrap());
+        let destination = "[::1]:9".parse().unwrap();
+        let payload = Payload::Datagram(Vec::new());
+        let output = Box::new(io::sink());
+        assert!(run_udp_client(&args, destination, payload, output, timeout).is_err());
+    }
+
+    #[test]
+    fn test_nodelay_echoes_small_writes_promptly() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let args = Args::parse_from(["test", "--nodelay"]);
+        let server = thread::spawn(move || {
+            let args = Args::parse_from(["test", "-l", "--nodelay", "127.0.0.1", "0"]);
+            let mut stream = accepted(listener.accept().map(|(stream, _)| stream), &args).unwrap();
+            assert!(stream.nodelay().unwrap());
+            let mut byte = [0u8; 1];
+            while stream.read_exact(&mut byte).is_ok() {
+                stream.write_all(&byte).unwrap();
+            }
+        });
+        let mut stream =
+            connect_client(&args, "127.0.0.1", port, Some(Duration::from_secs(5))).unwrap();
+        assert!(stream.nodelay().unwrap());
+        // Two writes before each read is the pattern Nagle's algorithm holds up.
+        let started = Instant::now();
+        for _ in 0..20 {
+            stream.write_all(b"a").unwrap();
+            stream.write_all(b"b").unwrap();
+            let mut echo = [0u8; 2];
+            stream.read_exact(&mut echo).unwrap();
+            assert_eq!(&echo, b"ab");
+        }
+        assert!(started.elapsed() < Duration::from_millis(500));
+        drop(stream);
+        server.join().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_keepalive_sets_probe_timing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let args = Args::parse_from([
+            "test",
+            "--keepalive=2m",
+            "--keepalive-interval",
+            "15",
+            "--keepalive-count",
+            "4",
+        ]);
+        let stream =
+            connect_client(&args, "127.0.0.1", port, Some(Duration::from_secs(5))).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(120));
+        assert_eq!(
+            socket.keepalive_interval().unwrap(),
+            Duration::from_secs(15)
+        );
+        assert_eq!(socket.keepalive_retries().unwrap(), 4);
+
+        let args = Args::parse_from(["test", "-l", "--keepalive", "127.0.0.1", "0"]);
+        let client = thread::spawn(move || TcpStream::connect(("127.0.0.1", port)).unwrap());
+        let stream = accepted(listener.accept().map(|(stream, _)| stream), &args).unwrap();
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_socket_buffers_are_set_before_connecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let args = Args::parse_from(["test", "--sndbuf", "64k"]);
+        let stream =
+            connect_client(&args, "127.0.0.1", port, Some(Duration::from_secs(5))).unwrap();
+        assert!(SockRef::from(&stream).send_buffer_size().unwrap() >= 64 << 10);
+
+        let args = Args::parse_from([
+            "test",
+            "-l",
+            "-p",
+            "udp",
+            "--rcvbuf",
+            "64k",
+            "127.0.0.1",
+            "0",
+        ]);
+        let server = bind_udp_server("127.0.0.1:0".parse().unwrap(), &args).unwrap();
+        assert!(SockRef::from(&server).recv_buffer_size().unwrap() >= 64 << 10);
+        for size in ["0", "2G"] {
+            assert!(Args::try_parse_from(["test", "--sndbuf", size]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_ttl_and_tos_are_set_by_destination() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let args = Args::parse_from(["test", "--ttl", "3"]);
+        let stream =
+            connect_client(&args, "127.0.0.1", port, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stream.ttl().unwrap(), 3);
+
+        let args = Args::parse_from(["test", "--ttl", "3", "--dscp", "af41"]);
+        let group = "239.1.2.3:5000".parse().unwrap();
+        let socket = socket_from(&Source::of(&args), group, Type::DGRAM).unwrap();
+        assert_eq!(socket.multicast_ttl_v4().unwrap(), 3);
+        assert_eq!(socket.tos().unwrap(), 34 << 2);
+        for ttl in ["0", "256"] {
+            assert!(Args::try_parse_from(["test", "--ttl", ttl]).is_err());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_fwmark_is_set_or_explained() {
+        let args = Args::parse_from(["test", "--fwmark", "0x42"]);
+        let destination = "127.0.0.1:9".parse().unwrap();
+        match socket_from(&Source::of(&args), destination, Type::DGRAM) {
+            Ok(socket) => assert_eq!(socket.mark().unwrap(), 0x42),
+            // Unprivileged runs cannot mark, but should be told why.
+            Err(e) => assert!(e.to_string().contains("CAP_NET_ADMIN"), "{}", e),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_freebind_listens_on_a_missing_address() {
+        let missing = "192.0.2.99:0".parse().unwrap();
+        let args = Args::parse_from(["test", "-l", "192.0.2.99", "0"]);
+        let err = bind_tcp_listener(missing, &args).unwrap_err();
+        assert!(format!("{:#}", err).contains("--freebind"), "{:#}", err);
+        let args = Args::parse_from(["test", "-l", "--freebind", "192.0.2.99", "0"]);
+        bind_tcp_listener(missing, &args).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_tcp_user_timeout_is_set() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let args = Args::parse_from(["test", "--tcp-user-timeout", "2500"]);
+        let stream =
+            connect_client(&args, "127.0.0.1", port, Some(Duration::from_secs(5))).unwrap();
+        let timeout = SockRef::from(&stream).tcp_user_timeout().unwrap();
+        assert_eq!(timeout, Some(Duration::from_millis(2500)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_fast_open_client_sends_everything() {
+        let server_args = Args::parse_from(["test", "-l", "--fastopen", "127.0.0.1", "0"]);
+        let listener = bind_tcp_listener("127.0.0.1:0".parse().unwrap(), &server_args).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let path = std::env::temp_dir().join(format!("netpipe-{}-fastopen", std::process::id()));
+        fs::write(&path, "first chunk and the rest").unwrap();
+        let server = thread::spawn(move || {
+            // The second connection can carry its data in the SYN, where the
+            // system lets servers accept it.
+            (0..2)
+                .map(|_| {
+                    let stream =
+                        accepted(listener.accept().map(|(stream, _)| stream), &server_args);
+                    let mut data = String::new();
+                    stream.unwrap().read_to_string(&mut data).unwrap();
+                    data
+                })
+                .collect::<Vec<_>>()
+        });
+        let args = Args::parse_from([
+            "test",
+            "-N",
+            "--fastopen",
+            "--buffer-size",
+            "512",
+            "-f",
+            path.to_str().unwrap(),
+        ]);
+        for _ in 0..2 {
+            run_fast_open_client(&args, "127.0.0.1", port, Some(Duration::from_secs(5))).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(server.join().unwrap(), ["first chunk and the rest"; 2]);
+    }
+
+    #[test]
+    fn test_zero_linger_resets_the_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let args = Args::parse_from(["test", "--linger", "0"]);
+        let stream =
+            connect_client(&args, "127.0.0.1", port, Some(Duration::from_secs(5))).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        drop(stream);
+        let err = peer.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reuseport_lets_listeners_share_a_port() {
+        let args = Args::parse_from(["test", "-l", "-p", "udp", "127.0.0.1", "0"]);
+        let first = bind_udp_server("127.0.0.1:0".parse().unwrap(), &args).unwrap();
+        let taken = first.local_addr().unwrap();
+        assert!(bind_udp_server(taken, &args).is_err());
+
+        let args = Args::parse_from(["test", "-l", "-p", "udp", "--reuseport", "127.0.0.1", "0"]);
+        let first = bind_udp_server("127.0.0.1:0".parse().unwrap(), &args).unwrap();
+        let shared = first.local_addr().unwrap();
+        bind_udp_server(shared, &args).unwrap();
+        let listening = bind_tcp_listener("127.0.0.1:0".parse().unwrap(), &args).unwrap();
+        bind_tcp_listener(listening.local_addr().unwrap(), &args).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_interface_keeps_sockets_to_it() {
+        let args = Args::parse_from(["test", "-l", "--interface", "lo", "127.0.0.1", "0"]);
+        let listener = bind_tcp_listener("127.0.0.1:0".parse().unwrap(), &args).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let args = Args::parse_from(["test", "--interface", "lo"]);
+        let timeout = Some(Duration::from_secs(5));
+        connect_client(&args, "127.0.0.1", port, timeout).unwrap();
+        listener.accept().unwrap();
+
+        let args = Args::parse_from(["test", "--interface", "netpipe-none0"]);
+        let err = connect_client(&args, "127.0.0.1", port, timeout).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("there is no interface by that name"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_multicast_group_receives_on_loopback() {
+        let output = temp_path("multicast");
+        let args = Args::parse_from([
+            "test",
+            "-l",
+            "--once",
+            "--multicast-group",
+            "239.255.78.80",
+            "--multicast-if",
+            "127.0.0.1",
+            "--file",
+            output.to_str().unwrap(),
+        ]);
+        let socket = bind_udp_server("0.0.0.0:0".parse().unwrap(), &args).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let memberships = Memberships::join(&socket, &args).unwrap();
+        let server = thread::spawn(move || serve_udp(&socket, &args, Some(Duration::from_secs(5))));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        SockRef::from(&client)
+            .set_multicast_if_v4(&Ipv4Addr::LOCALHOST)
+            .unwrap();
+        client
+            .send_to(b"to the group", ("239.255.78.80", port))
+            .unwrap();
+        server.join().unwrap().unwrap();
+        drop(memberships);
+        assert_eq!(fs::read(&output).unwrap(), b"to the group");
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_multicast_source_filters_other_senders() {
+        for interface in [["--multicast-if", "127.0.0.1"], ["--interface", "lo"]] {
+            let output = temp_path("multicast-source");
+            let mut argv = vec![
+                "test",
+                "-l",
+                "--once",
+                "--multicast-group",
+                "232.1.78.82",
+                "--multicast-source",
+                "127.0.0.2",
+                "--file",
+                output.to_str().unwrap(),
+            ];
+            argv.extend(interface);
+            let args = Args::parse_from(argv);
+            let socket = bind_udp_server("0.0.0.0:0".parse().unwrap(), &args).unwrap();
+            let port = socket.local_addr().unwrap().port();
+            let memberships = Memberships::join(&socket, &args).unwrap();
+            let server =
+                thread::spawn(move || serve_udp(&socket, &args, Some(Duration::from_secs(5))));
+
+            for (sender, data) in [("127.0.0.1:0", "unwanted"), ("127.0.0.2:0", "wanted")] {
+                let client = UdpSocket::bind(sender).unwrap();
+                SockRef::from(&client)
+                    .set_multicast_if_v4(&Ipv4Addr::LOCALHOST)
+                    .unwrap();
+                client
+                    .send_to(data.as_bytes(), ("232.1.78.82", port))
+                    .unwrap();
+            }
+            server.join().unwrap().unwrap();
+            drop(memberships);
+            assert_eq!(fs::read_to_string(&output).unwrap(), "wanted");
+            fs::remove_file(&output).unwrap();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_multicast_group_is_left_on_drop() {
+        let group: Ipv6Addr = "ff12::4e50:92".parse().unwrap();
+        let joined = || {
+            let group = format!("{:032x}", u128::from(group));
+            fs::read_to_string("/proc/net/igmp6")
+                .unwrap()
+                .lines()
+                .any(|line| line.split_whitespace().nth(2) == Some(group.as_str()))
+        };
+        let args = Args::parse_from([
+            "test",
+            "-l",
+            "--interface",
+            "lo",
+            "--multicast-group",
+            "ff12::4e50:92",
+        ]);
+        let socket = bind_udp_server("[::]:0".parse().unwrap(), &args).unwrap();
+        let memberships = Memberships::join(&socket, &args).unwrap();
+        assert!(joined());
+        drop(memberships);
+        assert!(!joined());
+
+        let args = Args::parse_from(["test", "-l", "--multicast-group", "239.255.78.80"]);
+        let err = Memberships::join(&socket, &args).err().unwrap();
+        assert!(err.to_string().ends_with("listen on 0.0.0.0"), "{}", err);
+    }
+
+    #[test]
+    fn test_client_reuses_source_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let source_port = {
+            let free = TcpListener::bind("127.0.0.1:0").unwrap();
+            free.local_addr().unwrap().port().to_string()
+        };
+        let args = Args::parse_from(["test", "--source-port", source_port.as_str()]);
+        let timeout = Some(Duration::from_secs(5));
+        // Closing first leaves the port in TIME_WAIT for the second connection.
+        for _ in 0..2 {
+            drop(connect_client(&args, "127.0.0.1", port, timeout).unwrap());
+            let (_, peer) = listener.accept().unwrap();
+            assert_eq!(peer.port().to_string(), source_port);
+        }
+
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = taken.local_addr().unwrap().port().to_string();
+        let args = Args::parse_from(["test", "-s", "127.0.0.1", "--source-port", taken.as_str()]);
+        let err = connect_client(&args, "127.0.0.1", port, timeout).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("pick another --source-port"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_send_resumable_keeps_the_failed_chunk() {
+        struct Failing(usize);
+        impl Write for Failing {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                self.0 -= 1;
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let input = Mutex::new(Resumable {
+            input: Box::new(&b"aaaabbbbcccc"[..]),
+            pending: None,
+        });
+        let err = send_resumable(&input, &mut Failing(1), 4, &mut |_| {}).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        let mut resent = Vec::new();
+        let sent = send_resumable(&input, &mut resent, 4, &mut |_| {}).unwrap();
+        assert_eq!((sent, resent.as_slice()), (8, &b"bbbbcccc"[..]));
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        let delays: Vec<_> = (1..=5)
+            .map(|failures| backoff(Duration::from_secs(1), Duration::from_secs(5), failures))
+            .map(|delay| delay.as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        assert_eq!(
+            backoff(Duration::from_secs(1), Duration::MAX, u32::MAX),
+            Duration::from_secs(1 << 16)
+        );
+    }
+
+    #[test]
+    fn test_retrying_client_waits_for_the_server() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let path = temp_path("retry-input");
+        fs::write(&path, "log line\n").unwrap();
+        let args = |retries: &str| {
+            Args::parse_from([
+                "test",
+                "-N",
+                "--retry",
+                retries,
+                "--retry-interval",
+                "50ms",
+                "--file",
+                path.to_str().unwrap(),
+                "127.0.0.1",
+                &port.to_string(),
+            ])
+        };
+        let timeout = Some(Duration::from_secs(5));
+        assert!(run_tcp_client(&args("1"), "127.0.0.1", port, timeout).is_err());
+
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = String::new();
+            stream.read_to_string(&mut received).unwrap();
+            received
+        });
+        run_tcp_client(&args("forever"), "127.0.0.1", port, timeout).unwrap();
+        assert_eq!(server.join().unwrap(), "log line\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_no_stdin_client_only_receives() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"SSH-2.0-banner\r\n").unwrap();
+            let mut sent = Vec::new();
+            stream.read_to_end(&mut sent).unwrap();
+            sent
+        });
+
+        let args = Args::parse_from(["test", "-N", "--no-stdin"]);
+        let output = SharedBuf::default();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(stream, open_input(&args).unwrap(), output.clone(), &args).unwrap();
+
+        assert!(server_handle.join().unwrap().is_empty());
+        assert_eq!(*output.0.lock().unwrap(), b"SSH-2.0-banner\r\n");
+    }
+
+    #[test]
+    fn test_large_transfer_streams_both_ways() {
+        const SIZE: u64 = 64 * 1024 * 1024;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+
+        let server_received = CountingSink::default();
+        let server_sink = server_received.clone();
+        let server_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let input = io::repeat(b's').take(SIZE);
+            pipe_duplex(
+                stream,
+                input,
+                server_sink,
+                &Args::parse_from(["test", "-N"]),
+            )
+            .unwrap();
+        });
+
+        let client_received = CountingSink::default();
+        let stream = TcpStream::connect(destination).unwrap();
+        let input = io::repeat(b'c').take(SIZE);
+        pipe_duplex(
+            stream,
+            input,
+            client_received.clone(),
+            &Args::parse_from(["test", "-N"]),
+        )
+        .unwrap();
+        server_handle.join().unwrap();
+
+        assert_eq!(server_received.0.load(Ordering::SeqCst) as u64, SIZE);
+        assert_eq!(client_received.0.load(Ordering::SeqCst) as u64, SIZE);
+    }
+
+    #[test]
+    fn test_transfer_counts_both_directions_even_on_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let (counted, sent_counted) = mpsc::channel();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"partial reply").unwrap();
+            let mut request = [0u8; 7];
+            stream.read_exact(&mut request).unwrap();
+            // The reset must not overtake the count of what was sent.
+            sent_counted.recv().unwrap();
+            // Reset the connection instead of closing it cleanly.
+            socket2::SockRef::from(&stream)
+                .set_linger(Some(Duration::ZERO))
+                .unwrap();
+        });
+
+        let stream = TcpStream::connect(destination).unwrap();
+        let transfer = Transfer::new(destination, Protocol::Tcp);
+        let send = move |writer: &mut Outgoing, on_chunk: &mut dyn FnMut(usize)| {
+            writer.write_all(b"request")?;
+            on_chunk(7);
+            counted.send(()).unwrap();
+            // Keep the write half open so only the reset ends the session.
+            thread::sleep(Duration::from_millis(500));
+            Ok(7)
+        };
+        let args = Args::parse_from(["test", "--no-shutdown"]);
+        let _ = exchange(stream, send, io::sink(), &args, &transfer);
+        server_handle.join().unwrap();
+
+        assert_eq!(transfer.sent(), 7);
+        assert_eq!(transfer.received(), 13);
+    }
+
+    #[test]
+    fn test_mirror_copies_received_data() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let mirror_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mirror_address = mirror_listener.local_addr().unwrap();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"live feed").unwrap();
+        });
+        let mirror_handle = thread::spawn(move || {
+            let (mut stream, _) = mirror_listener.accept().unwrap();
+            let mut copy = Vec::new();
+            stream.read_to_end(&mut copy).unwrap();
+            copy
+        });
+
+        let stream = TcpStream::connect(destination).unwrap();
+        let transfer = Transfer::new(destination, Protocol::Tcp);
+        let args = Args::parse_from(["test", "--mirror", &mirror_address.to_string()]);
+        let output = SharedBuf::default();
+        exchange(
+            stream,
+            |_: &mut Outgoing, _: &mut dyn FnMut(usize)| Ok(0),
+            output.clone(),
+            &args,
+            &transfer,
+        )
+        .unwrap();
+        server_handle.join().unwrap();
+
+        assert_eq!(*output.0.lock().unwrap(), b"live feed");
+        assert_eq!(mirror_handle.join().unwrap(), b"live feed");
+        assert_eq!(transfer.mirror_dropped(), Some(0));
+    }
+
+    #[test]
+    fn test_unreachable_mirror_does_not_stall_the_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for _ in 0..3 {
+                stream.write_all(b"chunk").unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let stream = TcpStream::connect(destination).unwrap();
+        let transfer = Transfer::new(destination, Protocol::Tcp);
+        let mirror = format!("127.0.0.1:{}", closed_port);
+        let args = Args::parse_from(["test", "--mirror", &mirror]);
+        let output = SharedBuf::default();
+        exchange(
+            stream,
+            |_: &mut Outgoing, _: &mut dyn FnMut(usize)| Ok(0),
+            output.clone(),
+            &args,
+            &transfer,
+        )
+        .unwrap();
+        server_handle.join().unwrap();
+
+        assert_eq!(*output.0.lock().unwrap(), b"chunkchunkchunk");
+        // Chunks queued before the connection was refused are counted as the
+        // mirror thread gives up.
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(transfer.mirror_dropped(), Some(15));
+    }
+
+    #[test]
+    fn test_compressed_round_trip_over_localhost() {
+        let data: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| format!("log line {}\n", i % 977).into_bytes())
+            .collect();
+        for algorithm in ["gzip", "zstd"] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let destination = listener.local_addr().unwrap();
+            let output = SharedBuf::default();
+            let server_output = output.clone();
+            let server_handle = thread::spawn(move || {
+                let args = Args::parse_from(["test", "--decompress", algorithm]);
+                let (stream, _) = listener.accept().unwrap();
+                let output = with_receive_filters(Box::new(server_output), &args).unwrap();
+                pipe_duplex(stream, io::empty(), output, &args).unwrap();
+            });
+
+            let args = Args::parse_from([
+                "test",
+                "-N",
+                "--compress",
+                algorithm,
+                "--compress-level",
+                "1",
+            ]);
+            let input = with_send_filters(Box::new(Cursor::new(data.clone())), &args).unwrap();
+            let stream = TcpStream::connect(destination).unwrap();
+            let transfer = Transfer::new(destination, Protocol::Tcp);
+            let send = move |writer: &mut Outgoing, on_chunk: &mut dyn FnMut(usize)| {
+                send_stream(input, writer, 65536, on_chunk)
+            };
+            exchange(stream, send, io::sink(), &args, &transfer).unwrap();
+            server_handle.join().unwrap();
+
+            assert!(
+                *output.0.lock().unwrap() == data,
+                "{} round trip differs",
+                algorithm
+            );
+            assert!(transfer.sent() < data.len() as u64 / 4, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_psk_round_trip_and_key_mismatch() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let output = SharedBuf::default();
+        let server_output = output.clone();
+        let server_handle = thread::spawn(move || {
+            let args = Args::parse_from(["test", "--psk", "hunter2"]);
+            let (stream, _) = listener.accept().unwrap();
+            pipe_duplex(stream, Cursor::new(b"reply".to_vec()), server_output, &args).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            pipe_duplex(stream, io::empty(), io::sink(), &args).unwrap_err()
+        });
+
+        let args = Args::parse_from(["test", "--psk", "hunter2"]);
+        let reply = SharedBuf::default();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(stream, Cursor::new(data.clone()), reply.clone(), &args).unwrap();
+
+        let args = Args::parse_from(["test", "--psk", "hunter3"]);
+        let stream = TcpStream::connect(destination).unwrap();
+        let err = pipe_duplex(stream, io::empty(), io::sink(), &args).unwrap_err();
+        let server_err = server_handle.join().unwrap();
+
+        assert!(*output.0.lock().unwrap() == data);
+        assert_eq!(*reply.0.lock().unwrap(), b"reply");
+        for err in [err, server_err] {
+            assert!(err.to_string().contains("does not match"), "{:#}", err);
+        }
+    }
+
+    /// Writes a self-signed certificate for localhost and its key to temp files.
+    fn self_signed_cert(name: &str) -> (PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let cert_path = temp_path(&format!("{}-cert.pem", name));
+        let key_path = temp_path(&format!("{}-key.pem", name));
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[test]
+    #[cfg(feature = "dtls")]
+    fn test_dtls_client_and_server_keep_datagram_boundaries() {
+        let (cert, key) = self_signed_cert("dtls-exchange");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let server_args = Args::parse_from([
+            "test".as_ref(),
+            "-l".as_ref(),
+            "--protocol".as_ref(),
+            "udp".as_ref(),
+            "--tls".as_ref(),
+            "--tls-cert".as_ref(),
+            cert.as_os_str(),
+            "--tls-key".as_ref(),
+            key.as_os_str(),
+            "--count".as_ref(),
+            "2".as_ref(),
+            "--reply".as_ref(),
+            "--exec".as_ref(),
+            "tr a-z A-Z".as_ref(),
+        ]);
+        let server_handle =
+            thread::spawn(move || serve_udp(&socket, &server_args, Some(Duration::from_secs(10))));
+        let connect = |args: &Args| {
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client.connect(destination).unwrap();
+            let session = dtls::connect(&client, "localhost", args, Some(Duration::from_secs(5)));
+            (client, session)
+        };
+
+        // Without --tls-ca the self-signed certificate is not trusted.
+        let args = Args::parse_from(["test", "--protocol", "udp", "--tls", "localhost", "1"]);
+        let err = connect(&args).1.err().unwrap();
+        assert!(format!("{:#}", err).contains("certificate"), "{:#}", err);
+
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "--protocol".as_ref(),
+            "udp".as_ref(),
+            "--tls".as_ref(),
+            "--tls-ca".as_ref(),
+            cert.as_os_str(),
+            "localhost".as_ref(),
+            "1".as_ref(),
+        ]);
+        let (client, session) = connect(&args);
+        let mut session = session.unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0u8; 65535];
+        let big = vec![b'x'; 3000];
+        for datagram in [&b"one"[..], &big] {
+            client.send(&session.seal(datagram).unwrap()).unwrap();
+            let amt = client.recv(&mut buffer).unwrap();
+            let received = session.handle(&buffer[..amt]);
+            assert_eq!(received.data, [datagram.to_ascii_uppercase()]);
+        }
+        server_handle.join().unwrap().unwrap();
+        fs::remove_file(&cert).unwrap();
+        fs::remove_file(&key).unwrap();
+    }
+
+    #[test]
+    fn test_quic_client_and_server_exchange_both_ways() {
+        let (cert, key) = self_signed_cert("quic-exchange");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let server_args = Args::parse_from([
+            "test".as_ref(),
+            "-l".as_ref(),
+            "--protocol".as_ref(),
+            "quic".as_ref(),
+            "--tls-cert".as_ref(),
+            cert.as_os_str(),
+            "--tls-key".as_ref(),
+            key.as_os_str(),
+            "--exec".as_ref(),
+            "tr a-z A-Z".as_ref(),
+        ]);
+        let server_handle = thread::spawn(move || {
+            let stream = quic::accept(&socket, &server_args, None).unwrap();
+            handle_connection(stream, &server_args, Some(Duration::from_secs(10)), None)
+        });
+
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "--protocol".as_ref(),
+            "quic".as_ref(),
+            "--tls-ca".as_ref(),
+            cert.as_os_str(),
+            "localhost".as_ref(),
+            "1".as_ref(),
+        ]);
+        let stream = quic::connect(destination, "localhost", &args, None).unwrap();
+        let data: Vec<u8> = b"abc".repeat(100_000);
+        let output = SharedBuf::default();
+        pipe_duplex(stream, Cursor::new(data.clone()), output.clone(), &args).unwrap();
+        assert_eq!(*output.0.lock().unwrap(), data.to_ascii_uppercase());
+        server_handle.join().unwrap().unwrap();
+        fs::remove_file(&cert).unwrap();
+        fs::remove_file(&key).unwrap();
+    }
+
+    #[test]
+    fn test_tls_client_and_server_exchange_both_ways() {
+        let (cert, key) = self_signed_cert("tls-exchange");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let server_args = Args::parse_from([
+            "test".as_ref(),
+            "-l".as_ref(),
+            "--tls".as_ref(),
+            "--tls-cert".as_ref(),
+            cert.as_os_str(),
+            "--tls-key".as_ref(),
+            key.as_os_str(),
+            "--exec".as_ref(),
+            "tr a-z A-Z".as_ref(),
+        ]);
+        let server_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &server_args, None, None).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &server_args, None, None).unwrap_err()
+        });
+
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "-N".as_ref(),
+            "--tls".as_ref(),
+            "--tls-ca".as_ref(),
+            cert.as_os_str(),
+            "localhost".as_ref(),
+            port.as_ref(),
+        ]);
+        let output = SharedBuf::default();
+        let stream = TcpStream::connect(("127.0.0.1", args.port().unwrap())).unwrap();
+        pipe_duplex(
+            stream,
+            Cursor::new(b"hello tls".to_vec()),
+            output.clone(),
+            &args,
+        )
+        .unwrap();
+        assert_eq!(*output.0.lock().unwrap(), b"HELLO TLS");
+
+        // Without --tls-ca the self-signed certificate is not trusted.
+        let args = Args::parse_from(["test", "--tls", "localhost", &port]);
+        let stream = TcpStream::connect(("127.0.0.1", args.port().unwrap())).unwrap();
+        let err = pipe_duplex(stream, io::empty(), io::sink(), &args).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("not signed by a trusted certificate authority"),
+            "{:#}",
+            err
+        );
+        server_handle.join().unwrap();
+        fs::remove_file(cert).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+
+    #[test]
+    fn test_starttls_upgrades_after_the_smtp_dialogue() {
+        let (cert, key) = self_signed_cert("starttls");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let server_args = Args::parse_from([
+            "test".as_ref(),
+            "-l".as_ref(),
+            "--tls".as_ref(),
+            "--tls-cert".as_ref(),
+            cert.as_os_str(),
+            "--tls-key".as_ref(),
+            key.as_os_str(),
+        ]);
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+            stream.write_all(b"220 mail.example ESMTP\r\n").unwrap();
+            assert!(lines
+                .next()
+                .unwrap()
+                .unwrap()
+                .starts_with("EHLO [127.0.0.1]"));
+            stream
+                .write_all(b"250-mail.example\r\n250 STARTTLS\r\n")
+                .unwrap();
+            assert_eq!(lines.next().unwrap().unwrap(), "STARTTLS");
+            stream.write_all(b"220 Go ahead\r\n").unwrap();
+            let (mut reader, mut writer) = tls::accept(stream, &server_args).unwrap();
+            writer.write_all(b"250 over TLS").unwrap();
+            writer.close_notify().unwrap();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "--tls".as_ref(),
+            "--starttls".as_ref(),
+            "smtp".as_ref(),
+            "--tls-ca".as_ref(),
+            cert.as_os_str(),
+            "localhost".as_ref(),
+            port.as_ref(),
+        ]);
+        let output = SharedBuf::default();
+        let stream = TcpStream::connect(("127.0.0.1", args.port().unwrap())).unwrap();
+        pipe_duplex(
+            stream,
+            Cursor::new(b"MAIL FROM:<a@example>".to_vec()),
+            output.clone(),
+            &args,
+        )
+        .unwrap();
+        // Only what was sent over TLS comes out, in both directions.
+        assert_eq!(*output.0.lock().unwrap(), b"250 over TLS");
+        assert_eq!(server_handle.join().unwrap(), b"MAIL FROM:<a@example>");
+        fs::remove_file(cert).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+
+    #[test]
+    fn test_tls_server_requires_client_certificates() {
+        let (server_cert, server_key) = self_signed_cert("mtls-server");
+        let (client_cert, client_key) = self_signed_cert("mtls-client");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let server_args = Args::parse_from([
+            "test".as_ref(),
+            "-l".as_ref(),
+            "--tls".as_ref(),
+            "--tls-cert".as_ref(),
+            server_cert.as_os_str(),
+            "--tls-key".as_ref(),
+            server_key.as_os_str(),
+            "--tls-ca".as_ref(),
+            client_cert.as_os_str(),
+            "--tls-require-client-cert".as_ref(),
+            "--exec".as_ref(),
+            "cat".as_ref(),
+        ]);
+        let server_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &server_args, None, None).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &server_args, None, None).unwrap_err()
+        });
+
+        let client_args = |with_cert: bool| {
+            let mut args = vec![
+                OsStr::new("test"),
+                OsStr::new("-N"),
+                OsStr::new("--tls"),
+                OsStr::new("--tls-ca"),
+                server_cert.as_os_str(),
+            ];
+            if with_cert {
+                args.extend([
+                    OsStr::new("--tls-client-cert"),
+                    client_cert.as_os_str(),
+                    OsStr::new("--tls-client-key"),
+                    client_key.as_os_str(),
+                ]);
+            }
+            args.extend([OsStr::new("localhost"), OsStr::new(&port)]);
+            Args::parse_from(args)
+        };
+        let output = SharedBuf::default();
+        let args = client_args(true);
+        let stream = TcpStream::connect(("127.0.0.1", args.port().unwrap())).unwrap();
+        pipe_duplex(
+            stream,
+            Cursor::new(b"it's me".to_vec()),
+            output.clone(),
+            &args,
+        )
+        .unwrap();
+        assert_eq!(*output.0.lock().unwrap(), b"it's me");
+
+        let args = client_args(false);
+        let stream = TcpStream::connect(("127.0.0.1", args.port().unwrap())).unwrap();
+        assert!(pipe_duplex(stream, io::empty(), io::sink(), &args).is_err());
+        let err = server_handle.join().unwrap();
+        assert!(
+            format!("{:#}", err).contains("The client did not present a certificate"),
+            "{:#}",
+            err
+        );
+        for path in [server_cert, server_key, client_cert, client_key] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_prepare_datagram_rejects_oversized_input() {
+        let path = temp_path("oversized-datagram");
+        fs::write(&path, vec![0u8; MAX_DATAGRAM_PAYLOAD + 1]).unwrap();
+        let args = Args::parse_from(["test", "--file", path.to_str().unwrap()]);
+        let result = prepare_datagram(&args);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_file_send_is_identical_with_and_without_zero_copy() {
+        let path = temp_path("zero-copy-source");
+        let contents: Vec<u8> = (0..1_000_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+
+        for extra in [None, Some("--no-zero-copy"), Some("--progress")] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port().to_string();
+            let server_handle = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut received = Vec::new();
+                stream.read_to_end(&mut received).unwrap();
+                received
+            });
+
+            let mut argv = vec!["test", "-N", "--file", path.to_str().unwrap()];
+            argv.extend(extra);
+            argv.extend(["127.0.0.1", &port]);
+            let args = Args::parse_from(argv);
+            run_tcp_client(&args, "127.0.0.1", args.port().unwrap(), None).unwrap();
+
+            assert_eq!(server_handle.join().unwrap(), contents);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rate_limits_both_directions() {
+        const SIZE: u64 = 300_000;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let server_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let input = io::repeat(b's').take(SIZE);
+            pipe_duplex(stream, input, io::sink(), &no_args()).unwrap();
+        });
+
+        // 300 kB each way at 500 kB/s, less the first 50 kB burst.
+        let args = Args::parse_from(["test", "-N", "--rate", "500000"]);
+        let received = CountingSink::default();
+        let started = Instant::now();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(stream, io::repeat(b'c').take(SIZE), received.clone(), &args).unwrap();
+        let elapsed = started.elapsed().as_secs_f64();
+        server_handle.join().unwrap();
+
+        assert_eq!(received.0.load(Ordering::SeqCst) as u64, SIZE);
+        assert!((0.47..0.7).contains(&elapsed), "took {}s", elapsed);
+    }
+
+    #[test]
+    fn test_max_bytes_caps_file_output() {
+        let output = temp_path("max-bytes");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--no-stdin",
+            "--max-bytes",
+            "1k",
+            "--file",
+            output.to_str().unwrap(),
+        ]);
+        let server_handle =
+            thread::spawn(move || serve_tcp(&listener, &args, Some(Duration::from_secs(5))));
+
+        let mut stream = TcpStream::connect(destination).unwrap();
+        // The server may reset the connection once it has had enough.
+        let _ = stream.write_all(&[b'x'; 100_000]);
+        server_handle.join().unwrap().unwrap();
+
+        let contents = fs::read(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert_eq!(contents.len(), 1024);
+    }
+
+    #[test]
+    fn test_max_bytes_caps_sending() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let args = Args::parse_from(["test", "-N", "--max-bytes", "100"]);
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(stream, io::repeat(b'z').take(10_000), io::sink(), &args).unwrap();
+
+        assert_eq!(server_handle.join().unwrap(), vec![b'z'; 100]);
+    }
+
+    #[test]
+    fn test_max_bytes_stops_udp_server_on_datagram_boundary() {
+        let output = temp_path("udp-max-bytes");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--max-bytes",
+            "10",
+            "--file",
+            output.to_str().unwrap(),
+        ]);
+
+        let server_handle =
+            thread::spawn(move || serve_udp(&socket, &args, Some(Duration::from_secs(5))));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for datagram in [b"abcd", b"efgh", b"ijkl"] {
+            client.send_to(datagram, destination).unwrap();
+        }
+        server_handle.join().unwrap().unwrap();
+
+        let contents = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert_eq!(contents, "abcdefgh");
+    }
+
+    #[test]
+    fn test_max_time_ends_busy_tcp_session_and_keeps_data() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Keep trickling data so neither side ever goes idle or sees EOF.
+            while stream.write_all(b"tick").is_ok() {
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        // The input never ends either, and its writer stays open until the end.
+        let (input, _input_writer) = io::pipe().unwrap();
+        let args = Args::parse_from(["test", "--max-time", "1"]);
+        let output = SharedBuf::default();
+        let started = Instant::now();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(stream, input, output.clone(), &args).unwrap();
+        let elapsed = started.elapsed();
+        server_handle.join().unwrap();
+
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+        let received = output.0.lock().unwrap();
+        assert!(received.starts_with(b"ticktick"));
+        assert_eq!(received.len() % 4, 0);
+    }
+
+    #[test]
+    fn test_max_time_stops_udp_server() {
+        let output = temp_path("udp-max-time");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = socket.local_addr().unwrap();
+        let args = Args::parse_from([
+            "test",
+            "--listen",
+            "--max-time",
+            "1",
+            "--file",
+            output.to_str().unwrap(),
+        ]);
+
+        let started = Instant::now();
+        let server_handle = thread::spawn(move || serve_udp(&socket, &args, None));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"captured", destination).unwrap();
+        server_handle.join().unwrap().unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let contents = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert_eq!(contents, "captured");
+    }
+
+    #[test]
+    fn test_interval_sends_one_datagram_per_line() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let args = Args::parse_from(["test", "--protocol", "udp", "--send-only"]);
+        let input: Box<dyn Read + Send> = Box::new(Cursor::new(b"AT\nATZ\nATI\n".to_vec()));
+        let payload = Payload::Lines(Paced::new(input, Some(Duration::from_millis(100))));
+
+        let started = Instant::now();
+        let output = Box::new(io::sink());
+        run_udp_client(&args, server.local_addr().unwrap(), payload, output, None).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let mut buffer = [0u8; 16];
+        for expected in [&b"AT\n"[..], b"ATZ\n", b"ATI\n"] {
+            let (amt, _) = server.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..amt], expected);
+        }
+    }
+
+    #[test]
+    fn test_crlf_client_sends_crlf_line_endings() {
+        let path = temp_path("crlf-source");
+        fs::write(&path, "EHLO example.com\nQUIT\r\n").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let args = Args::parse_from([
+            "test",
+            "-N",
+            "-C",
+            "--file",
+            path.to_str().unwrap(),
+            "127.0.0.1",
+            &port,
+        ]);
+        run_tcp_client(&args, "127.0.0.1", args.port().unwrap(), None).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            server_handle.join().unwrap(),
+            b"EHLO example.com\r\nQUIT\r\n"
+        );
+    }
+
+    #[test]
+    fn test_telnet_filter_refuses_options_and_strips_negotiation() {
+        let mut telnet = TelnetFilter::default();
+        let input = [
+            IAC, DO, 24, IAC, WILL, 1, b'l', b'o', IAC, WONT, 3, b'g', IAC, DONT, 5, b'i', b'n',
+            IAC, 241, b':',
+        ];
+        let (data, replies) = telnet.filter(&input);
+        assert_eq!(data, b"login:");
+        assert_eq!(replies, [IAC, WONT, 24, IAC, DONT, 1]);
+    }
+
+    #[test]
+    fn test_telnet_filter_handles_subnegotiation_and_escapes() {
+        let mut telnet = TelnetFilter::default();
+        let input = [
+            b'a', IAC, SB, 24, 1, IAC, IAC, 7, IAC, SE, b'b', IAC, IAC, b'c',
+        ];
+        let (data, replies) = telnet.filter(&input);
+        assert_eq!(data, [b'a', b'b', IAC, b'c']);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn test_telnet_filter_across_read_boundaries() {
+        let input = [
+            b'>', IAC, DO, 31, IAC, SB, 31, 0, 80, IAC, SE, IAC, IAC, IAC, WILL, 3, b'$',
+        ];
+        let (mut whole_data, mut whole_replies) = (Vec::new(), Vec::new());
+        let mut whole = TelnetFilter::default();
+        let (data, replies) = whole.filter(&input);
+        whole_data.extend(data);
+        whole_replies.extend(replies);
+
+        for split in 1..input.len() {
+            let mut telnet = TelnetFilter::default();
+            let (mut data, mut replies) = telnet.filter(&input[..split]);
+            let (rest_data, rest_replies) = telnet.filter(&input[split..]);
+            data.extend(rest_data);
+            replies.extend(rest_replies);
+            assert_eq!(data, whole_data, "split at {}", split);
+            assert_eq!(replies, whole_replies, "split at {}", split);
+        }
+        assert_eq!(whole_data, [b'>', IAC, b'$']);
+        assert_eq!(whole_replies, [IAC, WONT, 31, IAC, DONT, 3]);
+    }
+
+    #[test]
+    fn test_telnet_mode_answers_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[IAC, DO, 24, b'o', b'k']).unwrap();
+            let mut reply = [0u8; 3];
+            stream.read_exact(&mut reply).unwrap();
+            reply
+        });
+
+        let args = Args::parse_from(["test", "--telnet", "--no-shutdown"]);
+        let output = SharedBuf::default();
+        let stream = TcpStream::connect(destination).unwrap();
+        pipe_duplex(stream, io::empty(), output.clone(), &args).unwrap();
+
+        assert_eq!(server_handle.join().unwrap(), [IAC, WONT, 24]);
+        assert_eq!(*output.0.lock().unwrap(), b"ok");
     }
 }
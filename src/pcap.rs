@@ -0,0 +1,340 @@
+//! Capture files for `--pcap`.
+//!
+//! Only the byte streams are visible to us, so every read and write becomes
+//! one packet with made-up Ethernet, IP and TCP or UDP headers around it. The
+//! real endpoints and times are kept, and TCP sequence numbers follow the
+//! stream offset, which is enough for Wireshark to show the payload.
+
+use crate::{args::Protocol, hexdump::Direction};
+use log::error;
+use std::{
+    fs::File,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Where packets are written once `--pcap` is given.
+static SINK: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Microsecond timestamps, written in our byte order (little-endian).
+const MAGIC: u32 = 0xa1b2_c3d4;
+const SNAPLEN: u32 = 262_144;
+const LINKTYPE_ETHERNET: u32 = 1;
+/// The largest payload that fits in one synthetic packet of either IP version;
+/// longer reads and writes are split.
+const MAX_PAYLOAD: usize = 65_495;
+const LOCAL_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+/// The two ends of a session, as they appear in the capture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flow {
+    protocol: Protocol,
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+impl Flow {
+    /// Both addresses end up in the same IP header, so an IPv4 peer of a
+    /// dual-stack socket is written as IPv4 on both ends.
+    pub fn new(protocol: Protocol, local: SocketAddr, peer: SocketAddr) -> Self {
+        let peer = match peer {
+            SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+                Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+                None => peer,
+            },
+            SocketAddr::V4(_) => peer,
+        };
+        let local = match (local.ip(), peer.ip()) {
+            (IpAddr::V6(ip), IpAddr::V4(_)) => match ip.to_ipv4_mapped() {
+                Some(v4) => SocketAddr::new(v4.into(), local.port()),
+                None => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local.port()),
+            },
+            (IpAddr::V4(_), IpAddr::V6(_)) => {
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), local.port())
+            }
+            _ => local,
+        };
+        Flow {
+            protocol,
+            local,
+            peer,
+        }
+    }
+}
+
+/// Creates the capture file and writes its header.
+pub fn enable(path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    file.write_all(&header)?;
+    let _ = SINK.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Writes `data`, found `offset` bytes into the flow's traffic in `direction`,
+/// as one or more packets. Does nothing unless [`enable`] was called.
+pub fn record(flow: &Flow, direction: Direction, offset: u64, data: &[u8]) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let now = SystemTime::now();
+    let mut records = Vec::new();
+    for (i, segment) in data.chunks(MAX_PAYLOAD).enumerate() {
+        let offset = offset + (i * MAX_PAYLOAD) as u64;
+        records.extend(packet_record(flow, direction, now, offset, segment));
+    }
+    let mut sink = sink.lock().unwrap();
+    if let Err(e) = sink.write_all(&records) {
+        error!("Failed to write pcap packet: {}", e);
+    }
+}
+
+/// One pcap record: the record header and the Ethernet frame.
+fn packet_record(
+    flow: &Flow,
+    direction: Direction,
+    time: SystemTime,
+    offset: u64,
+    payload: &[u8],
+) -> Vec<u8> {
+    let (src, dst, src_mac, dst_mac) = match direction {
+        Direction::Sent => (flow.local, flow.peer, LOCAL_MAC, PEER_MAC),
+        Direction::Received => (flow.peer, flow.local, PEER_MAC, LOCAL_MAC),
+    };
+    // A QUIC stream is recorded as the byte stream it carries, like TLS is.
+    let segment = match flow.protocol {
+        Protocol::Tcp | Protocol::Quic => tcp_segment(src, dst, offset as u32, payload),
+        Protocol::Udp => udp_datagram(src, dst, payload),
+    };
+
+    let mut frame = Vec::with_capacity(14 + 40 + segment.len());
+    frame.extend_from_slice(&dst_mac);
+    frame.extend_from_slice(&src_mac);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            frame.extend_from_slice(&0x0800u16.to_be_bytes());
+            frame.extend(ipv4_header(src, dst, flow.protocol, segment.len()));
+        }
+        (src, dst) => {
+            frame.extend_from_slice(&0x86ddu16.to_be_bytes());
+            frame.extend(ipv6_header(
+                as_ipv6(src),
+                as_ipv6(dst),
+                flow.protocol,
+                segment.len(),
+            ));
+        }
+    }
+    frame.extend_from_slice(&segment);
+
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut record = Vec::with_capacity(16 + frame.len());
+    record.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    record.extend_from_slice(&frame);
+    record
+}
+
+fn as_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn ip_protocol(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Tcp | Protocol::Quic => 6,
+        Protocol::Udp => 17,
+    }
+}
+
+fn ipv4_header(src: Ipv4Addr, dst: Ipv4Addr, protocol: Protocol, len: usize) -> Vec<u8> {
+    let mut header = vec![0x45, 0];
+    header.extend_from_slice(&((20 + len) as u16).to_be_bytes());
+    // Identification, then flags with Don't Fragment set.
+    header.extend_from_slice(&[0, 0, 0x40, 0]);
+    header.extend_from_slice(&[64, ip_protocol(protocol), 0, 0]);
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+    let checksum = checksum(&header);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    header
+}
+
+fn ipv6_header(src: Ipv6Addr, dst: Ipv6Addr, protocol: Protocol, len: usize) -> Vec<u8> {
+    let mut header = vec![0x60, 0, 0, 0];
+    header.extend_from_slice(&(len as u16).to_be_bytes());
+    header.extend_from_slice(&[ip_protocol(protocol), 64]);
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+    header
+}
+
+fn tcp_segment(src: SocketAddr, dst: SocketAddr, seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&0u32.to_be_bytes());
+    // A 20 byte header with PSH and ACK set, and the largest unscaled window.
+    segment.extend_from_slice(&[0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+    segment.extend_from_slice(payload);
+    let checksum = transport_checksum(src.ip(), dst.ip(), Protocol::Tcp, &segment);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+fn udp_datagram(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(8 + payload.len());
+    datagram.extend_from_slice(&src.port().to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let checksum = match transport_checksum(src.ip(), dst.ip(), Protocol::Udp, &datagram) {
+        // Zero means "no checksum" in UDP, so a computed zero is sent as all ones.
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    datagram
+}
+
+/// The TCP or UDP checksum, which also covers a pseudo-header of the addresses.
+fn transport_checksum(src: IpAddr, dst: IpAddr, protocol: Protocol, segment: &[u8]) -> u16 {
+    let mut pseudo = Vec::with_capacity(40 + segment.len());
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, ip_protocol(protocol)]);
+            pseudo.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        }
+        (src, dst) => {
+            pseudo.extend_from_slice(&as_ipv6(src).octets());
+            pseudo.extend_from_slice(&as_ipv6(dst).octets());
+            pseudo.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, ip_protocol(protocol)]);
+        }
+    }
+    pseudo.extend_from_slice(segment);
+    checksum(&pseudo)
+}
+
+/// The Internet checksum of RFC 1071.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn flow(protocol: Protocol) -> Flow {
+        Flow::new(
+            protocol,
+            "10.0.0.1:40000".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_checksum_matches_rfc_1071_example() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&data), !0xddf2);
+        // A header with its checksum filled in sums to zero.
+        let header = ipv4_header(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Protocol::Tcp,
+            5,
+        );
+        assert_eq!(checksum(&header), 0);
+    }
+
+    #[test]
+    fn test_tcp_record_layout() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let record = packet_record(
+            &flow(Protocol::Tcp),
+            Direction::Received,
+            time,
+            1000,
+            b"hello",
+        );
+        let frame = &record[16..];
+        assert_eq!(record.len(), 16 + 14 + 20 + 20 + 5);
+        assert_eq!(record[0..4], 1_700_000_000u32.to_le_bytes());
+        assert_eq!(record[4..8], 123_456u32.to_le_bytes());
+        assert_eq!(record[8..12], (frame.len() as u32).to_le_bytes());
+        // Received traffic goes from the peer to us.
+        assert_eq!(frame[0..6], LOCAL_MAC);
+        assert_eq!(frame[12..14], [0x08, 0x00]);
+        let ip = &frame[14..34];
+        assert_eq!(ip[2..4], 45u16.to_be_bytes());
+        assert_eq!(ip[9], 6);
+        assert_eq!(ip[12..16], [10, 0, 0, 2]);
+        assert_eq!(ip[16..20], [10, 0, 0, 1]);
+        let tcp = &frame[34..];
+        assert_eq!(tcp[0..2], 80u16.to_be_bytes());
+        assert_eq!(tcp[2..4], 40000u16.to_be_bytes());
+        assert_eq!(tcp[4..8], 1000u32.to_be_bytes());
+        assert_eq!(&tcp[20..], b"hello");
+        let (src, dst) = (IpAddr::from([10, 0, 0, 2]), IpAddr::from([10, 0, 0, 1]));
+        assert_eq!(transport_checksum(src, dst, Protocol::Tcp, tcp), 0);
+    }
+
+    #[test]
+    fn test_udp_over_ipv6_record_layout() {
+        let flow = Flow::new(
+            Protocol::Udp,
+            "[::1]:5353".parse().unwrap(),
+            "[::1]:40000".parse().unwrap(),
+        );
+        let record = packet_record(&flow, Direction::Sent, UNIX_EPOCH, 0, b"ping");
+        let frame = &record[16..];
+        assert_eq!(frame[12..14], [0x86, 0xdd]);
+        let ip = &frame[14..54];
+        assert_eq!(ip[4..6], 12u16.to_be_bytes());
+        assert_eq!(ip[6], 17);
+        let udp = &frame[54..];
+        assert_eq!(udp[0..2], 5353u16.to_be_bytes());
+        assert_eq!(udp[4..6], 12u16.to_be_bytes());
+        assert_eq!(&udp[8..], b"ping");
+        let ip = IpAddr::from(Ipv6Addr::LOCALHOST);
+        assert_eq!(transport_checksum(ip, ip, Protocol::Udp, udp), 0);
+    }
+
+    #[test]
+    fn test_dual_stack_flows_are_written_as_ipv4() {
+        let flow = Flow::new(
+            Protocol::Tcp,
+            "[::]:8080".parse().unwrap(),
+            "[::ffff:192.0.2.7]:5000".parse().unwrap(),
+        );
+        assert_eq!(flow.local, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(flow.peer, "192.0.2.7:5000".parse().unwrap());
+    }
+}
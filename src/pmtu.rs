@@ -0,0 +1,322 @@
+//! `--probe-mtu`: finds the path MTU to a UDP destination on Linux, for
+//! chasing down "large packets disappear" problems.
+//!
+//! The socket forbids fragmentation (`IP_MTU_DISCOVER` or
+//! `IPV6_MTU_DISCOVER` set to `DO`), and packet sizes between
+//! `--probe-mtu-min` and `--probe-mtu-max` are binary-searched. A size is too
+//! big once the kernel refuses to send it with `EMSGSIZE`, which it does when
+//! the route, or an ICMP "fragmentation needed" or "packet too big" from a
+//! router on the way, says the path is narrower. With `--probe-mtu-echo` a
+//! size also needs to come back from a listener that echoes datagrams, as
+//! some paths drop large packets without a word.
+
+use crate::{
+    args::{Args, IpVersion},
+    network::{is_timeout, resolve, socket_from, Source},
+};
+use anyhow::{bail, Result};
+use log::info;
+use socket2::Type;
+use std::{
+    fmt,
+    io::{self, Write},
+    mem,
+    net::{SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a probe waits for an ICMP error or echo without `--timeout`.
+const PROBE_WAIT: Duration = Duration::from_millis(500);
+
+/// How many times a size is sent before a missing echo counts against it.
+const ECHO_TRIES: u32 = 3;
+
+/// The IPv4 and UDP headers in front of the payload.
+const IPV4_OVERHEAD: usize = 28;
+/// The IPv6 and UDP headers in front of the payload.
+const IPV6_OVERHEAD: usize = 48;
+
+/// What one probe found out about a packet size.
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    /// Sent, with no error back and an echo where one was asked for.
+    Fits,
+    /// Refused with the path MTU the kernel knows of.
+    TooBig(usize),
+    /// Sent, but never echoed.
+    NoEcho,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Fits => write!(f, "fits"),
+            Outcome::TooBig(mtu) => write!(f, "too big, the kernel knows a path MTU of {}", mtu),
+            Outcome::NoEcho => write!(f, "no echo after {} tries", ECHO_TRIES),
+        }
+    }
+}
+
+/// Searches for the path MTU and prints it.
+pub fn run(args: &Args, timeout: Option<Duration>) -> Result<()> {
+    let host = args.address.as_deref().unwrap();
+    let port = args.port().unwrap();
+    let version = args.ip_version.or(args.source.as_ref().map(IpVersion::of));
+    let destination = resolve(host, port, version)?;
+    let mtu = probe(destination, args, timeout)?;
+    writeln!(io::stdout(), "Path MTU to {}: {} bytes", destination, mtu)?;
+    Ok(())
+}
+
+/// The largest packet between the floor and the ceiling that reaches
+/// `destination`.
+fn probe(destination: SocketAddr, args: &Args, timeout: Option<Duration>) -> Result<usize> {
+    let socket: UdpSocket = socket_from(&Source::of(args), destination, Type::DGRAM)?.into();
+    socket.connect(destination)?;
+    forbid_fragmentation(&socket, destination)?;
+    let (floor, overhead) = match destination {
+        SocketAddr::V4(_) => (68, IPV4_OVERHEAD),
+        SocketAddr::V6(_) => (1280, IPV6_OVERHEAD),
+    };
+    let floor = args.probe_mtu_min.unwrap_or(floor);
+    let ceiling = match args.probe_mtu_max {
+        Some(ceiling) => ceiling,
+        None => path_mtu(&socket, destination)?.min(65535),
+    };
+    if floor > ceiling {
+        bail!(
+            "The smallest packet to try, {} bytes, is larger than the largest, {} bytes",
+            floor,
+            ceiling
+        );
+    }
+    info!(
+        "Probing {} with packets of {} to {} bytes",
+        destination, floor, ceiling
+    );
+    let mut prober = Prober {
+        socket,
+        destination,
+        overhead,
+        wait: timeout.unwrap_or(PROBE_WAIT),
+        echo: args.probe_mtu_echo,
+        number: 0,
+    };
+    if prober.send(floor)? != Outcome::Fits {
+        bail!("Even {} byte packets do not reach {}", floor, destination);
+    }
+    // `low` is known to fit and `high` not to, or to be past the ceiling.
+    let (mut low, mut high) = (floor, ceiling + 1);
+    while high - low > 1 {
+        let size = low + (high - low) / 2;
+        match prober.send(size)? {
+            Outcome::Fits => low = size,
+            // The kernel's word saves trying the sizes in between.
+            Outcome::TooBig(mtu) if mtu > low && mtu < size => high = mtu + 1,
+            _ => high = size,
+        }
+    }
+    Ok(low)
+}
+
+struct Prober {
+    socket: UdpSocket,
+    destination: SocketAddr,
+    overhead: usize,
+    wait: Duration,
+    echo: bool,
+    /// Numbers the probes, so that a late echo is not taken for a new one.
+    number: u32,
+}
+
+impl Prober {
+    /// Sends a packet of `size` bytes, IP and UDP headers included.
+    fn send(&mut self, size: usize) -> io::Result<Outcome> {
+        let mut payload = vec![0u8; size.saturating_sub(self.overhead).max(4)];
+        let tries = if self.echo { ECHO_TRIES } else { 1 };
+        for _ in 0..tries {
+            self.number += 1;
+            payload[..4].copy_from_slice(&self.number.to_be_bytes());
+            let sent = match self.socket.send(&payload) {
+                // Left over from the previous probe, which did arrive.
+                Err(e) if !self.echo && e.kind() == io::ErrorKind::ConnectionRefused => {
+                    self.socket.send(&payload)
+                }
+                sent => sent,
+            };
+            let outcome = match sent {
+                Ok(_) => self.await_verdict(&payload)?,
+                Err(e) if is_too_big(&e) => self.too_big()?,
+                Err(e) => return Err(e),
+            };
+            if outcome != Outcome::NoEcho {
+                info!("{} bytes: {}", size, outcome);
+                return Ok(outcome);
+            }
+        }
+        info!("{} bytes: {}", size, Outcome::NoEcho);
+        Ok(Outcome::NoEcho)
+    }
+
+    /// Waits for an ICMP error about the packet just sent or, with
+    /// `--probe-mtu-echo`, for it to come back.
+    fn await_verdict(&self, payload: &[u8]) -> io::Result<Outcome> {
+        if !self.echo {
+            thread::sleep(self.wait);
+            return match self.socket.take_error()? {
+                Some(e) if is_too_big(&e) => self.too_big(),
+                // Nobody listening still means the packet made it there.
+                Some(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(Outcome::Fits),
+                Some(e) => Err(e),
+                None => Ok(Outcome::Fits),
+            };
+        }
+        let deadline = Instant::now() + self.wait;
+        let mut buffer = vec![0u8; payload.len() + 1];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(Outcome::NoEcho);
+            }
+            self.socket.set_read_timeout(Some(left))?;
+            match self.socket.recv(&mut buffer) {
+                Ok(n) if buffer[..n] == *payload => return Ok(Outcome::Fits),
+                // An echo of an earlier probe.
+                Ok(_) => continue,
+                Err(e) if is_too_big(&e) => return self.too_big(),
+                Err(e) if is_timeout(&e) => return Ok(Outcome::NoEcho),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn too_big(&self) -> io::Result<Outcome> {
+        Ok(Outcome::TooBig(path_mtu(&self.socket, self.destination)?))
+    }
+}
+
+fn is_too_big(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+/// Sets the don't-fragment bit on everything `socket` sends, and has the
+/// kernel refuse packets larger than the path MTU it knows of.
+fn forbid_fragmentation(socket: &UdpSocket, destination: SocketAddr) -> io::Result<()> {
+    let (level, name, value) = match destination {
+        SocketAddr::V4(_) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        SocketAddr::V6(_) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        ),
+    };
+    // SAFETY: the descriptor is open and `value` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The path MTU the kernel knows of for the connected `socket`: that of the
+/// route, or less once ICMP has said so.
+fn path_mtu(socket: &UdpSocket, destination: SocketAddr) -> io::Result<usize> {
+    let (level, name) = match destination {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: the descriptor is open and `mtu` has room for the value.
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut mtu as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(mtu as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn args(extra: &[&str]) -> Args {
+        let mut argv = vec!["test", "-p", "udp", "--probe-mtu"];
+        argv.extend_from_slice(extra);
+        argv.extend(["127.0.0.1", "9"]);
+        Args::parse_from(argv)
+    }
+
+    #[test]
+    fn test_probe_stops_at_the_ceiling() {
+        // Nobody listens, which the ICMP errors show the packets reached.
+        let destination = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let args = args(&["--probe-mtu-max", "1400"]);
+        let mtu = probe(destination, &args, Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(mtu, 1400);
+    }
+
+    #[test]
+    fn test_probe_finds_where_echoes_stop() {
+        // Echoes up to 1000 byte packets, like a path that drops larger ones.
+        let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = echo.local_addr().unwrap();
+        echo.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let server = thread::spawn(move || {
+            let mut buffer = [0u8; 2048];
+            while let Ok((n, peer)) = echo.recv_from(&mut buffer) {
+                if n + IPV4_OVERHEAD <= 1000 {
+                    echo.send_to(&buffer[..n], peer).unwrap();
+                }
+            }
+        });
+        let args = args(&["--probe-mtu-echo", "--probe-mtu-max", "2000"]);
+        let mtu = probe(destination, &args, Some(Duration::from_millis(50))).unwrap();
+        assert_eq!(mtu, 1000);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_probe_fails_when_nothing_comes_back() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let args = args(&["--probe-mtu-echo"]);
+        let err = probe(
+            silent.local_addr().unwrap(),
+            &args,
+            Some(Duration::from_millis(10)),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Even 68 byte packets do not reach {}",
+                silent.local_addr().unwrap()
+            )
+        );
+    }
+}
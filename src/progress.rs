@@ -0,0 +1,164 @@
+//! `--progress` reporting for the sending side of a transfer.
+
+use std::{
+    io::{self, IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+/// How often the progress line on a terminal is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+/// How often a progress line is logged when stderr is not a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Tracks bytes sent and reports them on stderr, never touching stdout.
+pub struct Progress {
+    total: Option<u64>,
+    done: u64,
+    started: Instant,
+    last_report: Instant,
+    tty: bool,
+}
+
+impl Progress {
+    /// Starts tracking a transfer of `total` bytes, if known.
+    pub fn new(total: Option<u64>) -> Self {
+        let now = Instant::now();
+        Progress {
+            total,
+            done: 0,
+            started: now,
+            last_report: now,
+            tty: io::stderr().is_terminal(),
+        }
+    }
+
+    /// Records `n` more bytes sent, reporting if it is time to.
+    pub fn advance(&mut self, n: u64) {
+        self.done += n;
+        let interval = if self.tty {
+            REDRAW_INTERVAL
+        } else {
+            LOG_INTERVAL
+        };
+        if self.last_report.elapsed() >= interval {
+            self.last_report = Instant::now();
+            self.report(false);
+        }
+    }
+
+    /// Prints the final state of the transfer.
+    pub fn finish(&mut self) {
+        self.report(true);
+    }
+
+    fn report(&self, last: bool) {
+        let line = self.line(self.started.elapsed());
+        let mut stderr = io::stderr().lock();
+        // Progress output is best effort; a closed stderr must not end the transfer.
+        let _ = if self.tty {
+            let end = if last { "\n" } else { "" };
+            write!(stderr, "\r{}\x1b[K{}", line, end)
+        } else {
+            writeln!(stderr, "netpipe: {}", line)
+        };
+    }
+
+    fn line(&self, elapsed: Duration) -> String {
+        let rate = rate(self.done, elapsed);
+        let mut line = format!("{} sent", format_bytes(self.done));
+        if let Some(total) = self.total {
+            let percent = if total == 0 {
+                100.0
+            } else {
+                self.done as f64 * 100.0 / total as f64
+            };
+            line += &format!(" of {} ({:.1}%)", format_bytes(total), percent);
+        }
+        line += &format!(", {}/s", format_bytes(rate as u64));
+        if let Some(total) = self.total {
+            if rate > 0.0 && self.done < total {
+                let eta = Duration::from_secs_f64((total - self.done) as f64 / rate);
+                line += &format!(", ETA {}", format_duration(eta));
+            }
+        }
+        line
+    }
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        bytes as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// Formats a byte count with binary units, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    format!("{:.1} {}", value, unit)
+}
+
+/// Formats a duration as `h:mm:ss` or `m:ss`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(59)), "0:59");
+        assert_eq!(format_duration(Duration::from_secs(3 * 60 + 7)), "3:07");
+        assert_eq!(format_duration(Duration::from_secs(3600 + 61)), "1:01:01");
+    }
+
+    #[test]
+    fn test_line_with_known_size_has_percentage_and_eta() {
+        let mut progress = Progress::new(Some(4 * 1024 * 1024));
+        progress.done = 1024 * 1024;
+        assert_eq!(
+            progress.line(Duration::from_secs(1)),
+            "1.0 MiB sent of 4.0 MiB (25.0%), 1.0 MiB/s, ETA 0:03"
+        );
+    }
+
+    #[test]
+    fn test_line_with_unknown_size_has_only_rate() {
+        let mut progress = Progress::new(None);
+        progress.done = 2048;
+        assert_eq!(
+            progress.line(Duration::from_secs(2)),
+            "2.0 KiB sent, 1.0 KiB/s"
+        );
+    }
+}
@@ -0,0 +1,349 @@
+//! Tunnels through a `--proxy` to the real destination.
+//!
+//! Once the proxy has opened the tunnel, the connection carries our data
+//! unchanged, so TLS and everything else run end to end through it.
+
+use crate::args::{Endpoint, ProxyKind, ProxyUrl};
+use crate::encoding::base64;
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::{
+    io::{Read, Write},
+    net::IpAddr,
+};
+
+/// The longest response header we read before giving up on the proxy.
+const MAX_HEADER: usize = 16 * 1024;
+
+const SOCKS_VERSION: u8 = 5;
+/// The version of the RFC 1929 username/password subnegotiation.
+const SOCKS_AUTH_VERSION: u8 = 1;
+const SOCKS_NO_AUTH: u8 = 0x00;
+const SOCKS_USER_PASS: u8 = 0x02;
+const SOCKS_NO_ACCEPTABLE: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 0x01;
+const SOCKS_IPV4: u8 = 0x01;
+const SOCKS_DOMAIN: u8 = 0x03;
+const SOCKS_IPV6: u8 = 0x04;
+
+/// Asks the proxy `stream` is connected to for a tunnel to `target`,
+/// authenticating with `auth` (`user:pass`) when given.
+pub fn tunnel<S: Read + Write>(
+    stream: &mut S,
+    proxy: &ProxyUrl,
+    target: &Endpoint,
+    auth: Option<&str>,
+) -> Result<()> {
+    match proxy.kind {
+        ProxyKind::Http => http_connect(stream, target, auth),
+        ProxyKind::Socks5 | ProxyKind::Socks5h => socks5_connect(stream, target, auth),
+    }
+    .with_context(|| format!("Proxy {} could not reach {}", proxy, target))?;
+    info!("Tunnelled to {} through {}", target, proxy);
+    Ok(())
+}
+
+/// Sends an HTTP CONNECT request and waits for a 2xx answer, leaving the
+/// stream at the first byte from the destination.
+fn http_connect<S: Read + Write>(
+    stream: &mut S,
+    target: &Endpoint,
+    auth: Option<&str>,
+) -> Result<()> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(auth) = auth {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(auth.as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    // Read a byte at a time so that nothing from the destination is consumed.
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() == MAX_HEADER {
+            bail!("The proxy's response to CONNECT is too long");
+        }
+        if stream.read(&mut byte)? == 0 {
+            bail!("The proxy closed the connection before answering CONNECT");
+        }
+        response.push(byte[0]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split("\r\n").next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') && code.len() == 3 => Ok(()),
+        Some("407") if auth.is_none() => {
+            bail!(
+                "The proxy wants credentials, which --proxy-auth gives: {}",
+                status
+            )
+        }
+        _ => bail!("The proxy refused the tunnel: {}", status),
+    }
+}
+
+/// Runs the SOCKS5 greeting, authentication and CONNECT request, leaving the
+/// stream at the first byte from the destination.
+fn socks5_connect<S: Read + Write>(
+    stream: &mut S,
+    target: &Endpoint,
+    auth: Option<&str>,
+) -> Result<()> {
+    let greeting: &[u8] = match auth {
+        Some(_) => &[SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USER_PASS],
+        None => &[SOCKS_VERSION, 1, SOCKS_NO_AUTH],
+    };
+    stream.write_all(greeting)?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != SOCKS_VERSION {
+        bail!("The proxy does not speak SOCKS5");
+    }
+    match (choice[1], auth) {
+        (SOCKS_NO_AUTH, _) => {}
+        (SOCKS_USER_PASS, Some(auth)) => socks5_authenticate(stream, auth)?,
+        (SOCKS_NO_ACCEPTABLE, None) => {
+            bail!("The proxy wants credentials, which --proxy-auth gives")
+        }
+        (SOCKS_NO_ACCEPTABLE, Some(_)) => {
+            bail!("The proxy accepts none of our authentication methods")
+        }
+        (method, _) => bail!(
+            "The proxy chose authentication method {:#04x}, which we did not offer",
+            method
+        ),
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    request.extend(socks5_address(&target.host)?);
+    request.extend_from_slice(&target.port.to_be_bytes());
+    stream.write_all(&request)?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        bail!("The proxy could not connect: {}", socks5_error(reply[1]));
+    }
+    // The address the proxy connected from is of no use to us.
+    let bound = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            usize::from(len[0])
+        }
+        kind => bail!("The proxy replied with unknown address type {:#04x}", kind),
+    };
+    stream.read_exact(&mut vec![0u8; bound + 2])?;
+    Ok(())
+}
+
+/// Sends `user:pass` as in RFC 1929.
+fn socks5_authenticate<S: Read + Write>(stream: &mut S, auth: &str) -> Result<()> {
+    let (user, password) = auth.split_once(':').unwrap_or((auth, ""));
+    let (Ok(user_len), Ok(password_len)) = (u8::try_from(user.len()), u8::try_from(password.len()))
+    else {
+        bail!("SOCKS5 user names and passwords are limited to 255 bytes");
+    };
+    let mut request = vec![SOCKS_AUTH_VERSION, user_len];
+    request.extend_from_slice(user.as_bytes());
+    request.push(password_len);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request)?;
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status)?;
+    if status[1] != 0 {
+        bail!("The proxy rejected the --proxy-auth credentials");
+    }
+    Ok(())
+}
+
+/// Encodes a destination address: IP literals as such, anything else as a
+/// domain name for the proxy to resolve.
+fn socks5_address(host: &str) -> Result<Vec<u8>> {
+    Ok(match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => [&[SOCKS_IPV4][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[SOCKS_IPV6][..], &ip.octets()].concat(),
+        Err(_) => {
+            let Ok(len) = u8::try_from(host.len()) else {
+                bail!("SOCKS5 cannot carry host names longer than 255 bytes");
+            };
+            [&[SOCKS_DOMAIN, len][..], host.as_bytes()].concat()
+        }
+    })
+}
+
+/// Explains a SOCKS5 reply code (RFC 1928, section 6).
+fn socks5_error(code: u8) -> String {
+    match code {
+        0x01 => "general SOCKS server failure".to_string(),
+        0x02 => "connection not allowed by ruleset".to_string(),
+        0x03 => "network unreachable".to_string(),
+        0x04 => "host unreachable".to_string(),
+        0x05 => "connection refused".to_string(),
+        0x06 => "TTL expired".to_string(),
+        0x07 => "command not supported".to_string(),
+        0x08 => "address type not supported".to_string(),
+        code => format!("unknown error {:#04x}", code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// Runs a proxy that reads one request and gives `answer`, returning the
+    /// request it saw and whatever followed it once the client is done.
+    fn mock_proxy(answer: &'static str) -> (ProxyUrl, thread::JoinHandle<(Vec<String>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = ProxyUrl {
+            kind: ProxyKind::Http,
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            credentials: None,
+        };
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                request.push(line.trim_end().to_string());
+            }
+            stream.write_all(answer.as_bytes()).unwrap();
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).unwrap();
+            (request, rest)
+        });
+        (proxy, server)
+    }
+
+    /// Runs a SOCKS5 server that expects each message of `script` in turn and
+    /// gives its answer, then returns whatever followed.
+    fn mock_socks(script: Vec<(Vec<u8>, Vec<u8>)>) -> (ProxyUrl, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = ProxyUrl {
+            kind: ProxyKind::Socks5,
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            credentials: None,
+        };
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for (expected, answer) in script {
+                let mut received = vec![0u8; expected.len()];
+                stream.read_exact(&mut received).unwrap();
+                assert_eq!(received, expected);
+                stream.write_all(&answer).unwrap();
+            }
+            // A client that gives up leaves our answer unread, which may
+            // reset the connection.
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest);
+            rest
+        });
+        (proxy, server)
+    }
+
+    fn target() -> Endpoint {
+        Endpoint {
+            host: "::1".to_string(),
+            port: 443,
+        }
+    }
+
+    #[test]
+    fn test_http_connect_tunnels_with_credentials() {
+        let (proxy, server) = mock_proxy("HTTP/1.1 200 Connection established\r\n\r\nhi");
+        let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).unwrap();
+        tunnel(&mut stream, &proxy, &target(), Some("user:pass")).unwrap();
+        let mut greeting = [0u8; 2];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting, b"hi");
+        stream.write_all(b"data").unwrap();
+        drop(stream);
+
+        let (request, rest) = server.join().unwrap();
+        assert_eq!(
+            request,
+            [
+                "CONNECT [::1]:443 HTTP/1.1",
+                "Host: [::1]:443",
+                "Proxy-Authorization: Basic dXNlcjpwYXNz",
+            ]
+        );
+        assert_eq!(rest, "data");
+    }
+
+    #[test]
+    fn test_http_connect_reports_the_status_line() {
+        let (proxy, server) = mock_proxy("HTTP/1.1 403 Forbidden\r\n\r\n");
+        let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).unwrap();
+        let err = tunnel(&mut stream, &proxy, &target(), None).unwrap_err();
+        drop(stream);
+        server.join().unwrap();
+        let message = format!("{:#}", err);
+        assert!(message.contains("HTTP/1.1 403 Forbidden"), "{}", message);
+        assert!(message.contains("127.0.0.1"), "{}", message);
+    }
+
+    #[test]
+    fn test_socks5_connects_with_credentials() {
+        let mut connect = vec![5, 1, 0, 4];
+        connect.extend_from_slice(&"::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        connect.extend_from_slice(&[1, 187]);
+        let (proxy, server) = mock_socks(vec![
+            (vec![5, 2, 0, 2], vec![5, 2]),
+            (b"\x01\x04user\x04pass".to_vec(), vec![1, 0]),
+            (connect, vec![5, 0, 0, 1, 127, 0, 0, 1, 0x9c, 0x40]),
+        ]);
+        let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).unwrap();
+        tunnel(&mut stream, &proxy, &target(), Some("user:pass")).unwrap();
+        stream.write_all(b"data").unwrap();
+        drop(stream);
+        assert_eq!(server.join().unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_socks5_explains_reply_codes() {
+        let (proxy, server) = mock_socks(vec![
+            (vec![5, 1, 0], vec![5, 0]),
+            (
+                b"\x05\x01\x00\x03\x0bexample.com\x00\x50".to_vec(),
+                vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0],
+            ),
+        ]);
+        let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).unwrap();
+        let target = Endpoint {
+            host: "example.com".to_string(),
+            port: 80,
+        };
+        let err = tunnel(&mut stream, &proxy, &target, None).unwrap_err();
+        drop(stream);
+        server.join().unwrap();
+        let message = format!("{:#}", err);
+        assert!(message.contains("connection refused"), "{}", message);
+    }
+
+    #[test]
+    fn test_socks5_asks_for_credentials() {
+        let (proxy, server) = mock_socks(vec![(vec![5, 1, 0], vec![5, 0xff])]);
+        let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).unwrap();
+        let err = tunnel(&mut stream, &proxy, &target(), None).unwrap_err();
+        drop(stream);
+        server.join().unwrap();
+        assert!(format!("{:#}", err).contains("--proxy-auth"), "{:#}", err);
+    }
+}
@@ -0,0 +1,437 @@
+//! Pre-shared key encryption of TCP streams for `--psk`.
+//!
+//! Each side starts by sending the 8 byte magic `NPPSK001` and a random 32
+//! byte salt. The key for each direction is derived from the passphrase with
+//! HKDF-SHA256, salted with the sender's salt and bound to the receiver's, so
+//! the two directions never share a key. Everything after that travels in
+//! frames:
+//!
+//! | bytes | field                                                   |
+//! |-------|---------------------------------------------------------|
+//! | 4     | length of the sealed part, big-endian                   |
+//! | n     | ChaCha20-Poly1305 sealed frame type followed by data    |
+//!
+//! The nonce of a frame is its number in the stream, so frames cannot be
+//! dropped, reordered or replayed without failing to open. An `END` frame
+//! closes the stream, which tells a finished stream apart from one that was
+//! cut short. Each side's first frame is an empty one, which makes the
+//! handshake fail at once when the keys differ.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"NPPSK001";
+const SALT_LEN: usize = 32;
+/// Binds derived keys to this protocol.
+const INFO: &[u8] = b"netpipe psk v1";
+/// The most data one frame carries.
+const MAX_DATA: usize = 16 * 1024;
+const TAG_LEN: usize = 16;
+const MAX_SEALED: usize = 1 + MAX_DATA + TAG_LEN;
+
+const DATA: u8 = 0;
+const END: u8 = 1;
+
+/// The passphrase given to `--psk`: the contents of the file it names, or
+/// the argument itself.
+pub fn load_passphrase(value: &str) -> Result<Vec<u8>> {
+    let path = Path::new(value);
+    let mut passphrase = if path.is_file() {
+        fs::read(path).with_context(|| format!("Failed to read --psk file {}", path.display()))?
+    } else {
+        value.as_bytes().to_vec()
+    };
+    // Key files usually end with a newline that is not part of the key.
+    while matches!(passphrase.last(), Some(b'\n' | b'\r')) {
+        passphrase.pop();
+    }
+    if passphrase.is_empty() {
+        bail!("The --psk key is empty");
+    }
+    Ok(passphrase)
+}
+
+/// The key for frames sent by the owner of `sender_salt`.
+fn derive_key(passphrase: &[u8], sender_salt: &[u8], receiver_salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(sender_salt), passphrase)
+        .expand_multi_info(&[INFO, receiver_salt], &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// Exchanges salts with the peer over `reader` and `writer`, two handles on
+/// the same connection, and checks that both sides derived the same keys.
+pub fn handshake<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    passphrase: &[u8],
+) -> Result<(Opener<R>, Sealer<W>)> {
+    let mut salt = [0u8; SALT_LEN];
+    getrandom::getrandom(&mut salt)
+        .map_err(|e| anyhow!("Failed to generate a --psk salt: {}", e))?;
+    let mut hello = MAGIC.to_vec();
+    hello.extend_from_slice(&salt);
+    writer.write_all(&hello)?;
+    writer.flush()?;
+
+    let mut peer_hello = [0u8; MAGIC.len() + SALT_LEN];
+    reader
+        .read_exact(&mut peer_hello)
+        .context("The peer closed the connection during the --psk handshake")?;
+    let (peer_magic, peer_salt) = peer_hello.split_at(MAGIC.len());
+    if peer_magic != MAGIC {
+        bail!("The peer did not start a --psk session; is it using --psk too?");
+    }
+    if peer_salt == salt {
+        bail!("The peer sent our own --psk salt back");
+    }
+
+    let mut sealer = Sealer::new(writer, &derive_key(passphrase, &salt, peer_salt));
+    let mut opener = Opener::new(reader, &derive_key(passphrase, peer_salt, &salt));
+    sealer.write_frame(DATA, &[])?;
+    sealer.flush()?;
+    opener
+        .read_frame()
+        .context("The peer closed the connection during the --psk handshake")?;
+    if opener.open_frame().is_err() {
+        bail!("The peer's --psk key does not match ours");
+    }
+    Ok((opener, sealer))
+}
+
+/// Seals everything written through it into frames.
+pub struct Sealer<W> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl<W: Write> Sealer<W> {
+    fn new(inner: W, key: &[u8; 32]) -> Self {
+        Sealer {
+            inner,
+            cipher: ChaCha20Poly1305::new(key.into()),
+            counter: 0,
+        }
+    }
+
+    fn write_frame(&mut self, kind: u8, data: &[u8]) -> io::Result<()> {
+        let mut plain = Vec::with_capacity(1 + data.len());
+        plain.push(kind);
+        plain.extend_from_slice(data);
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(self.counter), &plain[..])
+            .map_err(|_| io::Error::other("Failed to seal a --psk frame"))?;
+        self.counter += 1;
+        let mut frame = Vec::with_capacity(4 + sealed.len());
+        frame.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&sealed);
+        self.inner.write_all(&frame)
+    }
+
+    /// Marks the end of the stream, which the peer reads as EOF.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_frame(END, &[])?;
+        self.inner.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+}
+
+impl<W: Write> Write for Sealer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = buf.len().min(MAX_DATA);
+        self.write_frame(DATA, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Opens the frames read from the inner reader, failing on any that was
+/// tampered with and on streams that end without an `END` frame.
+///
+/// A frame cut off by a read timeout is kept, so the next read carries on
+/// where the last one stopped.
+pub struct Opener<R> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    /// The raw bytes of the frame being read.
+    frame: Vec<u8>,
+    /// Opened data not handed out yet.
+    pending: Vec<u8>,
+    ended: bool,
+}
+
+impl<R: Read> Opener<R> {
+    fn new(inner: R, key: &[u8; 32]) -> Self {
+        Opener {
+            inner,
+            cipher: ChaCha20Poly1305::new(key.into()),
+            counter: 0,
+            frame: Vec::new(),
+            pending: Vec::new(),
+            ended: false,
+        }
+    }
+
+    /// Reads until `frame` holds a whole frame.
+    fn read_frame(&mut self) -> io::Result<()> {
+        loop {
+            let target = match self.frame.get(..4) {
+                None => 4,
+                Some(header) => {
+                    let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+                    if !(1 + TAG_LEN..=MAX_SEALED).contains(&len) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Malformed --psk frame of {} bytes", len),
+                        ));
+                    }
+                    4 + len
+                }
+            };
+            let start = self.frame.len();
+            if start == target && target > 4 {
+                return Ok(());
+            }
+            self.frame.resize(target, 0);
+            match self.inner.read(&mut self.frame[start..]) {
+                Ok(0) => {
+                    self.frame.truncate(start);
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "The --psk stream ended without its end marker, so it may have been cut short",
+                    ));
+                }
+                Ok(n) => self.frame.truncate(start + n),
+                Err(e) => {
+                    self.frame.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Opens the whole frame in `frame`.
+    fn open_frame(&mut self) -> io::Result<()> {
+        let plain = self
+            .cipher
+            .decrypt(&nonce(self.counter), &self.frame[4..])
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "A --psk frame failed authentication: the data was tampered with or corrupted",
+                )
+            })?;
+        self.counter += 1;
+        self.frame.clear();
+        match plain.split_first() {
+            Some((&DATA, data)) => self.pending = data.to_vec(),
+            Some((&END, [])) => self.ended = true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unknown --psk frame type",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Read> Read for Opener<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            if self.ended || buf.is_empty() {
+                return Ok(0);
+            }
+            self.read_frame()?;
+            self.open_frame()?;
+        }
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn seal(data: &[u8]) -> Vec<u8> {
+        let mut sealer = Sealer::new(Vec::new(), &KEY);
+        sealer.write_all(data).unwrap();
+        sealer.finish().unwrap();
+        sealer.inner
+    }
+
+    fn open(stream: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        Opener::new(stream, &KEY).read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    /// Hands out its data a few bytes at a time, timing out between pieces.
+    struct Trickle {
+        data: Vec<u8>,
+        timed_out: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.timed_out = !self.timed_out;
+            if self.timed_out {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = self.data.len().min(buf.len()).min(3);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_key_derivation_vector() {
+        let key = derive_key(b"correct horse", &[1; 32], &[2; 32]);
+        assert_eq!(
+            hex(&key),
+            "79e42e94c570ee82d84cd6e0ad4c6ad109a94cb201fc63b347b2fb4f8d2b29d8"
+        );
+        // Each direction gets its own key.
+        assert_ne!(key, derive_key(b"correct horse", &[2; 32], &[1; 32]));
+    }
+
+    #[test]
+    fn test_sealed_frame_vectors() {
+        assert_eq!(
+            hex(&seal(b"hi")),
+            concat!(
+                // Data frame 0: "hi".
+                "00000013",
+                "37ec40b6a355d4739bc6215608e97e39d0eb21",
+                // End frame 1.
+                "00000011",
+                "e817ac9d812ad587ceb09137a1beb6a2ed",
+            )
+        );
+    }
+
+    #[test]
+    fn test_round_trip_across_frames_and_timeouts() {
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        let sealed = seal(&data);
+        assert_eq!(open(&sealed).unwrap(), data);
+
+        let mut opener = Opener::new(
+            Trickle {
+                data: sealed,
+                timed_out: false,
+            },
+            &KEY,
+        );
+        let mut output = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            match opener.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => output.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_tampered_frames_are_rejected() {
+        let sealed = seal(b"pay 10 coins");
+        for i in [0, 4, 10, 20] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            let err = open(&tampered).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "byte {}", i);
+        }
+
+        // Dropping the first frame shifts the nonces of the rest.
+        let mut sealer = Sealer::new(Vec::new(), &KEY);
+        sealer.write_all(b"one").unwrap();
+        let first = sealer.inner.len();
+        sealer.write_all(b"two").unwrap();
+        sealer.finish().unwrap();
+        let err = open(&sealer.inner[first..]).unwrap_err();
+        assert!(err.to_string().contains("failed authentication"), "{}", err);
+    }
+
+    #[test]
+    fn test_truncated_stream_is_reported() {
+        let sealed = seal(b"all of it");
+        let mut opener = Opener::new(&sealed[..sealed.len() - 21], &KEY);
+        let mut output = Vec::new();
+        let err = opener.read_to_end(&mut output).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(output, b"all of it");
+    }
+
+    fn handshake_pair(ours: &'static [u8], theirs: &'static [u8]) -> (Result<()>, Result<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handshake(&stream, &stream, theirs).map(|_| ())
+        });
+        let stream = TcpStream::connect(address).unwrap();
+        let result = handshake(&stream, &stream, ours).map(|_| ());
+        (result, peer.join().unwrap())
+    }
+
+    #[test]
+    fn test_handshake_checks_both_sides_share_the_key() {
+        let (ours, theirs) = handshake_pair(b"secret", b"secret");
+        assert!(ours.is_ok() && theirs.is_ok());
+
+        let (ours, theirs) = handshake_pair(b"secret", b"guess");
+        for result in [ours, theirs] {
+            let err = result.unwrap_err().to_string();
+            assert!(err.contains("does not match"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,530 @@
+//! QUIC for `--protocol quic`, on quinn.
+//!
+//! A connection carries a single bidirectional stream, the one the client
+//! opens; streams the peer opens after it are ignored. quinn is async, so
+//! every connection gets a small tokio runtime whose one worker thread drives
+//! it, and [`QuicStream`] blocks on it to read and write like a TCP
+//! connection, splitting into a reading and a writing half.
+
+use crate::args::Args;
+use crate::network::unspecified_address;
+use crate::tls;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info};
+use quinn::{
+    crypto::rustls::{HandshakeData, QuicClientConfig, QuicServerConfig},
+    ClientConfig, Endpoint, EndpointConfig, RecvStream, SendStream, ServerConfig, TokioRuntime,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use std::{
+    future::Future,
+    io::{self, Read, Write},
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::runtime::{self, Runtime};
+
+/// How long a closing connection waits for the peer to acknowledge what is
+/// still in flight.
+const LINGER: Duration = Duration::from_secs(3);
+
+/// The runtime, endpoint and connection behind a [`QuicStream`], shared by
+/// its halves and handles.
+struct Shared {
+    runtime: Runtime,
+    endpoint: Endpoint,
+    connection: quinn::Connection,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+}
+
+impl Shared {
+    /// Runs `future` to completion, or fails with TimedOut after `timeout`.
+    fn block_on<T>(
+        &self,
+        timeout: Option<Duration>,
+        future: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        self.runtime.block_on(async {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, future)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "Timed out waiting on the QUIC connection",
+                        ))
+                    }),
+                None => future.await,
+            }
+        })
+    }
+}
+
+impl Drop for Shared {
+    /// Closes the connection once the stream and both of its halves are gone,
+    /// and gives the close a moment to reach the peer.
+    fn drop(&mut self) {
+        self.connection.close(0u32.into(), b"");
+        let _ = self
+            .runtime
+            .block_on(async { tokio::time::timeout(LINGER, self.endpoint.wait_idle()).await });
+    }
+}
+
+/// A QUIC connection and the one stream it carries.
+pub struct QuicStream {
+    shared: Arc<Shared>,
+    send: SendStream,
+    recv: RecvStream,
+}
+
+/// Shuts a QUIC connection down from another thread.
+#[derive(Clone)]
+pub struct QuicHandle(Arc<Shared>);
+
+impl QuicHandle {
+    pub fn read_timeout(&self) -> Option<Duration> {
+        *self.0.read_timeout.lock().unwrap()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        *self.0.read_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Closes the connection at once, waking up whatever waits on it.
+    pub fn shutdown(&self) {
+        self.0.connection.close(0u32.into(), b"");
+    }
+}
+
+impl QuicStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.shared.connection.remote_address()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.endpoint.local_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        *self.shared.read_timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) {
+        *self.shared.write_timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn handle(&self) -> QuicHandle {
+        QuicHandle(self.shared.clone())
+    }
+
+    pub fn split(self) -> (QuicReader, QuicWriter) {
+        let reader = QuicReader {
+            shared: self.shared.clone(),
+            recv: self.recv,
+        };
+        let writer = QuicWriter {
+            shared: self.shared,
+            send: self.send,
+        };
+        (reader, writer)
+    }
+}
+
+/// The receiving half of a [`QuicStream`].
+pub struct QuicReader {
+    shared: Arc<Shared>,
+    recv: RecvStream,
+}
+
+impl QuicReader {
+    pub fn handle(&self) -> QuicHandle {
+        QuicHandle(self.shared.clone())
+    }
+}
+
+impl Read for QuicReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.shared.read_timeout.lock().unwrap();
+        let recv = &mut self.recv;
+        self.shared
+            .block_on(timeout, async { Ok(recv.read(buf).await?.unwrap_or(0)) })
+            .map_err(|e| match e.kind() {
+                // Like a TCP socket, a read timeout surfaces as WouldBlock.
+                io::ErrorKind::TimedOut if timeout.is_some() => {
+                    io::Error::new(io::ErrorKind::WouldBlock, e.to_string())
+                }
+                _ => e,
+            })
+    }
+}
+
+/// The sending half of a [`QuicStream`].
+pub struct QuicWriter {
+    shared: Arc<Shared>,
+    send: SendStream,
+}
+
+impl QuicWriter {
+    /// Ends the stream, so the peer reads EOF once it has everything.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.send
+            .finish()
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+    }
+}
+
+impl Write for QuicWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let timeout = *self.shared.write_timeout.lock().unwrap();
+        let send = &mut self.send;
+        self.shared
+            .block_on(timeout, async { Ok(send.write(buf).await?) })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for QuicWriter {
+    /// Ends the stream if nobody did, and waits a moment for the peer to
+    /// acknowledge all of it, as closing the connection drops what is unsent.
+    fn drop(&mut self) {
+        let _ = self.send.finish();
+        let send = &mut self.send;
+        let _ = self.shared.block_on(Some(LINGER), async {
+            send.stopped().await.map_err(io::Error::other)?;
+            Ok(())
+        });
+    }
+}
+
+/// A runtime with one worker thread, which keeps the connection going while
+/// the caller is busy with something else.
+fn runtime() -> io::Result<Runtime> {
+    runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("quic")
+        .enable_all()
+        .build()
+}
+
+/// Logs what the handshake agreed on, and fails under `alpn_required` if
+/// that was no ALPN protocol.
+fn report_handshake(
+    connection: &quinn::Connection,
+    peer: &str,
+    peer_role: &str,
+    alpn_required: bool,
+) -> Result<()> {
+    info!("QUIC handshake with {} done", peer);
+    if let Some(cert) = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|certs| certs.into_iter().next())
+    {
+        info!(
+            "The {}'s certificate is for {}",
+            peer_role,
+            tls::subject(&cert)
+        );
+    }
+    let protocol = connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<HandshakeData>().ok())
+        .and_then(|data| data.protocol);
+    match protocol {
+        Some(protocol) => info!("ALPN protocol: {}", String::from_utf8_lossy(&protocol)),
+        None if alpn_required => bail!(
+            "QUIC handshake with {} failed: the {} agreed on no --tls-alpn protocol",
+            peer,
+            peer_role
+        ),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Checks server certificates with `inner`, keeping the error of a
+/// rejection for the message, as quinn only reports its text.
+#[derive(Debug)]
+struct RememberRejection {
+    inner: Arc<dyn ServerCertVerifier>,
+    rejection: Mutex<Option<rustls::Error>>,
+}
+
+impl ServerCertVerifier for RememberRejection {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            .inspect_err(|e| *self.rejection.lock().unwrap() = Some(e.clone()))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Turns a handshake that ran out of time into a plain "No answer".
+fn handshake_error(e: impl Into<anyhow::Error>, peer: &str) -> anyhow::Error {
+    let e = e.into();
+    let timed_out = matches!(
+        e.downcast_ref::<quinn::ConnectionError>(),
+        Some(quinn::ConnectionError::TimedOut)
+    ) || e
+        .downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut);
+    if timed_out {
+        anyhow!("No answer from {}", peer)
+    } else {
+        e
+    }
+}
+
+/// Connects to a QUIC server at `destination` and runs the handshake,
+/// verifying the certificate like [`tls::connect`] does.
+pub fn connect(
+    destination: SocketAddr,
+    server_name: &str,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<QuicStream> {
+    let server_name = args.tls_sni.as_deref().unwrap_or(server_name);
+    ServerName::try_from(server_name.to_string())
+        .with_context(|| format!("{} is not a valid TLS server name", server_name))?;
+    let verifier = Arc::new(RememberRejection {
+        inner: tls::server_verifier(args)?,
+        rejection: Mutex::new(None),
+    });
+    let mut tls_config = (*tls::client_config(args)?).clone();
+    tls_config
+        .dangerous()
+        .set_certificate_verifier(verifier.clone());
+    let config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls_config)?));
+    let socket = UdpSocket::bind(unspecified_address(destination))?;
+    let runtime = runtime()?;
+    let peer = destination.to_string();
+    let handshake = runtime.block_on(async {
+        let mut endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(TokioRuntime),
+        )?;
+        endpoint.set_default_client_config(config);
+        let connecting = endpoint.connect(destination, server_name)?;
+        let connection = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connecting)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+            None => connecting.await?,
+        };
+        let (mut send, recv) = connection.open_bi().await?;
+        // quinn tells the server about a stream with its first data; an empty
+        // write opens it at once, so that the server can speak first.
+        send.write(&[]).await?;
+        anyhow::Ok((endpoint, connection, send, recv))
+    });
+    let (endpoint, connection, send, recv) = handshake
+        .map_err(|e| match verifier.rejection.lock().unwrap().take() {
+            // quinn only passes on the text of the error.
+            Some(rejection) => anyhow!(tls::describe(&rejection, "server")),
+            None => handshake_error(e, &peer),
+        })
+        .with_context(|| format!("QUIC handshake with {} failed", peer))?;
+    report_handshake(&connection, &peer, "server", args.tls_alpn_required)?;
+    Ok(QuicStream {
+        shared: Arc::new(Shared {
+            runtime,
+            endpoint,
+            connection,
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+        }),
+        send,
+        recv,
+    })
+}
+
+/// Waits on `socket` for a client to open a QUIC connection and runs the
+/// handshake. Datagrams that do not start a connection are ignored.
+pub fn accept(socket: &UdpSocket, args: &Args, timeout: Option<Duration>) -> Result<QuicStream> {
+    let config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(
+        (*tls::server_config(args)?).clone(),
+    )?));
+    let socket = socket.try_clone()?;
+    let runtime = runtime()?;
+    let endpoint = runtime.block_on(async {
+        Endpoint::new(
+            EndpointConfig::default(),
+            Some(config),
+            socket,
+            Arc::new(TokioRuntime),
+        )
+    })?;
+    let incoming = runtime
+        .block_on(endpoint.accept())
+        .context("The QUIC endpoint was closed")?;
+    let peer = incoming.remote_address().to_string();
+    info!("QUIC connection from {}", peer);
+    let handshake = runtime.block_on(async {
+        let accept = async {
+            let connection = incoming.accept()?.await?;
+            let (send, recv) = connection.accept_bi().await?;
+            anyhow::Ok((connection, send, recv))
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, accept)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+            None => accept.await,
+        }
+    });
+    let (connection, send, recv) = handshake
+        .map_err(|e| handshake_error(e, &peer))
+        .with_context(|| format!("QUIC handshake with {} failed", peer))?;
+    debug!("{} opened the QUIC stream", peer);
+    report_handshake(&connection, &peer, "client", args.tls_alpn_required)?;
+    Ok(QuicStream {
+        shared: Arc::new(Shared {
+            runtime,
+            endpoint,
+            connection,
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+        }),
+        send,
+        recv,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::{fs, path::PathBuf, thread};
+
+    /// Arguments for a server for localhost with a fresh certificate and for
+    /// a client that pins it, and the files to remove afterwards.
+    fn endpoints(name: &str) -> (UdpSocket, Args, Args, [PathBuf; 2]) {
+        let cert = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let path = |kind: &str| {
+            std::env::temp_dir().join(format!(
+                "netpipe-quic-{}-{}-{}.pem",
+                std::process::id(),
+                name,
+                kind
+            ))
+        };
+        let (cert_path, key_path) = (path("cert"), path("key"));
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let server_args = Args::parse_from([
+            "test".as_ref(),
+            "-l".as_ref(),
+            "--protocol".as_ref(),
+            "quic".as_ref(),
+            "--tls-cert".as_ref(),
+            cert_path.as_os_str(),
+            "--tls-key".as_ref(),
+            key_path.as_os_str(),
+        ]);
+        let pin = tls::fingerprint(cert.cert.der());
+        let client_args = Args::parse_from(["test", "--protocol", "quic", "--tls-pin", &pin]);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        (socket, server_args, client_args, [cert_path, key_path])
+    }
+
+    #[test]
+    fn test_server_speaks_first() {
+        let (socket, server_args, client_args, files) = endpoints("greeting");
+        let destination = socket.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let stream = accept(&socket, &server_args, Some(Duration::from_secs(5))).unwrap();
+            let (_, mut writer) = stream.split();
+            writer.write_all(b"hello").unwrap();
+            writer.finish().unwrap();
+        });
+
+        let stream = connect(destination, "localhost", &client_args, None).unwrap();
+        let (mut reader, _writer) = stream.split();
+        let mut greeting = String::new();
+        reader.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "hello");
+        server.join().unwrap();
+        files.iter().for_each(|file| fs::remove_file(file).unwrap());
+    }
+
+    #[test]
+    fn test_read_timeout_surfaces_as_would_block() {
+        let (socket, server_args, client_args, files) = endpoints("quiet");
+        let destination = socket.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let stream = accept(&socket, &server_args, Some(Duration::from_secs(5))).unwrap();
+            let (mut reader, _writer) = stream.split();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let stream = connect(destination, "localhost", &client_args, None).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(100)));
+        let (mut reader, mut writer) = stream.split();
+        let err = reader.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        writer.write_all(b"still here").unwrap();
+        drop(writer);
+        assert_eq!(server.join().unwrap(), b"still here");
+        files.iter().for_each(|file| fs::remove_file(file).unwrap());
+    }
+
+    #[test]
+    fn test_pin_mismatch_fails_the_handshake() {
+        let (socket, server_args, _, files) = endpoints("mispinned");
+        let destination = socket.local_addr().unwrap();
+        let server =
+            thread::spawn(move || accept(&socket, &server_args, Some(Duration::from_secs(5))));
+
+        let other_pin = format!("sha256:{}", "ab".repeat(32));
+        let args = Args::parse_from(["test", "--protocol", "quic", "--tls-pin", &other_pin]);
+        let err = connect(destination, "localhost", &args, None)
+            .err()
+            .unwrap();
+        let message = format!("{:#}", err);
+        assert!(message.contains("matches no --tls-pin"), "{}", message);
+        assert!(server.join().unwrap().is_err());
+        files.iter().for_each(|file| fs::remove_file(file).unwrap());
+    }
+}
@@ -0,0 +1,304 @@
+//! Session recordings for `--record`, and replaying them with `--replay`.
+//!
+//! A recording starts with the 8 byte magic `NPREC001`, followed by one entry
+//! per chunk of traffic:
+//!
+//! | bytes | field                                                     |
+//! |-------|-----------------------------------------------------------|
+//! | 8     | microseconds since the recording started, big-endian      |
+//! | 1     | direction, `>` for sent and `<` for received              |
+//! | 4     | length of the data, big-endian                            |
+//! | n     | the data                                                  |
+//!
+//! Times come from a monotonic clock, so they only make sense relative to
+//! each other.
+
+use crate::hexdump::Direction;
+use anyhow::{bail, Result};
+use log::error;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+const MAGIC: &[u8; 8] = b"NPREC001";
+
+/// Where chunks are written once `--record` is given, and when it started.
+static SINK: OnceLock<(Mutex<BufWriter<File>>, Instant)> = OnceLock::new();
+
+/// One read or write, as stored in a recording.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub at: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// Creates the recording and writes its header.
+pub fn enable(path: &Path) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.flush()?;
+    let _ = SINK.set((Mutex::new(file), Instant::now()));
+    Ok(())
+}
+
+/// Appends `data` to the recording. Does nothing unless [`enable`] was called.
+pub fn record(direction: Direction, data: &[u8]) {
+    let Some((sink, started)) = SINK.get() else {
+        return;
+    };
+    if data.is_empty() {
+        return;
+    }
+    let chunk = Chunk {
+        at: started.elapsed(),
+        direction,
+        data: data.to_vec(),
+    };
+    let mut sink = sink.lock().unwrap();
+    // Flushing every chunk keeps the recording usable if netpipe is killed.
+    if let Err(e) = write_chunk(&mut *sink, &chunk).and_then(|_| sink.flush()) {
+        error!("Failed to write to the recording: {}", e);
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, chunk: &Chunk) -> io::Result<()> {
+    writer.write_all(&(chunk.at.as_micros() as u64).to_be_bytes())?;
+    writer.write_all(&[match chunk.direction {
+        Direction::Sent => b'>',
+        Direction::Received => b'<',
+    }])?;
+    writer.write_all(&(chunk.data.len() as u32).to_be_bytes())?;
+    writer.write_all(&chunk.data)
+}
+
+/// Reads every chunk of a recording.
+pub fn read_chunks<R: Read>(mut reader: R) -> Result<Vec<Chunk>> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Not a netpipe recording");
+    }
+    let mut chunks = Vec::new();
+    loop {
+        let mut header = [0u8; 13];
+        match reader.read_exact(&mut header[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(chunks),
+            Err(e) => return Err(e.into()),
+        }
+        reader.read_exact(&mut header[1..])?;
+        let at = Duration::from_micros(u64::from_be_bytes(header[..8].try_into().unwrap()));
+        let direction = match header[8] {
+            b'>' => Direction::Sent,
+            b'<' => Direction::Received,
+            other => bail!("Unknown direction {:#04x} in recording", other),
+        };
+        let len = u32::from_be_bytes(header[9..].try_into().unwrap()) as usize;
+        let mut data = vec![0u8; len];
+        reader.read_exact(&mut data)?;
+        chunks.push(Chunk {
+            at,
+            direction,
+            data,
+        });
+    }
+}
+
+/// Reads the recording at `path`.
+pub fn load(path: &Path) -> Result<Vec<Chunk>> {
+    read_chunks(BufReader::new(File::open(path)?))
+}
+
+/// Everything the peer sent in a recording, in order.
+pub fn received_data(chunks: &[Chunk]) -> Vec<u8> {
+    chunks
+        .iter()
+        .filter(|chunk| chunk.direction == Direction::Received)
+        .flat_map(|chunk| chunk.data.iter().copied())
+        .collect()
+}
+
+/// Hands out the sent chunks of a recording, each no earlier than it was sent
+/// originally, relative to the first one. `speed` divides the delays.
+pub struct Replay {
+    chunks: VecDeque<Chunk>,
+    speed: f64,
+    first_at: Option<Duration>,
+    started: Instant,
+    /// The part of the current chunk a short read left behind.
+    pending: Vec<u8>,
+}
+
+impl Replay {
+    pub fn new(chunks: Vec<Chunk>, speed: f64) -> Self {
+        let chunks: VecDeque<Chunk> = chunks
+            .into_iter()
+            .filter(|chunk| chunk.direction == Direction::Sent)
+            .collect();
+        Replay {
+            first_at: chunks.front().map(|chunk| chunk.at),
+            chunks,
+            speed,
+            started: Instant::now(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            let Some(chunk) = self.chunks.pop_front() else {
+                return Ok(0);
+            };
+            let offset = chunk.at.saturating_sub(self.first_at.unwrap_or_default());
+            let due = self.started + offset.div_f64(self.speed);
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+            self.pending = chunk.data;
+        }
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+/// Passes writes on while comparing them with what the recording says the
+/// peer sent, for `--replay-verify`.
+pub struct Verified<W> {
+    inner: W,
+    expected: Vec<u8>,
+    received: usize,
+    mismatch: Option<usize>,
+}
+
+impl<W> Verified<W> {
+    pub fn new(inner: W, expected: Vec<u8>) -> Self {
+        Verified {
+            inner,
+            expected,
+            received: 0,
+            mismatch: None,
+        }
+    }
+
+    /// Fails unless exactly the recorded data was written.
+    pub fn finish(&self) -> Result<()> {
+        if let Some(offset) = self.mismatch {
+            bail!("Response differs from the recording at byte {}", offset);
+        }
+        if self.received != self.expected.len() {
+            bail!(
+                "Received {} bytes but the recording has {}",
+                self.received,
+                self.expected.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Verified<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if self.mismatch.is_none() {
+            let expected = self.expected.get(self.received..).unwrap_or_default();
+            self.mismatch = (0..n)
+                .find(|&i| expected.get(i) != Some(&buf[i]))
+                .map(|i| self.received + i);
+        }
+        self.received += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(at_ms: u64, direction: Direction, data: &[u8]) -> Chunk {
+        Chunk {
+            at: Duration::from_millis(at_ms),
+            direction,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_chunks_round_trip() {
+        let chunks = vec![
+            chunk(0, Direction::Sent, b"GET / HTTP/1.0\r\n\r\n"),
+            chunk(12, Direction::Received, b"HTTP/1.0 200 OK\r\n"),
+            chunk(13, Direction::Received, &[0, 255, 10]),
+            chunk(1500, Direction::Sent, b"bye"),
+        ];
+        let mut bytes = MAGIC.to_vec();
+        for chunk in &chunks {
+            write_chunk(&mut bytes, chunk).unwrap();
+        }
+        assert_eq!(read_chunks(&bytes[..]).unwrap(), chunks);
+        assert_eq!(received_data(&chunks), b"HTTP/1.0 200 OK\r\n\0\xff\n");
+    }
+
+    #[test]
+    fn test_truncated_or_foreign_files_are_rejected() {
+        assert!(read_chunks(&b"PK\x03\x04 nope"[..]).is_err());
+        let mut bytes = MAGIC.to_vec();
+        write_chunk(&mut bytes, &chunk(0, Direction::Sent, b"hello")).unwrap();
+        bytes.pop();
+        assert!(read_chunks(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn test_replay_keeps_relative_timing() {
+        let chunks = vec![
+            chunk(1000, Direction::Sent, b"one"),
+            chunk(1050, Direction::Received, b"ignored"),
+            chunk(1200, Direction::Sent, b"two"),
+        ];
+        let mut replay = Replay::new(chunks, 2.0);
+        let started = Instant::now();
+        let mut output = Vec::new();
+        replay.read_to_end(&mut output).unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(output, b"onetwo");
+        assert!(elapsed >= Duration::from_millis(100), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(150), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_verified_reports_first_difference() {
+        let mut output = Verified::new(Vec::new(), b"hello world".to_vec());
+        output.write_all(b"hello").unwrap();
+        output.write_all(b" world").unwrap();
+        assert!(output.finish().is_ok());
+        assert_eq!(output.inner, b"hello world");
+
+        let mut output = Verified::new(io::sink(), b"hello world".to_vec());
+        output.write_all(b"hello").unwrap();
+        output.write_all(b" there").unwrap();
+        let err = output.finish().unwrap_err().to_string();
+        assert!(err.contains("at byte 6"), "{}", err);
+
+        let mut output = Verified::new(io::sink(), b"hi".to_vec());
+        output.write_all(b"hi!").unwrap();
+        let err = output.finish().unwrap_err().to_string();
+        assert!(err.contains("at byte 2"), "{}", err);
+
+        let mut output = Verified::new(io::sink(), b"hello".to_vec());
+        output.write_all(b"hel").unwrap();
+        let err = output.finish().unwrap_err().to_string();
+        assert!(err.contains("Received 3 bytes"), "{}", err);
+    }
+}
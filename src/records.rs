@@ -0,0 +1,185 @@
+//! Splits a byte stream into records for `--forward` bridges between TCP and
+//! UDP, where every record travels as one datagram.
+//!
+//! Records are lines or length-prefixed as `--datagram-framing` says, the
+//! same framing the bridge uses to write datagrams into the stream.
+
+use crate::args::{DatagramFraming, Oversize};
+use log::info;
+use std::io::{self, BufRead, BufReader, Read};
+
+/// Reads the records of a stream, applying `--oversize` to those longer than
+/// `--max-record`.
+pub struct RecordReader<R> {
+    inner: BufReader<R>,
+    framing: DatagramFraming,
+    max: usize,
+    oversize: Oversize,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(inner: R, framing: DatagramFraming, max: usize, oversize: Oversize) -> Self {
+        RecordReader {
+            inner: BufReader::new(inner),
+            framing,
+            max,
+            oversize,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    /// The next record, or None at the end of the stream.
+    pub fn next_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let read = match self.framing {
+                DatagramFraming::Length => self.read_prefixed()?,
+                _ => self.read_line()?,
+            };
+            let Some((record, complete)) = read else {
+                return Ok(None);
+            };
+            if complete {
+                return Ok(Some(record));
+            }
+            match self.oversize {
+                Oversize::Truncate => {
+                    info!("Truncated a record to {} bytes", self.max);
+                    return Ok(Some(record));
+                }
+                Oversize::Drop => info!("Dropped a record longer than {} bytes", self.max),
+                Oversize::Error => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("A record is longer than --max-record ({} bytes)", self.max),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Reads a line without its newline, keeping at most `max` bytes and
+    /// telling whether that was all of it. A last line may lack the newline.
+    fn read_line(&mut self) -> io::Result<Option<(Vec<u8>, bool)>> {
+        let mut record = Vec::new();
+        let mut complete = true;
+        let mut started = false;
+        loop {
+            let available = match self.inner.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                if !started {
+                    return Ok(None);
+                }
+                break;
+            }
+            started = true;
+            let end = available.iter().position(|&b| b == b'\n');
+            let line = &available[..end.unwrap_or(available.len())];
+            let room = self.max - record.len().min(self.max);
+            if line.len() > room {
+                complete = false;
+            }
+            record.extend_from_slice(&line[..line.len().min(room)]);
+            let consumed = end.map_or(available.len(), |end| end + 1);
+            self.inner.consume(consumed);
+            if end.is_some() {
+                break;
+            }
+        }
+        Ok(Some((record, complete)))
+    }
+
+    /// Reads a record preceded by its length as a big-endian u32, keeping at
+    /// most `max` bytes and skipping the rest.
+    fn read_prefixed(&mut self) -> io::Result<Option<(Vec<u8>, bool)>> {
+        if self.inner.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len)?;
+        let len = u64::from(u32::from_be_bytes(len));
+        let kept = len.min(self.max as u64);
+        let mut record = Vec::with_capacity(kept as usize);
+        (&mut self.inner).take(kept).read_to_end(&mut record)?;
+        let skipped = io::copy(&mut (&mut self.inner).take(len - kept), &mut io::sink())?;
+        if record.len() as u64 + skipped < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The stream ended in the middle of a record",
+            ));
+        }
+        Ok(Some((record, kept == len)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(
+        data: &[u8],
+        framing: DatagramFraming,
+        max: usize,
+        oversize: Oversize,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let mut reader = RecordReader::new(data, framing, max, oversize);
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record()? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    #[test]
+    fn test_lines_become_records() {
+        let lines = records(
+            b"one\n\nthree\nlast",
+            DatagramFraming::Newline,
+            16,
+            Oversize::Error,
+        )
+        .unwrap();
+        assert_eq!(lines, [&b"one"[..], b"", b"three", b"last"]);
+        assert!(records(b"", DatagramFraming::Newline, 16, Oversize::Error)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_length_prefixed_records() {
+        let data = b"\x00\x00\x00\x03abc\x00\x00\x00\x00\x00\x00\x00\x01z";
+        let parsed = records(data, DatagramFraming::Length, 16, Oversize::Error).unwrap();
+        assert_eq!(parsed, [&b"abc"[..], b"", b"z"]);
+        let err = records(
+            b"\x00\x00\x00\x05ab",
+            DatagramFraming::Length,
+            16,
+            Oversize::Error,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_oversize_records_follow_the_policy() {
+        let lines = b"short\ntoo long\nok\n";
+        let prefixed = b"\x00\x00\x00\x05short\x00\x00\x00\x08too long\x00\x00\x00\x02ok";
+        for (data, framing) in [
+            (&lines[..], DatagramFraming::Newline),
+            (&prefixed[..], DatagramFraming::Length),
+        ] {
+            let dropped = records(data, framing, 5, Oversize::Drop).unwrap();
+            assert_eq!(dropped, [&b"short"[..], b"ok"]);
+            let truncated = records(data, framing, 5, Oversize::Truncate).unwrap();
+            assert_eq!(truncated, [&b"short"[..], b"too l", b"ok"]);
+            let err = records(data, framing, 5, Oversize::Error).unwrap_err();
+            assert!(err.to_string().contains("--max-record"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! `--reliable`: a small protocol on top of UDP for getting the input across
+//! intact where TCP is blocked. Both ends must be netpipe.
+//!
+//! Every datagram starts with the protocol version, its kind and a sequence
+//! number. The sender keeps up to `--reliable-window` data datagrams
+//! unacknowledged, resending each once its retransmission timeout passes,
+//! and gives up after `--reliable-retries` resends of one. The receiver
+//! acknowledges every data datagram with its number and the number it
+//! expects next, and writes the payloads out in order. The transfer ends once
+//! the sender's FIN, carrying the number of data datagrams, is acknowledged.
+
+use crate::{
+    args::{Args, Protocol},
+    network::{is_timeout, open_output, report_transfer},
+    stats::{ExitReason, Transfer},
+};
+use anyhow::{bail, Result};
+use log::{error, info};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+/// The version of the protocol, which both ends must agree on.
+pub const VERSION: u8 = 1;
+
+/// The version, the kind and the sequence number.
+pub const HEADER: usize = 6;
+
+/// The datagram size without `--datagram-size`, which fits the MTU of most
+/// paths, tunnels included.
+pub const DEFAULT_DATAGRAM_SIZE: usize = 1200;
+
+/// How far ahead of the next expected datagram the receiver takes any, which
+/// bounds `--reliable-window`.
+pub const MAX_WINDOW: u32 = 4096;
+
+const INITIAL_RTO: Duration = Duration::from_millis(250);
+const MIN_RTO: Duration = Duration::from_millis(20);
+const MAX_RTO: Duration = Duration::from_secs(10);
+
+/// How long the receiver stays to answer a FIN again in case its
+/// acknowledgment was lost.
+const LINGER: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Data,
+    Ack,
+    Fin,
+    FinAck,
+}
+
+/// A decoded datagram. An `Ack` acknowledges data datagram `number` and
+/// carries the number the receiver expects next as its body.
+#[derive(Debug, PartialEq, Eq)]
+struct Packet<'a> {
+    kind: Kind,
+    number: u32,
+    body: &'a [u8],
+}
+
+fn encode(kind: Kind, number: u32, body: &[u8]) -> Vec<u8> {
+    let kind = match kind {
+        Kind::Data => 0,
+        Kind::Ack => 1,
+        Kind::Fin => 2,
+        Kind::FinAck => 3,
+    };
+    let mut datagram = Vec::with_capacity(HEADER + body.len());
+    datagram.extend_from_slice(&[VERSION, kind]);
+    datagram.extend_from_slice(&number.to_be_bytes());
+    datagram.extend_from_slice(body);
+    datagram
+}
+
+fn decode(datagram: &[u8]) -> Result<Packet<'_>> {
+    let Some((header, body)) = datagram.split_first_chunk::<HEADER>() else {
+        bail!(
+            "A {}-byte datagram is too short for the --reliable protocol",
+            datagram.len()
+        );
+    };
+    if header[0] != VERSION {
+        bail!(
+            "The peer speaks version {} of the --reliable protocol, but this netpipe speaks version {}",
+            header[0],
+            VERSION
+        );
+    }
+    let kind = match header[1] {
+        0 => Kind::Data,
+        1 => Kind::Ack,
+        2 => Kind::Fin,
+        3 => Kind::FinAck,
+        kind => bail!("Unknown --reliable datagram kind {}", kind),
+    };
+    let number = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+    Ok(Packet { kind, number, body })
+}
+
+fn ack(number: u32, next: u32) -> Vec<u8> {
+    encode(Kind::Ack, number, &next.to_be_bytes())
+}
+
+/// Whether `number` comes at or after `next`, within reach of the window.
+fn at_or_after(number: u32, next: u32) -> bool {
+    number.wrapping_sub(next) < MAX_WINDOW
+}
+
+/// The retransmission timeout, from the round trips measured as in RFC 6298.
+#[derive(Default)]
+struct Rto {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl Rto {
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// The timeout after `retries` resends, doubling with each.
+    fn backoff(&self, retries: u32) -> Duration {
+        let rto = match self.srtt {
+            None => INITIAL_RTO,
+            Some(srtt) => (srtt + self.rttvar * 4).max(MIN_RTO),
+        };
+        rto.saturating_mul(1 << retries.min(16)).min(MAX_RTO)
+    }
+}
+
+/// A data datagram waiting for its acknowledgment.
+struct InFlight {
+    datagram: Vec<u8>,
+    sent: Instant,
+    retries: u32,
+}
+
+/// Sends `input` over the connected `socket` and waits for the receiver to
+/// acknowledge all of it.
+pub fn send(
+    socket: &UdpSocket,
+    mut input: Box<dyn Read + Send>,
+    args: &Args,
+    transfer: &Transfer,
+) -> Result<ExitReason> {
+    let size = args.datagram_size.unwrap_or(DEFAULT_DATAGRAM_SIZE) - HEADER;
+    let window = args.reliable_window as usize;
+    let mut in_flight: HashMap<u32, InFlight> = HashMap::new();
+    let mut rto = Rto::default();
+    let (mut next, mut eof, mut resent) = (0u32, false, 0u64);
+    let mut chunk = Vec::with_capacity(size);
+    let mut buffer = vec![0u8; 64];
+    loop {
+        while !eof && in_flight.len() < window {
+            chunk.clear();
+            input.by_ref().take(size as u64).read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                eof = true;
+                break;
+            }
+            let datagram = encode(Kind::Data, next, &chunk);
+            socket.send(&datagram)?;
+            transfer.add_sent(chunk.len() as u64);
+            let sent = Instant::now();
+            in_flight.insert(
+                next,
+                InFlight {
+                    datagram,
+                    sent,
+                    retries: 0,
+                },
+            );
+            next = next.wrapping_add(1);
+        }
+        let Some(due) = in_flight
+            .values()
+            .map(|flight| flight.sent + rto.backoff(flight.retries))
+            .min()
+        else {
+            break;
+        };
+
+        let wait = due.saturating_duration_since(Instant::now());
+        socket.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+        match socket.recv(&mut buffer) {
+            Ok(n) => {
+                let packet = decode(&buffer[..n])?;
+                if packet.kind == Kind::Ack {
+                    if let Some(acked) = in_flight.remove(&packet.number) {
+                        // Resent datagrams leave the round trip ambiguous.
+                        if acked.retries == 0 {
+                            rto.sample(acked.sent.elapsed());
+                        }
+                    }
+                    if let Ok(expected) = <[u8; 4]>::try_from(packet.body) {
+                        let expected = u32::from_be_bytes(expected);
+                        in_flight.retain(|&number, _| at_or_after(number, expected));
+                    }
+                }
+            }
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let now = Instant::now();
+        for (number, flight) in &mut in_flight {
+            if now < flight.sent + rto.backoff(flight.retries) {
+                continue;
+            }
+            if flight.retries == args.reliable_retries {
+                bail!(
+                    "Datagram {} was not acknowledged after {} resends",
+                    number,
+                    flight.retries
+                );
+            }
+            socket.send(&flight.datagram)?;
+            flight.retries += 1;
+            flight.sent = now;
+            resent += 1;
+        }
+    }
+    finish(socket, next, args, &rto)?;
+    info!("Delivered {} datagrams, resending {}", next, resent);
+    Ok(ExitReason::Eof)
+}
+
+/// Sends the FIN for `total` data datagrams until the receiver acknowledges
+/// it.
+fn finish(socket: &UdpSocket, total: u32, args: &Args, rto: &Rto) -> Result<()> {
+    let fin = encode(Kind::Fin, total, &[]);
+    let mut buffer = vec![0u8; 64];
+    for retries in 0..=args.reliable_retries {
+        socket.send(&fin)?;
+        let deadline = Instant::now() + rto.backoff(retries);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(left))?;
+            match socket.recv(&mut buffer) {
+                Ok(n) => {
+                    let packet = decode(&buffer[..n])?;
+                    if packet.kind == Kind::FinAck && packet.number == total {
+                        return Ok(());
+                    }
+                }
+                Err(e) if is_timeout(&e) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    bail!(
+        "The receiver did not acknowledge the end of the transfer after {} resends",
+        args.reliable_retries
+    )
+}
+
+/// Receives transfers on `socket` into the output, one after another with
+/// `--keep-open`.
+pub fn serve(socket: &UdpSocket, args: &Args, timeout: Option<Duration>) -> Result<()> {
+    let mut output = open_output(args)?;
+    loop {
+        let result = receive(socket, &mut output, args, timeout);
+        if !args.keep_open {
+            return result;
+        }
+        if let Err(e) = result {
+            error!("Failed to receive a transfer: {:#}", e);
+        }
+    }
+}
+
+/// Receives one transfer from whoever sends first.
+fn receive(
+    socket: &UdpSocket,
+    output: &mut dyn Write,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    socket.set_read_timeout(timeout)?;
+    let mut buffer = vec![0u8; 65535];
+    let (n, peer) = match socket.recv_from(&mut buffer) {
+        Ok(received) => received,
+        Err(e) if is_timeout(&e) => {
+            info!("No datagram received within the timeout, stopping");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    info!("Receiving a transfer from {}", peer);
+    let transfer = Transfer::new(peer, Protocol::Udp);
+    let result = receive_from(socket, peer, &mut buffer, n, output, &transfer);
+    report_transfer(&transfer, &result, args);
+    if result.is_ok() {
+        linger(socket, peer)?;
+    }
+    result.map(|_| ())
+}
+
+/// The body of [`receive`], starting with the first `n` bytes of `buffer`
+/// that `peer` sent.
+fn receive_from(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    buffer: &mut [u8],
+    mut n: usize,
+    output: &mut dyn Write,
+    transfer: &Transfer,
+) -> Result<ExitReason> {
+    let mut next = 0u32;
+    let mut held: HashMap<u32, Vec<u8>> = HashMap::new();
+    loop {
+        let packet = decode(&buffer[..n])?;
+        match packet.kind {
+            Kind::Data => {
+                if at_or_after(packet.number, next) {
+                    held.entry(packet.number)
+                        .or_insert_with(|| packet.body.to_vec());
+                }
+                while let Some(body) = held.remove(&next) {
+                    output.write_all(&body)?;
+                    transfer.add_received(body.len() as u64);
+                    next = next.wrapping_add(1);
+                }
+                output.flush()?;
+                socket.send_to(&ack(packet.number, next), peer)?;
+            }
+            // An early FIN is sent again once the data before it is in.
+            Kind::Fin if packet.number == next => {
+                socket.send_to(&encode(Kind::FinAck, next, &[]), peer)?;
+                return Ok(ExitReason::Eof);
+            }
+            _ => {}
+        }
+        n = loop {
+            match socket.recv_from(buffer) {
+                Ok((n, from)) if from == peer => break n,
+                Ok((_, from)) => info!("Ignoring {} during the transfer from {}", from, peer),
+                Err(e) if is_timeout(&e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("{} went silent before the transfer ended", peer),
+                    )
+                    .into())
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+    }
+}
+
+/// Answers FINs from `peer` again until it has been quiet for [`LINGER`].
+fn linger(socket: &UdpSocket, peer: SocketAddr) -> Result<()> {
+    let mut buffer = vec![0u8; 65535];
+    socket.set_read_timeout(Some(LINGER))?;
+    loop {
+        match socket.recv_from(&mut buffer) {
+            Ok((n, from)) if from == peer => {
+                let packet = decode(&buffer[..n])?;
+                if packet.kind == Kind::Fin {
+                    socket.send_to(&encode(Kind::FinAck, packet.number, &[]), peer)?;
+                }
+            }
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::{
+        io::Cursor,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+    };
+
+    #[test]
+    fn test_packets_round_trip_and_check_the_version() {
+        let datagram = encode(Kind::Data, 0x01020304, b"payload");
+        assert_eq!(&datagram[..HEADER], [VERSION, 0, 1, 2, 3, 4]);
+        assert_eq!(
+            decode(&datagram).unwrap(),
+            Packet {
+                kind: Kind::Data,
+                number: 0x01020304,
+                body: b"payload",
+            }
+        );
+        let mut future = ack(7, 8);
+        future[0] = VERSION + 1;
+        let err = decode(&future).unwrap_err().to_string();
+        assert!(err.contains("speaks version 2"), "{}", err);
+        assert!(decode(&[VERSION, 0, 0]).is_err());
+        assert!(decode(&[VERSION, 9, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_rto_follows_the_round_trip() {
+        let mut rto = Rto::default();
+        assert_eq!(rto.backoff(0), INITIAL_RTO);
+        assert_eq!(rto.backoff(2), INITIAL_RTO * 4);
+        rto.sample(Duration::from_millis(100));
+        assert_eq!(rto.backoff(0), Duration::from_millis(300));
+        assert_eq!(rto.backoff(30), MAX_RTO);
+    }
+
+    /// Relays datagrams between a client and `server`, dropping every
+    /// `drop_every`th one in each direction, until `stop` is set.
+    fn lossy_relay(
+        server: SocketAddr,
+        drop_every: usize,
+        stop: Arc<AtomicBool>,
+    ) -> (SocketAddr, thread::JoinHandle<()>) {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = relay.local_addr().unwrap();
+        relay
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let mut client = None;
+            let mut counts = [0usize; 2];
+            let mut buffer = vec![0u8; 65535];
+            while !stop.load(Ordering::SeqCst) {
+                let Ok((n, from)) = relay.recv_from(&mut buffer) else {
+                    continue;
+                };
+                let (direction, to) = if from == server {
+                    match client {
+                        Some(client) => (1, client),
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    (0, server)
+                };
+                counts[direction] += 1;
+                if counts[direction] % drop_every != 0 {
+                    relay.send_to(&buffer[..n], to).unwrap();
+                }
+            }
+        });
+        (address, handle)
+    }
+
+    /// Collects what the receiver writes.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transfer_survives_lost_datagrams() {
+        let input: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (relay, relay_handle) = lossy_relay(server.local_addr().unwrap(), 7, Arc::clone(&stop));
+
+        let output = Shared::default();
+        let received = output.clone();
+        let receiver = thread::spawn(move || {
+            let args = Args::parse_from(["test", "-l", "-p", "udp", "--reliable"]);
+            let mut output = received;
+            receive(&server, &mut output, &args, Some(Duration::from_secs(10)))
+        });
+
+        let args = Args::parse_from([
+            "test",
+            "-p",
+            "udp",
+            "--reliable",
+            "--datagram-size",
+            "1000",
+            "--reliable-window",
+            "16",
+        ]);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(relay).unwrap();
+        let transfer = Transfer::new(relay, Protocol::Udp);
+        let reason = send(
+            &socket,
+            Box::new(Cursor::new(input.clone())),
+            &args,
+            &transfer,
+        )
+        .unwrap();
+        assert_eq!(reason, ExitReason::Eof);
+        receiver.join().unwrap().unwrap();
+        stop.store(true, Ordering::SeqCst);
+        relay_handle.join().unwrap();
+        assert!(*output.0.lock().unwrap() == input);
+    }
+
+    #[test]
+    fn test_sender_gives_up_without_acknowledgments() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(silent.local_addr().unwrap()).unwrap();
+        let args = Args::parse_from(["test", "-p", "udp", "--reliable", "--reliable-retries", "1"]);
+        let transfer = Transfer::new(silent.local_addr().unwrap(), Protocol::Udp);
+        let err = send(&socket, Box::new(Cursor::new(b"hello")), &args, &transfer).unwrap_err();
+        assert!(
+            err.to_string().contains("not acknowledged after 1 resends"),
+            "{}",
+            err
+        );
+    }
+}
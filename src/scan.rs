@@ -0,0 +1,656 @@
+//! `--scan`: which ports of a host accept connections, like `nc -z`.
+//!
+//! Over UDP a port counts as closed when the host answers the probe with an
+//! ICMP port unreachable, which a connected socket reports as a refusal.
+//!
+//! `--banner` also keeps what an open port sends first, here and in client
+//! mode.
+
+use crate::{
+    args::{Args, Protocol},
+    formats::{self, Format, Line},
+    network::{connect_timeout, is_timeout, resolve, unspecified_address},
+};
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Results waiting for those of lower ports before they are printed, keyed
+/// by position in the scan, so an interrupted scan can still show them.
+static GATHERED: Mutex<BTreeMap<usize, ScanResult>> = Mutex::new(BTreeMap::new());
+
+/// The exit code when every port refused, so the host is up.
+pub const EXIT_CLOSED: i32 = 1;
+/// The exit code when no port answered in time or the host was unreachable.
+/// Clap already exits with 2 on a usage error.
+pub const EXIT_TIMEOUT: i32 = 3;
+/// The exit code when the host did not resolve.
+pub const EXIT_DNS: i32 = 4;
+
+/// What a connection attempt says about a port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortState {
+    /// The connection was accepted.
+    Open,
+    /// The host refused the connection, so it is up but nothing listens.
+    Closed,
+    /// No answer before the timeout, or an error on the way there.
+    Filtered,
+    /// A UDP probe went unanswered, which open ports often do too.
+    OpenFiltered,
+}
+
+impl PortState {
+    /// Whether the port may be open, which is what a zero exit code reports.
+    pub fn may_be_open(self) -> bool {
+        matches!(self, PortState::Open | PortState::OpenFiltered)
+    }
+
+    /// The exit code of a scan whose most hopeful port is in this state.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            PortState::Open | PortState::OpenFiltered => 0,
+            PortState::Closed => EXIT_CLOSED,
+            PortState::Filtered => EXIT_TIMEOUT,
+        }
+    }
+}
+
+impl fmt::Display for PortState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PortState::Open => "open",
+            PortState::Closed => "closed",
+            PortState::Filtered => "filtered",
+            PortState::OpenFiltered => "open|filtered",
+        })
+    }
+}
+
+/// The outcome of probing one port.
+#[derive(Clone, Debug)]
+pub struct ScanResult {
+    pub address: SocketAddr,
+    pub protocol: Protocol,
+    pub state: PortState,
+    /// How long the probe took to get its answer, or to give up.
+    pub rtt: Duration,
+    /// What an open port sent first, under `--banner`.
+    pub banner: Option<Vec<u8>>,
+}
+
+impl fmt::Display for ScanResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}/{} {}",
+            self.address.ip(),
+            self.address.port(),
+            self.protocol,
+            self.state
+        )?;
+        match &self.banner {
+            Some(banner) => write!(f, " {}", escape(banner)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ScanResult {
+    fn rtt_ms(&self) -> f64 {
+        (self.rtt.as_secs_f64() * 1e6).round() / 1e3
+    }
+}
+
+impl Line for ScanResult {
+    fn text(&self) -> String {
+        self.to_string()
+    }
+
+    fn json(&self) -> Value {
+        let mut value = json!({
+            "host": self.address.ip().to_string(),
+            "port": self.address.port(),
+            "proto": self.protocol.to_string(),
+            "state": self.state.to_string(),
+            "rtt_ms": self.rtt_ms(),
+        });
+        if let Some(banner) = &self.banner {
+            value["banner"] = escape(banner).into();
+        }
+        value
+    }
+
+    fn grepable(&self) -> String {
+        let mut line = format!(
+            "host={} port={} proto={} state={} rtt_ms={}",
+            self.address.ip(),
+            self.address.port(),
+            self.protocol,
+            self.state,
+            self.rtt_ms()
+        );
+        // Spaces would split the field.
+        if let Some(banner) = &self.banner {
+            line += &format!(" banner={}", escape(banner).replace(' ', "\\x20"));
+        }
+        line
+    }
+}
+
+/// Writes `result` to `output` in `format`, where the grepable format only
+/// lists ports that may be open.
+fn write_result(output: &mut dyn Write, result: &ScanResult, format: Format) -> io::Result<()> {
+    if format == Format::Grepable && !result.state.may_be_open() {
+        return Ok(());
+    }
+    writeln!(output, "{}", formats::line(result, format))
+}
+
+/// Tries the ports of the positional argument with `--scan-concurrency`
+/// attempts at a time and prints one line per port in port order, returning
+/// the exit code: 0 if any port may be open, else the code of the most
+/// hopeful state, or [`EXIT_DNS`] if the host did not resolve.
+pub fn run(args: &Args, timeout: Option<Duration>) -> Result<i32> {
+    let host = args.address.as_deref().unwrap();
+    let ports = args.ports.as_ref().unwrap().ports();
+    let address = match resolve(host, ports[0], args.ip_version) {
+        Ok(address) => address,
+        Err(e) => {
+            writeln!(io::stderr(), "netpipe: {:#}", e)?;
+            return Ok(EXIT_DNS);
+        }
+    };
+    let timeout = connect_timeout(args, timeout);
+    // Each worker holds at most one socket, so this bounds the descriptors too.
+    let workers = args.scan_concurrency.min(ports.len());
+    check_descriptor_limit("--scan-concurrency", workers, 1)?;
+    let output = &mut io::stdout();
+    let format = args.scan_output.into();
+    let exit_code = match args.protocol {
+        Protocol::Udp => {
+            let wait = timeout.unwrap_or(UDP_WAIT);
+            scan(address, ports, workers, format, output, |address| {
+                probe_udp(address, udp_probe(args, address.port()), wait, args.banner)
+            })
+        }
+        _ => scan(address, ports, workers, format, output, |address| {
+            probe_tcp(address, timeout, args.banner)
+        }),
+    };
+    Ok(exit_code?)
+}
+
+/// Probes `ports` of `address` on `workers` threads, writing the results to
+/// `output` in `format` and the order of `ports`, and returns the exit code
+/// of the most hopeful result.
+fn scan(
+    address: SocketAddr,
+    ports: &[u16],
+    workers: usize,
+    format: Format,
+    output: &mut dyn Write,
+    probe: impl Fn(SocketAddr) -> ScanResult + Sync,
+) -> io::Result<i32> {
+    let next = AtomicUsize::new(0);
+    let (sender, results) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let (next, probe) = (&next, &probe);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(&port) = ports.get(index) else {
+                    break;
+                };
+                let mut address = address;
+                address.set_port(port);
+                if sender.send((index, probe(address))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut exit_code = EXIT_TIMEOUT;
+        let mut printed = 0;
+        for (index, result) in results {
+            exit_code = exit_code.min(result.state.exit_code());
+            let mut gathered = GATHERED.lock().unwrap();
+            gathered.insert(index, result);
+            while let Some(result) = gathered.remove(&printed) {
+                write_result(output, &result, format)?;
+                printed += 1;
+            }
+        }
+        Ok(exit_code)
+    })
+}
+
+/// Prints the results that were waiting for lower ports, for a scan cut
+/// short by a signal.
+pub fn print_gathered(args: &Args) {
+    let gathered = std::mem::take(&mut *GATHERED.lock().unwrap());
+    let mut stdout = io::stdout().lock();
+    for result in gathered.values() {
+        let _ = write_result(&mut stdout, result, args.scan_output.into());
+    }
+    let _ = stdout.flush();
+}
+
+/// Refuses a concurrency given with `flag` that would run out of file
+/// descriptors halfway, when each of the `workers` holds `each` of them.
+#[cfg(unix)]
+pub fn check_descriptor_limit(flag: &str, workers: usize, each: u64) -> Result<()> {
+    // Leaves room for stdio and whatever else the process has open.
+    const RESERVED: u64 = 16;
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit for getrlimit to fill in.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Ok(());
+    }
+    // rlim_t is not a u64 on every platform.
+    #[allow(clippy::unnecessary_cast)]
+    let available = (limit.rlim_cur as u64).saturating_sub(RESERVED);
+    if limit.rlim_cur != libc::RLIM_INFINITY && workers as u64 * each > available {
+        bail!(
+            "{} {} needs more file descriptors than the limit of {} allows; lower it or raise `ulimit -n`.",
+            flag,
+            workers,
+            limit.rlim_cur
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check_descriptor_limit(_flag: &str, _workers: usize, _each: u64) -> Result<()> {
+    Ok(())
+}
+
+/// Connects to `address` and closes the connection, after reading up to
+/// `banner` bytes when asked to.
+pub fn probe_tcp(
+    address: SocketAddr,
+    timeout: Option<Duration>,
+    banner: Option<usize>,
+) -> ScanResult {
+    let started = Instant::now();
+    let connected = match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+        None => TcpStream::connect(address),
+    };
+    let rtt = started.elapsed();
+    let (state, banner) = match connected {
+        Ok(stream) => {
+            let banner = banner.map(|limit| {
+                grab_banner(&stream, limit, timeout.unwrap_or(BANNER_WAIT)).unwrap_or_default()
+            });
+            (PortState::Open, banner)
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => (PortState::Closed, None),
+        Err(_) => (PortState::Filtered, None),
+    };
+    ScanResult {
+        address,
+        protocol: Protocol::Tcp,
+        state,
+        rtt,
+        banner,
+    }
+}
+
+/// How long a banner may take to arrive without a timeout given.
+pub const BANNER_WAIT: Duration = Duration::from_secs(2);
+/// The pause after which the data received so far is the whole banner.
+const BANNER_GAP: Duration = Duration::from_millis(250);
+
+/// Reads what `stream` sends on its own, up to `limit` bytes, waiting at most
+/// `wait` for it. A service that waits for the client to speak first gives an
+/// empty banner.
+pub fn grab_banner(stream: &TcpStream, limit: usize, wait: Duration) -> io::Result<Vec<u8>> {
+    let deadline = Instant::now() + wait;
+    let mut banner = vec![0u8; limit];
+    let mut len = 0;
+    while len < limit {
+        let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+            break;
+        };
+        // Once something arrived, a short pause means the service is done.
+        let pause = if len == 0 { left } else { left.min(BANNER_GAP) };
+        stream.set_read_timeout(Some(pause.max(Duration::from_millis(1))))?;
+        match (&*stream).read(&mut banner[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if is_timeout(&e) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            // What arrived before a reset is still the banner.
+            Err(_) if len > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    banner.truncate(len);
+    Ok(banner)
+}
+
+/// `banner` with backslashes, control characters and non-ASCII bytes
+/// escaped, so it fits on one line.
+pub fn escape(banner: &[u8]) -> String {
+    let mut escaped = String::with_capacity(banner.len());
+    for &b in banner {
+        match b {
+            b'\\' => escaped.push_str("\\\\"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\t' => escaped.push_str("\\t"),
+            b' '..=b'~' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    escaped
+}
+
+/// How long a UDP probe waits for an answer without a timeout given.
+const UDP_WAIT: Duration = Duration::from_secs(1);
+
+/// A DNS query for the root name servers.
+const DNS_PROBE: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x01";
+/// An NTP version 3 client request.
+const NTP_PROBE: &[u8] = &{
+    let mut request = [0u8; 48];
+    request[0] = 0x1b;
+    request
+};
+
+/// The datagram to send to `port`: a `--scan-probe` for it, a request its
+/// well-known service answers, or nothing at all.
+fn udp_probe(args: &Args, port: u16) -> &[u8] {
+    if let Some(probe) = args
+        .scan_probe
+        .iter()
+        .rev()
+        .find(|probe| probe.port == port)
+    {
+        return &probe.payload;
+    }
+    match port {
+        53 => DNS_PROBE,
+        123 => NTP_PROBE,
+        _ => &[],
+    }
+}
+
+/// Sends `payload` to `address` from a connected socket and waits up to
+/// `wait` for an answer or an ICMP error. Under `--banner` the answer, up to
+/// `banner` bytes of it, is the banner.
+pub fn probe_udp(
+    address: SocketAddr,
+    payload: &[u8],
+    wait: Duration,
+    banner: Option<usize>,
+) -> ScanResult {
+    let started = Instant::now();
+    let mut answer = vec![0u8; banner.unwrap_or(1).max(1)];
+    let (state, banner) = match exchange_probe(address, payload, wait, &mut answer) {
+        Ok(n) => (PortState::Open, banner.map(|_| answer[..n].to_vec())),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => (PortState::Closed, None),
+        Err(e) if is_timeout(&e) => (PortState::OpenFiltered, None),
+        Err(_) => (PortState::Filtered, None),
+    };
+    ScanResult {
+        address,
+        protocol: Protocol::Udp,
+        state,
+        rtt: started.elapsed(),
+        banner,
+    }
+}
+
+fn exchange_probe(
+    address: SocketAddr,
+    payload: &[u8],
+    wait: Duration,
+    answer: &mut [u8],
+) -> io::Result<usize> {
+    let socket = UdpSocket::bind(unspecified_address(address))?;
+    socket.connect(address)?;
+    socket.set_read_timeout(Some(wait))?;
+    socket.send(payload)?;
+    socket.recv(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_probe_tells_open_from_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let timeout = Some(Duration::from_secs(5));
+
+        let result = probe_tcp(open, timeout, None);
+        assert_eq!(result.state, PortState::Open);
+        assert_eq!(
+            result.to_string(),
+            format!("127.0.0.1 {}/tcp open", open.port())
+        );
+        assert_eq!(probe_tcp(closed, timeout, None).state, PortState::Closed);
+    }
+
+    #[test]
+    fn test_concurrent_scan_reports_in_port_order() {
+        let listeners: Vec<_> = (0..3)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let mut ports: Vec<u16> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap().port())
+            .collect();
+        let open = ports.clone();
+        // Closed ports around the open ones, as nothing else should listen there.
+        ports.extend(open.iter().map(|port| port.wrapping_add(1000)));
+        ports.sort_unstable();
+        ports.dedup();
+
+        let address = "127.0.0.1:0".parse().unwrap();
+        let mut output = Vec::new();
+        let timeout = Some(Duration::from_secs(5));
+        let exit_code = scan(address, &ports, 4, Format::Text, &mut output, |address| {
+            probe_tcp(address, timeout, None)
+        });
+        assert_eq!(exit_code.unwrap(), 0);
+        let lines: Vec<_> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        let reported: Vec<u16> = lines
+            .iter()
+            .map(|line| line.split([' ', '/']).nth(1).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(reported, ports);
+        for port in open {
+            assert!(lines.contains(&format!("127.0.0.1 {}/tcp open", port)));
+        }
+    }
+
+    #[test]
+    fn test_exit_code_reports_the_most_hopeful_port() {
+        let address = "192.0.2.7:0".parse().unwrap();
+        let exit_code = |states: &[(u16, PortState)]| {
+            let ports: Vec<u16> = states.iter().map(|(port, _)| *port).collect();
+            scan(
+                address,
+                &ports,
+                2,
+                Format::Text,
+                &mut Vec::new(),
+                |address| {
+                    let state = states.iter().find(|(port, _)| *port == address.port());
+                    ScanResult {
+                        address,
+                        protocol: Protocol::Tcp,
+                        state: state.unwrap().1,
+                        rtt: Duration::ZERO,
+                        banner: None,
+                    }
+                },
+            )
+            .unwrap()
+        };
+        use PortState::*;
+        assert_eq!(exit_code(&[(1, Filtered), (2, Closed), (3, Open)]), 0);
+        assert_eq!(exit_code(&[(1, OpenFiltered), (2, Filtered)]), 0);
+        assert_eq!(exit_code(&[(1, Filtered), (2, Closed)]), EXIT_CLOSED);
+        assert_eq!(exit_code(&[(1, Filtered)]), EXIT_TIMEOUT);
+    }
+
+    #[test]
+    fn test_udp_probe_classifies_ports() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let open = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            let (n, peer) = server.recv_from(&mut buffer).unwrap();
+            server.send_to(&buffer[..n], peer).unwrap();
+            // Keeps the port bound, but never answers again.
+            loop {
+                let _ = server.recv_from(&mut buffer);
+            }
+        });
+        let closed = {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.local_addr().unwrap()
+        };
+        let wait = Duration::from_millis(300);
+
+        let result = probe_udp(open, b"probe", wait, None);
+        assert_eq!(
+            result.to_string(),
+            format!("127.0.0.1 {}/udp open", open.port())
+        );
+        assert_eq!(
+            probe_udp(open, b"", wait, None).state,
+            PortState::OpenFiltered
+        );
+        assert_eq!(probe_udp(closed, b"", wait, None).state, PortState::Closed);
+    }
+
+    #[test]
+    fn test_udp_probes_follow_the_port() {
+        let args = Args::parse_from([
+            "test",
+            "-z",
+            "--protocol",
+            "udp",
+            "--scan-probe",
+            "53:00ff",
+            "--scan-probe",
+            "7:6563686f",
+            "localhost",
+            "1-100",
+        ]);
+        assert_eq!(udp_probe(&args, 53), [0x00, 0xff]);
+        assert_eq!(udp_probe(&args, 7), b"echo");
+        assert_eq!(udp_probe(&args, 123), NTP_PROBE);
+        assert!(udp_probe(&args, 9).is_empty());
+        assert_eq!(udp_probe(&Args::parse_from(["test"]), 53), DNS_PROBE);
+    }
+
+    fn sample_results() -> Vec<ScanResult> {
+        let result = |address: &str, protocol, state, micros| ScanResult {
+            address: address.parse().unwrap(),
+            protocol,
+            state,
+            rtt: Duration::from_micros(micros),
+            banner: None,
+        };
+        vec![
+            result("192.0.2.7:22", Protocol::Tcp, PortState::Open, 1234),
+            result("192.0.2.7:23", Protocol::Tcp, PortState::Closed, 310),
+            result("192.0.2.7:25", Protocol::Tcp, PortState::Filtered, 1000000),
+            result("[2001:db8::7]:53", Protocol::Udp, PortState::Open, 15500),
+            result(
+                "[2001:db8::7]:161",
+                Protocol::Udp,
+                PortState::OpenFiltered,
+                1000000,
+            ),
+            ScanResult {
+                banner: Some(b"220 mail ESMTP\\\r\n\xff".to_vec()),
+                ..result("192.0.2.7:587", Protocol::Tcp, PortState::Open, 800)
+            },
+        ]
+    }
+
+    fn written(format: Format) -> String {
+        let mut output = Vec::new();
+        for result in sample_results() {
+            write_result(&mut output, &result, format).unwrap();
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_json_output_matches_golden_file() {
+        assert_eq!(
+            written(Format::Json),
+            include_str!("../testdata/scan.jsonl")
+        );
+    }
+
+    #[test]
+    fn test_grepable_output_matches_golden_file() {
+        assert_eq!(
+            written(Format::Grepable),
+            include_str!("../testdata/scan.grepable")
+        );
+    }
+
+    #[test]
+    fn test_banner_is_what_the_service_sends_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut greeter, _) = listener.accept().unwrap();
+            greeter.write_all(b"SSH-2.0-test\r\n").unwrap();
+            // This one waits for the client to speak first.
+            let (_quiet, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+        let wait = Some(Duration::from_millis(500));
+
+        let result = probe_tcp(address, wait, Some(1024));
+        assert_eq!(result.banner.as_deref(), Some(&b"SSH-2.0-test\r\n"[..]));
+        assert!(result.to_string().ends_with("/tcp open SSH-2.0-test\\r\\n"));
+        let result = probe_tcp(address, wait, Some(1024));
+        assert_eq!(result.banner.as_deref(), Some(&b""[..]));
+    }
+
+    #[test]
+    fn test_escape_keeps_banners_on_one_line() {
+        assert_eq!(escape(b"220 ok\r\n"), "220 ok\\r\\n");
+        assert_eq!(escape(b"a\\b\tc\x00\xe9"), "a\\\\b\\tc\\x00\\xe9");
+    }
+}
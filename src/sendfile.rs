@@ -0,0 +1,81 @@
+//! Kernel-side file-to-socket copies with `sendfile(2)`.
+
+use std::{fs::File, io, net::TcpStream, os::fd::AsRawFd, ptr};
+
+/// Sends the rest of `file`, up to `limit` bytes, over `socket` without copying
+/// it through userspace, calling `on_chunk` with the size of every piece the
+/// kernel accepted.
+///
+/// Returns `Ok(None)` without sending anything when the kernel cannot
+/// `sendfile` from this file, so the caller can fall back to a normal copy.
+pub fn sendfile_all(
+    file: &File,
+    socket: &TcpStream,
+    chunk_size: usize,
+    limit: u64,
+    on_chunk: &mut dyn FnMut(usize),
+) -> io::Result<Option<u64>> {
+    let mut sent = 0u64;
+    while sent < limit {
+        let count = (limit - sent).min(chunk_size as u64) as usize;
+        // SAFETY: both descriptors stay open for the duration of the call, and a
+        // null offset makes the kernel use and advance the file's own offset.
+        let n =
+            unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), ptr::null_mut(), count) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EINVAL | libc::ENOSYS) if sent == 0 => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+        if n == 0 {
+            return Ok(Some(sent));
+        }
+        sent += n as u64;
+        on_chunk(n as usize);
+    }
+    Ok(Some(sent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    #[test]
+    fn test_sendfile_all_is_byte_identical() {
+        let path = std::env::temp_dir().join(format!("netpipe-{}-sendfile", std::process::id()));
+        let contents: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(&contents)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = listener.local_addr().unwrap();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let file = File::open(&path).unwrap();
+        let socket = TcpStream::connect(destination).unwrap();
+        let mut chunks = 0;
+        let sent = sendfile_all(&file, &socket, 64 * 1024, u64::MAX, &mut |_| chunks += 1).unwrap();
+        drop(socket);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(sent, Some(contents.len() as u64));
+        assert!(chunks > 1);
+        assert_eq!(server_handle.join().unwrap(), contents);
+    }
+}
@@ -0,0 +1,167 @@
+//! The `--sequenced` UDP framing: every datagram starts with a 4-byte
+//! big-endian sequence number, so that the listener can put a sender's
+//! datagrams back in order and count the ones that never arrived.
+//!
+//! Numbers start at 0 and wrap. A datagram that arrives early is held until
+//! the ones before it turn up; once too many are held, or the session ends,
+//! the missing ones are given up on as lost.
+
+use std::collections::HashMap;
+
+/// The length of the sequence number in front of each datagram.
+pub const HEADER: usize = 4;
+
+/// How many early datagrams a sender may have waiting before the gap ahead
+/// of them counts as lost.
+const WINDOW: usize = 256;
+
+/// Writes sequence number `number` in front of a datagram's payload.
+pub fn header(number: u32) -> [u8; HEADER] {
+    number.to_be_bytes()
+}
+
+/// One sender's datagrams on their way back into order.
+#[derive(Default)]
+pub struct Reassembly {
+    /// The number expected next, once the first datagram has set it.
+    next: Option<u32>,
+    /// Datagrams that arrived before `next`, by number.
+    held: HashMap<u32, Vec<u8>>,
+    lost: u64,
+    reordered: u64,
+    /// Datagrams that came after their turn had passed, or twice.
+    late: u64,
+}
+
+impl Reassembly {
+    /// Datagrams that never arrived, as far as gaps show.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Datagrams that arrived ahead of one sent before them.
+    pub fn reordered(&self) -> u64 {
+        self.reordered
+    }
+
+    /// Datagrams that were dropped for arriving after their turn, or twice.
+    pub fn late(&self) -> u64 {
+        self.late
+    }
+
+    /// Takes a datagram as received and returns the payloads it puts in
+    /// order, or `None` when it is too short to carry a sequence number.
+    pub fn push(&mut self, datagram: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let (number, payload) = datagram.split_first_chunk::<HEADER>()?;
+        let number = u32::from_be_bytes(*number);
+        let next = *self.next.get_or_insert(number);
+        // How far ahead of the expected one it is, in either direction.
+        let distance = number.wrapping_sub(next) as i32;
+        if distance < 0 || self.held.contains_key(&number) {
+            self.late += 1;
+            return Some(Vec::new());
+        }
+        if distance > 0 {
+            self.reordered += 1;
+        }
+        self.held.insert(number, payload.to_vec());
+        let mut ready = self.release();
+        if self.held.len() > WINDOW {
+            ready.extend(self.skip_gap());
+        }
+        Some(ready)
+    }
+
+    /// Gives up on the datagrams still missing and returns the ones held, in
+    /// order, as the session ends.
+    pub fn finish(&mut self) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+        while !self.held.is_empty() {
+            ready.extend(self.skip_gap());
+        }
+        ready
+    }
+
+    /// Hands out the held datagrams that follow on from `next`.
+    fn release(&mut self) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+        let Some(next) = &mut self.next else {
+            return ready;
+        };
+        while let Some(payload) = self.held.remove(next) {
+            ready.push(payload);
+            *next = next.wrapping_add(1);
+        }
+        ready
+    }
+
+    /// Counts the gap before the earliest held datagram as lost and hands
+    /// out the run that starts there.
+    fn skip_gap(&mut self) -> Vec<Vec<u8>> {
+        let Some(next) = self.next else {
+            return Vec::new();
+        };
+        let Some(earliest) = self
+            .held
+            .keys()
+            .map(|number| number.wrapping_sub(next))
+            .min()
+        else {
+            return Vec::new();
+        };
+        self.lost += u64::from(earliest);
+        self.next = Some(next.wrapping_add(earliest));
+        self.release()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(number: u32, payload: &str) -> Vec<u8> {
+        let mut datagram = header(number).to_vec();
+        datagram.extend_from_slice(payload.as_bytes());
+        datagram
+    }
+
+    fn push(reassembly: &mut Reassembly, number: u32, payload: &str) -> Vec<String> {
+        let ready = reassembly.push(&datagram(number, payload)).unwrap();
+        ready
+            .into_iter()
+            .map(|payload| String::from_utf8(payload).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_reassembly_restores_order_and_counts_gaps() {
+        let mut reassembly = Reassembly::default();
+        assert_eq!(push(&mut reassembly, 0, "a"), ["a"]);
+        assert!(push(&mut reassembly, 2, "c").is_empty());
+        assert_eq!(push(&mut reassembly, 1, "b"), ["b", "c"]);
+        assert!(push(&mut reassembly, 1, "b").is_empty());
+        // 3 never comes.
+        assert!(push(&mut reassembly, 5, "f").is_empty());
+        assert!(push(&mut reassembly, 4, "e").is_empty());
+        let rest: Vec<_> = reassembly.finish().concat();
+        assert_eq!(rest, b"ef");
+        assert_eq!(reassembly.lost(), 1);
+        assert_eq!(reassembly.reordered(), 3);
+        assert_eq!(reassembly.late(), 1);
+        assert!(reassembly.push(b"ab").is_none());
+    }
+
+    #[test]
+    fn test_reassembly_gives_up_when_the_window_fills() {
+        let mut reassembly = Reassembly::default();
+        assert_eq!(push(&mut reassembly, u32::MAX, "first"), ["first"]);
+        // 0 is lost; the numbers wrap around to 1 and on.
+        let mut ready = Vec::new();
+        for number in 1..=WINDOW as u32 + 1 {
+            ready.extend(push(&mut reassembly, number, "x"));
+        }
+        assert_eq!(ready.len(), WINDOW + 1);
+        assert_eq!(reassembly.lost(), 1);
+        assert_eq!(push(&mut reassembly, WINDOW as u32 + 2, "next"), ["next"]);
+    }
+}
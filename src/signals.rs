@@ -0,0 +1,67 @@
+//! Signal-driven statistics: SIGUSR1 dumps live counters, and SIGINT/SIGTERM
+//! report the sessions they interrupt or print what a scan or a `--targets`
+//! run got through so far.
+
+use crate::{
+    args::Args,
+    scan,
+    stats::{live_transfers, report_live, ExitReason},
+    targets,
+};
+use std::{io, mem, process, ptr, thread};
+
+/// Blocks the handled signals and waits for them on a dedicated thread.
+///
+/// SIGUSR1 writes the counters of every live session, like `dd` does. With
+/// stats enabled, SIGINT and SIGTERM report every live session before exiting
+/// like the default action would, as they do after printing the results a
+/// `--scan` or `--targets` run gathered.
+///
+/// Must be called before any other thread is spawned so they all inherit the
+/// blocked mask. Child processes get a fresh mask from `std::process`.
+pub fn watch(args: &Args) -> io::Result<()> {
+    let mut signals = vec![libc::SIGUSR1];
+    if args.stats_enabled() || args.scan || args.targets.is_some() {
+        signals.extend([libc::SIGINT, libc::SIGTERM]);
+    }
+    // SAFETY: the set is initialised by sigemptyset before being used.
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in signals {
+            libc::sigaddset(&mut set, signal);
+        }
+        set
+    };
+    // SAFETY: `set` is a valid signal set and the old mask is not needed.
+    let rc = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+
+    let args = args.clone();
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        // SAFETY: `set` and `signal` outlive the call.
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+            continue;
+        }
+        if signal == libc::SIGUSR1 {
+            report_live(&args);
+            continue;
+        }
+        if args.scan {
+            scan::print_gathered(&args);
+        }
+        if args.targets.is_some() {
+            targets::print_gathered();
+        }
+        if args.stats_enabled() {
+            for transfer in live_transfers() {
+                transfer.report(ExitReason::Signal, &args);
+            }
+        }
+        process::exit(128 + signal);
+    });
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Source-specific multicast joins on Linux, for `--multicast-source`: the
+//! listener asks for a group's traffic from one sender only, which is all an
+//! SSM network delivers.
+//!
+//! std has no such joins, so the requests of RFC 3678 are built here and set
+//! with `setsockopt`.
+
+use std::{
+    io, mem,
+    net::{IpAddr, Ipv4Addr},
+    os::fd::AsRawFd,
+    ptr,
+};
+
+fn set_option<T>(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    // SAFETY: the descriptor is open and `value` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `ip` as the socket address of a membership request, with no port.
+fn socket_address(ip: IpAddr) -> libc::sockaddr_storage {
+    // SAFETY: sockaddr_storage is plain data that all-zero bytes make valid,
+    // and it is large and aligned enough for either address type.
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        match ip {
+            IpAddr::V4(ip) => {
+                let mut address: libc::sockaddr_in = mem::zeroed();
+                address.sin_family = libc::AF_INET as libc::sa_family_t;
+                address.sin_addr.s_addr = u32::from_ne_bytes(ip.octets());
+                ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, address);
+            }
+            IpAddr::V6(ip) => {
+                let mut address: libc::sockaddr_in6 = mem::zeroed();
+                address.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                address.sin6_addr.s6_addr = ip.octets();
+                ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, address);
+            }
+        }
+        storage
+    }
+}
+
+/// The `MCAST_JOIN_SOURCE_GROUP` request for `group` from `source` on the
+/// interface with index `interface`, 0 letting the kernel pick.
+fn group_source_request(group: IpAddr, source: IpAddr, interface: u32) -> libc::group_source_req {
+    libc::group_source_req {
+        gsr_interface: interface,
+        gsr_group: socket_address(group),
+        gsr_source: socket_address(source),
+    }
+}
+
+/// The `IP_ADD_SOURCE_MEMBERSHIP` request for the IPv4 `group` from `source`
+/// on the interface with address `interface`.
+fn ipv4_source_request(
+    group: Ipv4Addr,
+    source: Ipv4Addr,
+    interface: Ipv4Addr,
+) -> libc::ip_mreq_source {
+    let address = |ip: Ipv4Addr| libc::in_addr {
+        s_addr: u32::from_ne_bytes(ip.octets()),
+    };
+    libc::ip_mreq_source {
+        imr_multiaddr: address(group),
+        imr_interface: address(interface),
+        imr_sourceaddr: address(source),
+    }
+}
+
+/// Joins or leaves `group` for the traffic from `source` on the interface
+/// with index `interface`. The group and source must be of the socket's IP
+/// version.
+pub fn update(
+    socket: &impl AsRawFd,
+    group: IpAddr,
+    source: IpAddr,
+    interface: u32,
+    join: bool,
+) -> io::Result<()> {
+    let level = match group {
+        IpAddr::V4(_) => libc::IPPROTO_IP,
+        IpAddr::V6(_) => libc::IPPROTO_IPV6,
+    };
+    let name = if join {
+        libc::MCAST_JOIN_SOURCE_GROUP
+    } else {
+        libc::MCAST_LEAVE_SOURCE_GROUP
+    };
+    set_option(
+        socket,
+        level,
+        name,
+        &group_source_request(group, source, interface),
+    )
+}
+
+/// Joins or leaves the IPv4 `group` for the traffic from `source` on the
+/// interface with address `interface`, for `--multicast-if`.
+pub fn update_v4(
+    socket: &impl AsRawFd,
+    group: Ipv4Addr,
+    source: Ipv4Addr,
+    interface: Ipv4Addr,
+    join: bool,
+) -> io::Result<()> {
+    let name = if join {
+        libc::IP_ADD_SOURCE_MEMBERSHIP
+    } else {
+        libc::IP_DROP_SOURCE_MEMBERSHIP
+    };
+    set_option(
+        socket,
+        libc::IPPROTO_IP,
+        name,
+        &ipv4_source_request(group, source, interface),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::Ipv6Addr, slice};
+
+    fn bytes<T>(value: &T) -> &[u8] {
+        // SAFETY: any initialized value can be read as bytes.
+        unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    #[test]
+    fn test_ipv4_source_request_layout() {
+        let request = ipv4_source_request(
+            Ipv4Addr::new(232, 1, 2, 3),
+            Ipv4Addr::new(192, 0, 2, 10),
+            Ipv4Addr::new(10, 0, 0, 1),
+        );
+        // Group, interface, then source, each in network order.
+        assert_eq!(bytes(&request), [232, 1, 2, 3, 10, 0, 0, 1, 192, 0, 2, 10]);
+    }
+
+    #[test]
+    fn test_group_source_request_layout() {
+        // The interface, padded to the alignment of the two addresses.
+        #[cfg(target_pointer_width = "64")]
+        {
+            assert_eq!(mem::offset_of!(libc::group_source_req, gsr_group), 8);
+            assert_eq!(mem::offset_of!(libc::group_source_req, gsr_source), 136);
+            assert_eq!(mem::size_of::<libc::group_source_req>(), 264);
+        }
+        let request = group_source_request(
+            Ipv4Addr::new(232, 1, 2, 3).into(),
+            Ipv4Addr::new(192, 0, 2, 10).into(),
+            7,
+        );
+        assert_eq!(request.gsr_interface, 7);
+        let group = bytes(&request.gsr_group);
+        assert_eq!(group[..2], (libc::AF_INET as u16).to_ne_bytes());
+        // sin_port, then sin_addr.
+        assert_eq!(group[2..8], [0, 0, 232, 1, 2, 3]);
+        assert_eq!(bytes(&request.gsr_source)[4..8], [192, 0, 2, 10]);
+
+        let group_v6: Ipv6Addr = "ff3e::8000:1".parse().unwrap();
+        let source_v6: Ipv6Addr = "2001:db8::10".parse().unwrap();
+        let request = group_source_request(group_v6.into(), source_v6.into(), 0);
+        let group = bytes(&request.gsr_group);
+        assert_eq!(group[..2], (libc::AF_INET6 as u16).to_ne_bytes());
+        // sin6_port and sin6_flowinfo come before sin6_addr.
+        assert_eq!(group[8..24], group_v6.octets());
+        assert_eq!(bytes(&request.gsr_source)[8..24], source_v6.octets());
+    }
+}
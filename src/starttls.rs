@@ -0,0 +1,242 @@
+//! Plaintext preambles that upgrade a TCP connection to TLS, for `--starttls`
+//! and `--starttls-after`.
+//!
+//! Replies are read one byte at a time so that nothing after the go-ahead is
+//! consumed: those bytes belong to the TLS handshake, and none of the
+//! preamble reaches the output.
+
+use crate::args::{Args, StartTls};
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, TcpStream},
+};
+
+/// Longer lines are not part of any dialogue we speak.
+const MAX_LINE: usize = 8 * 1024;
+
+/// The tag of our IMAP command.
+const IMAP_TAG: &str = "A1";
+
+/// Runs the plaintext part of a client connection up to the point where the
+/// TLS handshake starts, if `--starttls` or `--starttls-after` asks for one.
+pub fn negotiate(stream: &mut TcpStream, args: &Args) -> Result<()> {
+    match (args.starttls, &args.starttls_after) {
+        (Some(StartTls::Smtp), _) => {
+            let name = ehlo_name(stream.local_addr()?.ip());
+            smtp(stream, &name).context("SMTP STARTTLS failed")?
+        }
+        (Some(StartTls::Imap), _) => imap(stream).context("IMAP STARTTLS failed")?,
+        (None, Some(trigger)) => wait_for(stream, trigger)
+            .with_context(|| format!("Waiting for {:?} before TLS failed", trigger))?,
+        (None, None) => return Ok(()),
+    }
+    info!("The server is ready for TLS");
+    Ok(())
+}
+
+/// Reads one line, without its line ending.
+fn read_line<S: Read>(stream: &mut S) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the server closed the connection before upgrading to TLS",
+            )
+            .into());
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() == MAX_LINE {
+            bail!("The server sent a line longer than {} bytes", MAX_LINE);
+        }
+        line.push(byte[0]);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    let line = String::from_utf8_lossy(&line).into_owned();
+    debug!("< {}", line);
+    Ok(line)
+}
+
+fn send_line<S: Write>(stream: &mut S, line: &str) -> Result<()> {
+    debug!("> {}", line);
+    stream.write_all(format!("{}\r\n", line).as_bytes())?;
+    Ok(())
+}
+
+/// The RFC 5321 address literal of our own address, which is what a client
+/// without a domain name of its own greets with.
+fn ehlo_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("[{}]", ip),
+        IpAddr::V6(ip) => format!("[IPv6:{}]", ip),
+    }
+}
+
+/// Reads an SMTP reply, which spans lines such as `250-...` up to one with a
+/// space after the code, and fails unless its code is `expected`.
+fn smtp_reply<S: Read>(stream: &mut S, expected: u16, step: &str) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    loop {
+        let line = read_line(stream)?;
+        let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+        let Some(code) = code else {
+            bail!("The server sent {:?}, which is not an SMTP reply", line);
+        };
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        if last && code != expected {
+            bail!("The server answered {} with {:?}", step, line);
+        }
+        lines.push(line);
+        if last {
+            return Ok(lines);
+        }
+    }
+}
+
+fn smtp<S: Read + Write>(stream: &mut S, name: &str) -> Result<()> {
+    smtp_reply(stream, 220, "the connection")?;
+    send_line(stream, &format!("EHLO {}", name))?;
+    let extensions = smtp_reply(stream, 250, "EHLO")?;
+    // The first line greets us; the others list one extension each.
+    if !extensions
+        .iter()
+        .skip(1)
+        .any(|line| line.get(4..).unwrap_or("").split_whitespace().next() == Some("STARTTLS"))
+    {
+        bail!("The server does not offer STARTTLS");
+    }
+    send_line(stream, "STARTTLS")?;
+    smtp_reply(stream, 220, "STARTTLS")?;
+    Ok(())
+}
+
+fn imap<S: Read + Write>(stream: &mut S) -> Result<()> {
+    let greeting = read_line(stream)?;
+    if !greeting.starts_with("* OK") {
+        bail!("The server greeted us with {:?}", greeting);
+    }
+    send_line(stream, &format!("{} STARTTLS", IMAP_TAG))?;
+    loop {
+        let line = read_line(stream)?;
+        // Untagged responses such as capabilities may come first.
+        let Some(status) = line.strip_prefix(&format!("{} ", IMAP_TAG)) else {
+            continue;
+        };
+        if !status.starts_with("OK") {
+            bail!("The server answered STARTTLS with {:?}", line);
+        }
+        return Ok(());
+    }
+}
+
+/// Skips the server's lines up to and including one that contains `trigger`.
+fn wait_for<S: Read>(stream: &mut S, trigger: &str) -> Result<()> {
+    while !read_line(stream)?.contains(trigger) {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Plays the server side of a dialogue: everything the client will read,
+    /// and whatever it writes.
+    struct Script {
+        replies: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Script {
+        fn new(replies: &[u8]) -> Self {
+            Script {
+                replies: Cursor::new(replies.to_vec()),
+                sent: Vec::new(),
+            }
+        }
+
+        /// The bytes the dialogue left unread.
+        fn rest(&self) -> &[u8] {
+            &self.replies.get_ref()[self.replies.position() as usize..]
+        }
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_smtp_dialogue_stops_at_the_go_ahead() {
+        let mut script = Script::new(
+            b"220-mail.example ESMTP\r\n220 ready\r\n\
+              250-mail.example greets you\r\n250-PIPELINING\r\n250 STARTTLS\r\n\
+              220 Go ahead\r\n\x16\x03\x01",
+        );
+        smtp(&mut script, "[127.0.0.1]").unwrap();
+        assert_eq!(script.sent, b"EHLO [127.0.0.1]\r\nSTARTTLS\r\n");
+        assert_eq!(script.rest(), b"\x16\x03\x01");
+
+        let mut script = Script::new(b"220 ready\r\n250-mail.example\r\n250 SIZE 100\r\n");
+        let err = smtp(&mut script, "[127.0.0.1]").unwrap_err();
+        assert_eq!(err.to_string(), "The server does not offer STARTTLS");
+
+        let mut script =
+            Script::new(b"220 ready\r\n250-x\r\n250 STARTTLS\r\n454 TLS not available\r\n");
+        let err = smtp(&mut script, "[127.0.0.1]").unwrap_err();
+        assert!(err.to_string().contains("454 TLS not available"), "{}", err);
+
+        let mut script = Script::new(b"220 ready\r\n");
+        assert!(smtp(&mut script, "[127.0.0.1]").is_err());
+    }
+
+    #[test]
+    fn test_imap_dialogue_stops_at_the_go_ahead() {
+        let mut script = Script::new(
+            b"* OK IMAP4rev1 ready\r\n* CAPABILITY IMAP4rev1\r\nA1 OK Begin TLS\r\n\x16",
+        );
+        imap(&mut script).unwrap();
+        assert_eq!(script.sent, b"A1 STARTTLS\r\n");
+        assert_eq!(script.rest(), b"\x16");
+
+        let mut script = Script::new(b"* OK ready\r\nA1 BAD unknown command\r\n");
+        assert!(imap(&mut script).is_err());
+        let mut script = Script::new(b"* BYE busy\r\n");
+        assert!(imap(&mut script).is_err());
+    }
+
+    #[test]
+    fn test_wait_for_trigger() {
+        let mut script = Script::new(b"hello\nproceed with TLS\r\nafter");
+        wait_for(&mut script, "proceed").unwrap();
+        assert_eq!(script.rest(), b"after");
+        assert!(script.sent.is_empty());
+
+        let mut script = Script::new(b"hello\n");
+        assert!(wait_for(&mut script, "proceed").is_err());
+    }
+
+    #[test]
+    fn test_ehlo_name() {
+        assert_eq!(ehlo_name("192.0.2.1".parse().unwrap()), "[192.0.2.1]");
+        assert_eq!(ehlo_name("::1".parse().unwrap()), "[IPv6:::1]");
+    }
+}
@@ -0,0 +1,678 @@
+//! Per-session byte counters and the `--stats` summary.
+
+use crate::{
+    args::{Args, Protocol, StatsFormat},
+    formats::{self, Line},
+    progress::format_bytes,
+};
+use log::error;
+use serde_json::{json, Value};
+use std::{
+    fs::OpenOptions,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Transfers that have not been reported yet, so a signal can still report them.
+static LIVE: Mutex<Vec<Weak<Transfer>>> = Mutex::new(Vec::new());
+/// Sessions started since netpipe was launched.
+static STARTED: AtomicU64 = AtomicU64::new(0);
+
+/// Why a session ended, as reported in the `exit_reason` field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    Eof,
+    Timeout,
+    Reset,
+    Signal,
+    TimeLimit,
+    Error,
+}
+
+impl ExitReason {
+    /// Classifies the error that ended a session.
+    pub fn of(err: &anyhow::Error) -> Self {
+        let kind = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>())
+            .map(io::Error::kind);
+        match kind {
+            Some(io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => ExitReason::Timeout,
+            Some(
+                io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe,
+            ) => ExitReason::Reset,
+            _ => ExitReason::Error,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ExitReason::Eof => "eof",
+            ExitReason::Timeout => "timeout",
+            ExitReason::Reset => "reset",
+            ExitReason::Signal => "signal",
+            ExitReason::TimeLimit => "time_limit",
+            ExitReason::Error => "error",
+        }
+    }
+}
+
+/// How a TCP session under `--linger` closed, as the summary notes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Close {
+    /// Closed at EOF, after the unsent data went out.
+    Graceful,
+    /// Closed at EOF with `--linger 0`, which resets the connection.
+    Abort,
+    /// Reset by the peer before we closed it.
+    Reset,
+}
+
+impl Close {
+    /// Only a session that reached EOF closed the way `--linger` asks. One
+    /// that ended otherwise was reset, or says nothing about its close.
+    fn of(protocol: Protocol, reason: ExitReason, args: &Args) -> Option<Self> {
+        if protocol != Protocol::Tcp {
+            return None;
+        }
+        match (reason, args.linger?) {
+            (ExitReason::Eof, 0) => Some(Close::Abort),
+            (ExitReason::Eof, _) => Some(Close::Graceful),
+            (ExitReason::Reset, _) => Some(Close::Reset),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Close::Graceful => "graceful",
+            Close::Abort => "abort",
+            Close::Reset => "reset",
+        }
+    }
+}
+
+/// Bytes exchanged with one peer, updated as the copy loops run so a session
+/// cut short by an error still reports how far it got.
+pub struct Transfer {
+    peer: SocketAddr,
+    protocol: Protocol,
+    started: Instant,
+    sent: AtomicU64,
+    received: AtomicU64,
+    reported: AtomicBool,
+    /// Whether a `--mirror` copy is attached, and the bytes it had to drop.
+    mirrored: AtomicBool,
+    mirror_dropped: AtomicU64,
+    /// Whether the datagrams are `--sequenced`, and the gaps found in them.
+    sequenced: AtomicBool,
+    lost: AtomicU64,
+    reordered: AtomicU64,
+    /// Whether NAT keepalives are sent or dropped, and how many there were.
+    keepalive: AtomicBool,
+    keepalives: AtomicU64,
+    keepalive_bytes: AtomicU64,
+    /// Whether the kernel's drops on the socket are known, and how many.
+    drops_known: AtomicBool,
+    kernel_drops: AtomicU64,
+    /// Whether cut-short datagrams are detected, and how many there were.
+    truncation_known: AtomicBool,
+    truncated: AtomicU64,
+}
+
+impl Transfer {
+    pub fn new(peer: SocketAddr, protocol: Protocol) -> Arc<Self> {
+        let transfer = Arc::new(Transfer {
+            peer,
+            protocol,
+            started: Instant::now(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+            mirrored: AtomicBool::new(false),
+            mirror_dropped: AtomicU64::new(0),
+            sequenced: AtomicBool::new(false),
+            lost: AtomicU64::new(0),
+            reordered: AtomicU64::new(0),
+            keepalive: AtomicBool::new(false),
+            keepalives: AtomicU64::new(0),
+            keepalive_bytes: AtomicU64::new(0),
+            drops_known: AtomicBool::new(false),
+            kernel_drops: AtomicU64::new(0),
+            truncation_known: AtomicBool::new(false),
+            truncated: AtomicU64::new(0),
+        });
+        let mut live = LIVE.lock().unwrap();
+        live.retain(|weak| weak.strong_count() > 0);
+        live.push(Arc::downgrade(&transfer));
+        STARTED.fetch_add(1, Ordering::Relaxed);
+        transfer
+    }
+
+    pub fn add_sent(&self, n: u64) {
+        self.sent.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, n: u64) {
+        self.received.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Includes the mirror's drop counter in the summary.
+    pub fn attach_mirror(&self) {
+        self.mirrored.store(true, Ordering::Relaxed);
+    }
+
+    pub fn add_mirror_dropped(&self, n: u64) {
+        self.mirror_dropped.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Bytes the mirror could not keep up with, if there is a mirror.
+    pub fn mirror_dropped(&self) -> Option<u64> {
+        self.mirrored
+            .load(Ordering::Relaxed)
+            .then(|| self.mirror_dropped.load(Ordering::Relaxed))
+    }
+
+    /// Records how many of a `--sequenced` sender's datagrams were lost and
+    /// reordered so far, including the counts in the summary.
+    pub fn set_sequence_gaps(&self, lost: u64, reordered: u64) {
+        self.lost.store(lost, Ordering::Relaxed);
+        self.reordered.store(reordered, Ordering::Relaxed);
+        self.sequenced.store(true, Ordering::Relaxed);
+    }
+
+    /// The lost and reordered datagrams, if they are sequenced.
+    fn sequence_gaps(&self) -> Option<(u64, u64)> {
+        self.sequenced.load(Ordering::Relaxed).then(|| {
+            (
+                self.lost.load(Ordering::Relaxed),
+                self.reordered.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// Includes the NAT keepalive counters in the summary.
+    pub fn count_keepalives(&self) {
+        self.keepalive.store(true, Ordering::Relaxed);
+    }
+
+    /// Counts a keepalive datagram of `n` bytes, which is not payload.
+    pub fn add_keepalive(&self, n: u64) {
+        self.keepalives.fetch_add(1, Ordering::Relaxed);
+        self.keepalive_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The keepalive datagrams and their bytes, if they are counted.
+    fn keepalives(&self) -> Option<(u64, u64)> {
+        self.keepalive.load(Ordering::Relaxed).then(|| {
+            (
+                self.keepalives.load(Ordering::Relaxed),
+                self.keepalive_bytes.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// Records how many datagrams the kernel has dropped on the receiving
+    /// socket, for want of buffer space, including the count in the summary.
+    /// The socket may be shared with other peers.
+    pub fn set_kernel_drops(&self, n: u64) {
+        self.kernel_drops.store(n, Ordering::Relaxed);
+        self.drops_known.store(true, Ordering::Relaxed);
+    }
+
+    fn kernel_drops(&self) -> Option<u64> {
+        self.drops_known
+            .load(Ordering::Relaxed)
+            .then(|| self.kernel_drops.load(Ordering::Relaxed))
+    }
+
+    /// Includes the count of truncated datagrams in the summary.
+    pub fn count_truncations(&self) {
+        self.truncation_known.store(true, Ordering::Relaxed);
+    }
+
+    /// Counts a datagram that was too large for the receive buffer.
+    pub fn add_truncated(&self) {
+        self.truncated.fetch_add(1, Ordering::Relaxed);
+        self.truncation_known.store(true, Ordering::Relaxed);
+    }
+
+    fn truncated(&self) -> Option<u64> {
+        self.truncation_known
+            .load(Ordering::Relaxed)
+            .then(|| self.truncated.load(Ordering::Relaxed))
+    }
+
+    /// Writes the summary of the session to stderr or `--stats-file`, in
+    /// `--stats-format`. Only the first call for a session does anything.
+    pub fn report(&self, reason: ExitReason, args: &Args) {
+        if self.reported.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        write_stats(&self.line(Some(reason), args), args);
+    }
+
+    /// Writes the counters so far without ending the session.
+    pub fn report_progress(&self, args: &Args) {
+        if !self.reported.load(Ordering::SeqCst) {
+            write_stats(&self.line(None, args), args);
+        }
+    }
+
+    /// The summary in `--stats-format`; `reason` is `None` while the session
+    /// is still running.
+    fn line(&self, reason: Option<ExitReason>, args: &Args) -> String {
+        let summary = Summary {
+            transfer: self,
+            elapsed: self.started.elapsed(),
+            reason,
+            close: reason.and_then(|reason| Close::of(self.protocol, reason, args)),
+        };
+        formats::line(&summary, args.stats_format.into())
+    }
+
+    fn summary(&self, elapsed: Duration, reason: Option<ExitReason>) -> String {
+        let (sent, received) = (self.sent(), self.received());
+        let secs = elapsed.as_secs_f64();
+        let rate = if secs > 0.0 {
+            (sent + received) as f64 / secs
+        } else {
+            0.0
+        };
+        let mut line = format!(
+            "{}: sent {} ({} bytes), received {} ({} bytes) in {:.2}s, {}/s",
+            self.peer,
+            format_bytes(sent),
+            sent,
+            format_bytes(received),
+            received,
+            secs,
+            format_bytes(rate as u64)
+        );
+        if let Some(dropped) = self.mirror_dropped() {
+            line += &format!(", mirror dropped {} bytes", dropped);
+        }
+        if let Some((lost, reordered)) = self.sequence_gaps() {
+            line += &format!(", {} datagrams lost, {} reordered", lost, reordered);
+        }
+        if let Some((keepalives, bytes)) = self.keepalives() {
+            line += &format!(", {} keepalives ({} bytes)", keepalives, bytes);
+        }
+        if let Some(drops) = self.kernel_drops() {
+            line += &format!(", kernel dropped {} datagrams", drops);
+        }
+        if let Some(truncated) = self.truncated() {
+            line += &format!(", {} datagrams truncated", truncated);
+        }
+        match reason {
+            Some(ExitReason::Eof) => {}
+            Some(reason) => line += &format!(" ({})", reason.name()),
+            None => line += " so far",
+        }
+        line
+    }
+
+    fn to_json(&self, elapsed: Duration, reason: Option<ExitReason>) -> Value {
+        let mut value = json!({
+            "peer": self.peer.to_string(),
+            "protocol": self.protocol.to_string(),
+            "bytes_tx": self.sent(),
+            "bytes_rx": self.received(),
+            "duration_ms": elapsed.as_millis() as u64,
+            "exit_reason": reason.map(ExitReason::name),
+        });
+        if let Some(dropped) = self.mirror_dropped() {
+            value["mirror_dropped"] = dropped.into();
+        }
+        if let Some((lost, reordered)) = self.sequence_gaps() {
+            value["datagrams_lost"] = lost.into();
+            value["datagrams_reordered"] = reordered.into();
+        }
+        if let Some((keepalives, bytes)) = self.keepalives() {
+            value["keepalives"] = keepalives.into();
+            value["keepalive_bytes"] = bytes.into();
+        }
+        if let Some(drops) = self.kernel_drops() {
+            value["kernel_drops"] = drops.into();
+        }
+        if let Some(truncated) = self.truncated() {
+            value["datagrams_truncated"] = truncated.into();
+        }
+        value
+    }
+}
+
+/// A transfer as of one moment, for [`formats::line`].
+struct Summary<'a> {
+    transfer: &'a Transfer,
+    elapsed: Duration,
+    reason: Option<ExitReason>,
+    /// How the session closed, once it has and under `--linger`.
+    close: Option<Close>,
+}
+
+impl Line for Summary<'_> {
+    fn text(&self) -> String {
+        let line = self.transfer.summary(self.elapsed, self.reason);
+        match self.close {
+            Some(Close::Graceful) => line + ", closed gracefully",
+            Some(Close::Abort) => line + ", closed with a reset",
+            Some(Close::Reset) => line + ", reset by the peer",
+            None => line,
+        }
+    }
+
+    fn json(&self) -> Value {
+        let mut value = self.transfer.to_json(self.elapsed, self.reason);
+        if let Some(close) = self.close {
+            value["close"] = close.name().into();
+        }
+        value
+    }
+}
+
+/// Writes one stats line to `--stats-file`, or stderr.
+fn write_stats(line: &str, args: &Args) {
+    let result = match &args.stats_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", line)),
+        None if args.stats_format == StatsFormat::Json => writeln!(io::stderr(), "{}", line),
+        None => writeln!(io::stderr(), "netpipe: {}", line),
+    };
+    if let Err(e) = result {
+        error!("Failed to write transfer statistics: {}", e);
+    }
+}
+
+/// Transfers still in progress, for reporting them when a signal arrives.
+pub fn live_transfers() -> Vec<Arc<Transfer>> {
+    LIVE.lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+/// Writes the counters of every session in progress, like `dd` on SIGUSR1.
+pub fn report_live(args: &Args) {
+    let live: Vec<_> = live_transfers()
+        .into_iter()
+        .filter(|transfer| !transfer.reported.load(Ordering::SeqCst))
+        .collect();
+    if args.stats_format == StatsFormat::Text {
+        write_stats(
+            &format!(
+                "{} active of {} sessions",
+                live.len(),
+                STARTED.load(Ordering::Relaxed)
+            ),
+            args,
+        );
+    }
+    for transfer in live {
+        transfer.report_progress(args);
+    }
+}
+
+/// Reports the sessions in progress every `interval` on a background thread.
+pub fn report_every(interval: Duration, args: &Args) {
+    let args = args.clone();
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        report_live(&args);
+    });
+}
+
+/// Wraps a connection to the peer, counting reads as received and writes as
+/// sent.
+pub struct Counted<T> {
+    inner: T,
+    transfer: Arc<Transfer>,
+}
+
+impl<T> Counted<T> {
+    pub fn new(inner: T, transfer: Arc<Transfer>) -> Self {
+        Counted { inner, transfer }
+    }
+}
+
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.transfer.add_received(n as u64);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.transfer.add_sent(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use clap::Parser;
+
+    fn sample_transfer() -> Arc<Transfer> {
+        let transfer = Transfer::new("127.0.0.1:5000".parse().unwrap(), Protocol::Tcp);
+        transfer.add_sent(3 * 1024 * 1024);
+        transfer.add_received(1024 * 1024);
+        transfer
+    }
+
+    #[test]
+    fn test_summary_line() {
+        let transfer = sample_transfer();
+        assert_eq!(
+            transfer.summary(Duration::from_secs(2), Some(ExitReason::Eof)),
+            "127.0.0.1:5000: sent 3.0 MiB (3145728 bytes), received 1.0 MiB (1048576 bytes) in 2.00s, 2.0 MiB/s"
+        );
+        assert!(transfer
+            .summary(Duration::from_secs(2), Some(ExitReason::Reset))
+            .ends_with("2.0 MiB/s (reset)"));
+        assert!(transfer
+            .summary(Duration::from_secs(2), None)
+            .ends_with("2.0 MiB/s so far"));
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let value =
+            sample_transfer().to_json(Duration::from_millis(1500), Some(ExitReason::Timeout));
+        assert_eq!(
+            value,
+            json!({
+                "peer": "127.0.0.1:5000",
+                "protocol": "tcp",
+                "bytes_tx": 3145728,
+                "bytes_rx": 1048576,
+                "duration_ms": 1500,
+                "exit_reason": "timeout",
+            })
+        );
+    }
+
+    #[test]
+    fn test_mirror_drops_are_reported_once_attached() {
+        let transfer = sample_transfer();
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert!(value.get("mirror_dropped").is_none());
+        transfer.attach_mirror();
+        transfer.add_mirror_dropped(4096);
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert_eq!(value["mirror_dropped"], 4096);
+        assert!(transfer
+            .summary(Duration::from_secs(2), Some(ExitReason::Eof))
+            .ends_with("2.0 MiB/s, mirror dropped 4096 bytes"));
+    }
+
+    #[test]
+    fn test_sequence_gaps_are_reported_once_sequenced() {
+        let transfer = sample_transfer();
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert!(value.get("datagrams_lost").is_none());
+        transfer.set_sequence_gaps(2, 5);
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert_eq!(value["datagrams_lost"], 2);
+        assert_eq!(value["datagrams_reordered"], 5);
+        assert!(transfer
+            .summary(Duration::from_secs(2), Some(ExitReason::Eof))
+            .ends_with("2.0 MiB/s, 2 datagrams lost, 5 reordered"));
+    }
+
+    #[test]
+    fn test_keepalives_are_counted_apart_from_payload() {
+        let transfer = sample_transfer();
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert!(value.get("keepalives").is_none());
+        let received = transfer.received();
+        transfer.count_keepalives();
+        transfer.add_keepalive(1);
+        transfer.add_keepalive(1);
+        assert_eq!(transfer.received(), received);
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert_eq!(value["keepalives"], 2);
+        assert_eq!(value["keepalive_bytes"], 2);
+        assert!(transfer
+            .summary(Duration::from_secs(2), Some(ExitReason::Eof))
+            .ends_with("2.0 MiB/s, 2 keepalives (2 bytes)"));
+    }
+
+    #[test]
+    fn test_kernel_drops_are_reported_once_known() {
+        let transfer = sample_transfer();
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert!(value.get("kernel_drops").is_none());
+        transfer.set_kernel_drops(0);
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert_eq!(value["kernel_drops"], 0);
+        transfer.set_kernel_drops(17);
+        assert!(transfer
+            .summary(Duration::from_secs(2), Some(ExitReason::Eof))
+            .ends_with("2.0 MiB/s, kernel dropped 17 datagrams"));
+    }
+
+    #[test]
+    fn test_truncated_datagrams_are_reported_once_counted() {
+        let transfer = sample_transfer();
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert!(value.get("datagrams_truncated").is_none());
+        transfer.add_truncated();
+        let value = transfer.to_json(Duration::from_secs(1), Some(ExitReason::Eof));
+        assert_eq!(value["datagrams_truncated"], 1);
+        assert!(transfer
+            .summary(Duration::from_secs(2), Some(ExitReason::Eof))
+            .ends_with("2.0 MiB/s, 1 datagrams truncated"));
+    }
+
+    #[test]
+    fn test_linger_notes_how_the_session_closed() {
+        let transfer = sample_transfer();
+        let args = Args::parse_from(["test", "--linger", "0"]);
+        assert!(transfer
+            .line(Some(ExitReason::Eof), &args)
+            .ends_with("/s, closed with a reset"));
+        assert!(transfer.line(None, &args).ends_with("so far"));
+        let args = Args::parse_from(["test", "--linger", "5", "--stats-format", "json"]);
+        let value: Value =
+            serde_json::from_str(&transfer.line(Some(ExitReason::Eof), &args)).unwrap();
+        assert_eq!(value["close"], "graceful");
+
+        // Sessions that did not reach EOF did not close the way --linger asks.
+        let value: Value =
+            serde_json::from_str(&transfer.line(Some(ExitReason::Reset), &args)).unwrap();
+        assert_eq!(value["close"], "reset");
+        for reason in [ExitReason::Timeout, ExitReason::Error, ExitReason::Signal] {
+            let value: Value = serde_json::from_str(&transfer.line(Some(reason), &args)).unwrap();
+            assert!(value.get("close").is_none());
+        }
+        let args = Args::parse_from(["test", "--linger", "0"]);
+        assert!(transfer
+            .line(Some(ExitReason::Reset), &args)
+            .ends_with("(reset), reset by the peer"));
+        assert!(transfer
+            .line(Some(ExitReason::Timeout), &args)
+            .ends_with("(timeout)"));
+    }
+
+    #[test]
+    fn test_report_appends_json_to_stats_file() {
+        let path = std::env::temp_dir().join(format!("netpipe-{}-stats", std::process::id()));
+        let args = Args::parse_from([
+            "test",
+            "--stats-format",
+            "json",
+            "--stats-file",
+            path.to_str().unwrap(),
+        ]);
+        let transfer = sample_transfer();
+        transfer.report_progress(&args);
+        transfer.report(ExitReason::Eof, &args);
+        transfer.report(ExitReason::Signal, &args);
+        transfer.report_progress(&args);
+        sample_transfer().report(ExitReason::Reset, &args);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let reasons: Vec<Option<String>> = contents
+            .lines()
+            .map(|line| {
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                value["exit_reason"].as_str().map(str::to_string)
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            [None, Some("eof".to_string()), Some("reset".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_exit_reason_of_errors() {
+        let reset: anyhow::Error = io::Error::from(io::ErrorKind::ConnectionReset).into();
+        assert_eq!(ExitReason::of(&reset), ExitReason::Reset);
+        let timeout = Err::<(), _>(io::Error::from(io::ErrorKind::WouldBlock))
+            .context("reading")
+            .unwrap_err();
+        assert_eq!(ExitReason::of(&timeout), ExitReason::Timeout);
+        assert_eq!(ExitReason::of(&anyhow::anyhow!("bad")), ExitReason::Error);
+    }
+
+    #[test]
+    fn test_counted_tracks_both_directions() {
+        let transfer = Transfer::new("127.0.0.1:5000".parse().unwrap(), Protocol::Tcp);
+        let mut counted = Counted::new(io::Cursor::new(b"hello".to_vec()), transfer.clone());
+        counted.read_to_end(&mut Vec::new()).unwrap();
+        counted.write_all(b"hi").unwrap();
+        assert_eq!(transfer.received(), 5);
+        assert_eq!(transfer.sent(), 2);
+    }
+}
@@ -0,0 +1,366 @@
+//! `--targets`: sends the same input to a list of hosts, `--fanout` of them
+//! at a time.
+//!
+//! The input is read into memory first so that every target gets all of it.
+//! Targets are reported in the order of the list whatever order they finish
+//! in, and with several at once each reply is held back until its turn.
+
+use crate::{
+    args::{parse_endpoint, Args, Endpoint, Protocol},
+    network::{
+        client_output, connect_client, open_input, pipe_duplex, prepare_datagram, resolve,
+        run_udp_client, with_receive_filters, Payload,
+    },
+    scan::check_descriptor_limit,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Cursor, Read, Write},
+    mem,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// The run in progress, kept where a signal can still report it.
+static RUN: Mutex<Run> = Mutex::new(Run::new());
+
+struct Run {
+    targets: Vec<String>,
+    /// Outcomes waiting for those of earlier targets, by position in the list.
+    finished: BTreeMap<usize, Outcome>,
+    reported: usize,
+    failed: Vec<usize>,
+}
+
+/// How sending to one target went, and what it replied when held back.
+struct Outcome {
+    result: Result<()>,
+    reply: Vec<u8>,
+}
+
+impl Run {
+    const fn new() -> Self {
+        Run {
+            targets: Vec::new(),
+            finished: BTreeMap::new(),
+            reported: 0,
+            failed: Vec::new(),
+        }
+    }
+
+    /// Reports the outcomes whose turn has come.
+    fn report_ready(&mut self) -> io::Result<()> {
+        while let Some(outcome) = self.finished.remove(&self.reported) {
+            self.report(self.reported, outcome)?;
+            self.reported += 1;
+        }
+        Ok(())
+    }
+
+    fn report(&mut self, index: usize, outcome: Outcome) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(&outcome.reply)?;
+        stdout.flush()?;
+        let target = &self.targets[index];
+        match outcome.result {
+            Ok(()) => writeln!(io::stderr(), "netpipe: {}: done", target),
+            Err(e) => {
+                self.failed.push(index);
+                writeln!(io::stderr(), "netpipe: {}: failed: {:#}", target, e)
+            }
+        }
+    }
+
+    fn list(&self, indices: impl IntoIterator<Item = usize>) -> String {
+        let targets: Vec<_> = indices
+            .into_iter()
+            .map(|index| self.targets[index].as_str())
+            .collect();
+        targets.join(", ")
+    }
+}
+
+/// A reply held in memory until its target's turn to be reported.
+#[derive(Clone, Default)]
+struct Reply(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Reply {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the `host:port` lines of a `--targets` file, skipping blank lines and
+/// `#` comments.
+fn read_targets(path: &Path) -> Result<Vec<Endpoint>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read --targets {}", path.display()))?;
+    let mut targets = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let target = parse_endpoint(line)
+            .map_err(|e| anyhow!("{}:{}: {}", path.display(), number + 1, e))?;
+        targets.push(target);
+    }
+    if targets.is_empty() {
+        bail!("{} lists no targets", path.display());
+    }
+    Ok(targets)
+}
+
+/// Sends the input to every target in `path` with `--fanout` connections at a
+/// time, reporting each on stderr, and fails listing the targets that did not
+/// get it. Without `--continue-on-error` no new target is started after a
+/// failure.
+pub fn run(args: &Args, path: &Path, timeout: Option<Duration>) -> Result<()> {
+    let targets = read_targets(path)?;
+    let payload = match args.protocol {
+        Protocol::Udp if args.datagram_size.is_none() => prepare_datagram(args)?,
+        _ => {
+            let mut payload = Vec::new();
+            open_input(args)?.read_to_end(&mut payload)?;
+            payload
+        }
+    };
+    let workers = args.fanout.min(targets.len());
+    // A TCP connection holds its socket and the clone its sender writes to.
+    check_descriptor_limit("--fanout", workers, 2)?;
+    *RUN.lock().unwrap() = Run {
+        targets: targets.iter().map(ToString::to_string).collect(),
+        ..Run::new()
+    };
+
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let (sender, outcomes) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let (next, stop, targets, payload) = (&next, &stop, &targets, &payload);
+            scope.spawn(move || loop {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(target) = targets.get(index) else {
+                    break;
+                };
+                let outcome = send_to(target, payload, workers > 1, args, timeout);
+                if outcome.result.is_err() && !args.continue_on_error {
+                    stop.store(true, Ordering::SeqCst);
+                }
+                if sender.send((index, outcome)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        for (index, outcome) in outcomes {
+            let mut run = RUN.lock().unwrap();
+            run.finished.insert(index, outcome);
+            run.report_ready()?;
+        }
+        io::Result::Ok(())
+    })?;
+
+    // Every target that was started has been reported, so the rest never were.
+    let run = RUN.lock().unwrap();
+    let untried = run.reported..targets.len();
+    if !untried.is_empty() {
+        bail!(
+            "{} failed, so these targets were not tried: {}",
+            run.list(run.failed.iter().copied()),
+            run.list(untried)
+        );
+    }
+    if !run.failed.is_empty() {
+        bail!(
+            "{} of {} targets failed: {}",
+            run.failed.len(),
+            targets.len(),
+            run.list(run.failed.iter().copied())
+        );
+    }
+    Ok(())
+}
+
+/// Sends `payload` to `target` over its own connection, holding the reply
+/// back with `hold_reply` instead of writing it out as it arrives.
+fn send_to(
+    target: &Endpoint,
+    payload: &[u8],
+    hold_reply: bool,
+    args: &Args,
+    timeout: Option<Duration>,
+) -> Outcome {
+    let reply = Reply::default();
+    let output = if hold_reply {
+        with_receive_filters(Box::new(reply.clone()), args)
+    } else {
+        client_output(args)
+    };
+    let result = output
+        .map_err(anyhow::Error::from)
+        .and_then(|output| match args.protocol {
+            Protocol::Udp => {
+                let destination = resolve(&target.host, target.port, args.ip_version)?;
+                let payload = match args.datagram_size {
+                    Some(_) => Payload::Chunks(Box::new(Cursor::new(payload.to_vec()))),
+                    None => Payload::Datagram(payload.to_vec()),
+                };
+                run_udp_client(args, destination, payload, output, timeout)
+            }
+            _ => {
+                let stream = connect_client(args, &target.host, target.port, timeout)?;
+                pipe_duplex(stream, Cursor::new(payload.to_vec()), output, args)
+            }
+        });
+    let reply = mem::take(&mut *reply.0.lock().unwrap());
+    Outcome { result, reply }
+}
+
+/// Reports the targets that finished out of turn and lists those that had
+/// not, for a run cut short by a signal.
+pub fn print_gathered() {
+    let mut run = RUN.lock().unwrap();
+    let finished = mem::take(&mut run.finished);
+    let unfinished: Vec<usize> = (run.reported..run.targets.len())
+        .filter(|index| !finished.contains_key(index))
+        .collect();
+    for (index, outcome) in finished {
+        let _ = run.report(index, outcome);
+    }
+    if !run.failed.is_empty() {
+        let failed = run.list(run.failed.iter().copied());
+        let _ = writeln!(io::stderr(), "netpipe: these targets failed: {}", failed);
+    }
+    if !unfinished.is_empty() {
+        let _ = writeln!(
+            io::stderr(),
+            "netpipe: interrupted before these targets finished: {}",
+            run.list(unfinished)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::net::TcpListener;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("netpipe-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_read_targets_skips_comments() {
+        let path = temp_path("targets");
+        fs::write(&path, "# fleet\n\nrouter1:2323\n  [::1]:80  # lab\n").unwrap();
+        let targets: Vec<_> = read_targets(&path)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(targets, ["router1:2323", "[::1]:80"]);
+        fs::write(&path, "router1\n").unwrap();
+        let err = read_targets(&path).unwrap_err().to_string();
+        assert!(
+            err.ends_with(":1: 'router1' is not in host:port form"),
+            "{}",
+            err
+        );
+        fs::write(&path, "# nothing yet\n").unwrap();
+        assert!(read_targets(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    // One test, as the run is kept in a static.
+    #[test]
+    fn test_targets_all_get_the_input() {
+        let listeners: Vec<_> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let open: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let targets = temp_path("fleet");
+        let input = temp_path("fleet-config");
+        fs::write(&targets, format!("{}\n{}\n{}\n", open[0], closed, open[1])).unwrap();
+        fs::write(&input, "config blob").unwrap();
+        // Each server serves a sequential run and a concurrent one.
+        let servers: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| {
+                thread::spawn(move || {
+                    let mut received = Vec::new();
+                    for _ in 0..2 {
+                        let (mut stream, _) = listener.accept().unwrap();
+                        let mut data = String::new();
+                        stream.read_to_string(&mut data).unwrap();
+                        received.push(data);
+                    }
+                    received
+                })
+            })
+            .collect();
+
+        let args = |extra: &[&str]| {
+            let mut argv = vec![
+                "test",
+                "-N",
+                "--targets",
+                targets.to_str().unwrap(),
+                "--file",
+                input.to_str().unwrap(),
+            ];
+            argv.extend_from_slice(extra);
+            Args::parse_from(argv)
+        };
+        let timeout = Some(Duration::from_secs(5));
+        for fanout in ["1", "8"] {
+            let args = args(&["--continue-on-error", "--fanout", fanout]);
+            let err = run(&args, &targets, timeout).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("1 of 3 targets failed: {}", closed)
+            );
+        }
+        for server in servers {
+            assert_eq!(server.join().unwrap(), ["config blob", "config blob"]);
+        }
+
+        fs::write(&targets, format!("{}\n{}\n", closed, open[0])).unwrap();
+        let err = run(&args(&[]), &targets, timeout).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} failed, so these targets were not tried: {}",
+                closed, open[0]
+            )
+        );
+        fs::remove_file(&targets).unwrap();
+        fs::remove_file(&input).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+//! Token-bucket throttling for `--rate`, `--rate-rx` and `--rate-tx`, and
+//! `--interval` pacing.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How much traffic the bucket holds, as time at the full rate. This is the
+/// largest burst allowed after a quiet period.
+const BURST: Duration = Duration::from_millis(100);
+
+/// Limits a byte stream to `rate` bytes per second on average.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        let capacity = burst_size(rate) as f64;
+        TokenBucket {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Accounts for `n` bytes, sleeping until the average rate is respected.
+    ///
+    /// Chunks larger than the bucket are let through and paid back by
+    /// sleeping longer.
+    pub fn take(&mut self, n: usize) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.last_refill = now;
+
+        self.tokens -= n as f64;
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate));
+            self.tokens = 0.0;
+            self.last_refill = Instant::now();
+        }
+    }
+}
+
+/// The number of bytes a bucket for `rate` holds, and so a sensible chunk size
+/// for a throttled copy loop.
+pub fn burst_size(rate: u64) -> usize {
+    ((rate as f64 * BURST.as_secs_f64()) as usize).max(1)
+}
+
+/// Caps a copy loop's chunk size so throttled transfers stay smooth.
+pub fn chunk_size(buffer_size: usize, rate: Option<u64>) -> usize {
+    rate.map_or(buffer_size, |rate| buffer_size.min(burst_size(rate)))
+}
+
+/// Throttles reads and writes through an optional [`TokenBucket`].
+pub struct Throttled<T> {
+    inner: T,
+    bucket: Option<TokenBucket>,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, rate: Option<u64>) -> Self {
+        Throttled {
+            inner,
+            bucket: rate.map(TokenBucket::new),
+        }
+    }
+
+    fn limit(&self, len: usize) -> usize {
+        self.bucket
+            .as_ref()
+            .map_or(len, |bucket| len.min(bucket.capacity as usize))
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.limit(buf.len());
+        let n = self.inner.read(&mut buf[..len])?;
+        if let Some(bucket) = &mut self.bucket {
+            bucket.take(n);
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.limit(buf.len());
+        let n = self.inner.write(&buf[..len])?;
+        if let Some(bucket) = &mut self.bucket {
+            bucket.take(n);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hands out input one line per read, waiting `interval` before every line
+/// after the first. Without an interval reads pass straight through.
+pub struct Paced<R> {
+    inner: BufReader<R>,
+    interval: Option<Duration>,
+    started: bool,
+}
+
+impl<R: Read> Paced<R> {
+    pub fn new(inner: R, interval: Option<Duration>) -> Self {
+        Paced {
+            inner: BufReader::new(inner),
+            interval,
+            started: false,
+        }
+    }
+}
+
+impl<R: Read> Read for Paced<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(interval) = self.interval else {
+            return self.inner.read(buf);
+        };
+        // Wait for the next line before pausing, so EOF is not delayed.
+        let available = self.inner.fill_buf()?;
+        if available.is_empty() {
+            return Ok(0);
+        }
+        let line_end = available
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(available.len(), |i| i + 1);
+        let n = line_end.min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.inner.consume(n);
+
+        if self.started {
+            thread::sleep(interval);
+        }
+        self.started = true;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_holds_average_rate() {
+        const RATE: u64 = 1_000_000;
+        let mut bucket = TokenBucket::new(RATE);
+        let started = Instant::now();
+        // The first burst is free, the remaining 500 kB take half a second.
+        for _ in 0..60 {
+            bucket.take(10_000);
+        }
+        let elapsed = started.elapsed().as_secs_f64();
+        assert!((0.475..0.6).contains(&elapsed), "took {}s", elapsed);
+    }
+
+    #[test]
+    fn test_chunk_larger_than_bucket_is_paid_back() {
+        let mut bucket = TokenBucket::new(100_000);
+        let started = Instant::now();
+        bucket.take(30_000);
+        let elapsed = started.elapsed().as_secs_f64();
+        assert!((0.19..0.3).contains(&elapsed), "took {}s", elapsed);
+    }
+
+    #[test]
+    fn test_throttled_writes_are_capped_to_the_bucket() {
+        let mut output = Throttled::new(Vec::new(), Some(10_000));
+        assert_eq!(output.write(&[0u8; 4096]).unwrap(), 1000);
+        let mut unthrottled = Throttled::new(Vec::new(), None);
+        assert_eq!(unthrottled.write(&[0u8; 4096]).unwrap(), 4096);
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        assert_eq!(chunk_size(65536, None), 65536);
+        assert_eq!(chunk_size(65536, Some(100_000)), 10_000);
+        assert_eq!(chunk_size(4096, Some(10_000_000)), 4096);
+    }
+
+    #[test]
+    fn test_paced_reads_one_line_at_a_time() {
+        let input = io::Cursor::new(b"one\ntwo\nthree".to_vec());
+        let mut paced = Paced::new(input, Some(Duration::from_millis(50)));
+        let mut buffer = [0u8; 64];
+        let started = Instant::now();
+        let mut lines = Vec::new();
+        loop {
+            let n = paced.read(&mut buffer).unwrap();
+            if n == 0 {
+                break;
+            }
+            lines.push(String::from_utf8(buffer[..n].to_vec()).unwrap());
+        }
+        let elapsed = started.elapsed();
+        assert_eq!(lines, ["one\n", "two\n", "three"]);
+        assert!(elapsed >= Duration::from_millis(100), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(150), "took {:?}", elapsed);
+    }
+}
@@ -0,0 +1,1019 @@
+//! TLS for `--tls`, on top of rustls, as a client or, when listening, a
+//! server.
+//!
+//! The handshake runs to completion before any data moves. The connection is
+//! then split into a [`TlsReader`] and a [`TlsWriter`] for the two copy loops.
+//! They share the rustls state but do their socket I/O without holding it, so
+//! a write blocked on a slow peer never stops the other direction.
+
+use crate::args::Args;
+use crate::network::strip_zone;
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{self, CryptoProvider},
+    pki_types::{
+        pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+    },
+    server::WebPkiClientVerifier,
+    AlertDescription, CertificateError, ClientConfig, ClientConnection, Connection,
+    DigitallySignedStruct, InconsistentKeys, KeyLog, OtherError, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    env, fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    net::{IpAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("No PEM certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .with_context(|| format!("Failed to read a private key from {}", path.display()))
+}
+
+/// The SHA-256 fingerprint of a certificate, as `sha256:<hex>`.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let digest = Sha256::digest(cert);
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256:{}", hex)
+}
+
+/// The subject of a certificate, such as `CN=example.com`.
+pub fn subject(cert: &CertificateDer<'_>) -> String {
+    match x509_parser::parse_x509_certificate(cert) {
+        Ok((_, parsed)) => parsed.subject().to_string(),
+        Err(_) => "(unparsable)".to_string(),
+    }
+}
+
+/// Which server certificates to accept without checking their chain.
+#[derive(Debug)]
+enum Acceptance {
+    /// Any, for `--insecure`.
+    Any,
+    /// Those with one of these `--tls-pin` fingerprints.
+    Pinned(Vec<String>),
+}
+
+/// A certificate that matched none of the `--tls-pin` fingerprints.
+#[derive(Debug)]
+struct PinMismatch(String);
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "its fingerprint {} matches no --tls-pin", self.0)
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+/// Stands in for chain and host name verification with `--insecure` or
+/// `--tls-pin`. The handshake signatures are still checked, so the session is
+/// encrypted with the key of the certificate that was shown.
+#[derive(Debug)]
+struct ChainSkipped {
+    acceptance: Acceptance,
+    provider: CryptoProvider,
+}
+
+impl ServerCertVerifier for ChainSkipped {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(end_entity);
+        match &self.acceptance {
+            Acceptance::Any => {
+                let _ = writeln!(
+                    io::stderr(),
+                    "netpipe: WARNING: --insecure accepted the certificate of {} WITHOUT verifying it: subject {}, fingerprint {}",
+                    server_name.to_str(),
+                    subject(end_entity),
+                    fingerprint
+                );
+            }
+            Acceptance::Pinned(pins) if pins.contains(&fingerprint) => {
+                info!(
+                    "The certificate of {} matches --tls-pin",
+                    server_name.to_str()
+                );
+            }
+            Acceptance::Pinned(_) => {
+                return Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                    OtherError(Arc::new(PinMismatch(fingerprint))),
+                )));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+    }
+    Ok(roots)
+}
+
+/// Explains why a certificate and key were refused.
+fn key_pair_error(e: rustls::Error, cert_path: &Path, key_path: &Path) -> anyhow::Error {
+    match e {
+        rustls::Error::InconsistentKeys(InconsistentKeys::KeyMismatch) => anyhow!(
+            "The key in {} does not belong to the certificate in {}",
+            key_path.display(),
+            cert_path.display()
+        ),
+        e => anyhow!(
+            "Unusable certificate {} or key {}: {}",
+            cert_path.display(),
+            key_path.display(),
+            e
+        ),
+    }
+}
+
+/// Verifies servers against the `--tls-ca` certificates, or Mozilla's root
+/// certificates without it. `--insecure` skips verification altogether, and
+/// `--tls-pin` accepts the pinned certificates only. Presents
+/// `--tls-client-cert` when the server asks for a certificate.
+pub fn client_config(args: &Args) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(server_verifier(args)?);
+    let mut config = match (&args.tls_client_cert, &args.tls_client_key) {
+        (Some(cert_path), Some(key_path)) => builder
+            .with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)
+            .map_err(|e| key_pair_error(e, cert_path, key_path))?,
+        _ => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn_protocols(args);
+    if let Some(key_log) = key_log(args)? {
+        config.key_log = key_log;
+    }
+    Ok(Arc::new(config))
+}
+
+/// How a client checks server certificates, for [`client_config`] and DTLS.
+pub fn server_verifier(args: &Args) -> Result<Arc<dyn ServerCertVerifier>> {
+    let acceptance = if args.insecure {
+        Some(Acceptance::Any)
+    } else if !args.tls_pin.is_empty() {
+        Some(Acceptance::Pinned(args.tls_pin.clone()))
+    } else {
+        None
+    };
+    if let Some(acceptance) = acceptance {
+        return Ok(Arc::new(ChainSkipped {
+            acceptance,
+            provider: crypto::ring::default_provider(),
+        }));
+    }
+    let roots = match &args.tls_ca {
+        Some(path) => load_roots(path)?,
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let verifier = WebPkiServerVerifier::builder_with_provider(
+        Arc::new(roots),
+        Arc::new(crypto::ring::default_provider()),
+    )
+    .build()?;
+    Ok(verifier)
+}
+
+/// Presents the `--tls-cert` chain, signing with `--tls-key`, or the
+/// `--tls-self-signed` certificate. With `--tls-ca` client certificates are
+/// verified against it, and `--tls-require-client-cert` turns away clients
+/// without one.
+pub fn server_config(args: &Args) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder();
+    let builder = match &args.tls_ca {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_roots(path)?),
+                Arc::new(crypto::ring::default_provider()),
+            );
+            let verifier = if args.tls_require_client_cert {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let (certs, key) = server_identity(args)?;
+    let mut config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert_path), Some(key_path)) => builder
+            .with_single_cert(certs, key)
+            .map_err(|e| key_pair_error(e, cert_path, key_path))?,
+        _ => builder.with_single_cert(certs, key)?,
+    };
+    config.alpn_protocols = alpn_protocols(args);
+    if let Some(key_log) = key_log(args)? {
+        config.key_log = key_log;
+    }
+    Ok(Arc::new(config))
+}
+
+/// The certificate chain and key a server presents: `--tls-cert` and
+/// `--tls-key`, or the `--tls-self-signed` certificate.
+pub fn server_identity(
+    args: &Args,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    if args.tls_self_signed {
+        return self_signed(args);
+    }
+    let (Some(cert_path), Some(key_path)) = (&args.tls_cert, &args.tls_key) else {
+        bail!("A TLS server needs both --tls-cert and --tls-key, or --tls-self-signed");
+    };
+    Ok((load_certs(cert_path)?, load_key(key_path)?))
+}
+
+/// The `--tls-self-signed` certificate and key, made on first use so every
+/// connection presents the same fingerprint.
+static SELF_SIGNED: Mutex<Option<(CertificateDer<'static>, PrivatePkcs8KeyDer<'static>)>> =
+    Mutex::new(None);
+
+/// Returns the `--tls-self-signed` certificate, generating it for the listen
+/// address and localhost the first time. Its fingerprint goes to stderr for
+/// clients to pin, and it is saved to `--tls-self-signed-out` if given.
+fn self_signed(args: &Args) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let mut generated = SELF_SIGNED.lock().unwrap();
+    if generated.is_none() {
+        let mut names = vec!["localhost".to_string()];
+        if let Some(address) = args.address.as_deref().map(strip_zone) {
+            let unspecified = address
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| ip.is_unspecified());
+            if !unspecified && address != "localhost" {
+                names.insert(0, address.to_string());
+            }
+        }
+        let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names)
+            .context("Failed to generate a self-signed certificate")?;
+        writeln!(
+            io::stderr(),
+            "netpipe: serving a self-signed TLS certificate; clients can trust it with --tls-pin {}",
+            fingerprint(cert.der())
+        )?;
+        if let Some(dir) = &args.tls_self_signed_out {
+            save_self_signed(dir, &cert.pem(), &key_pair.serialize_pem())?;
+        }
+        *generated = Some((
+            cert.der().clone(),
+            PrivatePkcs8KeyDer::from(key_pair.serialize_der()),
+        ));
+    }
+    let (cert, key) = generated.as_ref().unwrap();
+    Ok((vec![cert.clone()], key.clone_key().into()))
+}
+
+/// Writes the self-signed certificate and key to `dir` as `cert.pem` and
+/// `key.pem`, for `--tls-cert` and `--tls-key` next time.
+fn save_self_signed(dir: &Path, cert: &str, key: &str) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    fs::write(&cert_path, cert)
+        .with_context(|| format!("Failed to write {}", cert_path.display()))?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&key_path)
+        .and_then(|mut file| file.write_all(key.as_bytes()))
+        .with_context(|| format!("Failed to write {}", key_path.display()))?;
+    info!(
+        "Saved the self-signed certificate to {} and its key to {}",
+        cert_path.display(),
+        key_path.display()
+    );
+    Ok(())
+}
+
+/// The `--tls-alpn` protocols in order of preference.
+fn alpn_protocols(args: &Args) -> Vec<Vec<u8>> {
+    args.tls_alpn
+        .iter()
+        .map(|p| p.as_bytes().to_vec())
+        .collect()
+}
+
+/// Where session keys go: `--tls-keylog`, or else `SSLKEYLOGFILE` as in
+/// browsers and curl.
+pub fn keylog_path(args: &Args) -> Option<PathBuf> {
+    args.tls_keylog.clone().or_else(|| {
+        env::var_os("SSLKEYLOGFILE")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+    })
+}
+
+/// Opens the key log of [`keylog_path`], if there is one.
+pub fn key_log(args: &Args) -> Result<Option<Arc<dyn KeyLog>>> {
+    match keylog_path(args) {
+        Some(path) => Ok(Some(Arc::new(KeyLogFile::open(&path)?))),
+        None => Ok(None),
+    }
+}
+
+/// Appends session secrets in the NSS key log format that Wireshark reads.
+#[derive(Debug)]
+struct KeyLogFile(Mutex<File>);
+
+impl KeyLogFile {
+    fn open(path: &Path) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        // The secrets decrypt every logged session, so only we may read them.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options
+            .open(path)
+            .with_context(|| format!("Failed to open TLS key log {}", path.display()))?;
+        Ok(KeyLogFile(Mutex::new(file)))
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        // A failed write loses keys for Wireshark, not data, so it only warns.
+        if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            let _ = writeln!(
+                io::stderr(),
+                "netpipe: failed to write the TLS key log: {}",
+                e
+            );
+        }
+    }
+}
+
+/// Runs a client handshake over `stream`, verifying that the server's
+/// certificate is valid for `--tls-sni` or else `server_name`, which is also
+/// sent as SNI unless it is an IP address.
+pub fn connect(
+    stream: TcpStream,
+    server_name: &str,
+    args: &Args,
+) -> Result<(TlsReader, TlsWriter)> {
+    let server_name = args.tls_sni.as_deref().unwrap_or(server_name);
+    let name = ServerName::try_from(server_name.to_string())
+        .with_context(|| format!("{} is not a valid TLS server name", server_name))?;
+    let connection = ClientConnection::new(client_config(args)?, name)?;
+    handshake(
+        stream,
+        connection.into(),
+        server_name,
+        args.tls_alpn_required,
+    )
+}
+
+/// Runs a server handshake over an accepted `stream`.
+pub fn accept(stream: TcpStream, args: &Args) -> Result<(TlsReader, TlsWriter)> {
+    let peer = stream.peer_addr()?.to_string();
+    let connection = ServerConnection::new(server_config(args)?)?;
+    handshake(stream, connection.into(), &peer, args.tls_alpn_required)
+}
+
+fn handshake(
+    mut stream: TcpStream,
+    mut connection: Connection,
+    peer: &str,
+    alpn_required: bool,
+) -> Result<(TlsReader, TlsWriter)> {
+    let role = peer_role(&connection);
+    while connection.is_handshaking() {
+        connection
+            .complete_io(&mut stream)
+            .map_err(|e| explain(e, role))
+            .with_context(|| format!("TLS handshake with {} failed", peer))?;
+    }
+    info!(
+        "TLS handshake with {} done: {:?}, {:?}",
+        peer,
+        connection.protocol_version().unwrap(),
+        connection.negotiated_cipher_suite().unwrap().suite()
+    );
+    if let Some(cert) = connection
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    {
+        info!("The {}'s certificate is for {}", role, subject(cert));
+    }
+    if let Connection::Server(server) = &connection {
+        if let Some(name) = server.server_name() {
+            info!("The client asked for server name {}", name);
+        }
+    }
+    match connection.alpn_protocol() {
+        Some(protocol) => info!("ALPN protocol: {}", String::from_utf8_lossy(protocol)),
+        None if alpn_required => {
+            bail!(
+                "TLS handshake with {} failed: the {} agreed on no --tls-alpn protocol",
+                peer,
+                role
+            )
+        }
+        None => {}
+    }
+    let shared = Arc::new(Shared {
+        connection: Mutex::new(connection),
+        sending: Mutex::new(()),
+    });
+    let writer = TlsWriter {
+        socket: stream.try_clone()?,
+        shared: shared.clone(),
+    };
+    let reader = TlsReader {
+        socket: stream,
+        shared,
+        records: Vec::new(),
+    };
+    Ok((reader, writer))
+}
+
+/// Names the other end of `connection`, for error messages.
+fn peer_role(connection: &Connection) -> &'static str {
+    match connection {
+        Connection::Client(_) => "server",
+        Connection::Server(_) => "client",
+    }
+}
+
+/// Spells out TLS errors that are about certificates, naming whose
+/// certificate failed and at which step.
+pub fn describe(error: &rustls::Error, peer: &str) -> String {
+    match error {
+        rustls::Error::InvalidCertificate(problem) => {
+            let reason = match problem {
+                CertificateError::UnknownIssuer => {
+                    "it is not signed by a trusted certificate authority"
+                }
+                CertificateError::Expired | CertificateError::ExpiredContext { .. } => {
+                    "it has expired"
+                }
+                CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. } => {
+                    "it is not valid yet"
+                }
+                CertificateError::NotValidForName
+                | CertificateError::NotValidForNameContext { .. } => {
+                    "it is not valid for this host name"
+                }
+                CertificateError::Revoked => "it has been revoked",
+                CertificateError::BadSignature => "its signature is invalid",
+                CertificateError::Other(other) => {
+                    return format!("The {}'s certificate was rejected because {}", peer, other)
+                }
+                other => return format!("The {}'s certificate was rejected: {:?}", peer, other),
+            };
+            format!("The {}'s certificate was rejected because {}", peer, reason)
+        }
+        rustls::Error::NoCertificatesPresented => {
+            format!("The {} did not present a certificate", peer)
+        }
+        rustls::Error::AlertReceived(AlertDescription::CertificateRequired) => format!(
+            "The {} requires a certificate; give one with --tls-client-cert and --tls-client-key",
+            peer
+        ),
+        rustls::Error::AlertReceived(
+            alert @ (AlertDescription::BadCertificate
+            | AlertDescription::UnsupportedCertificate
+            | AlertDescription::CertificateRevoked
+            | AlertDescription::CertificateExpired
+            | AlertDescription::CertificateUnknown
+            | AlertDescription::UnknownCA),
+        ) => format!("The {} rejected our certificate: {:?}", peer, alert),
+        other => other.to_string(),
+    }
+}
+
+/// Rewrites the rustls error inside `e`, if any, with [`describe`].
+fn explain(e: io::Error, peer: &str) -> io::Error {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+    {
+        Some(error) => io::Error::new(e.kind(), describe(error, peer)),
+        None => e,
+    }
+}
+
+/// The rustls state both halves of a connection work on.
+struct Shared {
+    connection: Mutex<Connection>,
+    /// Held while records are taken from rustls and written to the socket, so
+    /// they go out in order.
+    sending: Mutex<()>,
+}
+
+impl Shared {
+    /// Writes whatever records rustls has queued. The caller holds `sending`.
+    fn send_records(&self, mut socket: &TcpStream) -> io::Result<()> {
+        let mut records = Vec::new();
+        {
+            let mut connection = self.connection.lock().unwrap();
+            while connection.wants_write() {
+                connection.write_tls(&mut records)?;
+            }
+        }
+        socket.write_all(&records)
+    }
+}
+
+/// The receiving half of a TLS connection.
+pub struct TlsReader {
+    socket: TcpStream,
+    shared: Arc<Shared>,
+    /// Received records rustls has not taken yet.
+    records: Vec<u8>,
+}
+
+impl TlsReader {
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut connection = self.shared.connection.lock().unwrap();
+                match connection.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        // Plenty of servers never send close_notify.
+                        info!("The peer closed the connection without a TLS close_notify");
+                        return Ok(0);
+                    }
+                    result => return result,
+                }
+                if !self.records.is_empty() {
+                    let taken = connection.read_tls(&mut &self.records[..])?;
+                    self.records.drain(..taken);
+                    let processed = connection.process_new_packets().map_err(|e| {
+                        let message = describe(&e, peer_role(&connection));
+                        io::Error::new(io::ErrorKind::InvalidData, message)
+                    });
+                    let reply = connection.wants_write();
+                    drop(connection);
+                    // Replies such as alerts go out now unless the writer is busy,
+                    // in which case it sends them along with its next write.
+                    if reply {
+                        if let Ok(_sending) = self.shared.sending.try_lock() {
+                            self.shared.send_records(&self.socket)?;
+                        }
+                    }
+                    processed?;
+                    continue;
+                }
+            }
+
+            let mut chunk = [0u8; 16 * 1024];
+            let n = self.socket.read(&mut chunk)?;
+            if n == 0 {
+                // Tells rustls the connection has ended.
+                self.shared
+                    .connection
+                    .lock()
+                    .unwrap()
+                    .read_tls(&mut io::empty())?;
+                continue;
+            }
+            self.records.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+/// The sending half of a TLS connection.
+pub struct TlsWriter {
+    socket: TcpStream,
+    shared: Arc<Shared>,
+}
+
+impl TlsWriter {
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// Sends close_notify, which the peer reads as the end of the stream.
+    pub fn close_notify(&mut self) -> io::Result<()> {
+        let _sending = self.shared.sending.lock().unwrap();
+        self.shared.connection.lock().unwrap().send_close_notify();
+        self.shared.send_records(&self.socket)
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _sending = self.shared.sending.lock().unwrap();
+        let n = self.shared.connection.lock().unwrap().writer().write(buf)?;
+        self.shared.send_records(&self.socket)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _sending = self.shared.sending.lock().unwrap();
+        self.shared.send_records(&self.socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::{
+        ffi::OsStr,
+        fs,
+        net::{SocketAddr, TcpListener},
+        path::PathBuf,
+        thread,
+    };
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("netpipe-tls-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_certificate_problems_are_spelled_out() {
+        let rejected = |problem| {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidData,
+                rustls::Error::InvalidCertificate(problem),
+            );
+            explain(e, "server").to_string()
+        };
+        assert!(rejected(CertificateError::UnknownIssuer).contains("trusted certificate authority"));
+        assert!(rejected(CertificateError::Expired).contains("expired"));
+        assert!(rejected(CertificateError::NotValidForName).contains("not valid for this host"));
+        assert!(rejected(CertificateError::BadEncoding).contains("BadEncoding"));
+        assert_eq!(
+            explain(io::ErrorKind::ConnectionReset.into(), "server").to_string(),
+            io::Error::from(io::ErrorKind::ConnectionReset).to_string()
+        );
+        assert_eq!(
+            describe(&rustls::Error::NoCertificatesPresented, "client"),
+            "The client did not present a certificate"
+        );
+        assert!(describe(
+            &rustls::Error::AlertReceived(AlertDescription::CertificateRequired),
+            "server"
+        )
+        .contains("--tls-client-cert"));
+    }
+
+    #[test]
+    fn test_handshake_with_plain_server_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"220 plain text service\r\n").unwrap();
+        });
+        let stream = TcpStream::connect(address).unwrap();
+        let args = Args::parse_from(["test", "--tls"]);
+        let err = connect(stream, "127.0.0.1", &args).err().unwrap();
+        server.join().unwrap();
+        assert!(
+            format!("{:#}", err).starts_with("TLS handshake with 127.0.0.1 failed"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_server_config_reports_bad_files() {
+        let cert_path = temp_path("cert.pem");
+        let key_path = temp_path("key.pem");
+        let other_key_path = temp_path("other-key.pem");
+        let cert = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        fs::write(&other_key_path, other.key_pair.serialize_pem()).unwrap();
+        let server_args = |cert: &Path, key: &Path| {
+            Args::parse_from([
+                "test".as_ref(),
+                "--tls".as_ref(),
+                "--tls-cert".as_ref(),
+                cert.as_os_str(),
+                "--tls-key".as_ref(),
+                key.as_os_str(),
+            ])
+        };
+
+        assert!(server_config(&server_args(&cert_path, &key_path)).is_ok());
+        let err = server_config(&server_args(&cert_path, &other_key_path)).unwrap_err();
+        assert!(err.to_string().contains("does not belong"), "{}", err);
+        let err = server_config(&server_args(&key_path, &key_path)).unwrap_err();
+        assert!(err.to_string().contains("No PEM certificates"), "{}", err);
+        let err = server_config(&server_args(&cert_path, &cert_path)).unwrap_err();
+        assert!(err.to_string().contains("private key"), "{}", err);
+
+        for path in [cert_path, key_path, other_key_path] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_and_subject() {
+        assert_eq!(
+            fingerprint(&CertificateDer::from(Vec::new())),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        let cert = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        assert_eq!(subject(cert.cert.der()), "CN=rcgen self signed cert");
+    }
+
+    /// Serves one TLS connection with a fresh self-signed certificate for
+    /// localhost and the `extra` arguments, returning its address and
+    /// certificate, and what the client sent.
+    fn serve_once(
+        name: &str,
+        extra: &[&str],
+    ) -> (
+        SocketAddr,
+        rcgen::Certificate,
+        thread::JoinHandle<Result<Vec<u8>>>,
+    ) {
+        let cert_path = temp_path(&format!("{}-cert.pem", name));
+        let key_path = temp_path(&format!("{}-key.pem", name));
+        let cert = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let mut server_args = vec![
+            "test".as_ref(),
+            "-l".as_ref(),
+            "--tls".as_ref(),
+            "--tls-cert".as_ref(),
+            cert_path.as_os_str(),
+            "--tls-key".as_ref(),
+            key_path.as_os_str(),
+        ];
+        server_args.extend(extra.iter().map(OsStr::new));
+        let server_args = Args::parse_from(server_args);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let accepted = accept(stream, &server_args);
+            fs::remove_file(cert_path).unwrap();
+            fs::remove_file(key_path).unwrap();
+            let (mut reader, _writer) = accepted?;
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received)?;
+            Ok(received)
+        });
+        (address, cert.cert, server)
+    }
+
+    #[test]
+    fn test_insecure_accepts_a_certificate_for_another_name() {
+        let (address, _, server) = serve_once("insecure", &[]);
+        let args = Args::parse_from(["test", "--tls", "--insecure"]);
+        let (_reader, mut writer) =
+            connect(TcpStream::connect(address).unwrap(), "127.0.0.1", &args).unwrap();
+        writer.write_all(b"still encrypted").unwrap();
+        writer.close_notify().unwrap();
+        assert_eq!(server.join().unwrap().unwrap(), b"still encrypted");
+    }
+
+    #[test]
+    fn test_pinned_fingerprints() {
+        let (address, cert, server) = serve_once("pinned", &[]);
+        let pin = fingerprint(cert.der());
+        let other_pin = format!("sha256:{}", "ab".repeat(32));
+        let args = Args::parse_from(["test", "--tls", "--tls-pin", &other_pin, "--tls-pin", &pin]);
+        let (_reader, mut writer) =
+            connect(TcpStream::connect(address).unwrap(), "127.0.0.1", &args).unwrap();
+        writer.write_all(b"pinned").unwrap();
+        writer.close_notify().unwrap();
+        assert_eq!(server.join().unwrap().unwrap(), b"pinned");
+
+        let (address, cert, server) = serve_once("mispinned", &[]);
+        let pin = fingerprint(cert.der());
+        let args = Args::parse_from(["test", "--tls", "--tls-pin", &other_pin]);
+        let err = connect(TcpStream::connect(address).unwrap(), "localhost", &args)
+            .err()
+            .unwrap();
+        let message = format!("{:#}", err);
+        assert!(
+            message.contains(&format!("its fingerprint {} matches no --tls-pin", pin)),
+            "{}",
+            message
+        );
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_sni_override_is_verified() {
+        let (address, cert, server) = serve_once("sni", &[]);
+        let ca_path = temp_path("sni-ca.pem");
+        fs::write(&ca_path, cert.pem()).unwrap();
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "--tls".as_ref(),
+            "--tls-ca".as_ref(),
+            ca_path.as_os_str(),
+            "--tls-sni".as_ref(),
+            "localhost".as_ref(),
+        ]);
+        // The certificate is for localhost, not the address connected to.
+        let connected = connect(TcpStream::connect(address).unwrap(), "127.0.0.1", &args);
+        fs::remove_file(&ca_path).unwrap();
+        let (_reader, mut writer) = connected.unwrap();
+        writer.write_all(b"virtual host").unwrap();
+        writer.close_notify().unwrap();
+        assert_eq!(server.join().unwrap().unwrap(), b"virtual host");
+    }
+
+    #[test]
+    fn test_alpn_negotiation() {
+        let client = |address, alpn: &str| {
+            let args = Args::parse_from([
+                "test",
+                "--tls",
+                "--insecure",
+                "--tls-alpn",
+                alpn,
+                "--tls-alpn-required",
+            ]);
+            connect(TcpStream::connect(address).unwrap(), "localhost", &args)
+        };
+
+        let (address, _, server) = serve_once("alpn", &["--tls-alpn", "h2,http/1.1"]);
+        let (_reader, mut writer) = client(address, "spdy/3,http/1.1").unwrap();
+        writer.write_all(b"GET /").unwrap();
+        writer.close_notify().unwrap();
+        assert_eq!(server.join().unwrap().unwrap(), b"GET /");
+
+        // A server without ALPN picks nothing.
+        let (address, _, server) = serve_once("no-alpn", &[]);
+        let err = client(address, "h2").err().unwrap();
+        assert!(
+            err.to_string().contains("agreed on no --tls-alpn protocol"),
+            "{}",
+            err
+        );
+        let _ = server.join().unwrap();
+
+        // And one without a protocol in common refuses the handshake.
+        let (address, _, server) = serve_once("other-alpn", &["--tls-alpn", "h2"]);
+        assert!(client(address, "http/1.1").is_err());
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_key_log_files() {
+        let server_log = temp_path("server-keys.log");
+        let client_log = temp_path("client-keys.log");
+        let (address, _, server) =
+            serve_once("keylog", &["--tls-keylog", server_log.to_str().unwrap()]);
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "--tls".as_ref(),
+            "--insecure".as_ref(),
+            "--tls-keylog".as_ref(),
+            client_log.as_os_str(),
+        ]);
+        let (_reader, mut writer) =
+            connect(TcpStream::connect(address).unwrap(), "localhost", &args).unwrap();
+        writer.write_all(b"logged").unwrap();
+        writer.close_notify().unwrap();
+        assert_eq!(server.join().unwrap().unwrap(), b"logged");
+
+        let client_keys = fs::read_to_string(&client_log).unwrap();
+        let server_keys = fs::read_to_string(&server_log).unwrap();
+        assert!(
+            client_keys.contains("CLIENT_TRAFFIC_SECRET_0 "),
+            "{}",
+            client_keys
+        );
+        // Both sides log the same secrets, keyed by the client random.
+        let mut client_lines: Vec<_> = client_keys.lines().collect();
+        let mut server_lines: Vec<_> = server_keys.lines().collect();
+        client_lines.sort();
+        server_lines.sort();
+        assert_eq!(client_lines, server_lines);
+        let fields: Vec<_> = client_lines[0].split(' ').collect();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[1].len(), 64);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&client_log).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(client_log).unwrap();
+        fs::remove_file(server_log).unwrap();
+    }
+
+    #[test]
+    fn test_self_signed_certificate() {
+        let dir = temp_path("self-signed");
+        let server_args = Args::parse_from([
+            "test".as_ref(),
+            "-l".as_ref(),
+            "--tls".as_ref(),
+            "--tls-self-signed".as_ref(),
+            "--tls-self-signed-out".as_ref(),
+            dir.as_os_str(),
+        ]);
+        let (certs, _) = self_signed(&server_args).unwrap();
+        let pin = fingerprint(&certs[0]);
+        // Every connection presents the same certificate, as saved.
+        assert_eq!(fingerprint(&self_signed(&server_args).unwrap().0[0]), pin);
+        assert_eq!(load_certs(&dir.join("cert.pem")).unwrap(), certs);
+        load_key(&dir.join("key.pem")).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join("key.pem"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (mut reader, _writer) = accept(stream, &server_args).unwrap();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).unwrap();
+            received
+        });
+        let args = Args::parse_from(["test", "--tls", "--tls-pin", &pin]);
+        let (_reader, mut writer) =
+            connect(TcpStream::connect(address).unwrap(), "localhost", &args).unwrap();
+        writer.write_all(b"one command").unwrap();
+        writer.close_notify().unwrap();
+        assert_eq!(server.join().unwrap(), b"one command");
+    }
+}
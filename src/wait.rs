@@ -0,0 +1,125 @@
+//! `--wait-for`: retries connecting until a TCP service accepts, in place of
+//! scripts such as `wait-for-it.sh`.
+//!
+//! Nothing is sent. With `--wait-for-banner` the service must also greet the
+//! connection with the given text, as some accept connections before they
+//! are ready.
+
+use crate::{
+    args::Args,
+    network::{connect_any, connect_timeout, connection_candidates, Source},
+    scan::{escape, grab_banner, BANNER_WAIT},
+};
+use anyhow::{bail, Result};
+use log::info;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How much of the greeting `--wait-for-banner` reads at least.
+const BANNER_LIMIT: usize = 1024;
+
+/// Connects every `--retry-interval` until the service is ready, failing once
+/// `--max-time` has passed.
+pub fn run(args: &Args, timeout: Option<Duration>) -> Result<()> {
+    let host = args.address.as_deref().unwrap();
+    let port = args.port().unwrap();
+    let deadline = args.max_time().map(|max_time| Instant::now() + max_time);
+    for attempt in 1.. {
+        let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if left == Some(Duration::ZERO) {
+            break;
+        }
+        match check(host, port, args, timeout, left) {
+            Ok(()) => {
+                info!("{}:{} is ready after {} attempts", host, port, attempt);
+                return Ok(());
+            }
+            Err(e) => info!("Attempt {} failed: {:#}", attempt, e),
+        }
+        let pause = match deadline {
+            Some(deadline) => args
+                .retry_interval
+                .min(deadline.saturating_duration_since(Instant::now())),
+            None => args.retry_interval,
+        };
+        thread::sleep(pause);
+    }
+    bail!("{}:{} was not ready within --max-time", host, port)
+}
+
+/// Makes one attempt, spending no more than `left` on it.
+fn check(
+    host: &str,
+    port: u16,
+    args: &Args,
+    timeout: Option<Duration>,
+    left: Option<Duration>,
+) -> Result<()> {
+    let candidates = connection_candidates(host, port, args)?;
+    let stream = connect_any(
+        &candidates,
+        &Source::of(args),
+        shortest(connect_timeout(args, timeout), left),
+    )?;
+    let Some(expected) = &args.wait_for_banner else {
+        return Ok(());
+    };
+    let wait = shortest(Some(timeout.unwrap_or(BANNER_WAIT)), left).unwrap();
+    let banner = grab_banner(&stream, BANNER_LIMIT.max(expected.len()), wait)?;
+    let expected = expected.as_bytes();
+    if !banner
+        .windows(expected.len())
+        .any(|window| window == expected)
+    {
+        bail!("The service greeted with '{}'", escape(&banner));
+    }
+    Ok(())
+}
+
+fn shortest(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    a.into_iter().chain(b).min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::{io::Write, net::TcpListener};
+
+    fn args(extra: &[&str], port: u16) -> Args {
+        let port = port.to_string();
+        let mut argv = vec!["test", "--wait-for", "--retry-interval", "50ms"];
+        argv.extend_from_slice(extra);
+        argv.extend(["127.0.0.1", port.as_str()]);
+        Args::parse_from(argv)
+    }
+
+    #[test]
+    fn test_waits_until_the_banner_arrives() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            for greeting in [&b"starting up\n"[..], b"ready for connections\n"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.write_all(greeting).unwrap();
+            }
+        });
+        let args = args(&["--wait-for-banner", "ready", "--max-time", "10"], port);
+        run(&args, Some(Duration::from_secs(1))).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_gives_up_after_max_time() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let started = Instant::now();
+        let err = run(&args(&["--max-time", "1"], port), None).unwrap_err();
+        assert!(err.to_string().contains("--max-time"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,702 @@
+//! WebSocket (RFC 6455) for `--ws`, as a client or with `--listen` as a server.
+//!
+//! After the HTTP upgrade, every line written goes out as a text message, or
+//! with `--ws-binary` every write as a binary message. Received text messages
+//! are read back one per line and binary ones as they are. Pings are
+//! answered, and a close frame from the peer reads as the end of the stream,
+//! answered with ours once we are done sending.
+
+use crate::args::WsUrl;
+use crate::encoding::base64;
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Appended to the client's key to derive the accept header.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The longest request or response header we read before giving up.
+const MAX_HEADER: usize = 16 * 1024;
+/// The largest frame we take in, and the longest line sent as one message.
+const MAX_FRAME: usize = 16 << 20;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// The close code for a session that ended as planned.
+const NORMAL_CLOSURE: u16 = 1000;
+
+/// Which end of the connection we are; only clients mask their frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    fn peer(self) -> &'static str {
+        match self {
+            Role::Client => "server",
+            Role::Server => "client",
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, ACCEPT_GUID).as_bytes(),
+    );
+    base64(digest.as_ref())
+}
+
+/// Reads an HTTP header up to the blank line, a byte at a time so that no
+/// frame is taken along with it.
+fn read_header<R: Read>(reader: &mut R, what: &str) -> Result<String> {
+    let mut header = Vec::new();
+    let mut byte = [0u8; 1];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() == MAX_HEADER {
+            bail!("The {} is too long", what);
+        }
+        if reader.read(&mut byte)? == 0 {
+            bail!("The connection closed in the middle of the {}", what);
+        }
+        header.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&header).into_owned())
+}
+
+/// The value of the header field `name`, if present.
+fn field<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.split("\r\n").skip(1).find_map(|line| {
+        let (field, value) = line.split_once(':')?;
+        field
+            .trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim())
+    })
+}
+
+/// Whether the comma-separated header field `name` lists `token`.
+fn field_lists(header: &str, name: &str, token: &str) -> bool {
+    field(header, name).is_some_and(|value| {
+        value
+            .split(',')
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// Sends the upgrade request and checks the server's answer, leaving the
+/// connection at the first frame.
+pub fn connect<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    url: &WsUrl,
+    headers: &[String],
+) -> Result<()> {
+    let mut nonce = [0u8; 16];
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("No randomness for --ws: {}", e))?;
+    let key = base64(&nonce);
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        url.resource,
+        url.host_header(),
+        key
+    );
+    for header in headers {
+        request.push_str(header);
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    writer.write_all(request.as_bytes())?;
+    writer.flush()?;
+
+    let response = read_header(reader, "response to the WebSocket upgrade")?;
+    let status = response.split("\r\n").next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("101") {
+        bail!("The server refused the WebSocket upgrade: {}", status);
+    }
+    if !field_lists(&response, "Upgrade", "websocket") {
+        bail!("The server answered the WebSocket upgrade without switching to WebSocket");
+    }
+    if field(&response, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        bail!("The server's Sec-WebSocket-Accept does not match our key");
+    }
+    info!("WebSocket connection to {} established", url);
+    Ok(())
+}
+
+/// Answers a client's upgrade request, turning away anything but a WebSocket
+/// upgrade (for `url`'s resource, when given) with an HTTP error.
+pub fn accept<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    url: Option<&WsUrl>,
+) -> Result<()> {
+    let request = read_header(reader, "WebSocket upgrade request")?;
+    let request_line = request.split("\r\n").next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target) = (parts.next(), parts.next());
+    let key = field(&request, "Sec-WebSocket-Key");
+    let refusal = if method != Some("GET")
+        || !field_lists(&request, "Upgrade", "websocket")
+        || !field_lists(&request, "Connection", "upgrade")
+        || key.is_none()
+    {
+        Some(("400 Bad Request", ""))
+    } else if field(&request, "Sec-WebSocket-Version") != Some("13") {
+        Some(("426 Upgrade Required", "Sec-WebSocket-Version: 13\r\n"))
+    } else if url.is_some_and(|url| Some(url.resource.as_str()) != target) {
+        Some(("404 Not Found", ""))
+    } else {
+        None
+    };
+    if let Some((status, extra)) = refusal {
+        let body = "This port only speaks WebSocket.\n";
+        let response = format!(
+            "HTTP/1.1 {}\r\n{}Content-Type: text/plain\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            extra,
+            body.len(),
+            body
+        );
+        // The client hears the refusal if it can; the error is ours to report.
+        let _ = writer.write_all(response.as_bytes());
+        let _ = writer.flush();
+        bail!(
+            "Refused a request that is not a WebSocket upgrade ({}): {}",
+            status,
+            request_line
+        );
+    }
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key.unwrap_or_default())
+    );
+    writer.write_all(response.as_bytes())?;
+    writer.flush()?;
+    info!("WebSocket upgrade for {} accepted", target.unwrap_or("/"));
+    Ok(())
+}
+
+/// Encodes a final frame, masked when a client sends it.
+fn encode_frame(opcode: u8, payload: &[u8], role: Role) -> io::Result<Vec<u8>> {
+    let mask_bit = if role == Role::Client { 0x80 } else { 0 };
+    let mut out = Vec::with_capacity(payload.len() + 14);
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => out.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    if role == Role::Server {
+        out.extend_from_slice(payload);
+        return Ok(out);
+    }
+    let mut mask = [0u8; 4];
+    getrandom::getrandom(&mut mask).map_err(|e| io::Error::other(e.to_string()))?;
+    out.extend_from_slice(&mask);
+    out.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    Ok(out)
+}
+
+/// A decoded frame.
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Decodes the frame at the start of `data` as received by `role`, returning
+/// it and its encoded length, or None while it is incomplete.
+fn decode_frame(data: &[u8], role: Role) -> io::Result<Option<(Frame, usize)>> {
+    let peer = role.peer();
+    let [first, second, ..] = *data else {
+        return Ok(None);
+    };
+    if first & 0x70 != 0 {
+        return Err(invalid_data(format!(
+            "The {} set reserved WebSocket frame bits without negotiating an extension",
+            peer
+        )));
+    }
+    let masked = second & 0x80 != 0;
+    if masked != (role == Role::Server) {
+        return Err(invalid_data(match role {
+            Role::Client => "The server sent a masked WebSocket frame".to_string(),
+            Role::Server => "The client sent an unmasked WebSocket frame".to_string(),
+        }));
+    }
+    let (len, mut offset) = match second & 0x7f {
+        126 if data.len() < 4 => return Ok(None),
+        126 => (u64::from(u16::from_be_bytes([data[2], data[3]])), 4),
+        127 if data.len() < 10 => return Ok(None),
+        127 => (u64::from_be_bytes(data[2..10].try_into().unwrap()), 10),
+        len => (u64::from(len), 2),
+    };
+    let opcode = first & 0x0f;
+    if opcode >= CLOSE && (len > 125 || first & 0x80 == 0) {
+        return Err(invalid_data(format!(
+            "The {} sent a fragmented or oversized WebSocket control frame",
+            peer
+        )));
+    }
+    if len > MAX_FRAME as u64 {
+        return Err(invalid_data(format!(
+            "The {} sent a {} byte WebSocket frame, more than the {} MiB we accept",
+            peer,
+            len,
+            MAX_FRAME >> 20
+        )));
+    }
+    let mask = if masked {
+        let Some(mask) = data.get(offset..offset + 4) else {
+            return Ok(None);
+        };
+        offset += 4;
+        Some([mask[0], mask[1], mask[2], mask[3]])
+    } else {
+        None
+    };
+    let end = offset + len as usize;
+    let Some(payload) = data.get(offset..end) else {
+        return Ok(None);
+    };
+    let payload = match mask {
+        Some(mask) => payload
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(b, m)| b ^ m)
+            .collect(),
+        None => payload.to_vec(),
+    };
+    Ok(Some((
+        Frame {
+            fin: first & 0x80 != 0,
+            opcode,
+            payload,
+        },
+        end,
+    )))
+}
+
+/// The sending side, shared so that the reading half can answer pings and
+/// close frames.
+struct Shared<W> {
+    writer: Mutex<W>,
+    role: Role,
+    close_sent: AtomicBool,
+}
+
+impl<W: Write> Shared<W> {
+    fn send(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let frame = encode_frame(opcode, payload, self.role)?;
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&frame)?;
+        writer.flush()
+    }
+
+    /// Sends a close frame unless one went out already.
+    fn send_close(&self, code: Option<u16>) -> io::Result<()> {
+        if self.close_sent.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let payload = code.map_or(Vec::new(), |code| code.to_be_bytes().to_vec());
+        self.send(CLOSE, &payload)
+    }
+}
+
+/// Splits an upgraded connection into its message-reading and
+/// message-writing halves.
+pub fn split<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    role: Role,
+    binary: bool,
+) -> (WsReader<R, W>, WsWriter<W>) {
+    let shared = Arc::new(Shared {
+        writer: Mutex::new(writer),
+        role,
+        close_sent: AtomicBool::new(false),
+    });
+    let reader = WsReader {
+        inner: reader,
+        shared: shared.clone(),
+        received: Vec::new(),
+        ready: Vec::new(),
+        position: 0,
+        message: None,
+        closed: None,
+    };
+    let writer = WsWriter {
+        shared,
+        binary,
+        line: Vec::new(),
+        lines: 0,
+    };
+    (reader, writer)
+}
+
+/// Reads the payload of received messages, a newline after each text one.
+pub struct WsReader<R, W> {
+    inner: R,
+    shared: Arc<Shared<W>>,
+    /// Received bytes that do not make up a whole frame yet.
+    received: Vec<u8>,
+    /// Decoded data not read yet, from `position` on.
+    ready: Vec<u8>,
+    position: usize,
+    /// The opcode of the message whose fragments are arriving.
+    message: Option<u8>,
+    /// The code of the peer's close frame, once one arrived.
+    closed: Option<Option<u16>>,
+}
+
+impl<R, W> WsReader<R, W> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Read, W: Write> WsReader<R, W> {
+    /// Answers the peer's close frame with its own code when nothing more
+    /// will be sent, completing the closing handshake.
+    pub fn close(&self) -> io::Result<()> {
+        match self.closed {
+            Some(code) => self.shared.send_close(code),
+            None => Ok(()),
+        }
+    }
+
+    /// Handles one frame, leaving any message data in `ready`.
+    fn handle(&mut self, frame: Frame) -> io::Result<()> {
+        let peer = self.shared.role.peer();
+        match frame.opcode {
+            TEXT | BINARY | CONTINUATION => {
+                let opcode = match (frame.opcode, self.message) {
+                    (CONTINUATION, Some(opcode)) => opcode,
+                    (CONTINUATION, None) => {
+                        return Err(invalid_data(format!(
+                            "The {} continued a WebSocket message it never started",
+                            peer
+                        )))
+                    }
+                    (_, Some(_)) => {
+                        return Err(invalid_data(format!(
+                            "The {} started a WebSocket message inside another",
+                            peer
+                        )))
+                    }
+                    (opcode, None) => opcode,
+                };
+                self.ready = frame.payload;
+                self.position = 0;
+                self.message = (!frame.fin).then_some(opcode);
+                if frame.fin && opcode == TEXT {
+                    self.ready.push(b'\n');
+                }
+            }
+            PING => self.shared.send(PONG, &frame.payload)?,
+            PONG => {}
+            CLOSE => {
+                let code = frame
+                    .payload
+                    .get(..2)
+                    .map(|code| u16::from_be_bytes([code[0], code[1]]));
+                let reason = String::from_utf8_lossy(frame.payload.get(2..).unwrap_or_default());
+                match code {
+                    Some(code) if reason.is_empty() => {
+                        info!("The {} closed the WebSocket with code {}", peer, code)
+                    }
+                    Some(code) => info!(
+                        "The {} closed the WebSocket with code {}: {}",
+                        peer, code, reason
+                    ),
+                    None => info!("The {} closed the WebSocket without a code", peer),
+                }
+                // Our own close frame follows once we are done sending, like
+                // the second half of a TCP half-close.
+                self.closed = Some(code);
+            }
+            opcode => {
+                return Err(invalid_data(format!(
+                    "The {} sent an unknown WebSocket opcode {:#x}",
+                    peer, opcode
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read, W: Write> Read for WsReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.ready.len() {
+                let n = buf.len().min(self.ready.len() - self.position);
+                buf[..n].copy_from_slice(&self.ready[self.position..self.position + n]);
+                self.position += n;
+                return Ok(n);
+            }
+            if self.closed.is_some() {
+                return Ok(0);
+            }
+            if let Some((frame, len)) = decode_frame(&self.received, self.shared.role)? {
+                self.received.drain(..len);
+                self.handle(frame)?;
+                continue;
+            }
+            // A partial frame stays buffered if the read times out.
+            let mut chunk = [0u8; 16 * 1024];
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "The {} closed the connection without a WebSocket close frame",
+                        self.shared.role.peer()
+                    ),
+                ));
+            }
+            self.received.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+/// Sends what is written as messages: one text message per line, or one
+/// binary message per write.
+pub struct WsWriter<W> {
+    shared: Arc<Shared<W>>,
+    binary: bool,
+    /// The start of a line still waiting for its end.
+    line: Vec<u8>,
+    lines: u64,
+}
+
+impl<W: Write> WsWriter<W> {
+    fn send_line(&mut self, line: &[u8]) -> io::Result<()> {
+        self.lines += 1;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if std::str::from_utf8(line).is_err() {
+            return Err(invalid_data(format!(
+                "Input line {} is not valid UTF-8, which text messages must be; use --ws-binary",
+                self.lines
+            )));
+        }
+        self.shared.send(TEXT, line)
+    }
+
+    /// Sends what is left of the last line and starts the closing handshake.
+    pub fn close(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.send_line(&line)?;
+        }
+        self.shared.send_close(Some(NORMAL_CLOSURE))
+    }
+}
+
+impl<W: Write> Write for WsWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.shared.close_sent.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The WebSocket is closed",
+            ));
+        }
+        if self.binary {
+            self.shared.send(BINARY, buf)?;
+            return Ok(buf.len());
+        }
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            let mut line = std::mem::take(&mut self.line);
+            line.extend_from_slice(&rest[..end]);
+            self.send_line(&line)?;
+            rest = &rest[end + 1..];
+        }
+        if self.line.len() + rest.len() > MAX_FRAME {
+            return Err(invalid_data(format!(
+                "Input line {} is longer than {} MiB; use --ws-binary",
+                self.lines + 1,
+                MAX_FRAME >> 20
+            )));
+        }
+        self.line.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Cursor};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn decode_all(mut data: &[u8], role: Role) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some((frame, len)) = decode_frame(data, role).unwrap() {
+            frames.push(frame);
+            data = &data[len..];
+        }
+        assert!(data.is_empty());
+        frames
+    }
+
+    #[test]
+    fn test_accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_frames_round_trip_with_masking_by_role() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let sent = encode_frame(BINARY, &payload, Role::Client).unwrap();
+            assert_eq!(sent[1] & 0x80, 0x80);
+            let (frame, used) = decode_frame(&sent, Role::Server).unwrap().unwrap();
+            assert_eq!(used, sent.len());
+            assert_eq!(frame.payload, payload);
+            // A frame missing its last byte is merely incomplete.
+            assert!(decode_frame(&sent[..sent.len() - 1], Role::Server)
+                .unwrap()
+                .is_none());
+
+            let sent = encode_frame(TEXT, &payload, Role::Server).unwrap();
+            assert_eq!(sent[1] & 0x80, 0);
+            let (frame, _) = decode_frame(&sent, Role::Client).unwrap().unwrap();
+            assert_eq!((frame.fin, frame.opcode), (true, TEXT));
+            assert_eq!(frame.payload, payload);
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_wrong_masking_and_bad_control_frames() {
+        let masked = encode_frame(TEXT, b"x", Role::Client).unwrap();
+        assert!(decode_frame(&masked, Role::Client).is_err());
+        let unmasked = encode_frame(TEXT, b"x", Role::Server).unwrap();
+        assert!(decode_frame(&unmasked, Role::Server).is_err());
+        // A ping without FIN, and a frame with reserved bits set.
+        assert!(decode_frame(&[PING, 0], Role::Client).is_err());
+        assert!(decode_frame(&[0xc0 | TEXT, 0], Role::Client).is_err());
+    }
+
+    #[test]
+    fn test_reader_joins_fragments_and_answers_pings() {
+        let mut received = Vec::new();
+        for (fin, opcode, payload) in [
+            (false, TEXT, &b"hel"[..]),
+            (true, PING, b"p"),
+            (true, CONTINUATION, b"lo"),
+            (true, BINARY, b"\x00\x01"),
+            (true, CLOSE, &[0x03, 0xe8]),
+        ] {
+            let mut frame = encode_frame(opcode, payload, Role::Client).unwrap();
+            frame[0] = u8::from(fin) << 7 | opcode;
+            received.extend(frame);
+        }
+        let (mut reader, _writer) = split(Cursor::new(received), Vec::new(), Role::Server, false);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"hello\n\x00\x01");
+        reader.close().unwrap();
+
+        let written = reader.shared.writer.lock().unwrap();
+        let frames = decode_all(&written, Role::Client);
+        let opcodes: Vec<u8> = frames.iter().map(|frame| frame.opcode).collect();
+        assert_eq!(opcodes, [PONG, CLOSE]);
+        assert_eq!(frames[1].payload, [0x03, 0xe8]);
+    }
+
+    #[test]
+    fn test_writer_sends_lines_as_text_messages() {
+        let (_reader, mut writer) = split(io::empty(), Vec::new(), Role::Server, false);
+        writer.write_all(b"one\r\ntw").unwrap();
+        writer.write_all(b"o\nthree").unwrap();
+        writer.close().unwrap();
+        let written = writer.shared.writer.lock().unwrap();
+        let frames = decode_all(&written, Role::Client);
+        let payloads: Vec<&[u8]> = frames.iter().map(|frame| &frame.payload[..]).collect();
+        assert_eq!(payloads, [&b"one"[..], b"two", b"three", &[0x03, 0xe8]]);
+
+        let (_reader, mut writer) = split(io::empty(), Vec::new(), Role::Client, false);
+        let err = writer.write_all(b"\xff\n").unwrap_err();
+        assert!(err.to_string().contains("--ws-binary"), "{}", err);
+    }
+
+    #[test]
+    fn test_reader_fails_without_close_frame() {
+        let frame = encode_frame(TEXT, b"x", Role::Server).unwrap();
+        let (mut reader, _writer) = split(Cursor::new(frame), io::sink(), Role::Client, true);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("close frame"), "{}", err);
+    }
+
+    #[test]
+    fn test_server_accepts_upgrades_and_refuses_plain_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let url = WsUrl {
+            secure: false,
+            host: "127.0.0.1".to_string(),
+            port,
+            resource: "/chat".to_string(),
+        };
+        let server_url = url.clone();
+        let server = thread::spawn(move || {
+            let mut results = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut writer = stream.try_clone().unwrap();
+                results.push(accept(&mut stream, &mut writer, Some(&server_url)).is_ok());
+            }
+            results
+        });
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        connect(&mut stream, &mut writer, &url, &["X-Test: 1".to_string()]).unwrap();
+
+        // A plain HTTP request gets an HTTP answer rather than frames.
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 400 Bad Request\r\n");
+        assert_eq!(server.join().unwrap(), [true, false]);
+    }
+}